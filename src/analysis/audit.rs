@@ -0,0 +1,233 @@
+// Audit trail of privileged state changes
+// Records every change a step makes to memmap, the interrupt mask, and the privileged flags
+// (interrupt enable, user ring, memory map enable, and shadow stack), along with the address of
+// the instruction or interrupt that made it. Only the most recent changes are kept. Changes made
+// by the host between steps are not recorded.
+
+use std::collections::VecDeque;
+
+use crate::{Address, Cpu, F_INTERRUPT_ENABLE, F_MEMMAP_ENABLE, F_SHADOW_STACK, F_USER_RING, R_PC};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditChange {
+    InterruptEnable(bool),
+    UserRing(bool),
+    MemmapEnable(bool),
+    ShadowStack(bool),
+    Memmap { old: u32, new: u32 },
+    InterruptMask { old: u8, new: u8 },
+}
+
+type FlagChange = fn(bool) -> AuditChange;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AuditEntry {
+    // Cycle of the step that made the change
+    pub cycle: u64,
+
+    // Address of the instruction, or where the interrupt was taken
+    pub pc: u32,
+
+    pub change: AuditChange,
+}
+
+// Privileged state compared before and after each step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PrivilegedState {
+    pub flags: u32,
+    pub memmap: u32,
+    pub interrupt_mask: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditTrail {
+    entries: VecDeque<AuditEntry>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl AuditTrail {
+    // Panics if capacity is 0
+    pub fn new(capacity: usize) -> AuditTrail {
+        assert!(capacity != 0, "audit trail must hold at least one entry");
+        AuditTrail {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    // Oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter()
+    }
+
+    // Most recent change matching a predicate, eg the last time the memory map was disabled
+    pub fn last<F>(&self, predicate: F) -> Option<&AuditEntry>
+    where
+        F: Fn(&AuditChange) -> bool,
+    {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| predicate(&entry.change))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Number of entries discarded to make room for newer ones
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.dropped = 0;
+    }
+
+    fn push(&mut self, entry: AuditEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        cycle: u64,
+        pc: u32,
+        old: PrivilegedState,
+        new: PrivilegedState,
+    ) {
+        let mut push = |change| self.push(AuditEntry { cycle, pc, change });
+
+        let flags: [(u32, FlagChange); 4] = [
+            (F_INTERRUPT_ENABLE, AuditChange::InterruptEnable),
+            (F_USER_RING, AuditChange::UserRing),
+            (F_MEMMAP_ENABLE, AuditChange::MemmapEnable),
+            (F_SHADOW_STACK, AuditChange::ShadowStack),
+        ];
+        for &(bit, change) in flags.iter() {
+            let set = new.flags & (1 << bit) != 0;
+            if (old.flags & (1 << bit) != 0) != set {
+                push(change(set));
+            }
+        }
+        if old.memmap != new.memmap {
+            push(AuditChange::Memmap {
+                old: old.memmap,
+                new: new.memmap,
+            });
+        }
+        if old.interrupt_mask != new.interrupt_mask {
+            push(AuditChange::InterruptMask {
+                old: old.interrupt_mask,
+                new: new.interrupt_mask,
+            });
+        }
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_audit_trail(&mut self, trail: Option<AuditTrail>) {
+        self.audit = trail;
+    }
+
+    pub fn audit_trail(&self) -> Option<&AuditTrail> {
+        self.audit.as_ref()
+    }
+
+    pub fn audit_trail_mut(&mut self) -> Option<&mut AuditTrail> {
+        self.audit.as_mut()
+    }
+
+    pub(crate) fn privileged_state(&self) -> PrivilegedState {
+        PrivilegedState {
+            flags: self.flags,
+            memmap: self.mmu.root(),
+            interrupt_mask: self.interrupt_mask,
+        }
+    }
+
+    pub(crate) fn start_audit(&self) -> Option<(u32, PrivilegedState)> {
+        self.audit
+            .as_ref()
+            .map(|_| (self.xs[R_PC], self.privileged_state()))
+    }
+
+    pub(crate) fn finish_audit(&mut self, cycle: u64, start: Option<(u32, PrivilegedState)>) {
+        let new = self.privileged_state();
+        if let (Some(audit), Some((pc, old))) = (self.audit.as_mut(), start) {
+            audit.record(cycle, pc, old, new);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn bounded() {
+        let mut trail = AuditTrail::new(2);
+        let old = PrivilegedState {
+            flags: 0,
+            memmap: 0,
+            interrupt_mask: 0,
+        };
+        let new = PrivilegedState {
+            flags: 1 << F_MEMMAP_ENABLE,
+            memmap: 0x100,
+            interrupt_mask: 0xff,
+        };
+        trail.record(3, 0x40, old, new);
+        assert_eq!(trail.len(), 2);
+        assert_eq!(trail.dropped(), 1);
+        assert_eq!(
+            trail.entries().next(),
+            Some(&AuditEntry {
+                cycle: 3,
+                pc: 0x40,
+                change: AuditChange::Memmap { old: 0, new: 0x100 }
+            })
+        );
+    }
+
+    #[test]
+    fn who_disabled_the_memory_map() {
+        // Identity map the first 64K read, write, and execute
+        let mut memory = SimpleAddress::default();
+        memory.load(0x100, &0x200u32.to_le_bytes());
+        memory.load(0x200, &0xf0000000u32.to_le_bytes());
+
+        // load x0 <- 0; move flags <- x0
+        memory.load(0x1000, &[0x40, 0x00, 0x00, 0x00, 0x00, 0x9a, 0x00]);
+
+        let mut cpu = Cpu::new(memory);
+        cpu.set_audit_trail(Some(AuditTrail::new(16)));
+        cpu.mmu.set_root(0x100);
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+        cpu.xs[R_PC] = 0x1000;
+        cpu.step();
+        cpu.step();
+
+        let entry = cpu
+            .audit_trail()
+            .unwrap()
+            .last(|change| *change == AuditChange::MemmapEnable(false))
+            .unwrap();
+        assert_eq!(entry.pc, 0x1005);
+        assert_eq!(entry.cycle, 1);
+    }
+}
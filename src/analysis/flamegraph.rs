@@ -0,0 +1,68 @@
+// Flamegraph export
+// Writes the call stacks of a call graph profile in the collapsed stack format read by
+// flamegraph.pl and compatible tools. Each line is a stack of function names separated by
+// semicolons, outermost first, then a space and the exclusive cycles spent in that stack.
+
+use crate::analysis::CallGraphProfile;
+use crate::symbols::SymbolTable;
+
+pub fn collapsed_stacks(profile: &CallGraphProfile, symbols: &SymbolTable) -> String {
+    let mut out = String::new();
+    for (stack, cycles) in profile.stacks() {
+        let names: Vec<String> = stack
+            .iter()
+            .map(|&addr| frame_name(symbols, addr))
+            .collect();
+        out += &format!("{} {}\n", names.join(";"), cycles);
+    }
+    out
+}
+
+// Semicolons and spaces separate frames and counts, so they cannot appear in names
+fn frame_name(symbols: &SymbolTable, addr: u32) -> String {
+    symbols.describe(addr).replace(&[';', ' '][..], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Instruction, Operands};
+    use crate::object::Object;
+    use crate::{Cpu, SimpleAddress, R_PC, R_SP};
+
+    #[test]
+    fn collapsed() {
+        // main:     call f
+        //           call g
+        // f:        call g
+        //           ret
+        // g weird:  ret
+        let mut object = Object::new(0x1000);
+        let push = |object: &mut Object, opcode, operands| {
+            object.push(&Instruction { opcode, operands });
+        };
+        object.define("main");
+        push(&mut object, 0x18, Operands::Word(0x100a));
+        push(&mut object, 0x18, Operands::Word(0x1010));
+        object.define("f");
+        push(&mut object, 0x18, Operands::Word(0x1010));
+        push(&mut object, 0x19, Operands::None);
+        object.define("g weird");
+        push(&mut object, 0x19, Operands::None);
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_PC] = object.base;
+        cpu.set_call_graph_profile(Some(CallGraphProfile::new()));
+        for _ in 0..6 {
+            cpu.step();
+        }
+
+        let profile = cpu.call_graph_profile().unwrap();
+        assert_eq!(
+            collapsed_stacks(profile, &object.symbols),
+            "main 2\nmain;f 2\nmain;f;g_weird 1\nmain;g_weird 1\n"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+// Stack frame integrity checking
+// Every call records the frame it pushed and ret checks that it pops the same frame, with the base
+// pointer where call left it. Writes by other instructions to the saved base pointer and return
+// address of a live frame are remembered so a corrupted frame can be blamed on the instruction that
+// overwrote it. Addresses are virtual.
+
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError {
+    // The base pointer at ret does not point at the frame pushed by the matching call
+    BaseMismatch {
+        expected: u32,
+        actual: u32,
+    },
+
+    // The saved return address or base pointer was overwritten, by the instruction at
+    // corrupted_by if the write was seen
+    Corrupted {
+        expected_pc: u32,
+        actual_pc: u32,
+        corrupted_by: Option<u32>,
+    },
+
+    // ret without a matching call
+    Unmatched,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameViolation {
+    // Address of the ret instruction
+    pub pc: u32,
+
+    // Address of the call instruction that pushed the frame
+    pub call_pc: Option<u32>,
+
+    pub error: FrameError,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    call_pc: u32,
+
+    // Base pointer after the call
+    base: u32,
+
+    // Lowest address of the saved return address and base pointer
+    addr: u32,
+
+    return_pc: u32,
+    saved_base: u32,
+    corrupted_by: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FrameChecker {
+    frames: Vec<Frame>,
+    violations: Vec<FrameViolation>,
+    trap: bool,
+}
+
+impl FrameChecker {
+    pub fn trap(mut self, trap: bool) -> FrameChecker {
+        self.trap = trap;
+        self
+    }
+
+    pub fn violations(&self) -> &[FrameViolation] {
+        &self.violations
+    }
+
+    // Number of calls that have not returned yet
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub(crate) fn on_call(
+        &mut self,
+        call_pc: u32,
+        base: u32,
+        addr: u32,
+        return_pc: u32,
+        saved_base: u32,
+    ) {
+        self.frames.push(Frame {
+            call_pc,
+            base,
+            addr,
+            return_pc,
+            saved_base,
+            corrupted_by: None,
+        });
+    }
+
+    pub(crate) fn on_write(&mut self, pc: u32, addr: u32) {
+        for frame in self.frames.iter_mut().rev() {
+            if addr.wrapping_sub(frame.addr) < 8 {
+                frame.corrupted_by.get_or_insert(pc);
+            }
+        }
+    }
+
+    // Returns whether the violation should trap
+    pub(crate) fn on_ret(&mut self, pc: u32, base: u32, return_pc: u32, saved_base: u32) -> bool {
+        let frame = match self.frames.pop() {
+            Some(frame) => frame,
+            None => {
+                self.violations.push(FrameViolation {
+                    pc,
+                    call_pc: None,
+                    error: FrameError::Unmatched,
+                });
+                return self.trap;
+            }
+        };
+
+        let error = if base != frame.base {
+            FrameError::BaseMismatch {
+                expected: frame.base,
+                actual: base,
+            }
+        } else if return_pc != frame.return_pc || saved_base != frame.saved_base {
+            FrameError::Corrupted {
+                expected_pc: frame.return_pc,
+                actual_pc: return_pc,
+                corrupted_by: frame.corrupted_by,
+            }
+        } else {
+            return false;
+        };
+
+        self.violations.push(FrameViolation {
+            pc,
+            call_pc: Some(frame.call_pc),
+            error,
+        });
+        self.trap
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_frame_checker(&mut self, checker: Option<FrameChecker>) {
+        self.frames = checker;
+    }
+
+    pub fn frame_checker(&self) -> Option<&FrameChecker> {
+        self.frames.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvalidMemoryAccess, SimpleAddress, R_PC, R_SP};
+
+    fn machine(program: &[(u32, &[u8])]) -> Cpu<SimpleAddress> {
+        let mut memory = SimpleAddress::default();
+        for (start, bytes) in program.iter() {
+            memory.load(*start, bytes);
+        }
+        let mut cpu = Cpu::new(memory);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.set_frame_checker(Some(FrameChecker::default().trap(true)));
+        cpu
+    }
+
+    #[test]
+    fn balanced() {
+        // call 0x100; at 0x100: ret
+        let mut cpu = machine(&[(0, &[0x18, 0x00, 0x01, 0, 0]), (0x100, &[0x19])]);
+        cpu.step();
+        assert_eq!(cpu.frame_checker().unwrap().depth(), 1);
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 5);
+        assert_eq!(cpu.frame_checker().unwrap().depth(), 0);
+        assert!(cpu.frame_checker().unwrap().violations().is_empty());
+    }
+
+    #[test]
+    fn corrupted_return_address() {
+        // call 0x100; at 0x100: x0 = 0; store x0 -> [0x7ff8]; ret
+        let mut cpu = machine(&[
+            (0, &[0x18, 0x00, 0x01, 0, 0]),
+            (0x100, &[0x40, 0, 0, 0, 0, 0xc0, 0xf8, 0x7f, 0, 0, 0x19]),
+        ]);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert!(matches!(
+            cpu.decode_instruction(),
+            Err(InvalidMemoryAccess::CorruptedFrame)
+        ));
+        assert_eq!(
+            cpu.frame_checker().unwrap().violations(),
+            &[FrameViolation {
+                pc: 0x10a,
+                call_pc: Some(0),
+                error: FrameError::Corrupted {
+                    expected_pc: 5,
+                    actual_pc: 0,
+                    corrupted_by: Some(0x105),
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn moved_base() {
+        // call 0x100; at 0x100: ret, with the base pointer moved in between
+        let mut cpu = machine(&[(0, &[0x18, 0x00, 0x01, 0, 0]), (0x100, &[0x19])]);
+        cpu.step();
+        cpu.xs[crate::R_BASE] -= 4;
+        assert!(cpu.decode_instruction().is_err());
+        assert_eq!(
+            cpu.frame_checker().unwrap().violations()[0].error,
+            FrameError::BaseMismatch {
+                expected: 0x7ff8,
+                actual: 0x7ff4
+            }
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// Hotspot reports
+// A histogram of how many times each instruction was executed, grouped by the symbol containing it
+// and disassembled, for a quick look at where a guest spends its time. Interrupt deliveries are not
+// counted. Instructions are disassembled from memory when the report is made, so code that was
+// overwritten since is shown as it is now.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::decode;
+use crate::symbols::SymbolTable;
+use crate::{Address, Cpu};
+
+#[derive(Debug, Default, Clone)]
+pub struct PcHistogram {
+    counts: HashMap<u32, u64>,
+    total: u64,
+}
+
+impl PcHistogram {
+    pub fn new() -> PcHistogram {
+        PcHistogram::default()
+    }
+
+    pub fn count(&self, pc: u32) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // Lowest address first
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u64)> {
+        let mut counts: Vec<_> = self.counts.iter().map(|(&pc, &n)| (pc, n)).collect();
+        counts.sort_unstable();
+        counts.into_iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+        self.total = 0;
+    }
+
+    pub(crate) fn record(&mut self, pc: u32) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        self.total += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstructionHotspot {
+    pub pc: u32,
+    pub samples: u64,
+
+    // None if the instruction could not be read
+    pub disassembly: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionHotspot {
+    // None for instructions that no symbol covers
+    pub name: Option<String>,
+    pub samples: u64,
+
+    // Lowest address first
+    pub instructions: Vec<InstructionHotspot>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotspotReport {
+    pub total: u64,
+
+    // Most samples first
+    pub functions: Vec<FunctionHotspot>,
+}
+
+impl fmt::Display for HotspotReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        for function in self.functions.iter() {
+            let percent = 100.0 * function.samples as f64 / self.total.max(1) as f64;
+            writeln!(
+                f,
+                "{} ({} samples, {:.1}%)",
+                function.name.as_deref().unwrap_or("??"),
+                function.samples,
+                percent
+            )?;
+            for instruction in function.instructions.iter() {
+                writeln!(
+                    f,
+                    "  {:#010x} {:>8}  {}",
+                    instruction.pc,
+                    instruction.samples,
+                    instruction.disassembly.as_deref().unwrap_or("??")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_pc_histogram(&mut self, histogram: Option<PcHistogram>) {
+        self.histogram = histogram;
+    }
+
+    pub fn pc_histogram(&self) -> Option<&PcHistogram> {
+        self.histogram.as_ref()
+    }
+
+    pub fn pc_histogram_mut(&mut self) -> Option<&mut PcHistogram> {
+        self.histogram.as_mut()
+    }
+
+    // The n functions with the most samples, None without a pc histogram
+    pub fn report_hotspots(&mut self, symbols: &SymbolTable, n: usize) -> Option<HotspotReport> {
+        let histogram = self.histogram.as_ref()?;
+        let total = histogram.total();
+        let mut functions: HashMap<Option<u32>, FunctionHotspot> = HashMap::new();
+        for (pc, samples) in histogram.iter().collect::<Vec<_>>() {
+            let symbol = symbols.lookup(pc).map(|s| s.symbol);
+            let function = functions
+                .entry(symbol.map(|s| s.addr))
+                .or_insert(FunctionHotspot {
+                    name: symbol.map(|s| s.name.clone()),
+                    samples: 0,
+                    instructions: vec![],
+                });
+            function.samples += samples;
+
+            let mut addr = pc;
+            let instruction = decode::decode(4, || {
+                let byte = self.peek(addr).ok_or(());
+                addr = addr.wrapping_add(1);
+                byte
+            });
+            function.instructions.push(InstructionHotspot {
+                pc,
+                samples,
+                disassembly: instruction.ok().map(|i| i.to_string()),
+            });
+        }
+
+        let mut functions: Vec<_> = functions.into_values().collect();
+        functions.sort_by(|a, b| b.samples.cmp(&a.samples).then(a.name.cmp(&b.name)));
+        functions.truncate(n);
+        Some(HotspotReport { total, functions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Instruction, Operands};
+    use crate::object::Object;
+    use crate::{SimpleAddress, R_PC};
+
+    #[test]
+    fn report() {
+        // loop: ldi x1, 1
+        //       bz loop     ; ldi leaves the flags alone, so this never branches
+        // tail: ldi x2, 2
+        let mut object = Object::new(0x1000);
+        object.define("loop");
+        object.push(&Instruction {
+            opcode: 0x41,
+            operands: Operands::RegisterWord(1, 1),
+        });
+        object.push(&Instruction {
+            opcode: 0x00,
+            operands: Operands::Word(0x1000),
+        });
+        object.define("tail");
+        object.push(&Instruction {
+            opcode: 0x42,
+            operands: Operands::RegisterWord(2, 2),
+        });
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[R_PC] = object.base;
+        cpu.set_pc_histogram(Some(PcHistogram::new()));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc_histogram().unwrap().count(0x1005), 1);
+
+        let report = cpu.report_hotspots(&object.symbols, 1).unwrap();
+        assert_eq!(report.total, 3);
+        assert_eq!(
+            report.to_string(),
+            "loop (2 samples, 66.7%)\n  0x00001000        1  ldi x1, 0x1\n  0x00001005        1  bz 0x1000\n"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+// Interrupt latency statistics
+// Cycles are counted in steps. An interrupt is raised when it is requested, queued once it is the
+// pending interrupt that would be taken next (see interrupts.rs), and started on the step its
+// handler is entered.
+
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySample {
+    pub id: u32,
+    pub raised: u64,
+    pub queued: u64,
+    pub started: u64,
+}
+
+impl LatencySample {
+    // Cycles from the request to the handler being entered
+    pub fn latency(&self) -> u64 {
+        self.started - self.raised
+    }
+
+    // Cycles spent behind higher priority pending interrupts
+    pub fn queue_time(&self) -> u64 {
+        self.queued - self.raised
+    }
+
+    // Cycles spent as the next interrupt waiting for interrupts to be enabled
+    pub fn dispatch_time(&self) -> u64 {
+        self.started - self.queued
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct LatencyStats {
+    samples: Vec<LatencySample>,
+}
+
+impl LatencyStats {
+    pub fn samples(&self) -> &[LatencySample] {
+        &self.samples
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub(crate) fn record(&mut self, sample: LatencySample) {
+        self.samples.push(sample);
+    }
+
+    // Nearest rank percentile (0 to 100) of the total latency over all interrupts
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        percentile(self.samples.iter().map(LatencySample::latency).collect(), p)
+    }
+
+    // Nearest rank percentile of the total latency of one interrupt
+    pub fn percentile_for(&self, id: u32, p: f64) -> Option<u64> {
+        percentile(
+            self.samples
+                .iter()
+                .filter(|s| s.id == id)
+                .map(LatencySample::latency)
+                .collect(),
+            p,
+        )
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        self.samples.iter().map(LatencySample::latency).max()
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            let total: u64 = self.samples.iter().map(LatencySample::latency).sum();
+            Some(total as f64 / self.samples.len() as f64)
+        }
+    }
+}
+
+fn percentile(mut values: Vec<u64>, p: f64) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_unstable();
+    let rank = (p.clamp(0.0, 100.0) / 100.0 * values.len() as f64).ceil() as usize;
+    Some(values[rank.max(1) - 1])
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_latency_stats(&mut self, stats: Option<LatencyStats>) {
+        self.latency = stats;
+    }
+
+    pub fn latency_stats(&self) -> Option<&LatencyStats> {
+        self.latency.as_ref()
+    }
+
+    pub fn latency_stats_mut(&mut self) -> Option<&mut LatencyStats> {
+        self.latency.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn percentiles() {
+        let mut stats = LatencyStats::default();
+        for (i, latency) in [5, 1, 4, 2, 3, 10, 6, 8, 7, 9].iter().enumerate() {
+            stats.record(LatencySample {
+                id: i as u32 % 2,
+                raised: 100,
+                queued: 100,
+                started: 100 + latency,
+            });
+        }
+
+        assert_eq!(stats.percentile(0.0), Some(1));
+        assert_eq!(stats.percentile(50.0), Some(5));
+        assert_eq!(stats.percentile(90.0), Some(9));
+        assert_eq!(stats.percentile(100.0), Some(10));
+        assert_eq!(stats.percentile_for(1, 100.0), Some(10));
+        assert_eq!(stats.percentile_for(0, 100.0), Some(7));
+        assert_eq!(stats.max(), Some(10));
+        assert_eq!(stats.mean(), Some(5.5));
+        assert_eq!(LatencyStats::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn delivery() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.set_latency_stats(Some(LatencyStats::default()));
+
+        // Both interrupts wait for interrupts to be enabled, the second also waits behind the first,
+        // which has the higher priority
+        cpu.irq(1);
+        cpu.step();
+        cpu.irq(2);
+        cpu.step();
+        cpu.flags |= 1 << crate::F_INTERRUPT_ENABLE;
+        cpu.step();
+
+        // Entering the first handler disables interrupts
+        cpu.flags |= 1 << crate::F_INTERRUPT_ENABLE;
+        cpu.step();
+
+        assert_eq!(
+            cpu.latency_stats().unwrap().samples(),
+            &[
+                LatencySample {
+                    id: 1,
+                    raised: 0,
+                    queued: 0,
+                    started: 2
+                },
+                LatencySample {
+                    id: 2,
+                    raised: 1,
+                    queued: 3,
+                    started: 3
+                },
+            ]
+        );
+    }
+}
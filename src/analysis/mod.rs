@@ -0,0 +1,20 @@
+// Optional analyses that observe the execution of a cpu
+
+pub mod audit;
+pub mod flamegraph;
+pub mod frames;
+pub mod hotspots;
+pub mod latency;
+pub mod profile;
+pub mod shadow;
+pub mod taint;
+pub mod uninit;
+
+pub use audit::{AuditChange, AuditEntry, AuditTrail};
+pub use frames::{FrameChecker, FrameError, FrameViolation};
+pub use hotspots::{FunctionHotspot, HotspotReport, InstructionHotspot, PcHistogram};
+pub use latency::{LatencySample, LatencyStats};
+pub use profile::{CallGraphProfile, EdgeProfile, FunctionProfile};
+pub use shadow::{MemoryHook, ShadowMemory};
+pub use taint::{TaintSink, TaintTracker, TaintViolation};
+pub use uninit::{UninitChecker, UninitRead};
@@ -0,0 +1,301 @@
+// Call graph profiling
+// Every step is charged to the function on top of a shadow call stack, which call pushes and ret
+// pops. A function is identified by the address its calls jump to, and code executed before the
+// first call is charged to a root function at the address profiling started. Exclusive cycles are
+// spent in the function itself, inclusive cycles also count its callees. Recursive activations
+// are only counted once towards inclusive cycles. Interrupt handlers are charged to the function
+// they interrupted. Exclusive cycles are also kept for every distinct call stack, in a tree of the
+// paths that were taken.
+
+use std::collections::HashMap;
+
+use crate::symbols::SymbolTable;
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FunctionProfile {
+    pub addr: u32,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+impl FunctionProfile {
+    pub fn name(&self, symbols: &SymbolTable) -> String {
+        symbols.describe(self.addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgeProfile {
+    pub caller: u32,
+    pub callee: u32,
+    pub calls: u64,
+
+    // Inclusive cycles of the callee when called from the caller
+    pub cycles: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    function: u32,
+    entered: u64,
+
+    // Index of the call path in the stack tree
+    path: usize,
+}
+
+#[derive(Debug, Clone)]
+struct PathNode {
+    function: u32,
+    parent: Option<usize>,
+    children: HashMap<u32, usize>,
+    cycles: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CallGraphProfile {
+    stack: Vec<Frame>,
+    functions: HashMap<u32, FunctionProfile>,
+    edges: HashMap<(u32, u32), EdgeProfile>,
+    paths: Vec<PathNode>,
+    cycles: u64,
+}
+
+impl CallGraphProfile {
+    pub fn new() -> CallGraphProfile {
+        CallGraphProfile::default()
+    }
+
+    // Cycles profiled
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Functions on the shadow call stack, outermost first
+    pub fn stack(&self) -> Vec<u32> {
+        self.stack.iter().map(|frame| frame.function).collect()
+    }
+
+    // Cycles of activations that have not returned yet are counted up to the last step
+    pub fn function(&self, addr: u32) -> Option<FunctionProfile> {
+        let mut profile = *self.functions.get(&addr)?;
+        if let Some(i) = self.stack.iter().position(|frame| frame.function == addr) {
+            profile.inclusive += self.cycles - self.stack[i].entered;
+        }
+        Some(profile)
+    }
+
+    // Most inclusive cycles first
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: Vec<_> = self
+            .functions
+            .keys()
+            .filter_map(|&addr| self.function(addr))
+            .collect();
+        functions.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.addr.cmp(&b.addr)));
+        functions
+    }
+
+    // Most inclusive cycles first
+    pub fn callers(&self, callee: u32) -> Vec<EdgeProfile> {
+        self.edges_where(|edge| edge.callee == callee)
+    }
+
+    // Most inclusive cycles first
+    pub fn callees(&self, caller: u32) -> Vec<EdgeProfile> {
+        self.edges_where(|edge| edge.caller == caller)
+    }
+
+    fn edges_where<F>(&self, predicate: F) -> Vec<EdgeProfile>
+    where
+        F: Fn(&EdgeProfile) -> bool,
+    {
+        let mut edges: Vec<_> = self.edges.values().copied().filter(predicate).collect();
+        edges.sort_by(|a, b| {
+            b.cycles
+                .cmp(&a.cycles)
+                .then((a.caller, a.callee).cmp(&(b.caller, b.callee)))
+        });
+        edges
+    }
+
+    // Exclusive cycles of each call stack that executed at least one step, outermost function
+    // first
+    pub fn stacks(&self) -> Vec<(Vec<u32>, u64)> {
+        let mut stacks = vec![];
+        for node in self.paths.iter().filter(|node| node.cycles != 0) {
+            let mut stack = vec![node.function];
+            let mut parent = node.parent;
+            while let Some(i) = parent {
+                stack.push(self.paths[i].function);
+                parent = self.paths[i].parent;
+            }
+            stack.reverse();
+            stacks.push((stack, node.cycles));
+        }
+        stacks.sort();
+        stacks
+    }
+
+    pub fn clear(&mut self) {
+        *self = CallGraphProfile::default();
+    }
+
+    fn enter(&mut self, function: u32) {
+        let parent = self.stack.last().map(|frame| frame.path);
+        let existing = match parent {
+            Some(parent) => self.paths[parent].children.get(&function).copied(),
+            None => self.paths.iter().position(|node| node.parent.is_none()),
+        };
+        let path = match existing {
+            Some(path) => path,
+            None => {
+                self.paths.push(PathNode {
+                    function,
+                    parent,
+                    children: HashMap::new(),
+                    cycles: 0,
+                });
+                let path = self.paths.len() - 1;
+                if let Some(parent) = parent {
+                    self.paths[parent].children.insert(function, path);
+                }
+                path
+            }
+        };
+
+        self.stack.push(Frame {
+            function,
+            entered: self.cycles,
+            path,
+        });
+        let profile = self.functions.entry(function).or_insert(FunctionProfile {
+            addr: function,
+            calls: 0,
+            inclusive: 0,
+            exclusive: 0,
+        });
+        profile.calls += 1;
+    }
+
+    pub(crate) fn on_step(&mut self, pc: u32) {
+        if self.stack.is_empty() {
+            self.enter(pc);
+        }
+        self.cycles += 1;
+        let top = *self.stack.last().unwrap();
+        self.functions.get_mut(&top.function).unwrap().exclusive += 1;
+        self.paths[top.path].cycles += 1;
+    }
+
+    pub(crate) fn on_call(&mut self, target: u32) {
+        let caller = match self.stack.last() {
+            Some(frame) => frame.function,
+            None => return,
+        };
+        self.enter(target);
+        let edge = self.edges.entry((caller, target)).or_insert(EdgeProfile {
+            caller,
+            callee: target,
+            calls: 0,
+            cycles: 0,
+        });
+        edge.calls += 1;
+    }
+
+    // A ret from the root function is ignored
+    pub(crate) fn on_ret(&mut self) {
+        if self.stack.len() < 2 {
+            return;
+        }
+        let frame = self.stack.pop().unwrap();
+        let cycles = self.cycles - frame.entered;
+        let caller = self.stack.last().unwrap().function;
+
+        if self.stack.iter().all(|f| f.function != frame.function) {
+            self.functions.get_mut(&frame.function).unwrap().inclusive += cycles;
+        }
+        if let Some(edge) = self.edges.get_mut(&(caller, frame.function)) {
+            edge.cycles += cycles;
+        }
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_call_graph_profile(&mut self, profile: Option<CallGraphProfile>) {
+        self.profile = profile;
+    }
+
+    pub fn call_graph_profile(&self) -> Option<&CallGraphProfile> {
+        self.profile.as_ref()
+    }
+
+    pub fn call_graph_profile_mut(&mut self) -> Option<&mut CallGraphProfile> {
+        self.profile.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Instruction, Operands};
+    use crate::object::Object;
+    use crate::{SimpleAddress, R_PC, R_SP};
+
+    #[test]
+    fn inclusive_and_exclusive() {
+        // main: call f
+        //       call g
+        //       ldi x1, 0
+        // f:    call g
+        //       ret
+        // g:    ret
+        let mut object = Object::new(0x1000);
+        let push = |object: &mut Object, opcode, operands| {
+            object.push(&Instruction { opcode, operands });
+        };
+        object.define("main");
+        push(&mut object, 0x18, Operands::Word(0x100f));
+        push(&mut object, 0x18, Operands::Word(0x1015));
+        push(&mut object, 0x41, Operands::RegisterWord(1, 0));
+        object.define("f");
+        push(&mut object, 0x18, Operands::Word(0x1015));
+        push(&mut object, 0x19, Operands::None);
+        object.define("g");
+        push(&mut object, 0x19, Operands::None);
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_PC] = object.base;
+        cpu.set_call_graph_profile(Some(CallGraphProfile::new()));
+        for _ in 0..7 {
+            cpu.step();
+        }
+
+        let profile = cpu.call_graph_profile().unwrap();
+        assert_eq!(profile.total_cycles(), 7);
+        let summary: Vec<_> = profile
+            .functions()
+            .iter()
+            .map(|f| (f.name(&object.symbols), f.calls, f.inclusive, f.exclusive))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("main".to_string(), 1, 7, 3),
+                ("f".to_string(), 1, 3, 2),
+                ("g".to_string(), 2, 2, 2),
+            ]
+        );
+
+        let callers = profile.callers(0x1015);
+        assert_eq!(callers.len(), 2);
+        assert_eq!(callers.iter().map(|e| e.cycles).sum::<u64>(), 2);
+        assert_eq!(profile.callees(0x100f)[0].callee, 0x1015);
+    }
+}
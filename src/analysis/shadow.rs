@@ -0,0 +1,180 @@
+// Shadow memory and memory access hooks for analyses
+// A shadow memory stores a few bits of metadata for every byte of physical memory, allocated
+// lazily a page at a time. Hooks registered on a cpu see every data read and write after address
+// translation and can keep their own shadow memory up to date.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::{Address, Cpu, InvalidMemoryAccess};
+
+const PAGE_BITS: u32 = 12;
+
+#[derive(Debug, Clone)]
+pub struct ShadowMemory {
+    bits: u8,
+    pages: HashMap<u32, Box<[u8]>>,
+}
+
+impl ShadowMemory {
+    // Bits of metadata per byte, which must be 1, 2, 4, or 8
+    pub fn new(bits: u8) -> ShadowMemory {
+        assert!(
+            bits.is_power_of_two() && bits <= 8,
+            "shadow memory must use 1, 2, 4, or 8 bits per byte"
+        );
+        ShadowMemory {
+            bits,
+            pages: HashMap::new(),
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+
+    // Index into the page and shift within that byte for an address
+    fn locate(&self, addr: u32) -> (usize, u32) {
+        let bit = (addr & ((1 << PAGE_BITS) - 1)) * self.bits as u32;
+        ((bit / 8) as usize, bit % 8)
+    }
+
+    fn mask(&self) -> u8 {
+        (0xffu16 >> (8 - self.bits)) as u8
+    }
+
+    pub fn get(&self, addr: u32) -> u8 {
+        let (index, shift) = self.locate(addr);
+        self.pages
+            .get(&(addr >> PAGE_BITS))
+            .map(|page| page[index] >> shift & self.mask())
+            .unwrap_or(0)
+    }
+
+    pub fn set(&mut self, addr: u32, value: u8) {
+        let (index, shift) = self.locate(addr);
+        let mask = self.mask();
+        let size = (1 << PAGE_BITS) * self.bits as usize / 8;
+
+        let page = if value & mask == 0 {
+            // Unallocated pages are already zero
+            match self.pages.get_mut(&(addr >> PAGE_BITS)) {
+                Some(page) => page,
+                None => return,
+            }
+        } else {
+            self.pages
+                .entry(addr >> PAGE_BITS)
+                .or_insert_with(|| vec![0; size].into_boxed_slice())
+        };
+        page[index] = page[index] & !(mask << shift) | (value & mask) << shift;
+    }
+
+    pub fn fill(&mut self, start: u32, len: u32, value: u8) {
+        for addr in (0..len).map(|i| start.wrapping_add(i)) {
+            self.set(addr, value);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+    }
+}
+
+pub trait MemoryHook {
+    // Called for every byte read as data, with the physical address and the address of the
+    // instruction performing the access. Returning an error faults that instruction.
+    fn read(&mut self, pc: u32, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        let _ = (pc, addr, data);
+        Ok(())
+    }
+
+    // Called for every byte written, before the write reaches memory
+    fn write(&mut self, pc: u32, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        let _ = (pc, addr, data);
+        Ok(())
+    }
+}
+
+// Lets the host keep a handle to a hook's state while the cpu owns the hook
+impl<H> MemoryHook for Rc<RefCell<H>>
+where
+    H: MemoryHook,
+{
+    fn read(&mut self, pc: u32, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.borrow_mut().read(pc, addr, data)
+    }
+
+    fn write(&mut self, pc: u32, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.borrow_mut().write(pc, addr, data)
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn add_memory_hook(&mut self, hook: Box<dyn MemoryHook>) {
+        self.memory_hooks.push(hook);
+    }
+
+    pub fn clear_memory_hooks(&mut self) {
+        self.memory_hooks.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn packing() {
+        for &bits in [1u8, 2, 4, 8].iter() {
+            let mut shadow = ShadowMemory::new(bits);
+            let max = (0xffu16 >> (8 - bits)) as u8;
+            shadow.set(0x1001, max);
+            shadow.set(0x1002, 1);
+            assert_eq!(shadow.get(0x1000), 0);
+            assert_eq!(shadow.get(0x1001), max);
+            assert_eq!(shadow.get(0x1002), 1);
+            assert_eq!(shadow.get(0x1003), 0);
+
+            shadow.set(0x1001, 0);
+            assert_eq!(shadow.get(0x1001), 0);
+            assert_eq!(shadow.get(0x1002), 1);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_width() {
+        ShadowMemory::new(3);
+    }
+
+    // Counts reads of each byte, saturating at 15
+    struct ReadCounter(ShadowMemory);
+
+    impl MemoryHook for ReadCounter {
+        fn read(&mut self, _pc: u32, addr: u32, _data: u8) -> Result<(), InvalidMemoryAccess> {
+            let count = self.0.get(addr);
+            self.0.set(addr, (count + 1).min(15));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let counter = Rc::new(RefCell::new(ReadCounter(ShadowMemory::new(4))));
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.add_memory_hook(Box::new(counter.clone()));
+
+        cpu.xs[1] = 0x4000;
+        cpu.load_indirect_int(0, 1).unwrap();
+        cpu.load_indirect_int(0, 1).unwrap();
+        assert_eq!(counter.borrow().0.get(0x4000), 2);
+        assert_eq!(counter.borrow().0.get(0x4003), 2);
+        assert_eq!(counter.borrow().0.get(0x4004), 0);
+    }
+}
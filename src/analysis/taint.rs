@@ -0,0 +1,576 @@
+// Byte granularity taint tracking
+// Data read from source regions (eg the registers of an input device) or explicitly tainted memory
+// is tainted. Taint follows values through loads, register operations, and stores, and using
+// tainted data as a jump target or writing it to a system register is reported as a violation.
+// Tainted addresses are not tracked, only tainted values.
+
+use super::ShadowMemory;
+use crate::vector::VECTOR_REGISTERS;
+use crate::{Address, Cpu, InvalidMemoryAccess, R_PC};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaintSink {
+    ProgramCounter,
+    PrivilegedRegister(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaintViolation {
+    // Address of the instruction that used the tainted data
+    pub pc: u32,
+    pub sink: TaintSink,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaintTracker {
+    memory: ShadowMemory,
+    sources: Vec<(u32, u32)>,
+
+    // Bits 0-31 are the integer registers, bits 32-63 the float registers
+    registers: u64,
+
+    // Bit N is the vector register vN
+    vectors: u8,
+
+    // Whether any data read by the current instruction was tainted
+    loaded: bool,
+
+    // Taint of the data written by the current instruction
+    storing: bool,
+
+    // Whether the data written also depends on the data read, as for fetch-and-add
+    storing_loaded: bool,
+
+    violations: Vec<TaintViolation>,
+    trap: bool,
+}
+
+impl Default for TaintTracker {
+    fn default() -> TaintTracker {
+        TaintTracker {
+            memory: ShadowMemory::new(1),
+            sources: vec![],
+            registers: 0,
+            vectors: 0,
+            loaded: false,
+            storing: false,
+            storing_loaded: false,
+            violations: vec![],
+            trap: false,
+        }
+    }
+}
+
+impl TaintTracker {
+    pub fn trap(mut self, trap: bool) -> TaintTracker {
+        self.trap = trap;
+        self
+    }
+
+    // Every byte read from this physical range is tainted
+    pub fn source(mut self, start: u32, len: u32) -> TaintTracker {
+        self.sources.push((start, start.wrapping_add(len)));
+        self
+    }
+
+    pub fn violations(&self) -> &[TaintViolation] {
+        &self.violations
+    }
+
+    pub fn taint_memory(&mut self, start: u32, len: u32, tainted: bool) {
+        self.memory.fill(start, len, tainted as u8);
+    }
+
+    pub fn is_tainted(&self, addr: u32) -> bool {
+        self.sources
+            .iter()
+            .any(|&(start, end)| addr >= start && addr < end)
+            || self.memory.get(addr) != 0
+    }
+
+    pub fn int_tainted(&self, x: usize) -> bool {
+        self.registers & 1 << x != 0
+    }
+
+    pub fn float_tainted(&self, f: usize) -> bool {
+        self.registers & 1 << (f + 32) != 0
+    }
+
+    pub fn set_int(&mut self, x: usize, tainted: bool) {
+        self.registers = self.registers & !(1 << x) | (tainted as u64) << x;
+    }
+
+    pub fn set_float(&mut self, f: usize, tainted: bool) {
+        self.set_int(f + 32, tainted);
+    }
+
+    // A double is tainted if either of its float registers is
+    pub fn double_tainted(&self, d: usize) -> bool {
+        self.float_tainted(d) || self.float_tainted(d | 1)
+    }
+
+    pub fn set_double(&mut self, d: usize, tainted: bool) {
+        self.set_float(d, tainted);
+        self.set_float(d | 1, tainted);
+    }
+
+    pub fn vector_tainted(&self, v: usize) -> bool {
+        v < VECTOR_REGISTERS && self.vectors & 1 << v != 0
+    }
+
+    pub fn set_vector(&mut self, v: usize, tainted: bool) {
+        self.vectors = self.vectors & !(1 << v) | (tainted as u8) << v;
+    }
+
+    pub(crate) fn on_read(&mut self, addr: u32) {
+        if self.is_tainted(addr) {
+            self.loaded = true;
+        }
+    }
+
+    pub(crate) fn on_write(&mut self, addr: u32) {
+        let tainted = self.storing || self.storing_loaded && self.loaded;
+        self.memory.set(addr, tainted as u8);
+    }
+
+    // Interrupt frames are written outside of any instruction and are never tainted
+    pub(crate) fn clear_store(&mut self) {
+        self.storing = false;
+        self.storing_loaded = false;
+    }
+
+    // Returns whether the violation should trap
+    fn violation(&mut self, pc: u32, sink: TaintSink) -> bool {
+        self.violations.push(TaintViolation { pc, sink });
+        self.trap
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_taint_tracker(&mut self, tracker: Option<TaintTracker>) {
+        self.taint = tracker;
+    }
+
+    pub fn taint_tracker(&self) -> Option<&TaintTracker> {
+        self.taint.as_ref()
+    }
+
+    pub fn taint_tracker_mut(&mut self) -> Option<&mut TaintTracker> {
+        self.taint.as_mut()
+    }
+
+    // Called before executing an instruction, with the register operands decoded from it
+    // (fst is the register encoded in the opcode for the one register pages)
+    pub(crate) fn taint_before(
+        &mut self,
+        opcode: u8,
+        fst: usize,
+        snd: usize,
+    ) -> Result<(), InvalidMemoryAccess> {
+        let pc = self.instruction_pc;
+        let taint = match self.taint.as_mut() {
+            Some(taint) => taint,
+            None => return Ok(()),
+        };
+        taint.loaded = false;
+        taint.storing = false;
+        taint.storing_loaded = false;
+
+        match opcode {
+            // Stores
+            0x96..=0x98 | 0xb0 | 0xc0..=0xef => taint.storing = taint.int_tainted(fst),
+            0x99 | 0xb2 | 0xf0..=0xff => taint.storing = taint.float_tainted(fst),
+
+            // Calls, syscalls, and firmware calls write frames and return addresses the cpu
+            // makes itself
+            0x18 | 0x20 | 0x21 | 0x38 | 0xb4 => taint.storing = false,
+            _ => (),
+        }
+
+        // Privileged move
+        if opcode == 0x9a
+            && taint.int_tainted(fst)
+            && taint.violation(pc, TaintSink::PrivilegedRegister(snd))
+        {
+            return Err(InvalidMemoryAccess::TaintedData);
+        }
+
+        Ok(())
+    }
+
+    // Called after executing an instruction to propagate taint into its destination register
+    pub(crate) fn taint_after(
+        &mut self,
+        opcode: u8,
+        fst: usize,
+        snd: usize,
+    ) -> Result<(), InvalidMemoryAccess> {
+        let pc = self.instruction_pc;
+        let taint = match self.taint.as_mut() {
+            Some(taint) => taint,
+            None => return Ok(()),
+        };
+
+        let (x, f) = (taint.int_tainted(snd), taint.float_tainted(snd));
+        match opcode {
+            // Returning to a tainted address
+            0x19 | 0x1a => {
+                if taint.loaded && taint.violation(pc, TaintSink::ProgramCounter) {
+                    return Err(InvalidMemoryAccess::TaintedData);
+                }
+                return Ok(());
+            }
+
+            // Literals and memory loads
+            0x40..=0x4f => taint.set_int(fst, false),
+            0x50..=0x5f => taint.set_float(fst, false),
+            0x60..=0x6f | 0x94 | 0xb1 | 0xb6..=0xbd => taint.set_int(fst, taint.loaded),
+            0x70..=0x7f | 0x95 | 0xb3 => taint.set_float(fst, taint.loaded),
+
+            // Integer and float arithmetic and bitwise operations
+            0x80..=0x84 | 0x89..=0x8d | 0x9d..=0x9f | 0xa5..=0xa8 => {
+                taint.set_int(fst, taint.int_tainted(fst) || x)
+            }
+            0x9c => {
+                // The wide product goes to both registers
+                let tainted = taint.int_tainted(fst) || x;
+                taint.set_int(fst, tainted);
+                taint.set_int(snd, tainted);
+            }
+            0x85..=0x88 => taint.set_float(fst, taint.float_tainted(fst) || f),
+            0xa4 | 0xbe | 0xbf => taint.set_float(fst, f),
+
+            // Complement and negation
+            0xa2 | 0xa3 => taint.set_int(fst, x),
+
+            // Moves and conversions
+            0x8e => taint.set_int(fst, x),
+            0x8f => taint.set_float(fst, f),
+            0x90 | 0x92 => taint.set_int(fst, f),
+            0x91 | 0x93 => taint.set_float(fst, x),
+
+            // Unprivileged move
+            0x9b => taint.set_int(snd, false),
+
+            // Extensions
+            0x3a..=0x3d => taint.set_int(fst, x),
+
+            // Calls and jumps through a tainted register
+            0xb4 | 0xb5 => taint.set_int(R_PC, taint.int_tainted(fst)),
+
+            _ => (),
+        }
+
+        if taint.int_tainted(R_PC) {
+            taint.set_int(R_PC, false);
+            if taint.violation(pc, TaintSink::ProgramCounter) {
+                return Err(InvalidMemoryAccess::TaintedData);
+            }
+        }
+
+        Ok(())
+    }
+
+    // The same as taint_before for an instruction in the escaped page, given the byte after the
+    // escape, called once taint_before has run for the escape itself
+    pub(crate) fn taint_before_extended(&mut self, func: u8, fst: usize) {
+        let taint = match self.taint.as_mut() {
+            Some(taint) => taint,
+            None => return,
+        };
+
+        match func {
+            // Atomics store the register after the expected value for compare-and-swap, the first
+            // register otherwise, and fetch-and-add also stores what it read
+            0x38 => taint.storing = taint.int_tainted(fst + 1),
+            0x39 => taint.storing = taint.int_tainted(fst),
+            0x3a => {
+                taint.storing = taint.int_tainted(fst);
+                taint.storing_loaded = true;
+            }
+
+            // Double and vector stores
+            0x19 => taint.storing = taint.double_tainted(fst),
+            0x31 => taint.storing = taint.vector_tainted(fst),
+            _ => (),
+        }
+    }
+
+    // The same as taint_after for an instruction in the escaped page
+    pub(crate) fn taint_after_extended(&mut self, func: u8, fst: usize, snd: usize) {
+        let taint = match self.taint.as_mut() {
+            Some(taint) => taint,
+            None => return,
+        };
+
+        let f = taint.float_tainted(snd);
+        match func {
+            // Rounding, conversion to an integer, and min and max
+            0x00..=0x03 => taint.set_float(fst, f),
+            0x04..=0x07 => taint.set_int(fst, f),
+            0x08 | 0x09 => taint.set_float(fst, taint.float_tainted(fst) || f),
+
+            // Double arithmetic, load, and conversions
+            0x10..=0x13 => {
+                taint.set_double(fst, taint.double_tainted(fst) || taint.double_tainted(snd))
+            }
+            0x18 => taint.set_double(fst, taint.loaded),
+            0x1c => taint.set_float(fst, taint.double_tainted(snd)),
+            0x1d => taint.set_double(fst, f),
+            0x1e => taint.set_int(fst, taint.double_tainted(snd)),
+            0x1f => taint.set_double(fst, taint.int_tainted(snd)),
+
+            // Vector arithmetic and comparisons, and vector load
+            0x20..=0x24 | 0x28..=0x2c => {
+                taint.set_vector(fst, taint.vector_tainted(fst) || taint.vector_tainted(snd))
+            }
+            0x30 => taint.set_vector(fst, taint.loaded),
+
+            // Atomics leave the old value of the word in the first register
+            0x38..=0x3a => taint.set_int(fst, taint.loaded),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn propagation() {
+        let program = [
+            0x60, 0x00, 0x10, 0, 0, // x0 = [0x1000]
+            0x41, 0x05, 0, 0, 0, // x1 = 5
+            0x80, 0x10, // x1 += x0
+            0xc1, 0x00, 0x20, 0, 0, // [0x2000] = x1
+            0x62, 0x00, 0x20, 0, 0, // x2 = [0x2000]
+            0x40, 0x00, 0, 0, 0, // x0 = 0
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        for _ in 0..6 {
+            cpu.step();
+        }
+
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.int_tainted(1));
+        assert!(taint.int_tainted(2));
+        assert!(!taint.int_tainted(0));
+        assert!(taint.is_tainted(0x2003));
+        assert!(!taint.is_tainted(0x2004));
+        assert!(taint.violations().is_empty());
+    }
+
+    #[test]
+    fn tainted_jump() {
+        // x0 = [0x1000]; mov x13, x0
+        let program = [0x60, 0x00, 0x10, 0, 0, 0x8e, 0xd0];
+        let mut tracker = TaintTracker::default().trap(true);
+        tracker.taint_memory(0x1000, 4, true);
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(tracker));
+        cpu.step();
+        assert!(matches!(
+            cpu.decode_instruction(),
+            Err(InvalidMemoryAccess::TaintedData)
+        ));
+        assert_eq!(
+            cpu.taint_tracker().unwrap().violations(),
+            &[TaintViolation {
+                pc: 5,
+                sink: TaintSink::ProgramCounter
+            }]
+        );
+    }
+
+    #[test]
+    fn atomics() {
+        let program = [
+            0x60, 0x00, 0x10, 0, 0, // x0 = [0x1000]
+            0x3f, 0x39, 0x01, // xchg x0, x1
+            0x3f, 0x3a, 0x21, // xadd x2, x1
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        cpu.xs[1] = 0x2000;
+        cpu.step();
+        cpu.step();
+
+        // The tainted word went to memory and the clean one it replaced to x0
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.is_tainted(0x2000));
+        assert!(!taint.int_tainted(0));
+
+        // Adding a clean register keeps the word tainted and taints the register with it
+        cpu.step();
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.is_tainted(0x2003));
+        assert!(taint.int_tainted(2));
+    }
+
+    #[test]
+    fn signed_arithmetic() {
+        let program = [
+            0x60, 0x00, 0x10, 0, 0, // x0 = [0x1000]
+            0x9d, 0x20, // imuls x2, x0
+            0x9c, 0x34, // imulw x3, x4
+            0x9c, 0x50, // imulw x5, x0
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        for _ in 0..4 {
+            cpu.step();
+        }
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.int_tainted(2));
+        assert!(!taint.int_tainted(3) && !taint.int_tainted(4));
+
+        // Both halves of a wide product are tainted
+        assert!(taint.int_tainted(5));
+        assert!(taint.int_tainted(0));
+    }
+
+    #[test]
+    fn complement_and_negation() {
+        let program = [
+            0x60, 0x00, 0x10, 0, 0, // x0 = [0x1000]
+            0xa2, 0x10, // not x1, x0
+            0xa3, 0x21, // neg x2, x1
+            0xa2, 0x03, // not x0, x3
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        for _ in 0..4 {
+            cpu.step();
+        }
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.int_tainted(1) && taint.int_tainted(2));
+        assert!(!taint.int_tainted(0));
+    }
+
+    #[test]
+    fn rotates() {
+        let program = [
+            0x60, 0x00, 0x10, 0, 0, // x0 = [0x1000]
+            0xa5, 0x30, // rol x3, x0
+            0xa8, 0x41, // rcr x4, x1
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        for _ in 0..3 {
+            cpu.step();
+        }
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.int_tainted(3));
+        assert!(!taint.int_tainted(4));
+    }
+
+    #[test]
+    fn escaped_floats() {
+        let program = [
+            0x70, 0x00, 0x10, 0, 0, // f0 = [0x1000]
+            0x3f, 0x04, 0x10, // fcvt.rne x1, f0
+            0x3f, 0x00, 0x20, // fround.rne f2, f0
+            0x3f, 0x09, 0x30, // fmax f3, f0
+            0x3f, 0x08, 0x45, // fmin f4, f5
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        for _ in 0..5 {
+            cpu.step();
+        }
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.int_tainted(1));
+        assert!(taint.float_tainted(2) && taint.float_tainted(3));
+        assert!(!taint.float_tainted(4));
+    }
+
+    #[test]
+    fn doubles() {
+        let program = [
+            0x3f, 0x18, 0x01, // ldrd d0, [x1]
+            0x3f, 0x10, 0x20, // dadd d2, d0
+            0x3f, 0x19, 0x22, // strd d2, [x2]
+            0x3f, 0x1e, 0x32, // dtoi x3, d2
+            0x3f, 0x1c, 0x42, // dtof f4, d2
+            0x3f, 0x1f, 0x67, // itod d6, x7
+            0x3f, 0x19, 0x65, // strd d6, [x5]
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 8)));
+        cpu.xs[1] = 0x1000;
+        cpu.xs[2] = 0x2000;
+        cpu.xs[5] = 0x3000;
+        for _ in 0..7 {
+            cpu.step();
+        }
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.float_tainted(0) && taint.float_tainted(1));
+        assert!(taint.float_tainted(2) && taint.float_tainted(3));
+        assert!(taint.int_tainted(3) && taint.float_tainted(4));
+        assert!(!taint.double_tainted(6));
+        assert!(taint.is_tainted(0x2000) && taint.is_tainted(0x2007));
+        assert!(!taint.is_tainted(0x3000));
+    }
+
+    #[test]
+    fn vectors() {
+        let program = [
+            0x3f, 0x30, 0x01, // vld v0, [x1]
+            0x3f, 0x20, 0x10, // vadd.i v1, v0
+            0x3f, 0x31, 0x12, // vst v1, [x2]
+            0x3f, 0x28, 0x23, // vadd.f v2, v3
+            0x3f, 0x31, 0x23, // vst v2, [x3]
+        ];
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 16)));
+        cpu.xs[1] = 0x1000;
+        cpu.xs[2] = 0x2000;
+        cpu.xs[3] = 0x3000;
+        for _ in 0..5 {
+            cpu.step();
+        }
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.vector_tainted(0) && taint.vector_tainted(1));
+        assert!(!taint.vector_tainted(2));
+        assert!(taint.is_tainted(0x2000) && taint.is_tainted(0x200f));
+        assert!(!taint.is_tainted(0x3000));
+    }
+
+    #[test]
+    fn tainted_return_address() {
+        let mut tracker = TaintTracker::default();
+        tracker.taint_memory(0xbf00, 0x100, true);
+        let mut cpu = Cpu::with_program(&[0x19]);
+        cpu.set_taint_tracker(Some(tracker));
+        cpu.xs[crate::R_BASE] = 0xbf80;
+        cpu.step();
+        assert_eq!(cpu.taint_tracker().unwrap().violations().len(), 1);
+    }
+
+    #[test]
+    fn store_then_call() {
+        let mut program = vec![
+            0x60, 0x00, 0x10, 0, 0, // x0 = [0x1000]
+            0xc0, 0x00, 0x20, 0, 0, // [0x2000] = x0
+            0x18, 0x00, 0x01, 0, 0, // call 0x100
+        ];
+        program.resize(0x100, 0);
+        program.push(0x19); // ret
+        let mut cpu = Cpu::with_program(&program);
+        cpu.set_taint_tracker(Some(TaintTracker::default().source(0x1000, 4)));
+        cpu.xs[crate::R_SP] = 0xc000;
+        cpu.xs[crate::R_BASE] = 0xc000;
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.xs[R_PC], 15);
+        let taint = cpu.taint_tracker().unwrap();
+        assert!(taint.violations().is_empty());
+        assert!(taint.is_tainted(0x2000));
+    }
+}
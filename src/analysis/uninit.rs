@@ -0,0 +1,130 @@
+// Detection of reads from memory that has never been written
+// Bytes are tracked by physical address. Only data reads are checked, instruction fetches and
+// page table walks are not, so program images loaded before the cpu starts do not need to be
+// allowlisted unless the program reads them as data.
+
+use super::ShadowMemory;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UninitRead {
+    // Address of the instruction that performed the read
+    pub pc: u32,
+
+    // Physical address that was read
+    pub addr: u32,
+}
+
+type Callback = Box<dyn FnMut(&UninitRead)>;
+
+pub struct UninitChecker {
+    written: ShadowMemory,
+    allowed: Vec<(u32, u32)>,
+    reads: Vec<UninitRead>,
+    trap: bool,
+    callback: Option<Callback>,
+}
+
+impl Default for UninitChecker {
+    fn default() -> UninitChecker {
+        UninitChecker {
+            written: ShadowMemory::new(1),
+            allowed: vec![],
+            reads: vec![],
+            trap: false,
+            callback: None,
+        }
+    }
+}
+
+// The callback is not cloned
+impl Clone for UninitChecker {
+    fn clone(&self) -> UninitChecker {
+        UninitChecker {
+            written: self.written.clone(),
+            allowed: self.allowed.clone(),
+            reads: self.reads.clone(),
+            trap: self.trap,
+            callback: None,
+        }
+    }
+}
+
+impl UninitChecker {
+    // Raise a fault on the offending instruction instead of only recording the read
+    pub fn trap(mut self, trap: bool) -> UninitChecker {
+        self.trap = trap;
+        self
+    }
+
+    pub fn callback<F>(mut self, callback: F) -> UninitChecker
+    where
+        F: FnMut(&UninitRead) + 'static,
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    // Treat a region (eg ROM or a framebuffer) as always initialised
+    pub fn allow(mut self, start: u32, len: u32) -> UninitChecker {
+        self.allowed.push((start, start.wrapping_add(len)));
+        self
+    }
+
+    pub fn reads(&self) -> &[UninitRead] {
+        &self.reads
+    }
+
+    pub fn is_initialised(&self, addr: u32) -> bool {
+        self.allowed
+            .iter()
+            .any(|&(start, end)| addr >= start && addr < end)
+            || self.written.get(addr) != 0
+    }
+
+    pub fn mark_written(&mut self, addr: u32) {
+        self.written.set(addr, 1);
+    }
+
+    // Returns whether the read should trap
+    // Each byte is only reported once, after which it counts as initialised
+    pub(crate) fn check_read(&mut self, pc: u32, addr: u32) -> bool {
+        if self.is_initialised(addr) {
+            return false;
+        }
+
+        let read = UninitRead { pc, addr };
+        self.mark_written(addr);
+        if let Some(callback) = self.callback.as_mut() {
+            callback(&read);
+        }
+        self.reads.push(read);
+        self.trap
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracking() {
+        let mut checker = UninitChecker::default().allow(0x100, 0x10);
+        assert!(checker.is_initialised(0x10f));
+        assert!(!checker.is_initialised(0x110));
+
+        checker.mark_written(0x12345);
+        assert!(checker.is_initialised(0x12345));
+        assert!(!checker.is_initialised(0x12344));
+        assert!(!checker.is_initialised(0x12346));
+
+        assert!(!checker.check_read(0x20, 0x500));
+        assert!(!checker.check_read(0x20, 0x500));
+        assert_eq!(
+            checker.reads(),
+            &[UninitRead {
+                pc: 0x20,
+                addr: 0x500
+            }]
+        );
+    }
+}
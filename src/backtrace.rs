@@ -0,0 +1,134 @@
+// Stack unwinding
+// call pushes the base pointer and then the return address, and points the base pointer at them:
+//
+// base + 4  saved base pointer
+// base + 0  return address
+//
+// Following the saved base pointers from the current one walks every frame on the stack. The walk
+// ends at a base pointer of 0, which is what it is at reset, or at a saved base pointer that does
+// not point further up the stack, which means the frame is corrupted. Stack memory is read with
+// peek so the walk has no effect on the guest.
+
+use std::fmt;
+
+use crate::symbols::SymbolTable;
+use crate::{Address, Cpu, R_BASE, R_PC};
+
+// Frames to walk at most, in case a corrupted stack never ends
+pub const MAX_FRAMES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktraceFrame {
+    // The current pc for the innermost frame, a return address for the others
+    pub pc: u32,
+
+    // Base pointer of the frame
+    pub base: u32,
+
+    // Symbol containing pc, eg "main+0x10"
+    pub symbol: Option<String>,
+}
+
+impl fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{:#010x}", self.pc)?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " in {}", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // Innermost frame first
+    pub fn backtrace(&mut self, symbols: &SymbolTable) -> Vec<BacktraceFrame> {
+        let frame = |pc, base| BacktraceFrame {
+            pc,
+            base,
+            symbol: symbols.lookup(pc).map(|s| s.to_string()),
+        };
+
+        let mut base = self.xs[R_BASE];
+        let mut frames = vec![frame(self.xs[R_PC], base)];
+        while base != 0 && frames.len() < MAX_FRAMES {
+            let addr = self.stack_top(base);
+            let (pc, saved) = match (self.peek_word(addr), self.peek_word(addr.wrapping_add(4))) {
+                (Some(pc), Some(saved)) => (pc, saved),
+                _ => break,
+            };
+
+            frames.push(frame(pc, saved));
+            if saved <= base {
+                break;
+            }
+            base = saved;
+        }
+        frames
+    }
+
+    fn peek_word(&mut self, addr: u32) -> Option<u32> {
+        let bytes = self.peek_range(addr, 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Instruction, Operands};
+    use crate::object::Object;
+    use crate::{SimpleAddress, R_SP};
+
+    #[test]
+    fn nested_calls() {
+        // _start: call f
+        //         ret
+        // f:      call g
+        //         ret
+        // g:      ldi x1, 1
+        let mut object = Object::new(0x1000);
+        let call = |object: &mut Object, target| {
+            object.push(&Instruction {
+                opcode: 0x18,
+                operands: Operands::Word(target),
+            });
+            object.push(&Instruction {
+                opcode: 0x19,
+                operands: Operands::None,
+            });
+        };
+        object.define("_start");
+        call(&mut object, 0x1006);
+        object.define("f");
+        call(&mut object, 0x100c);
+        object.define("g");
+        object.push(&Instruction {
+            opcode: 0x41,
+            operands: Operands::RegisterWord(1, 1),
+        });
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_PC] = object.base;
+        cpu.step();
+        cpu.step();
+
+        let trace: Vec<String> = cpu
+            .backtrace(&object.symbols)
+            .iter()
+            .map(|frame| frame.to_string())
+            .collect();
+        assert_eq!(
+            trace,
+            vec![
+                "0x0000100c in g",
+                "0x0000100b in f+0x5",
+                "0x00001005 in _start+0x5",
+            ]
+        );
+    }
+}
@@ -0,0 +1,654 @@
+// Physical address space built from devices mapped at address ranges
+// Each device sees addresses relative to the start of its range. Accesses to reserved ranges
+// raise a bus error, and accesses that hit nothing read the open bus value (zero unless set) and
+// ignore writes.
+// When ranges overlap the one mapped last wins. try_map refuses ranges that overlap instead, for
+// machines that are built from devices that should never cover each other.
+// Ranges can also be protected, eg to keep ROM from being written or MMIO from being executed.
+// Protections apply to physical addresses whatever the memory map says, and accesses they forbid
+// raise a bus error without reaching the device.
+// Devices mapped as MMIO are treated specially by instruction fetches, which can be allowed, fault,
+// or be redirected to a boot ROM shim depending on the exec policy.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::{Address, ReadEffect, Width, EXEC, READ, WRITE};
+
+enum Target {
+    Device(Box<dyn Address>),
+    Reserved,
+}
+
+struct Mapping {
+    start: u32,
+
+    // Inclusive, so that a mapping can reach the end of the address space
+    end: u32,
+
+    target: Target,
+    mmio: bool,
+}
+
+// What instruction fetches from MMIO do
+#[derive(Default)]
+pub enum ExecPolicy {
+    // Execute whatever the device returns
+    #[default]
+    Allow,
+
+    // Raise a bus error
+    Fault,
+
+    // Fetch from the shim instead, which sees the bus address
+    // The device is not accessed, so a boot ROM can supply code at the addresses of devices
+    Redirect(Box<dyn Address>),
+}
+
+// Range already mapped where a new one was to go
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overlap {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl fmt::Display for Overlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "overlaps the range {:#010x}-{:#010x}",
+            self.start, self.end
+        )
+    }
+}
+
+impl std::error::Error for Overlap {}
+
+struct Protection {
+    start: u32,
+    end: u32,
+    permissions: u8,
+}
+
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+    protections: Vec<Protection>,
+    exec_policy: ExecPolicy,
+    open_bus: u8,
+    bus_error: Option<u32>,
+}
+
+impl Bus {
+    pub fn new() -> Bus {
+        Bus::default()
+    }
+
+    pub fn exec_policy(mut self, exec_policy: ExecPolicy) -> Bus {
+        self.exec_policy = exec_policy;
+        self
+    }
+
+    // Sets the byte read from addresses that nothing is mapped at, eg 0xff for a bus with pull ups
+    pub fn open_bus(mut self, value: u8) -> Bus {
+        self.open_bus = value;
+        self
+    }
+
+    // Maps memory, eg RAM or ROM
+    // Panics if len is 0
+    pub fn map(&mut self, start: u32, len: u32, device: Box<dyn Address>) {
+        self.add(start, len, Target::Device(device), false);
+    }
+
+    // Maps device registers, which instruction fetches are subject to the exec policy for
+    // Panics if len is 0
+    pub fn map_mmio(&mut self, start: u32, len: u32, device: Box<dyn Address>) {
+        self.add(start, len, Target::Device(device), true);
+    }
+
+    // Maps memory unless the range overlaps one that is already mapped or reserved
+    // Panics if len is 0
+    pub fn try_map(
+        &mut self,
+        start: u32,
+        len: u32,
+        device: Box<dyn Address>,
+    ) -> Result<(), Overlap> {
+        self.check_overlap(start, len)?;
+        self.map(start, len, device);
+        Ok(())
+    }
+
+    // Maps device registers unless the range overlaps one that is already mapped or reserved
+    // Panics if len is 0
+    pub fn try_map_mmio(
+        &mut self,
+        start: u32,
+        len: u32,
+        device: Box<dyn Address>,
+    ) -> Result<(), Overlap> {
+        self.check_overlap(start, len)?;
+        self.map_mmio(start, len, device);
+        Ok(())
+    }
+
+    fn check_overlap(&self, start: u32, len: u32) -> Result<(), Overlap> {
+        let end = start.wrapping_add(len.max(1) - 1);
+        match self
+            .mappings
+            .iter()
+            .find(|mapping| mapping.start <= end && start <= mapping.end)
+        {
+            Some(mapping) => Err(Overlap {
+                start: mapping.start,
+                end: mapping.end,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Makes every access to a range raise a bus error, eg to catch wild pointers
+    // Panics if len is 0
+    pub fn reserve(&mut self, start: u32, len: u32) {
+        self.add(start, len, Target::Reserved, false);
+    }
+
+    // Limits accesses to a range to the given read, write, and execute bits (READ, WRITE, EXEC)
+    // When protected ranges overlap the one protected last wins
+    // Panics if len is 0
+    pub fn protect(&mut self, start: u32, len: u32, permissions: u8) {
+        assert!(len != 0, "protected ranges must not be empty");
+        self.protections.push(Protection {
+            start,
+            end: start.wrapping_add(len - 1),
+            permissions,
+        });
+    }
+
+    // Returns the first byte of an access that is not permitted
+    fn forbidden(&self, addr: u32, width: Width, permission: u8) -> Option<u32> {
+        (0..width.bytes())
+            .map(|i| addr.wrapping_add(i))
+            .find(|&addr| {
+                self.protections
+                    .iter()
+                    .rev()
+                    .find(|p| p.start <= addr && addr <= p.end)
+                    .is_some_and(|p| p.permissions & permission == 0)
+            })
+    }
+
+    fn add(&mut self, start: u32, len: u32, target: Target, mmio: bool) {
+        assert!(len != 0, "mapped ranges must not be empty");
+        self.mappings.push(Mapping {
+            start,
+            end: start.wrapping_add(len - 1),
+            target,
+            mmio,
+        });
+    }
+
+    fn position(&self, addr: u32) -> Option<usize> {
+        self.mappings
+            .iter()
+            .rposition(|mapping| mapping.start <= addr && addr <= mapping.end)
+    }
+
+    fn find(&mut self, addr: u32) -> Option<&mut Mapping> {
+        let i = self.position(addr)?;
+        Some(&mut self.mappings[i])
+    }
+
+    // Finds the mapping that handles a whole access, None if the access is split between mappings
+    // or only partly hits one
+    fn find_whole(&mut self, addr: u32, width: Width) -> Option<Option<&mut Mapping>> {
+        addr.checked_add(width.bytes() - 1)?;
+        let i = self.position(addr);
+        if (1..width.bytes()).any(|offset| self.position(addr + offset) != i) {
+            return None;
+        }
+        Some(i.map(move |i| &mut self.mappings[i]))
+    }
+}
+
+impl Address for Bus {
+    fn read(&mut self, addr: u32) -> u8 {
+        self.read_width(addr, Width::Byte) as u8
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        self.write_width(addr, Width::Byte, data as u32)
+    }
+
+    // Devices see accesses at their original width as long as a single mapping handles them
+    fn read_width(&mut self, addr: u32, width: Width) -> u32 {
+        if let Some(addr) = self.forbidden(addr, width, READ) {
+            self.bus_error = Some(addr);
+            return 0;
+        }
+
+        let (data, error) = match self.find_whole(addr, width) {
+            Some(Some(Mapping {
+                start,
+                target: Target::Device(device),
+                ..
+            })) => {
+                let data = device.read_width(addr - *start, width);
+                (data, device.bus_error().map(|offset| offset + *start))
+            }
+            Some(Some(Mapping {
+                target: Target::Reserved,
+                ..
+            })) => (0, Some(addr)),
+            Some(None) => {
+                let data =
+                    (0..width.bytes()).fold(0, |acc, i| acc | (self.open_bus as u32) << (8 * i));
+                (data, None)
+            }
+            None => {
+                let data = (0..width.bytes()).fold(0, |acc, i| {
+                    acc | (self.read(addr.wrapping_add(i)) as u32) << (8 * i)
+                });
+                (data, None)
+            }
+        };
+        if error.is_some() {
+            self.bus_error = error;
+        }
+        data
+    }
+
+    fn write_width(&mut self, addr: u32, width: Width, data: u32) {
+        if let Some(addr) = self.forbidden(addr, width, WRITE) {
+            self.bus_error = Some(addr);
+            return;
+        }
+
+        let error = match self.find_whole(addr, width) {
+            Some(Some(Mapping {
+                start,
+                target: Target::Device(device),
+                ..
+            })) => {
+                device.write_width(addr - *start, width, data);
+                device.bus_error().map(|offset| offset + *start)
+            }
+            Some(Some(Mapping {
+                target: Target::Reserved,
+                ..
+            })) => Some(addr),
+            Some(None) => None,
+            None => {
+                for i in 0..width.bytes() {
+                    self.write(addr.wrapping_add(i), (data >> (8 * i)) as u8);
+                }
+                None
+            }
+        };
+        if error.is_some() {
+            self.bus_error = error;
+        }
+    }
+
+    // A word within one device is handed to it whole, so that shared memory can keep other agents
+    // out between the read and the write
+    fn read_modify_write(&mut self, addr: u32, op: &mut dyn FnMut(u32) -> Option<u32>) -> u32 {
+        let forbidden = self.forbidden(addr, Width::Word, READ);
+        if let Some(addr) = forbidden.or_else(|| self.forbidden(addr, Width::Word, WRITE)) {
+            self.bus_error = Some(addr);
+            return 0;
+        }
+
+        if let Some(Some(Mapping {
+            start,
+            target: Target::Device(device),
+            ..
+        })) = self.find_whole(addr, Width::Word)
+        {
+            let old = device.read_modify_write(addr - *start, op);
+            let error = device.bus_error().map(|offset| offset + *start);
+            if error.is_some() {
+                self.bus_error = error;
+            }
+            return old;
+        }
+
+        let old = self.read_width(addr, Width::Word);
+        if let Some(new) = op(old) {
+            self.write_width(addr, Width::Word, new);
+        }
+        old
+    }
+
+    fn fetch(&mut self, addr: u32) -> u8 {
+        if self.forbidden(addr, Width::Byte, EXEC).is_some() {
+            self.bus_error = Some(addr);
+            return 0;
+        }
+
+        let mapping = match self.position(addr) {
+            Some(i) => Some(&mut self.mappings[i]),
+            None => None,
+        };
+        let (data, error) = match (mapping, &mut self.exec_policy) {
+            (Some(Mapping { mmio: true, .. }), ExecPolicy::Fault) => (0, Some(addr)),
+            (Some(Mapping { mmio: true, .. }), ExecPolicy::Redirect(shim)) => {
+                (shim.fetch(addr), shim.bus_error())
+            }
+            (
+                Some(Mapping {
+                    start,
+                    target: Target::Device(device),
+                    ..
+                }),
+                _,
+            ) => {
+                let data = device.fetch(addr - *start);
+                (data, device.bus_error().map(|offset| offset + *start))
+            }
+            (
+                Some(Mapping {
+                    target: Target::Reserved,
+                    ..
+                }),
+                _,
+            ) => (0, Some(addr)),
+            (None, _) => (self.open_bus, None),
+        };
+        if error.is_some() {
+            self.bus_error = error;
+        }
+        data
+    }
+
+    // Reading a reserved range faults, so it counts as a side effect
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        match self.position(addr).map(|i| &self.mappings[i]) {
+            Some(Mapping {
+                start,
+                target: Target::Device(device),
+                ..
+            }) => device.read_effect(addr - *start),
+            Some(Mapping {
+                target: Target::Reserved,
+                ..
+            }) => ReadEffect::Other,
+            None => ReadEffect::Pure,
+        }
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        match self.find(addr) {
+            Some(Mapping {
+                start,
+                target: Target::Device(device),
+                ..
+            }) => device.read_debug(addr - *start),
+            Some(_) => 0,
+            None => self.open_bus,
+        }
+    }
+
+    fn bus_error(&mut self) -> Option<u32> {
+        self.bus_error.take()
+    }
+}
+
+// Lets the host keep a handle to a device while the bus owns it
+impl<T> Address for Rc<RefCell<T>>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        self.borrow_mut().read(addr)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        self.borrow_mut().write(addr, data)
+    }
+
+    fn fetch(&mut self, addr: u32) -> u8 {
+        self.borrow_mut().fetch(addr)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        self.borrow().read_effect(addr)
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        self.borrow_mut().read_debug(addr)
+    }
+
+    fn read_width(&mut self, addr: u32, width: Width) -> u32 {
+        self.borrow_mut().read_width(addr, width)
+    }
+
+    fn write_width(&mut self, addr: u32, width: Width, data: u32) {
+        self.borrow_mut().write_width(addr, width, data)
+    }
+
+    fn read_modify_write(&mut self, addr: u32, op: &mut dyn FnMut(u32) -> Option<u32>) -> u32 {
+        self.borrow_mut().read_modify_write(addr, op)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.borrow().size()
+    }
+
+    fn bus_error(&mut self) -> Option<u32> {
+        self.borrow_mut().bus_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Rng;
+    use crate::snapshot::memory_snapshot;
+    use crate::{Cpu, SimpleAddress, R_PC, R_SP};
+
+    #[test]
+    fn mapping() {
+        let ram = Rc::new(RefCell::new(SimpleAddress::new(0x1000)));
+        let mut bus = Bus::new();
+        bus.map(0x1000, 0x1000, Box::new(ram.clone()));
+        bus.map_mmio(0x1800, 8, Box::new(Rng::new(1)));
+        bus.reserve(0xf000, 0x1000);
+
+        bus.write(0x1004, 7);
+        assert_eq!(ram.borrow_mut().read(4), 7);
+        assert_eq!(bus.read(0x1004), 7);
+
+        // The rng was mapped later so it covers the ram
+        bus.write(0x1804, 1);
+        assert_eq!(ram.borrow_mut().read(0x804), 0);
+
+        assert_eq!(bus.read(0x3000), 0);
+        assert_eq!(bus.bus_error(), None);
+        bus.write(0xf010, 1);
+        assert_eq!(bus.bus_error(), Some(0xf010));
+        assert_eq!(bus.read_debug(0xf010), 0);
+        assert_eq!(bus.bus_error(), None);
+    }
+
+    #[test]
+    fn overlaps_and_open_bus() {
+        let mut bus = Bus::new().open_bus(0xff);
+        bus.try_map(0x1000, 0x1000, Box::new(SimpleAddress::new(0x1000)))
+            .unwrap();
+        bus.reserve(0xf000, 0x1000);
+        assert_eq!(
+            bus.try_map_mmio(0x1ff0, 0x20, Box::new(Rng::new(1))),
+            Err(Overlap {
+                start: 0x1000,
+                end: 0x1fff
+            })
+        );
+        assert_eq!(
+            bus.try_map(0xe000, 0x1001, Box::new(SimpleAddress::new(0x1001))),
+            Err(Overlap {
+                start: 0xf000,
+                end: 0xffff
+            })
+        );
+        assert!(bus.try_map_mmio(0x2000, 8, Box::new(Rng::new(1))).is_ok());
+
+        // Nothing is mapped at 0x3000, and the rng was not mapped over the ram
+        assert_eq!(bus.read_width(0x3000, Width::Word), 0xffff_ffff);
+        assert_eq!(bus.read_debug(0x3000), 0xff);
+        assert_eq!(bus.fetch(0x3000), 0xff);
+        assert_eq!(bus.read(0x1ff8), 0);
+        assert_eq!(bus.bus_error(), None);
+    }
+
+    #[test]
+    fn reserved_fault() {
+        // load x0 <- [0x8000]
+        let mut ram = SimpleAddress::new(0x8000);
+        ram.load(0, &[0x60, 0x00, 0x80, 0x00, 0x00]);
+        ram.load(0x1024, &0x2000u32.to_le_bytes());
+        let mut bus = Bus::new();
+        bus.map(0, 0x8000, Box::new(ram));
+        bus.reserve(0x8000, 0x8000);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.vector_base = 0x1000;
+        cpu.xs[R_SP] = 0x7000;
+        cpu.step();
+        assert_eq!(cpu.fault_cause, 9);
+        assert_eq!(cpu.fault_address, 0x8000);
+        assert_eq!(cpu.xs[R_PC], 0x2000);
+    }
+
+    // Pops a value on every read regardless of its width
+    struct Fifo {
+        next: u32,
+    }
+
+    impl Address for Fifo {
+        fn read(&mut self, addr: u32) -> u8 {
+            self.read_width(addr, Width::Byte) as u8
+        }
+
+        fn write(&mut self, _addr: u32, _data: u8) {}
+
+        fn read_width(&mut self, _addr: u32, _width: Width) -> u32 {
+            self.next += 1;
+            self.next
+        }
+
+        fn read_effect(&self, _addr: u32) -> ReadEffect {
+            ReadEffect::Pop
+        }
+    }
+
+    #[test]
+    fn access_width() {
+        // load x0 <- [0x8000]; load x1 <- [0x8000]
+        let mut ram = SimpleAddress::new(0x8000);
+        ram.load(
+            0,
+            &[0x60, 0x00, 0x80, 0x00, 0x00, 0x61, 0x00, 0x80, 0x00, 0x00],
+        );
+        let fifo = Rc::new(RefCell::new(Fifo { next: 0x11223340 }));
+        let mut bus = Bus::new();
+        bus.map(0, 0x8000, Box::new(ram));
+        bus.map_mmio(0x8000, 4, Box::new(fifo.clone()));
+
+        let mut cpu = Cpu::new(bus);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.xs[0], 0x11223341);
+        assert_eq!(cpu.xs[1], 0x11223342);
+        assert_eq!(fifo.borrow().next, 0x11223342);
+
+        // Accesses that straddle the end of a mapping are split into bytes
+        cpu.addressing.read_width(0x8002, Width::Word);
+        assert_eq!(fifo.borrow().next, 0x11223344);
+    }
+
+    #[test]
+    fn read_effects() {
+        let fifo = Rc::new(RefCell::new(Fifo { next: 0 }));
+        let mut bus = Bus::new();
+        bus.map(0, 0x100, Box::new(SimpleAddress::new(0x100)));
+        bus.map_mmio(0x100, 4, Box::new(fifo.clone()));
+        bus.map_mmio(0x200, 8, Box::new(Rng::new(1)));
+        bus.reserve(0x300, 0x100);
+        bus.write(0x10, 5);
+
+        assert_eq!(bus.read_effect(0x10), ReadEffect::Pure);
+        assert_eq!(bus.read_effect(0x100), ReadEffect::Pop);
+        assert_eq!(bus.read_effect(0x200), ReadEffect::Pop);
+        assert_eq!(bus.read_effect(0x204), ReadEffect::Pure);
+        assert!(bus.read_effect(0x300).has_side_effects());
+
+        // Observing the machine does not pop the fifo
+        let snapshot = memory_snapshot(&mut bus, 0, 0x110);
+        assert!(snapshot.contains("00000100: 00 00 00 00"));
+        assert_eq!(fifo.borrow().next, 0);
+        assert_eq!(bus.read_debug(0x10), 5);
+    }
+
+    #[test]
+    fn protection() {
+        // store x0 -> [0x0800]
+        let mut ram = SimpleAddress::new(0x8000);
+        ram.load(0, &[0xc0, 0x00, 0x08, 0x00, 0x00]);
+        ram.load(0x1024, &0x2000u32.to_le_bytes());
+        let mut bus = Bus::new();
+        bus.map(0, 0x8000, Box::new(ram));
+        bus.map_mmio(0x9000, 8, Box::new(Rng::new(1)));
+        bus.protect(0, 0x1000, READ | EXEC);
+        bus.protect(0x9000, 8, READ | WRITE);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.vector_base = 0x1000;
+        cpu.xs[0] = 0xff;
+        cpu.xs[R_SP] = 0x7000;
+        cpu.step();
+        assert_eq!(cpu.fault_cause, 9);
+        assert_eq!(cpu.fault_address, 0x800);
+        assert_eq!(cpu.addressing.read(0x800), 0);
+
+        // Devices can be read but not executed
+        cpu.addressing.read(0x9000);
+        assert_eq!(cpu.addressing.bus_error(), None);
+        cpu.xs[R_PC] = 0x9000;
+        cpu.step();
+        assert_eq!(cpu.fault_address, 0x9000);
+        assert_eq!(cpu.xs[R_PC], 0x2000);
+    }
+
+    #[test]
+    fn exec_policy() {
+        let run = |policy| {
+            let mut ram = SimpleAddress::new(0x8000);
+            ram.load(0x1024, &0x2000u32.to_le_bytes());
+            let mut bus = Bus::new().exec_policy(policy);
+            bus.map(0, 0x8000, Box::new(ram));
+            bus.map_mmio(0x9000, 8, Box::new(Rng::new(1)));
+
+            let mut cpu = Cpu::new(bus);
+            cpu.vector_base = 0x1000;
+            cpu.xs[R_SP] = 0x7000;
+            cpu.xs[R_PC] = 0x9000;
+            cpu.step();
+            cpu
+        };
+
+        let cpu = run(ExecPolicy::Fault);
+        assert_eq!(cpu.fault_cause, 9);
+        assert_eq!(cpu.fault_address, 0x9000);
+        assert_eq!(cpu.xs[R_PC], 0x2000);
+
+        // load x0 <- 0x12345678 from the shim
+        let mut shim = SimpleAddress::new(0x10000);
+        shim.load(0x9000, &[0x40, 0x78, 0x56, 0x34, 0x12]);
+        let cpu = run(ExecPolicy::Redirect(Box::new(shim)));
+        assert_eq!(cpu.xs[0], 0x12345678);
+        assert_eq!(cpu.xs[R_PC], 0x9005);
+    }
+}
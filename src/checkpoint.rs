@@ -0,0 +1,166 @@
+// Named checkpoints of a machine for exploratory debugging
+// Each checkpoint is a fork of the machine, so with copy-on-write memory saving one is cheap.
+// Checkpoints form a tree: a new checkpoint's parent is the checkpoint last saved or restored,
+// which makes it possible to see which points in time were reached from which.
+
+use std::collections::HashMap;
+
+use crate::{Address, Cpu};
+
+struct Checkpoint<T, const N: usize>
+where
+    T: Address,
+{
+    cpu: Cpu<T, N>,
+    parent: Option<String>,
+    children: Vec<String>,
+}
+
+pub struct Checkpoints<T, const N: usize = 16>
+where
+    T: Address + Clone,
+{
+    checkpoints: HashMap<String, Checkpoint<T, N>>,
+    current: Option<String>,
+}
+
+impl<T, const N: usize> Default for Checkpoints<T, N>
+where
+    T: Address + Clone,
+{
+    fn default() -> Checkpoints<T, N> {
+        Checkpoints {
+            checkpoints: HashMap::new(),
+            current: None,
+        }
+    }
+}
+
+impl<T, const N: usize> Checkpoints<T, N>
+where
+    T: Address + Clone,
+{
+    // Saves the machine under a name, as a child of the current checkpoint
+    // Saving over an existing name replaces its state but keeps its place in the tree
+    pub fn save(&mut self, name: &str, cpu: &Cpu<T, N>) {
+        if let Some(checkpoint) = self.checkpoints.get_mut(name) {
+            checkpoint.cpu = cpu.fork();
+        } else {
+            let parent = self.current.clone();
+            if let Some(parent) = parent.as_ref() {
+                if let Some(parent) = self.checkpoints.get_mut(parent) {
+                    parent.children.push(name.to_string());
+                }
+            }
+            self.checkpoints.insert(
+                name.to_string(),
+                Checkpoint {
+                    cpu: cpu.fork(),
+                    parent,
+                    children: vec![],
+                },
+            );
+        }
+        self.current = Some(name.to_string());
+    }
+
+    // Returns a copy of the machine saved under a name and makes it the current checkpoint
+    // The checkpoint itself is left untouched so it can be restored again
+    pub fn restore(&mut self, name: &str) -> Option<Cpu<T, N>> {
+        let cpu = self.checkpoints.get(name)?.cpu.fork();
+        self.current = Some(name.to_string());
+        Some(cpu)
+    }
+
+    // Removes a checkpoint, moving its children to its parent
+    pub fn remove(&mut self, name: &str) -> bool {
+        let checkpoint = match self.checkpoints.remove(name) {
+            Some(checkpoint) => checkpoint,
+            None => return false,
+        };
+
+        for child in checkpoint.children.iter() {
+            if let Some(child) = self.checkpoints.get_mut(child) {
+                child.parent = checkpoint.parent.clone();
+            }
+        }
+        if let Some(parent) = checkpoint.parent.as_ref() {
+            if let Some(parent) = self.checkpoints.get_mut(parent) {
+                parent.children.retain(|child| child != name);
+                parent.children.extend(checkpoint.children.iter().cloned());
+            }
+        }
+        if self.current.as_deref() == Some(name) {
+            self.current = checkpoint.parent;
+        }
+        true
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.checkpoints.contains_key(name)
+    }
+
+    // Names of every checkpoint, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.checkpoints.keys().map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Checkpoint last saved or restored
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn parent(&self, name: &str) -> Option<&str> {
+        self.checkpoints.get(name)?.parent.as_deref()
+    }
+
+    // Children in the order they were saved
+    pub fn children(&self, name: &str) -> &[String] {
+        match self.checkpoints.get(name) {
+            Some(checkpoint) => &checkpoint.children,
+            None => &[],
+        }
+    }
+
+    // Saved machine, without making it the current checkpoint
+    pub fn get(&self, name: &str) -> Option<&Cpu<T, N>> {
+        self.checkpoints.get(name).map(|checkpoint| &checkpoint.cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cow::CowAddress;
+    use crate::R_PC;
+
+    #[test]
+    fn tree() {
+        let mut cpu = Cpu::new(CowAddress::default());
+        let mut checkpoints = Checkpoints::default();
+        checkpoints.save("start", &cpu);
+        cpu.step();
+        checkpoints.save("a", &cpu);
+        cpu.step();
+        checkpoints.save("b", &cpu);
+
+        let mut cpu = checkpoints.restore("a").unwrap();
+        assert_eq!(cpu.xs[R_PC], 5);
+        cpu.xs[0] = 5;
+        checkpoints.save("c", &cpu);
+
+        assert_eq!(checkpoints.names(), vec!["a", "b", "c", "start"]);
+        assert_eq!(checkpoints.parent("a"), Some("start"));
+        assert_eq!(checkpoints.children("a"), &["b", "c"]);
+        assert_eq!(checkpoints.current(), Some("c"));
+
+        assert!(checkpoints.remove("a"));
+        assert_eq!(checkpoints.parent("c"), Some("start"));
+        assert_eq!(checkpoints.children("start"), &["b", "c"]);
+        assert_eq!(checkpoints.get("b").unwrap().xs[R_PC], 10);
+        assert_eq!(checkpoints.restore("c").unwrap().xs[0], 5);
+        assert!(checkpoints.restore("a").is_none());
+    }
+}
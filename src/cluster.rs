@@ -0,0 +1,554 @@
+// Symmetric multiprocessing machine
+// Several cores share one physical memory. Each core sees memory through its own bus, which maps
+// the shared memory from address 0, the core's local interrupt controller (see devices/local.rs)
+// over it at LOCAL_CONTROLLER_BASE, and the mailbox shared by all cores (see devices/mailbox.rs)
+// at MAILBOX_BASE. Interrupts from outside the cluster are raised on a chosen core with irq, local
+// timers and IPIs are raised by the cluster itself.
+//
+// Only core 0 runs after reset, the others are parked until they are started through the mailbox.
+// Cores are stepped one instruction at a time in order of their index, so runs are deterministic.
+//
+// A cluster can model contention for the shared memory (see contention.rs). The data accesses of
+// each step, and those of DMA engines reaching the memory through dma_memory during it, are then
+// arbitrated as issued at the cycle the step started in, and the stall is added to the cycles of
+// the stepping core. Accesses are counted once per bank and bus master in a step, and instruction
+// fetches are not counted.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::analysis::MemoryHook;
+use crate::bus::Bus;
+use crate::contention::{ContentionModel, Requester};
+use crate::devices::local::{LocalController, LOCAL_IPI_IRQ, LOCAL_TIMER_IRQ};
+use crate::devices::mailbox::{Mailbox, MailboxRequest};
+use crate::{Address, Cpu, InvalidMemoryAccess, ReadEffect, StepOutcome, R_PC};
+
+pub const LOCAL_CONTROLLER_BASE: u32 = 0xffff_f000;
+pub const LOCAL_CONTROLLER_SIZE: u32 = 0x18;
+pub const MAILBOX_BASE: u32 = 0xffff_e000;
+pub const MAILBOX_SIZE: u32 = 0x10;
+
+// One bit per core in the IPI registers
+pub const MAX_CORES: usize = 32;
+
+struct Core {
+    cpu: Cpu<Bus>,
+    local: Rc<RefCell<LocalController>>,
+    parked: bool,
+}
+
+type Accesses = Rc<RefCell<Vec<(Requester, u32)>>>;
+
+// Notes the words of the shared memory a bus master touches
+#[derive(Clone)]
+struct AccessLog {
+    requester: Requester,
+    len: u32,
+    accesses: Accesses,
+}
+
+impl AccessLog {
+    fn record(&self, addr: u32) {
+        if addr >= self.len {
+            return;
+        }
+        let access = (self.requester, addr & !3);
+        let mut accesses = self.accesses.borrow_mut();
+        if accesses.last() != Some(&access) {
+            accesses.push(access);
+        }
+    }
+}
+
+impl MemoryHook for AccessLog {
+    fn read(&mut self, _pc: u32, addr: u32, _data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.record(addr);
+        Ok(())
+    }
+
+    fn write(&mut self, _pc: u32, addr: u32, _data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.record(addr);
+        Ok(())
+    }
+}
+
+// The shared memory as a DMA engine sees it, eg the memory handle of a Disk
+pub struct DmaMemory<T>
+where
+    T: Address,
+{
+    memory: Rc<RefCell<T>>,
+    log: AccessLog,
+}
+
+impl<T> Address for DmaMemory<T>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        self.log.record(addr);
+        self.memory.borrow_mut().read(addr)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        self.log.record(addr);
+        self.memory.borrow_mut().write(addr, data)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        self.memory.borrow().read_effect(addr)
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        self.memory.borrow_mut().read_debug(addr)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.memory.borrow().size()
+    }
+}
+
+pub struct Cluster<T>
+where
+    T: Address,
+{
+    memory: Rc<RefCell<T>>,
+    len: u32,
+    mailbox: Rc<RefCell<Mailbox>>,
+    cores: Vec<Core>,
+    contention: Option<ContentionModel>,
+
+    // Accesses to the shared memory made during the current step
+    accesses: Accesses,
+}
+
+impl<T> Cluster<T>
+where
+    T: Address + 'static,
+{
+    // Panics if there are no cores or more than MAX_CORES
+    pub fn new(memory: T, cores: usize) -> Cluster<T> {
+        assert!(
+            (1..=MAX_CORES).contains(&cores),
+            "a cluster has 1 to {} cores",
+            MAX_CORES
+        );
+        let len = memory.size().unwrap_or(u64::MAX).min(u32::MAX as u64) as u32;
+        let memory = Rc::new(RefCell::new(memory));
+        let mailbox = Rc::new(RefCell::new(Mailbox::new(cores as u32)));
+        mailbox.borrow_mut().set_running(1);
+
+        let cores = (0..cores)
+            .map(|id| {
+                let local = Rc::new(RefCell::new(LocalController::new(id as u32)));
+                let mut bus = Bus::new();
+                bus.map(0, len, Box::new(memory.clone()));
+                bus.map_mmio(
+                    LOCAL_CONTROLLER_BASE,
+                    LOCAL_CONTROLLER_SIZE,
+                    Box::new(local.clone()),
+                );
+                bus.map_mmio(MAILBOX_BASE, MAILBOX_SIZE, Box::new(mailbox.clone()));
+                Core {
+                    cpu: Cpu::new(bus),
+                    local,
+                    parked: id != 0,
+                }
+            })
+            .collect();
+        Cluster {
+            memory,
+            len,
+            mailbox,
+            cores,
+            contention: None,
+            accesses: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    // Models contention for the shared memory, adding the stalls to the cycles of the cores
+    // Clearing the memory hooks of a core stops its accesses being counted
+    pub fn contention(mut self, model: ContentionModel) -> Cluster<T> {
+        for (i, core) in self.cores.iter_mut().enumerate() {
+            core.cpu.add_memory_hook(Box::new(AccessLog {
+                requester: Requester::Core(i),
+                len: self.len,
+                accesses: self.accesses.clone(),
+            }));
+        }
+        self.contention = Some(model);
+        self
+    }
+
+    pub fn contention_model(&self) -> Option<&ContentionModel> {
+        self.contention.as_ref()
+    }
+
+    // A handle to the shared memory for a device that accesses it directly, whose accesses count
+    // as those of DMA engine number engine
+    pub fn dma_memory(&self, engine: usize) -> DmaMemory<T> {
+        DmaMemory {
+            memory: self.memory.clone(),
+            log: AccessLog {
+                requester: Requester::Dma(engine),
+                len: self.len,
+                accesses: self.accesses.clone(),
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cores.is_empty()
+    }
+
+    // The memory shared by every core
+    pub fn memory(&self) -> Rc<RefCell<T>> {
+        self.memory.clone()
+    }
+
+    // Panics if there is no such core
+    pub fn core(&self, index: usize) -> &Cpu<Bus> {
+        &self.cores[index].cpu
+    }
+
+    // Panics if there is no such core
+    pub fn core_mut(&mut self, index: usize) -> &mut Cpu<Bus> {
+        &mut self.cores[index].cpu
+    }
+
+    // Panics if there is no such core
+    pub fn local_controller(&self, index: usize) -> Rc<RefCell<LocalController>> {
+        self.cores[index].local.clone()
+    }
+
+    // Panics if there is no such core
+    pub fn is_parked(&self, index: usize) -> bool {
+        self.cores[index].parked
+    }
+
+    // Releases a core at entry, leaving its other registers as they were
+    // Panics if there is no such core
+    pub fn start(&mut self, index: usize, entry: u32) {
+        let mut state = self.cores[index].cpu.state();
+        state.xs[R_PC] = entry;
+        self.cores[index].cpu.set_state(&state);
+        self.cores[index].parked = false;
+        self.update_running();
+    }
+
+    // Stops stepping a core until it is started again
+    // Panics if there is no such core
+    pub fn park(&mut self, index: usize) {
+        self.cores[index].parked = true;
+        self.update_running();
+    }
+
+    fn update_running(&mut self) {
+        let running = self
+            .cores
+            .iter()
+            .enumerate()
+            .filter(|(_, core)| !core.parked)
+            .fold(0, |running, (i, _)| running | 1 << i);
+        self.mailbox.borrow_mut().set_running(running);
+    }
+
+    // Raises an external interrupt on a core
+    // Panics if there is no such core
+    pub fn irq(&mut self, core: usize, id: u8) {
+        self.cores[core].cpu.irq(id);
+    }
+
+    // Steps every core that is not parked and has not shut down once, returning whether any are
+    // still running
+    // Mailbox requests take effect as soon as the step making them is over, so a core started by
+    // a lower numbered core runs in the same round
+    pub fn step(&mut self) -> bool {
+        let mut running = false;
+        for i in 0..self.cores.len() {
+            let core = &mut self.cores[i];
+            if core.parked {
+                continue;
+            }
+            let cycle = core.cpu.cycles();
+            let outcome = core.cpu.step();
+            self.charge_accesses(i, cycle);
+
+            let requests = self.mailbox.borrow_mut().take_requests();
+            for request in requests {
+                match request {
+                    MailboxRequest::Start { core, entry } => self.start(core as usize, entry),
+                    MailboxRequest::Park { core } => self.park(core as usize),
+                }
+            }
+            if outcome == StepOutcome::Shutdown {
+                continue;
+            }
+            running = true;
+
+            let core = &mut self.cores[i];
+
+            let (timer, ipis) = {
+                let mut local = core.local.borrow_mut();
+                (local.tick(), local.take_ipis())
+            };
+            if timer {
+                core.cpu.irq(LOCAL_TIMER_IRQ);
+            }
+            for target in (0..self.cores.len()).filter(|target| ipis & 1 << target != 0) {
+                let target = &mut self.cores[target];
+                if target.local.borrow_mut().receive_ipi(i as u32) {
+                    target.cpu.irq(LOCAL_IPI_IRQ);
+                }
+            }
+        }
+        running
+    }
+
+    // Passes the accesses of a step to the contention model and stalls the core that made it
+    fn charge_accesses(&mut self, index: usize, cycle: u64) {
+        let mut accesses = std::mem::take(&mut *self.accesses.borrow_mut());
+        let model = match self.contention.as_mut() {
+            Some(model) => model,
+            None => return,
+        };
+
+        let mut banks = HashSet::new();
+        accesses.retain(|&(requester, addr)| banks.insert((requester, model.bank(addr))));
+        let stalls = model.access_all(cycle, &accesses);
+        let stall = accesses
+            .iter()
+            .zip(stalls)
+            .filter(|((requester, _), _)| *requester == Requester::Core(index))
+            .map(|(_, stall)| stall)
+            .max()
+            .unwrap_or(0);
+        self.cores[index].cpu.stall(stall);
+    }
+
+    // Steps the cores until they have all shut down or max_rounds rounds have run, returning the
+    // number of rounds
+    pub fn run(&mut self, max_rounds: u64) -> u64 {
+        for round in 0..max_rounds {
+            if !self.step() {
+                return round;
+            }
+        }
+        max_rounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contention::Arbitration;
+    use crate::devices::disk::{
+        Disk, COMMAND_READ, DISK_BUFFER, DISK_COMMAND, DISK_COUNT, DISK_SECTOR,
+    };
+    use crate::devices::local::{LOCAL_IPI_SEND, LOCAL_MASK, MASK_IPI};
+    use crate::devices::mailbox::{MAILBOX_CONTROL, MAILBOX_CORE, MAILBOX_ENTRY};
+    use crate::{SimpleAddress, Width};
+
+    #[test]
+    fn ipis_between_cores() {
+        let mut memory = SimpleAddress::new(0x1000);
+
+        // Both cores run the same program, enabling IPIs and then sending one to core 1
+        // ldi x1, 2; stb x1, MASK; ldi x1, 2; st x1, IPI_SEND
+        let mut program = vec![0x41, 0x02, 0x00, 0x00, 0x00, 0xe1];
+        program.extend_from_slice(&(LOCAL_CONTROLLER_BASE + LOCAL_MASK).to_le_bytes());
+        program.extend_from_slice(&[0x41, 0x02, 0x00, 0x00, 0x00, 0xc1]);
+        program.extend_from_slice(&(LOCAL_CONTROLLER_BASE + LOCAL_IPI_SEND).to_le_bytes());
+        memory.load(0, &program);
+
+        let mut cluster = Cluster::new(memory, 2);
+        assert_eq!(cluster.len(), 2);
+        assert_eq!(cluster.core_mut(1).peek(LOCAL_CONTROLLER_BASE), Some(1));
+        cluster.start(1, 0);
+
+        assert_eq!(cluster.run(4), 4);
+        assert_eq!(cluster.local_controller(0).borrow().ipi_pending(), 0);
+        assert_eq!(cluster.local_controller(1).borrow().ipi_pending(), 0b11);
+        assert_eq!(
+            cluster.local_controller(0).borrow_mut().read(LOCAL_MASK),
+            MASK_IPI
+        );
+
+        // Every core sees the shared memory
+        cluster.memory().borrow_mut().write(0x10, 0xaa);
+        assert_eq!(cluster.core_mut(0).peek(0x10), Some(0xaa));
+    }
+
+    #[test]
+    fn atomics_between_cores() {
+        let mut memory = SimpleAddress::new(0x1000);
+
+        // Both cores add 1 to the word at 0x200, each getting back the count before its add
+        // ldi x1, 1; ldi x2, 0x200; xadd x1, x2
+        memory.load(
+            0,
+            &[
+                0x41, 0x01, 0x00, 0x00, 0x00, 0x42, 0x00, 0x02, 0x00, 0x00, 0x3f, 0x3a, 0x12,
+            ],
+        );
+
+        let mut cluster = Cluster::new(memory, 2);
+        cluster.start(1, 0);
+        cluster.run(3);
+        assert_eq!(cluster.core_mut(0).peek(0x200), Some(2));
+        assert_eq!(cluster.core(0).xs[1], 0);
+        assert_eq!(cluster.core(1).xs[1], 1);
+    }
+
+    #[test]
+    fn mailbox_bring_up() {
+        let mut memory = SimpleAddress::new(0x1000);
+
+        // Core 0 starts core 1 at 0x100, where it loads 5 into x2
+        // ldi x1, 1; st x1, CORE; ldi x1, 0x100; st x1, ENTRY; ldi x1, 1; stb x1, CONTROL
+        let mut program = vec![];
+        for (value, register) in [(1, MAILBOX_CORE), (0x100, MAILBOX_ENTRY)].iter() {
+            program.push(0x41);
+            program.extend_from_slice(&(*value as u32).to_le_bytes());
+            program.push(0xc1);
+            program.extend_from_slice(&(MAILBOX_BASE + register).to_le_bytes());
+        }
+        program.extend_from_slice(&[0x41, 0x01, 0x00, 0x00, 0x00, 0xe1]);
+        program.extend_from_slice(&(MAILBOX_BASE + MAILBOX_CONTROL).to_le_bytes());
+        memory.load(0, &program);
+        memory.load(0x100, &[0x42, 0x05, 0x00, 0x00, 0x00]);
+
+        let mut cluster = Cluster::new(memory, 2);
+        assert!(cluster.is_parked(1));
+        cluster.run(5);
+        assert_eq!(cluster.core(1).cycles(), 0);
+
+        // Core 1 runs in the same round it is started in
+        cluster.run(1);
+        assert!(!cluster.is_parked(1));
+        assert_eq!(cluster.core(1).xs[2], 5);
+        assert_eq!(cluster.core(0).xs[2], 0);
+        assert_eq!(
+            cluster.core_mut(0).peek(MAILBOX_BASE + MAILBOX_CONTROL),
+            Some(1)
+        );
+
+        cluster.park(1);
+        cluster.run(3);
+        assert_eq!(cluster.core(1).cycles(), 1);
+    }
+
+    #[test]
+    fn memory_contention() {
+        let mut memory = SimpleAddress::new(0x4000);
+
+        // Both cores load from the same bank every step
+        // ld x1, 0x1000; ld x1, 0x1004
+        memory.load(
+            0,
+            &[0x61, 0x00, 0x10, 0x00, 0x00, 0x61, 0x04, 0x10, 0x00, 0x00],
+        );
+        let mut cluster = Cluster::new(memory, 2).contention(ContentionModel::new(12, 1));
+        cluster.start(1, 0);
+        cluster.run(2);
+
+        // The bank serves one access a cycle, so core 1 waits for core 0 in the first round and
+        // then they take turns waiting for each other
+        assert_eq!(cluster.core(0).cycles(), 3);
+        assert_eq!(cluster.core(1).cycles(), 4);
+        let model = cluster.contention_model().unwrap();
+        assert_eq!(model.stats(Requester::Core(0)).stall_cycles, 1);
+        assert_eq!(model.stats(Requester::Core(1)).stall_cycles, 2);
+
+        // Without a model the cores never stall
+        let mut memory = SimpleAddress::new(0x4000);
+        memory.load(0, &[0x61, 0x00, 0x10, 0x00, 0x00]);
+        let mut cluster = Cluster::new(memory, 2);
+        cluster.start(1, 0);
+        cluster.run(1);
+        assert_eq!(cluster.core(1).cycles(), 1);
+    }
+
+    #[test]
+    fn dma_contention() {
+        const BASE: u32 = 0x8000;
+        let mut memory = SimpleAddress::new(0x4000);
+
+        // Core 0 reads a sector into 0x1000, then loads from the buffer while the disk still has
+        // the bank
+        // ldi x1, 0x1000; st x1, BUFFER; ldi x1, 1; st x1, COUNT; stb x1, COMMAND;
+        // ld x2, 0x1000
+        let mut program = vec![0x41, 0x00, 0x10, 0x00, 0x00, 0xc1];
+        program.extend_from_slice(&(BASE + DISK_BUFFER).to_le_bytes());
+        program.extend_from_slice(&[0x41, 0x01, 0x00, 0x00, 0x00, 0xc1]);
+        program.extend_from_slice(&(BASE + DISK_COUNT).to_le_bytes());
+        program.push(0xe1);
+        program.extend_from_slice(&(BASE + DISK_COMMAND).to_le_bytes());
+        program.extend_from_slice(&[0x62, 0x00, 0x10, 0x00, 0x00]);
+        memory.load(0, &program);
+
+        let mut cluster = Cluster::new(memory, 1).contention(ContentionModel::new(12, 4));
+        let disk = Disk::from_vec(vec![0x5a; DISK_SECTOR as usize], cluster.dma_memory(0));
+        cluster
+            .core_mut(0)
+            .addressing()
+            .map_mmio(BASE, 0x14, Box::new(disk));
+        cluster.run(5);
+        assert_eq!(cluster.core(0).cycles(), 5);
+
+        // The sector fits in one bank, which stays busy for the next step
+        cluster.run(1);
+        assert_eq!(cluster.core(0).xs[2], 0x5a5a_5a5a);
+        assert_eq!(cluster.core(0).cycles(), 9);
+        let model = cluster.contention_model().unwrap();
+        assert_eq!(model.stats(Requester::Dma(0)).accesses, 1);
+        assert_eq!(model.stats(Requester::Core(0)).stall_cycles, 3);
+    }
+
+    #[test]
+    fn dma_arbitration() {
+        // The host starts a transfer into the bank core 0 loads from, before the first and the
+        // last of five steps
+        // ld x2, 0x1000; ldi x1, 0; ldi x1, 0; ldi x1, 0; ld x2, 0x1000
+        let mut memory = SimpleAddress::new(0x4000);
+        let mut program = vec![0x62, 0x00, 0x10, 0x00, 0x00];
+        for _ in 0..3 {
+            program.extend_from_slice(&[0x41, 0x00, 0x00, 0x00, 0x00]);
+        }
+        program.extend_from_slice(&[0x62, 0x00, 0x10, 0x00, 0x00]);
+        memory.load(0, &program);
+
+        // The loser of the bank waits one cycle for the winner each time
+        for (arbitration, stalls) in [
+            (Arbitration::CpuPriority, 0),
+            (Arbitration::DmaPriority, 2),
+            (Arbitration::RoundRobin, 1),
+        ]
+        .iter()
+        {
+            let model = ContentionModel::new(12, 1).arbitration(*arbitration);
+            let mut cluster = Cluster::new(memory.clone(), 1).contention(model);
+            let mut disk = Disk::from_vec(vec![0x5a; DISK_SECTOR as usize], cluster.dma_memory(0));
+            disk.write_width(DISK_BUFFER, Width::Word, 0x1000);
+            disk.write_width(DISK_COUNT, Width::Word, 1);
+            disk.write(DISK_COMMAND, COMMAND_READ);
+            cluster.run(4);
+            disk.write(DISK_COMMAND, COMMAND_READ);
+            cluster.run(1);
+            assert_eq!(cluster.core(0).cycles(), 5 + stalls, "{:?}", arbitration);
+            let model = cluster.contention_model().unwrap();
+            assert_eq!(
+                model.stats(Requester::Dma(0)).stall_cycles,
+                2 - stalls,
+                "{:?}",
+                arbitration
+            );
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Memory that keeps cold pages compressed
+// Only a limited number of 4K pages are kept uncompressed. Touching a compressed page decompresses
+// it, and when too many pages are uncompressed the least recently used one is compressed again.
+// Pages of zeros take no space at all, so large mostly idle machines fit in little host memory.
+// The built in codec is the run length encoding used by save states, other codecs (eg lz4 or
+// zstd) can be plugged in by implementing Codec.
+
+use crate::savestate::{rle_compress, rle_decompress};
+use crate::{Address, SIMPLE_ADDRESS_SIZE};
+
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+pub trait Codec {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    // Decompresses data produced by compress back into len bytes
+    fn decompress(&self, data: &[u8], len: usize) -> Vec<u8>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rle;
+
+impl Codec for Rle {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        rle_compress(data)
+    }
+
+    fn decompress(&self, data: &[u8], len: usize) -> Vec<u8> {
+        rle_decompress(data, len).expect("compressed page is corrupted")
+    }
+}
+
+#[derive(Clone)]
+enum Page {
+    Zero,
+    Hot(Box<[u8]>),
+    Cold(Vec<u8>),
+}
+
+pub struct CompressedAddress<C = Rle> {
+    pages: Vec<Page>,
+
+    // Access time of each page, for picking the least recently used hot page
+    last_used: Vec<u64>,
+    hot: Vec<usize>,
+    max_hot: usize,
+    clock: u64,
+    codec: C,
+}
+
+impl Default for CompressedAddress {
+    fn default() -> CompressedAddress {
+        CompressedAddress::new(SIMPLE_ADDRESS_SIZE, 64)
+    }
+}
+
+impl CompressedAddress {
+    pub fn new(size: usize, max_hot: usize) -> CompressedAddress {
+        CompressedAddress::with_codec(size, max_hot, Rle)
+    }
+}
+
+impl<C> CompressedAddress<C>
+where
+    C: Codec,
+{
+    // Size is rounded up to a whole number of 4K pages, accesses past the end read as zero
+    // Panics if max_hot is 0
+    pub fn with_codec(size: usize, max_hot: usize, codec: C) -> CompressedAddress<C> {
+        assert!(max_hot != 0, "at least one page must be uncompressed");
+        let pages = (size + PAGE_SIZE - 1) >> PAGE_BITS;
+        CompressedAddress {
+            pages: vec![Page::Zero; pages],
+            last_used: vec![0; pages],
+            hot: vec![],
+            max_hot,
+            clock: 0,
+            codec,
+        }
+    }
+
+    pub fn hot_pages(&self) -> usize {
+        self.hot.len()
+    }
+
+    pub fn cold_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|page| matches!(page, Page::Cold(_)))
+            .count()
+    }
+
+    // Host memory used by page contents
+    pub fn stored_bytes(&self) -> usize {
+        self.pages
+            .iter()
+            .map(|page| match page {
+                Page::Zero => 0,
+                Page::Hot(data) => data.len(),
+                Page::Cold(data) => data.len(),
+            })
+            .sum()
+    }
+
+    // Compresses every uncompressed page, eg before leaving a machine idle
+    pub fn compact(&mut self) {
+        for page in std::mem::take(&mut self.hot) {
+            self.freeze(page);
+        }
+    }
+
+    fn freeze(&mut self, page: usize) {
+        if let Page::Hot(data) = &self.pages[page] {
+            self.pages[page] = if data.iter().all(|&b| b == 0) {
+                Page::Zero
+            } else {
+                Page::Cold(self.codec.compress(data))
+            };
+        }
+    }
+
+    // Makes a page uncompressed, allocating it if it is all zeros and allocate is set
+    fn thaw(&mut self, page: usize, allocate: bool) -> Option<&mut [u8]> {
+        self.clock += 1;
+        self.last_used[page] = self.clock;
+
+        let data: Box<[u8]> = match &self.pages[page] {
+            Page::Hot(_) => match &mut self.pages[page] {
+                Page::Hot(data) => return Some(data),
+                _ => unreachable!("nya :("),
+            },
+            Page::Zero if !allocate => return None,
+            Page::Zero => vec![0; PAGE_SIZE].into(),
+            Page::Cold(data) => self.codec.decompress(data, PAGE_SIZE).into(),
+        };
+
+        if self.hot.len() == self.max_hot {
+            let (i, _) = self
+                .hot
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, &page)| self.last_used[page])
+                .unwrap();
+            let evicted = self.hot.swap_remove(i);
+            self.freeze(evicted);
+        }
+        self.hot.push(page);
+        self.pages[page] = Page::Hot(data);
+        match &mut self.pages[page] {
+            Page::Hot(data) => Some(data),
+            _ => unreachable!("nya :("),
+        }
+    }
+}
+
+impl<C> Address for CompressedAddress<C>
+where
+    C: Codec,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        let page = (addr >> PAGE_BITS) as usize;
+        if page >= self.pages.len() {
+            return 0;
+        }
+        match self.thaw(page, false) {
+            Some(data) => data[addr as usize & (PAGE_SIZE - 1)],
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        let page = (addr >> PAGE_BITS) as usize;
+        if page >= self.pages.len() {
+            return;
+        }
+        if let Some(page) = self.thaw(page, true) {
+            page[addr as usize & (PAGE_SIZE - 1)] = data;
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some((self.pages.len() << PAGE_BITS) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eviction() {
+        let mut memory = CompressedAddress::new(0x10000, 2);
+        memory.write(0x1000, 1);
+        memory.write(0x2000, 2);
+        memory.read(0x1000);
+        assert_eq!(memory.hot_pages(), 2);
+
+        // 0x2000 is the least recently used page
+        memory.write(0x3000, 3);
+        assert_eq!(memory.cold_pages(), 1);
+        assert_eq!(memory.read(0x2000), 2);
+        assert_eq!(memory.read(0x1000), 1);
+        assert_eq!(memory.read(0x3000), 3);
+
+        // Reading zero pages does not allocate them
+        assert_eq!(memory.read(0x8000), 0);
+        assert_eq!(memory.hot_pages(), 2);
+
+        memory.compact();
+        assert_eq!(memory.hot_pages(), 0);
+        assert_eq!(memory.cold_pages(), 3);
+        assert!(memory.stored_bytes() < 3 * PAGE_SIZE / 16);
+        assert_eq!(memory.read(0x3000), 3);
+    }
+
+    #[test]
+    fn zeroed_pages_are_freed() {
+        let mut memory = CompressedAddress::new(0x10000, 1);
+        memory.write(0x1000, 1);
+        memory.write(0x1000, 0);
+        memory.write(0x2000, 1);
+        assert_eq!(memory.cold_pages(), 0);
+        assert_eq!(memory.stored_bytes(), PAGE_SIZE);
+    }
+}
@@ -0,0 +1,843 @@
+// ISA conformance tests
+// Generates small self-contained programs for each opcode along with the results and flags the
+// architecture specifies for them, computed by a reference model that is independent of the
+// interpreter. The cases are plain data so other implementations of the ISA can run them too.
+
+use std::fmt;
+
+use crate::opcodes::ESCAPE;
+use crate::{
+    Address, Cpu, F_CARRY, F_INFINITE, F_MEMMAP_ENABLE, F_NAN, F_NEGATIVE, F_OVERFLOW, F_PARITY,
+    F_ZERO, R_PC, R_SP,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Check {
+    Int(usize, u32),
+    Float(usize, f32),
+    Flags { mask: u32, value: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub opcode: u8,
+
+    // Loaded at address 0 with the program counter starting at 0
+    pub program: Vec<u8>,
+    pub steps: usize,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub name: String,
+    pub opcode: u8,
+    pub failures: Vec<String>,
+}
+
+impl CaseResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub results: Vec<CaseResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.len() - self.passed()
+    }
+
+    pub fn is_conformant(&self) -> bool {
+        self.failed() == 0
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        for result in self.results.iter().filter(|r| !r.passed()) {
+            writeln!(f, "FAIL {} (opcode {:#04x})", result.name, result.opcode)?;
+            for failure in result.failures.iter() {
+                writeln!(f, "    {}", failure)?;
+            }
+        }
+        write!(f, "{}/{} cases passed", self.passed(), self.results.len())
+    }
+}
+
+const INT_OPERANDS: [u32; 8] = [0, 1, 2, 5, 0x7fffffff, 0x80000000, 0xfffffffe, 0xffffffff];
+const SHIFT_OPERANDS: [u32; 6] = [0, 1, 2, 31, 32, 33];
+const FLOAT_OPERANDS: [f32; 8] = [
+    0.0,
+    -0.0,
+    1.5,
+    -2.25,
+    3.0e38,
+    f32::INFINITY,
+    f32::NEG_INFINITY,
+    f32::NAN,
+];
+
+const INT_FLAGS: u32 = 1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_PARITY;
+const ADD_FLAGS: u32 = INT_FLAGS | 1 << F_CARRY | 1 << F_OVERFLOW;
+const SHIFT_FLAGS: u32 = INT_FLAGS | 1 << F_CARRY;
+const FLOAT_FLAGS: u32 = 1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_NAN | 1 << F_INFINITE;
+const COMPARE_FLAGS: u32 = 1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_CARRY | 1 << F_OVERFLOW;
+const CONVERT_FLAGS: u32 = INT_FLAGS | 1 << F_OVERFLOW | 1 << F_NAN;
+
+// Names of the compare branches, 0x22-0x27
+const COMPARE_BRANCHES: [&str; 6] = ["blt", "ble", "bgt", "bge", "blo", "bhs"];
+
+fn flag(val: bool, flag: u32) -> u32 {
+    (val as u32) << flag
+}
+
+fn int_flags(x: u32) -> u32 {
+    flag(x == 0, F_ZERO) | flag(x & 0x80000000 != 0, F_NEGATIVE) | flag(x & 1 != 0, F_PARITY)
+}
+
+fn float_flags(x: f32) -> u32 {
+    flag(x == 0.0, F_ZERO)
+        | flag(x.is_sign_negative(), F_NEGATIVE)
+        | flag(x.is_nan(), F_NAN)
+        | flag(x.is_infinite(), F_INFINITE)
+}
+
+// Add with carry in, as specified for both add and subtract (which adds the complement)
+fn add_reference(a: u32, b: u32, carry: bool) -> (u32, u32) {
+    let wide = a as u64 + b as u64 + carry as u64;
+    let res = wide as u32;
+    let overflow = (a ^ b) & 0x80000000 == 0 && (a ^ res) & 0x80000000 != 0;
+    let flags = int_flags(res) | flag(wide >> 32 != 0, F_CARRY) | flag(overflow, F_OVERFLOW);
+    (res, flags)
+}
+
+fn shift_reference(a: u32, b: u32, left: bool) -> (u32, u32) {
+    let res = match (b < 32, left) {
+        (true, true) => a << b,
+        (true, false) => a >> b,
+        (false, _) => 0,
+    };
+    let carry = b == 1
+        && if left {
+            a & 0x80000000 != 0
+        } else {
+            a & 1 != 0
+        };
+    (res, int_flags(res) | flag(carry, F_CARRY))
+}
+
+// Whether compare branch 0x22 + condition is taken with the given flags
+// The signed conditions compare negative against overflow, the unsigned ones use carry as no borrow
+fn branch_reference(flags: u32, condition: usize) -> bool {
+    let set = |f: u32| flags & (1 << f) != 0;
+    let less = set(F_NEGATIVE) != set(F_OVERFLOW);
+    match condition {
+        0 => less,
+        1 => less || set(F_ZERO),
+        2 => !less && !set(F_ZERO),
+        3 => !less,
+        4 => !set(F_CARRY),
+        _ => set(F_CARRY),
+    }
+}
+
+// fcmp orders the operands like cmp, with unordered operands neither less nor equal
+fn fcmp_reference(a: f32, b: f32) -> u32 {
+    let less = a < b;
+    flag(a == b, F_ZERO) | flag(less, F_NEGATIVE) | flag(!less, F_CARRY)
+}
+
+// Rotates by one bit at a time, through carry for the 33 bit rotates
+fn rotate_reference(a: u32, b: u32, right: bool, through_carry: bool, carry: bool) -> (u32, bool) {
+    let (mut res, mut carry) = (a, carry);
+    let count = if through_carry { b % 33 } else { b % 32 };
+    for _ in 0..count {
+        let (out, top) = if right {
+            (res & 1 != 0, res >> 1)
+        } else {
+            (res & 0x80000000 != 0, res << 1)
+        };
+        let rotated_in = if through_carry { carry } else { out };
+        res = if right {
+            top | (rotated_in as u32) << 31
+        } else {
+            top | rotated_in as u32
+        };
+        carry = out;
+    }
+    (res, carry)
+}
+
+// Shifts by a literal, returning the last bit shifted out unless nothing was shifted
+fn shift_immediate_reference(a: u32, b: u32, left: bool) -> (u32, Option<bool>) {
+    match b {
+        0 => (a, None),
+        1..=32 => {
+            let bits = (0..b).fold(a as u64, |x, _| if left { x << 1 } else { x >> 1 });
+            let out = if left {
+                a >> (32 - b) & 1
+            } else {
+                a >> (b - 1) & 1
+            };
+            (bits as u32, Some(out != 0))
+        }
+        _ => (0, Some(false)),
+    }
+}
+
+// Rounds to an integral value with the rounding mode of the escaped page functions
+fn round_reference(x: f32, mode: u8) -> f32 {
+    match mode {
+        0 => x.round_ties_even(),
+        1 => x.trunc(),
+        2 => x.floor(),
+        _ => x.ceil(),
+    }
+}
+
+// Picks a number over nan, and -0 as less than +0
+fn min_max_reference(a: f32, b: f32, max: bool) -> f32 {
+    if a.is_nan() {
+        b
+    } else if b.is_nan() {
+        a
+    } else if a == b {
+        if a.is_sign_negative() != max {
+            a
+        } else {
+            b
+        }
+    } else if (a < b) != max {
+        a
+    } else {
+        b
+    }
+}
+
+fn lit_int(program: &mut Vec<u8>, reg: usize, value: u32) {
+    program.push(0x40 | reg as u8);
+    program.extend_from_slice(&value.to_le_bytes());
+}
+
+fn lit_float(program: &mut Vec<u8>, reg: usize, value: f32) {
+    program.push(0x50 | reg as u8);
+    program.extend_from_slice(&value.to_bits().to_le_bytes());
+}
+
+fn two_reg(program: &mut Vec<u8>, opcode: u8, fst: usize, snd: usize) {
+    program.push(opcode);
+    program.push((fst << 4 | snd) as u8);
+}
+
+fn set_flags(program: &mut Vec<u8>, flags: u32) {
+    lit_int(program, 2, flags);
+    two_reg(program, 0x9a, 2, 0);
+}
+
+fn escaped(program: &mut Vec<u8>, func: u8, fst: usize, snd: usize) {
+    program.extend_from_slice(&[ESCAPE, func, (fst << 4 | snd) as u8]);
+}
+
+fn int_case(name: &str, opcode: u8, a: u32, b: u32, res: u32, mask: u32, flags: u32) -> TestCase {
+    let mut program = vec![];
+    lit_int(&mut program, 0, a);
+    lit_int(&mut program, 1, b);
+    two_reg(&mut program, opcode, 0, 1);
+    TestCase {
+        name: format!("{} {:#010x}, {:#010x}", name, a, b),
+        opcode,
+        program,
+        steps: 3,
+        checks: vec![Check::Int(0, res), Check::Flags { mask, value: flags }],
+    }
+}
+
+// Integer operations that only update the zero, negative, and parity flags
+fn logic_case(name: &str, opcode: u8, a: u32, b: u32, res: u32) -> TestCase {
+    int_case(name, opcode, a, b, res, INT_FLAGS, int_flags(res))
+}
+
+fn float_case(name: &str, opcode: u8, a: f32, b: f32, res: f32) -> TestCase {
+    let mut program = vec![];
+    lit_float(&mut program, 0, a);
+    lit_float(&mut program, 1, b);
+    two_reg(&mut program, opcode, 0, 1);
+    TestCase {
+        name: format!("{} {}, {}", name, a, b),
+        opcode,
+        program,
+        steps: 3,
+        checks: vec![
+            Check::Float(0, res),
+            Check::Flags {
+                mask: FLOAT_FLAGS,
+                value: float_flags(res),
+            },
+        ],
+    }
+}
+
+fn arithmetic_cases(cases: &mut Vec<TestCase>) {
+    for &a in INT_OPERANDS.iter() {
+        for &b in INT_OPERANDS.iter() {
+            let (res, flags) = add_reference(a, b, false);
+            cases.push(int_case("add", 0x80, a, b, res, ADD_FLAGS, flags));
+
+            let (res, flags) = add_reference(a, !b, false);
+            cases.push(int_case("sub", 0x81, a, b, res, ADD_FLAGS, flags));
+
+            cases.push(logic_case("mul", 0x82, a, b, a.wrapping_mul(b)));
+            if b != 0 {
+                cases.push(logic_case("div", 0x83, a, b, a / b));
+                cases.push(logic_case("mod", 0x84, a, b, a % b));
+            }
+        }
+    }
+
+    for &a in FLOAT_OPERANDS.iter() {
+        for &b in FLOAT_OPERANDS.iter() {
+            cases.push(float_case("fadd", 0x85, a, b, a + b));
+            cases.push(float_case("fsub", 0x86, a, b, a - b));
+            cases.push(float_case("fmul", 0x87, a, b, a * b));
+            cases.push(float_case("fdiv", 0x88, a, b, a / b));
+        }
+    }
+}
+
+fn bitwise_cases(cases: &mut Vec<TestCase>) {
+    for &a in INT_OPERANDS.iter() {
+        for &b in SHIFT_OPERANDS.iter() {
+            let (res, flags) = shift_reference(a, b, true);
+            cases.push(int_case("bsl", 0x89, a, b, res, SHIFT_FLAGS, flags));
+
+            let (res, flags) = shift_reference(a, b, false);
+            cases.push(int_case("bsr", 0x8a, a, b, res, SHIFT_FLAGS, flags));
+        }
+
+        for &b in INT_OPERANDS.iter() {
+            cases.push(logic_case("and", 0x8b, a, b, a & b));
+            cases.push(logic_case("or", 0x8c, a, b, a | b));
+            cases.push(logic_case("xor", 0x8d, a, b, a ^ b));
+        }
+    }
+}
+
+fn move_cases(cases: &mut Vec<TestCase>) {
+    for &a in INT_OPERANDS.iter() {
+        cases.push(logic_case("mov", 0x8e, 0, a, a));
+
+        let mut program = vec![];
+        lit_int(&mut program, 1, a);
+        two_reg(&mut program, 0x91, 0, 1);
+        two_reg(&mut program, 0x93, 1, 1);
+        cases.push(TestCase {
+            name: format!("itof/transmute {:#010x}", a),
+            opcode: 0x91,
+            program,
+            steps: 3,
+            checks: vec![
+                Check::Float(0, a as i32 as f32),
+                Check::Float(1, f32::from_bits(a)),
+                Check::Flags {
+                    mask: FLOAT_FLAGS,
+                    value: float_flags(f32::from_bits(a)),
+                },
+            ],
+        });
+    }
+
+    for &a in FLOAT_OPERANDS.iter() {
+        let mut program = vec![];
+        lit_float(&mut program, 1, a);
+        two_reg(&mut program, 0x8f, 0, 1);
+        two_reg(&mut program, 0x90, 0, 1);
+        two_reg(&mut program, 0x92, 1, 1);
+        cases.push(TestCase {
+            name: format!("fmov/ftoi/transmute {}", a),
+            opcode: 0x90,
+            program,
+            steps: 4,
+            checks: vec![
+                Check::Float(0, a),
+                Check::Int(0, a as i32 as u32),
+                Check::Int(1, a.to_bits()),
+                Check::Flags {
+                    mask: INT_FLAGS,
+                    value: int_flags(a.to_bits()),
+                },
+            ],
+        });
+    }
+}
+
+fn memory_cases(cases: &mut Vec<TestCase>) {
+    let widths = [
+        ("int", 0x96, 0xffffffff),
+        ("short", 0x97, 0xffff),
+        ("byte", 0x98, 0xff),
+    ];
+    for &a in INT_OPERANDS.iter() {
+        for &(name, opcode, mask) in widths.iter() {
+            // Clobber the destination first so short and byte stores are observable
+            let mut program = vec![];
+            lit_int(&mut program, 0, 0xffffffff);
+            lit_int(&mut program, 1, 0x1000);
+            two_reg(&mut program, 0x96, 0, 1);
+            lit_int(&mut program, 0, a);
+            two_reg(&mut program, opcode, 0, 1);
+            two_reg(&mut program, 0x94, 2, 1);
+            cases.push(TestCase {
+                name: format!("store {} {:#010x}", name, a),
+                opcode,
+                program,
+                steps: 6,
+                checks: vec![Check::Int(2, a & mask | !mask)],
+            });
+        }
+    }
+
+    for &a in FLOAT_OPERANDS.iter() {
+        let mut program = vec![];
+        lit_float(&mut program, 0, a);
+        lit_int(&mut program, 1, 0x1000);
+        two_reg(&mut program, 0x99, 0, 1);
+        two_reg(&mut program, 0x95, 2, 1);
+        cases.push(TestCase {
+            name: format!("store float {}", a),
+            opcode: 0x99,
+            program,
+            steps: 4,
+            checks: vec![Check::Float(2, a)],
+        });
+    }
+}
+
+fn branch_cases(cases: &mut Vec<TestCase>) {
+    let conditions = [
+        F_ZERO,
+        F_OVERFLOW,
+        F_CARRY,
+        F_NEGATIVE,
+        F_PARITY,
+        F_NAN,
+        F_INFINITE,
+        F_MEMMAP_ENABLE,
+    ];
+    for (i, &flag) in conditions.iter().enumerate() {
+        // Enabling the memory map would make the branch itself fault, so it is only tested clear
+        let values: &[bool] = if flag == F_MEMMAP_ENABLE {
+            &[false]
+        } else {
+            &[false, true]
+        };
+        for &set in values.iter() {
+            for &(opcode, taken) in [(i as u8, set), (i as u8 | 0x08, !set)].iter() {
+                let mut program = vec![];
+                lit_int(&mut program, 0, (set as u32) << flag);
+                two_reg(&mut program, 0x9a, 0, 0);
+                program.push(opcode);
+                program.extend_from_slice(&0x100u32.to_le_bytes());
+                let fallthrough = program.len() as u32;
+                cases.push(TestCase {
+                    name: format!(
+                        "branch {:#04x} with flag {} {}",
+                        opcode,
+                        flag,
+                        if set { "set" } else { "clear" }
+                    ),
+                    opcode,
+                    program,
+                    steps: 3,
+                    checks: vec![Check::Int(R_PC, if taken { 0x100 } else { fallthrough })],
+                });
+            }
+        }
+    }
+
+    for &(opcode, carry) in [(0x10, false), (0x11, true)].iter() {
+        let mut program = vec![];
+        lit_int(&mut program, 0, (!carry as u32) << F_CARRY);
+        two_reg(&mut program, 0x9a, 0, 0);
+        program.push(opcode);
+        cases.push(TestCase {
+            name: format!("set carry {}", carry),
+            opcode,
+            program,
+            steps: 3,
+            checks: vec![Check::Flags {
+                mask: 1 << F_CARRY,
+                value: (carry as u32) << F_CARRY,
+            }],
+        });
+    }
+}
+
+// cmp and fcmp followed by each compare branch, with overflow and carry left set beforehand so
+// stale flags would show
+fn compare_cases(cases: &mut Vec<TestCase>) {
+    let stale = 1 << F_OVERFLOW | 1 << F_CARRY;
+    let mut compare_case = |name: String, opcode: u8, program: Vec<u8>, flags: u32| {
+        for (condition, branch) in COMPARE_BRANCHES.iter().enumerate() {
+            let mut program = program.clone();
+            program.push(0x22 + condition as u8);
+            program.extend_from_slice(&0x100u32.to_le_bytes());
+            let fallthrough = program.len() as u32;
+            let taken = branch_reference(flags, condition);
+            cases.push(TestCase {
+                name: format!("{} {}", name, branch),
+                opcode,
+                steps: 6,
+                checks: vec![
+                    Check::Int(R_PC, if taken { 0x100 } else { fallthrough }),
+                    Check::Flags {
+                        mask: COMPARE_FLAGS,
+                        value: flags & COMPARE_FLAGS,
+                    },
+                ],
+                program,
+            });
+        }
+    };
+
+    for &a in INT_OPERANDS.iter() {
+        for &b in INT_OPERANDS.iter() {
+            let mut program = vec![];
+            set_flags(&mut program, stale);
+            lit_int(&mut program, 0, a);
+            lit_int(&mut program, 1, b);
+            two_reg(&mut program, 0xa0, 0, 1);
+            let (_, flags) = add_reference(a, !b, true);
+            compare_case(
+                format!("cmp {:#010x}, {:#010x}", a, b),
+                0xa0,
+                program,
+                flags,
+            );
+        }
+    }
+
+    for &a in FLOAT_OPERANDS.iter() {
+        for &b in FLOAT_OPERANDS.iter() {
+            let mut program = vec![];
+            set_flags(&mut program, stale);
+            lit_float(&mut program, 0, a);
+            lit_float(&mut program, 1, b);
+            two_reg(&mut program, 0xa1, 0, 1);
+            let flags = fcmp_reference(a, b);
+            compare_case(format!("fcmp {}, {}", a, b), 0xa1, program, flags);
+        }
+    }
+}
+
+fn rotate_cases(cases: &mut Vec<TestCase>) {
+    let rotates = [
+        ("rol", 0xa5, false, false),
+        ("ror", 0xa6, true, false),
+        ("rcl", 0xa7, false, true),
+        ("rcr", 0xa8, true, true),
+    ];
+    for &a in INT_OPERANDS.iter() {
+        for &b in SHIFT_OPERANDS.iter() {
+            for &(name, opcode, right, through_carry) in rotates.iter() {
+                for &carry in [false, true].iter() {
+                    let (res, carry_out) = rotate_reference(a, b, right, through_carry, carry);
+                    let mut program = vec![];
+                    set_flags(&mut program, flag(carry, F_CARRY));
+                    lit_int(&mut program, 0, a);
+                    lit_int(&mut program, 1, b);
+                    two_reg(&mut program, opcode, 0, 1);
+                    cases.push(TestCase {
+                        name: format!("{} {:#010x}, {} with carry {}", name, a, b, carry),
+                        opcode,
+                        program,
+                        steps: 5,
+                        checks: vec![
+                            Check::Int(0, res),
+                            Check::Flags {
+                                mask: SHIFT_FLAGS,
+                                value: int_flags(res) | flag(carry_out, F_CARRY),
+                            },
+                        ],
+                    });
+                }
+            }
+        }
+    }
+}
+
+// The forms taking a literal after the register byte, which never add the carry in
+fn immediate_cases(cases: &mut Vec<TestCase>) {
+    let mut immediate_case = |name: &str, opcode: u8, a: u32, b: u32, res: u32, flags| {
+        // Carry starts set to show that it is neither added in nor cleared by the logic forms
+        let mut program = vec![];
+        set_flags(&mut program, 1 << F_CARRY);
+        lit_int(&mut program, 0, a);
+        program.extend_from_slice(&[opcode, 0x00]);
+        program.extend_from_slice(&b.to_le_bytes());
+        cases.push(TestCase {
+            name: format!("{} {:#010x}, {:#010x}", name, a, b),
+            opcode,
+            program,
+            steps: 4,
+            checks: vec![
+                Check::Int(0, res),
+                Check::Flags {
+                    mask: ADD_FLAGS,
+                    value: flags,
+                },
+            ],
+        });
+    };
+
+    for &a in INT_OPERANDS.iter() {
+        for &b in INT_OPERANDS.iter() {
+            let (res, flags) = add_reference(a, b, false);
+            immediate_case("addi", 0xa9, a, b, res, flags);
+            let (res, flags) = add_reference(a, !b, true);
+            immediate_case("subi", 0xaa, a, b, res, flags);
+
+            let carry = 1 << F_CARRY;
+            immediate_case("andi", 0xab, a, b, a & b, int_flags(a & b) | carry);
+            immediate_case("ori", 0xac, a, b, a | b, int_flags(a | b) | carry);
+            immediate_case("xori", 0xad, a, b, a ^ b, int_flags(a ^ b) | carry);
+        }
+
+        for &b in SHIFT_OPERANDS.iter() {
+            for &(name, opcode, left) in [("shli", 0xae, true), ("shri", 0xaf, false)].iter() {
+                let (res, carry) = shift_immediate_reference(a, b, left);
+                let flags = int_flags(res) | flag(carry.unwrap_or(true), F_CARRY);
+                immediate_case(name, opcode, a, b, res, flags);
+            }
+        }
+    }
+}
+
+// Pushes go below the stack pointer a word at a time and pops take them back
+fn stack_cases(cases: &mut Vec<TestCase>) {
+    for &a in INT_OPERANDS.iter() {
+        let mut program = vec![];
+        lit_int(&mut program, R_SP, 0x2000);
+        lit_int(&mut program, 0, a);
+        two_reg(&mut program, 0xb0, 0, 0);
+        two_reg(&mut program, 0xb1, 1, 0);
+        program.push(0x62);
+        program.extend_from_slice(&0x1ffcu32.to_le_bytes());
+        cases.push(TestCase {
+            name: format!("push/pop {:#010x}", a),
+            opcode: 0xb0,
+            program,
+            steps: 5,
+            checks: vec![Check::Int(1, a), Check::Int(2, a), Check::Int(R_SP, 0x2000)],
+        });
+    }
+
+    for &a in FLOAT_OPERANDS.iter() {
+        let mut program = vec![];
+        lit_int(&mut program, R_SP, 0x2000);
+        lit_float(&mut program, 0, a);
+        two_reg(&mut program, 0xb2, 0, 0);
+        two_reg(&mut program, 0xb3, 1, 0);
+        program.push(0x62);
+        program.extend_from_slice(&0x1ffcu32.to_le_bytes());
+        cases.push(TestCase {
+            name: format!("fpush/fpop {}", a),
+            opcode: 0xb2,
+            program,
+            steps: 5,
+            checks: vec![
+                Check::Float(1, a),
+                Check::Int(2, a.to_bits()),
+                Check::Int(R_SP, 0x2000),
+            ],
+        });
+    }
+}
+
+// Rounding, conversion to an integer, and min and max from the escaped page
+fn escaped_cases(cases: &mut Vec<TestCase>) {
+    let rounding = [0.5, 1.5, 2.5, -0.5, -1.5, -2.7, 2.0e9, 3.0e9, -3.0e9];
+    for &a in rounding.iter().chain(FLOAT_OPERANDS.iter()) {
+        for mode in 0..4 {
+            let res = round_reference(a, mode);
+            let mut program = vec![];
+            lit_float(&mut program, 1, a);
+            escaped(&mut program, mode, 0, 1);
+            cases.push(TestCase {
+                name: format!("fround mode {} {}", mode, a),
+                opcode: ESCAPE,
+                program,
+                steps: 2,
+                checks: vec![
+                    Check::Float(0, res),
+                    Check::Flags {
+                        mask: FLOAT_FLAGS,
+                        value: float_flags(res),
+                    },
+                ],
+            });
+
+            // Saturates out of range, and converts nan to 0
+            let res = if res.is_nan() { 0 } else { res as i32 as u32 };
+            let out_of_range = res as i32 as f32 != round_reference(a, mode) && !a.is_nan();
+            let mut program = vec![];
+            lit_float(&mut program, 1, a);
+            escaped(&mut program, 0x04 | mode, 0, 1);
+            cases.push(TestCase {
+                name: format!("fcvt mode {} {}", mode, a),
+                opcode: ESCAPE,
+                program,
+                steps: 2,
+                checks: vec![
+                    Check::Int(0, res),
+                    Check::Flags {
+                        mask: CONVERT_FLAGS,
+                        value: int_flags(res)
+                            | flag(out_of_range, F_OVERFLOW)
+                            | flag(a.is_nan(), F_NAN),
+                    },
+                ],
+            });
+        }
+    }
+
+    for &a in FLOAT_OPERANDS.iter() {
+        for &b in FLOAT_OPERANDS.iter() {
+            for &(name, func, max) in [("fmin", 0x08, false), ("fmax", 0x09, true)].iter() {
+                let res = min_max_reference(a, b, max);
+                let mut program = vec![];
+                lit_float(&mut program, 0, a);
+                lit_float(&mut program, 1, b);
+                escaped(&mut program, func, 0, 1);
+                cases.push(TestCase {
+                    name: format!("{} {}, {}", name, a, b),
+                    opcode: ESCAPE,
+                    program,
+                    steps: 3,
+                    checks: vec![
+                        Check::Float(0, res),
+                        Check::Flags {
+                            mask: FLOAT_FLAGS,
+                            value: float_flags(res),
+                        },
+                    ],
+                });
+            }
+        }
+    }
+}
+
+pub fn generate() -> Vec<TestCase> {
+    let mut cases = vec![];
+    arithmetic_cases(&mut cases);
+    bitwise_cases(&mut cases);
+    move_cases(&mut cases);
+    memory_cases(&mut cases);
+    branch_cases(&mut cases);
+    compare_cases(&mut cases);
+    rotate_cases(&mut cases);
+    immediate_cases(&mut cases);
+    stack_cases(&mut cases);
+    escaped_cases(&mut cases);
+    cases
+}
+
+// Loads a test case into a fresh cpu, runs it, and checks the results
+pub fn run_case<T>(mut cpu: Cpu<T>, case: &TestCase) -> CaseResult
+where
+    T: Address,
+{
+    for (addr, byte) in case.program.iter().enumerate() {
+        cpu.addressing.write(addr as u32, *byte);
+    }
+    for _ in 0..case.steps {
+        cpu.step();
+    }
+
+    let mut failures = vec![];
+    for check in case.checks.iter() {
+        match *check {
+            Check::Int(r, expected) => {
+                if cpu.xs[r] != expected {
+                    failures.push(format!(
+                        "x{}: expected {:#010x}, got {:#010x}",
+                        r, expected, cpu.xs[r]
+                    ));
+                }
+            }
+
+            Check::Float(r, expected) => {
+                let got = cpu.fs[r];
+                if !(got.to_bits() == expected.to_bits() || got.is_nan() && expected.is_nan()) {
+                    failures.push(format!("f{}: expected {}, got {}", r, expected, got));
+                }
+            }
+
+            Check::Flags { mask, value } => {
+                if cpu.flags & mask != value {
+                    failures.push(format!(
+                        "flags: expected {:#015b}, got {:#015b} (mask {:#015b})",
+                        value,
+                        cpu.flags & mask,
+                        mask
+                    ));
+                }
+            }
+        }
+    }
+
+    CaseResult {
+        name: case.name.clone(),
+        opcode: case.opcode,
+        failures,
+    }
+}
+
+// Runs every generated case, creating a new cpu for each one
+pub fn run<T, F>(mut new_cpu: F) -> Report
+where
+    T: Address,
+    F: FnMut() -> Cpu<T>,
+{
+    Report {
+        results: generate()
+            .iter()
+            .map(|case| run_case(new_cpu(), case))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn interpreter_conforms() {
+        let report = run(|| Cpu::new(SimpleAddress::default()));
+        assert!(report.is_conformant(), "{}", report);
+        assert!(report.passed() > 2500);
+    }
+
+    #[test]
+    fn report_failures() {
+        let mut case = generate().remove(0);
+        case.checks.push(Check::Int(0, 0xdeadbeef));
+        let result = run_case(Cpu::new(SimpleAddress::default()), &case);
+        assert!(!result.passed());
+
+        let report = Report {
+            results: vec![result],
+        };
+        assert!(report.to_string().starts_with("FAIL add"));
+        assert!(report.to_string().ends_with("0/1 cases passed"));
+    }
+}
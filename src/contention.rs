@@ -0,0 +1,211 @@
+// Memory contention model for systems with several bus masters (cores and DMA engines)
+// Memory is split into banks which can each serve one access at a time. An access to a busy bank
+// is delayed until the bank is free and the delay is counted as stall cycles for the requester.
+// Time is measured in the same cycles as the cpus being modelled.
+// Accesses issued in the same cycle are arbitrated, which decides who gets a contended bank first.
+// A Cluster given a model feeds it the accesses made by its cores and DMA engines (see cluster.rs).
+
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Requester {
+    Core(usize),
+    Dma(usize),
+}
+
+impl Requester {
+    pub fn is_dma(self) -> bool {
+        matches!(self, Requester::Dma(_))
+    }
+}
+
+// Order in which accesses issued in the same cycle to the same bank are served
+// Requesters of the same kind are served in the order their accesses were given
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arbitration {
+    CpuPriority,
+    DmaPriority,
+
+    // Cpus and DMA engines take turns winning each bank
+    RoundRobin,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RequesterStats {
+    pub accesses: u64,
+    pub stalled_accesses: u64,
+    pub stall_cycles: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentionModel {
+    // Banks are 2^bank_bits bytes
+    bank_bits: u32,
+
+    // Cycles a bank is busy for after each access
+    access_cycles: u64,
+
+    // Cycle each bank becomes free at
+    busy_until: HashMap<u32, u64>,
+
+    arbitration: Arbitration,
+
+    // Banks DMA engines win next under round robin arbitration
+    dma_turn: HashSet<u32>,
+
+    stats: HashMap<Requester, RequesterStats>,
+}
+
+impl ContentionModel {
+    pub fn new(bank_bits: u32, access_cycles: u64) -> ContentionModel {
+        ContentionModel {
+            bank_bits: bank_bits.min(32),
+            access_cycles,
+            busy_until: HashMap::new(),
+            arbitration: Arbitration::CpuPriority,
+            dma_turn: HashSet::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    pub fn arbitration(mut self, arbitration: Arbitration) -> ContentionModel {
+        self.arbitration = arbitration;
+        self
+    }
+
+    pub fn bank(&self, addr: u32) -> u32 {
+        addr.checked_shr(self.bank_bits).unwrap_or(0)
+    }
+
+    // Records an access issued at a cycle and returns how many cycles it is delayed by
+    pub fn access(&mut self, requester: Requester, cycle: u64, addr: u32) -> u64 {
+        let bank = self.bank(addr);
+        let busy_until = self.busy_until.entry(bank).or_insert(0);
+        let start = cycle.max(*busy_until);
+        *busy_until = start + self.access_cycles;
+
+        let stall = start - cycle;
+        let stats = self.stats.entry(requester).or_default();
+        stats.accesses += 1;
+        if stall != 0 {
+            stats.stalled_accesses += 1;
+            stats.stall_cycles += stall;
+        }
+        stall
+    }
+
+    // Records accesses issued in the same cycle, returning how many cycles each is delayed by
+    pub fn access_all(&mut self, cycle: u64, requests: &[(Requester, u32)]) -> Vec<u64> {
+        let dma_first = |model: &ContentionModel, bank: u32| match model.arbitration {
+            Arbitration::CpuPriority => false,
+            Arbitration::DmaPriority => true,
+            Arbitration::RoundRobin => model.dma_turn.contains(&bank),
+        };
+        let mut order: Vec<usize> = (0..requests.len()).collect();
+        order.sort_by_key(|&i| {
+            let (requester, addr) = requests[i];
+            requester.is_dma() != dma_first(self, self.bank(addr))
+        });
+
+        let mut stalls = vec![0; requests.len()];
+        for &i in order.iter() {
+            let (requester, addr) = requests[i];
+            stalls[i] = self.access(requester, cycle, addr);
+        }
+
+        // Whoever won a bank that both kinds of requester wanted waits its turn next time
+        if self.arbitration == Arbitration::RoundRobin {
+            let banks = |dma: bool| -> HashSet<u32> {
+                requests
+                    .iter()
+                    .filter(|(requester, _)| requester.is_dma() == dma)
+                    .map(|&(_, addr)| self.bank(addr))
+                    .collect()
+            };
+            for bank in banks(false).intersection(&banks(true)) {
+                if !self.dma_turn.remove(bank) {
+                    self.dma_turn.insert(*bank);
+                }
+            }
+        }
+        stalls
+    }
+
+    pub fn stats(&self, requester: Requester) -> RequesterStats {
+        self.stats.get(&requester).copied().unwrap_or_default()
+    }
+
+    pub fn total_stall_cycles(&self) -> u64 {
+        self.stats.values().map(|s| s.stall_cycles).sum()
+    }
+
+    // Frees every bank and clears the statistics
+    pub fn reset(&mut self) {
+        self.busy_until.clear();
+        self.dma_turn.clear();
+        self.stats.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_bank() {
+        let mut model = ContentionModel::new(12, 2);
+        assert_eq!(model.access(Requester::Core(0), 10, 0x1000), 0);
+        assert_eq!(model.access(Requester::Dma(0), 10, 0x1004), 2);
+        assert_eq!(model.access(Requester::Core(1), 11, 0x1ffc), 3);
+
+        // The bank is free again by cycle 16
+        assert_eq!(model.access(Requester::Core(0), 16, 0x1000), 0);
+
+        assert_eq!(
+            model.stats(Requester::Core(0)),
+            RequesterStats {
+                accesses: 2,
+                stalled_accesses: 0,
+                stall_cycles: 0
+            }
+        );
+        assert_eq!(model.stats(Requester::Dma(0)).stall_cycles, 2);
+        assert_eq!(model.total_stall_cycles(), 5);
+    }
+
+    #[test]
+    fn separate_banks() {
+        let mut model = ContentionModel::new(12, 4);
+        assert_eq!(model.access(Requester::Core(0), 0, 0x1000), 0);
+        assert_eq!(model.access(Requester::Core(1), 0, 0x2000), 0);
+        assert_eq!(model.access(Requester::Dma(0), 1, 0x0fff), 0);
+        assert_eq!(model.total_stall_cycles(), 0);
+
+        model.reset();
+        assert_eq!(model.stats(Requester::Core(0)).accesses, 0);
+    }
+
+    #[test]
+    fn arbitration() {
+        let requests = [(Requester::Core(0), 0x1000), (Requester::Dma(0), 0x1004)];
+
+        let mut model = ContentionModel::new(12, 2);
+        assert_eq!(model.access_all(0, &requests), vec![0, 2]);
+
+        let mut model = ContentionModel::new(12, 2).arbitration(Arbitration::DmaPriority);
+        assert_eq!(model.access_all(0, &requests), vec![2, 0]);
+        assert_eq!(model.stats(Requester::Core(0)).stall_cycles, 2);
+
+        let mut model = ContentionModel::new(12, 2).arbitration(Arbitration::RoundRobin);
+        assert_eq!(model.access_all(0, &requests), vec![0, 2]);
+        assert_eq!(model.access_all(10, &requests), vec![2, 0]);
+        assert_eq!(model.access_all(20, &requests), vec![0, 2]);
+
+        // Requests to other banks do not take a turn
+        model.access_all(
+            30,
+            &[(Requester::Core(0), 0x1000), (Requester::Dma(0), 0x2000)],
+        );
+        assert_eq!(model.access_all(40, &requests), vec![2, 0]);
+    }
+}
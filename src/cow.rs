@@ -0,0 +1,170 @@
+// Copy-on-write memory for forking machines
+// Memory is split into pages shared between every fork until one of them writes to a page, which
+// then gets its own copy. Pages that have never been written are not allocated and read as zero,
+// so forking only copies the page list rather than the contents of memory.
+
+use std::rc::Rc;
+
+use crate::{Address, Cpu, SIMPLE_ADDRESS_SIZE};
+
+const PAGE_BITS: u32 = 12;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+#[derive(Clone)]
+pub struct CowAddress {
+    pages: Vec<Option<Rc<[u8; PAGE_SIZE]>>>,
+}
+
+impl Default for CowAddress {
+    fn default() -> CowAddress {
+        CowAddress::new(SIMPLE_ADDRESS_SIZE)
+    }
+}
+
+impl CowAddress {
+    // Size is rounded up to a whole number of 4K pages, accesses past the end read as zero
+    pub fn new(size: usize) -> CowAddress {
+        CowAddress {
+            pages: vec![None; (size + PAGE_SIZE - 1) >> PAGE_BITS],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages.len() << PAGE_BITS
+    }
+
+    pub fn load(&mut self, addr: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.write(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+
+    // Number of pages with their own copy, rather than shared with another fork or unallocated
+    pub fn private_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|page| matches!(page, Some(page) if Rc::strong_count(page) == 1))
+            .count()
+    }
+
+    fn split(addr: u32) -> (usize, usize) {
+        (
+            (addr >> PAGE_BITS) as usize,
+            addr as usize & (PAGE_SIZE - 1),
+        )
+    }
+}
+
+impl Address for CowAddress {
+    fn read(&mut self, addr: u32) -> u8 {
+        let (page, offset) = CowAddress::split(addr);
+        match self.pages.get(page) {
+            Some(Some(page)) => page[offset],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        let (page, offset) = CowAddress::split(addr);
+        if let Some(page) = self.pages.get_mut(page) {
+            let page = page.get_or_insert_with(|| Rc::new([0; PAGE_SIZE]));
+            Rc::make_mut(page)[offset] = data;
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some((self.pages.len() << PAGE_BITS) as u64)
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address + Clone,
+{
+    // Copies the machine, including its analyses, sharing memory with the original if the memory
+    // is copy-on-write
+    // Memory hooks, the uninitialised read callback, and the firmware are not carried over, since
+    // the fork would share them with the original
+    pub fn fork(&self) -> Cpu<T, N> {
+        Cpu {
+            xs: self.xs,
+            fs: self.fs,
+            vs: self.vs,
+            flags: self.flags,
+            interrupt_mask: self.interrupt_mask,
+            mmu: self.mmu.clone(),
+            prefetch: self.prefetch.clone(),
+            dirty: self.dirty.clone(),
+            system_sp: self.system_sp,
+            vector_base: self.vector_base,
+            fault_address: self.fault_address,
+            fault_cause: self.fault_cause,
+            last_fault: self.last_fault,
+            paused: self.paused,
+            halted: self.halted,
+            shadow_sp: self.shadow_sp,
+            legacy_stack: self.legacy_stack,
+            soft_float: self.soft_float,
+            features: self.features,
+            traps: self.traps,
+            trapped_instruction: self.trapped_instruction,
+            shutdown: self.shutdown,
+            interrupts: self.interrupts.clone(),
+            cycles: self.cycles,
+            latency: self.latency.clone(),
+            instruction_pc: self.instruction_pc,
+            uninit: self.uninit.clone(),
+            taint: self.taint.clone(),
+            frames: self.frames.clone(),
+            audit: self.audit.clone(),
+            profile: self.profile.clone(),
+            histogram: self.histogram.clone(),
+            firmware: None,
+            memory_hooks: vec![],
+            addressing: self.addressing.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_on_write() {
+        let mut memory = CowAddress::new(0x10000);
+        memory.load(0x1000, &[1, 2, 3]);
+        memory.write(0x5000, 4);
+        assert_eq!(memory.private_pages(), 2);
+
+        let mut fork = memory.clone();
+        assert_eq!(memory.private_pages(), 0);
+        fork.write(0x1001, 9);
+        assert_eq!(fork.private_pages(), 1);
+        assert_eq!(fork.read(0x1001), 9);
+        assert_eq!(memory.read(0x1001), 2);
+        assert_eq!(fork.read(0x5000), 4);
+
+        // Past the end
+        memory.write(0x10000, 1);
+        assert_eq!(memory.read(0x10000), 0);
+    }
+
+    #[test]
+    fn fork() {
+        // x0 = 1; x1 = 0x100; store x0 -> [x1]
+        let mut memory = CowAddress::default();
+        memory.load(0, &[0x40, 1, 0, 0, 0, 0x41, 0, 1, 0, 0, 0x96, 0x01]);
+        let mut cpu = Cpu::new(memory);
+        cpu.step();
+        cpu.step();
+
+        let mut fork = cpu.fork();
+        fork.xs[0] = 2;
+        cpu.step();
+        fork.step();
+        assert_eq!(cpu.addressing.read(0x100), 1);
+        assert_eq!(fork.addressing.read(0x100), 2);
+        assert_eq!(fork.cycles(), 3);
+    }
+}
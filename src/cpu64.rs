@@ -0,0 +1,617 @@
+// 64 bit variant of the architecture
+// Integer registers, addresses, literals, and the integer loads and stores are 64 bits wide, and
+// the integer flags are computed on 64 bit values. Float registers stay 32 bits wide. Instructions
+// use the same opcode map as the 32 bit machine, with 8 byte immediates instead of 4 byte ones.
+//
+// Memory mapping uses a four level page table covering 48 bit virtual addresses with 4KiB pages.
+// Each level is indexed by 9 bits of the virtual address and holds 8 byte entries:
+// 0xaaaaaaaaaaaaapxx
+// a - physical address of the next level table or page
+// p - 1 if present, then the read, write, and execute permissions (checked on the last level)
+//
+// Interrupts are not supported yet, so faults are returned from step.
+//
+// Only the original instruction set is implemented: the flag branches, clc to usr, call and ret,
+// the literal and memory loads and stores, and 0x80-0x9b, where msr and mrs reach the flags, the
+// memory map, and the interrupt mask as system registers 0-2. Every other opcode, the escaped page
+// and the register extension prefix included, faults with IllegalOpcode. call and ret keep the
+// base and return address on the stack as before, without a shadow stack or frame checks.
+
+use crate::decode::{self, Instruction, Operands};
+use crate::{
+    InvalidMemoryAccess, SimpleAddress, EXEC, F_CARRY, F_INFINITE, F_INTERRUPT_ENABLE,
+    F_MEMMAP_ENABLE, F_NAN, F_NEGATIVE, F_OVERFLOW, F_PARITY, F_USER_RING, F_ZERO, READ, R_BASE,
+    R_PC, R_SP, WRITE,
+};
+
+pub trait Address64 {
+    fn read(&mut self, addr: u64) -> u8;
+
+    fn write(&mut self, addr: u64, data: u8);
+}
+
+impl Address64 for SimpleAddress {
+    fn read(&mut self, addr: u64) -> u8 {
+        match self.index(addr) {
+            Some(i) => self.memory[i],
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u64, data: u8) {
+        if let Some(i) = self.index(addr) {
+            self.memory[i] = data;
+        }
+    }
+}
+
+const PAGE_BITS: u32 = 12;
+const LEVEL_BITS: u32 = 9;
+const LEVELS: u32 = 4;
+const VIRTUAL_BITS: u32 = PAGE_BITS + LEVEL_BITS * LEVELS;
+
+#[derive(Clone, Debug)]
+pub struct Cpu64State {
+    pub xs: [u64; 16],
+    pub fs: [f32; 16],
+    pub flags: u32,
+    pub interrupt_mask: u8,
+    pub memmap: u64,
+    pub system_sp: u64,
+}
+
+pub struct Cpu64<T>
+where
+    T: Address64,
+{
+    xs: [u64; 16],
+    fs: [f32; 16],
+    flags: u32,
+    interrupt_mask: u8,
+
+    // Physical address of the top level page table
+    memmap: u64,
+
+    system_sp: u64,
+    addressing: T,
+}
+
+impl<T> Cpu64<T>
+where
+    T: Address64,
+{
+    pub fn new(t: T) -> Cpu64<T> {
+        Cpu64 {
+            xs: [0; 16],
+            fs: [0.0; 16],
+            flags: 0,
+            interrupt_mask: 0xff,
+            memmap: 0,
+            system_sp: 0,
+            addressing: t,
+        }
+    }
+
+    pub fn state(&self) -> Cpu64State {
+        Cpu64State {
+            xs: self.xs,
+            fs: self.fs,
+            flags: self.flags,
+            interrupt_mask: self.interrupt_mask,
+            memmap: self.memmap,
+            system_sp: self.system_sp,
+        }
+    }
+
+    pub fn addressing(&mut self) -> &mut T {
+        &mut self.addressing
+    }
+
+    fn get_flag(&self, flag: u32) -> bool {
+        self.flags & (1 << flag) != 0
+    }
+
+    fn clear_flags(&mut self, flags: &[u32]) {
+        for flag in flags.iter() {
+            self.flags &= !(1 << flag);
+        }
+    }
+
+    fn set_flag(&mut self, flag: u32, val: bool) {
+        self.flags |= (val as u32) << flag;
+    }
+
+    fn check_memory(&mut self, addr: u64, permissions: u8) -> Result<u64, InvalidMemoryAccess> {
+        if !self.get_flag(F_MEMMAP_ENABLE) {
+            return Ok(addr);
+        }
+        if addr >> VIRTUAL_BITS != 0 {
+            return Err(InvalidMemoryAccess::UsedFreePage);
+        }
+
+        let mut table = self.memmap;
+        let mut entry = 0;
+        for level in (0..LEVELS).rev() {
+            let index = addr >> (PAGE_BITS + LEVEL_BITS * level) & ((1 << LEVEL_BITS) - 1);
+            let entry_addr = table.wrapping_add(index * 8);
+            entry = 0;
+            for i in 0..8 {
+                entry |= (self.addressing.read(entry_addr.wrapping_add(i)) as u64) << (8 * i);
+            }
+
+            if entry & 0x08 == 0 {
+                return Err(InvalidMemoryAccess::UsedFreePage);
+            }
+            table = entry & !((1 << PAGE_BITS) - 1);
+        }
+
+        let p = (entry & 0x0f) as u8;
+        if p & permissions != permissions {
+            Err(InvalidMemoryAccess::InvalidPermissions(p, permissions))
+        } else {
+            Ok(table | addr & ((1 << PAGE_BITS) - 1))
+        }
+    }
+
+    fn exec(&mut self) -> Result<u8, InvalidMemoryAccess> {
+        let addr = self.check_memory(self.xs[R_PC], EXEC)?;
+        let res = self.addressing.read(addr);
+        self.xs[R_PC] = self.xs[R_PC].wrapping_add(1);
+        Ok(res)
+    }
+
+    fn read(&mut self, addr: u64, bytes: u32) -> Result<u64, InvalidMemoryAccess> {
+        let mut data = 0;
+        for i in 0..bytes {
+            let addr = self.check_memory(addr.wrapping_add(i as u64), READ)?;
+            data |= (self.addressing.read(addr) as u64) << (8 * i);
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, addr: u64, data: u64, bytes: u32) -> Result<(), InvalidMemoryAccess> {
+        for i in 0..bytes {
+            let addr = self.check_memory(addr.wrapping_add(i as u64), WRITE)?;
+            self.addressing.write(addr, (data >> (8 * i)) as u8);
+        }
+        Ok(())
+    }
+
+    fn update_flags_int(&mut self, x: u64) {
+        self.clear_flags(&[F_ZERO, F_NEGATIVE, F_PARITY]);
+        self.set_flag(F_ZERO, x == 0);
+        self.set_flag(F_NEGATIVE, x >> 63 != 0);
+        self.set_flag(F_PARITY, x & 1 != 0);
+    }
+
+    fn update_flags_float(&mut self, x: f32) {
+        self.clear_flags(&[F_ZERO, F_NEGATIVE, F_NAN, F_INFINITE]);
+        self.set_flag(F_ZERO, x == 0.0);
+        self.set_flag(F_NEGATIVE, x.is_sign_negative());
+        self.set_flag(F_NAN, x.is_nan());
+        self.set_flag(F_INFINITE, x.is_infinite());
+    }
+
+    fn set_int(&mut self, x0: usize, data: u64) {
+        self.xs[x0] = data;
+        self.update_flags_int(data);
+    }
+
+    fn set_float(&mut self, f0: usize, data: f32) {
+        self.fs[f0] = data;
+        self.update_flags_float(data);
+    }
+
+    fn iadd(&mut self, x0: usize, x1: u64) {
+        let a = self.xs[x0];
+        let res = a as u128 + x1 as u128 + self.get_flag(F_CARRY) as u128;
+        self.clear_flags(&[F_OVERFLOW, F_CARRY]);
+        self.set_flag(F_CARRY, res >> 64 != 0);
+        self.set_flag(
+            F_OVERFLOW,
+            a >> 63 == x1 >> 63 && a >> 63 != (res as u64) >> 63,
+        );
+        self.set_int(x0, res as u64);
+    }
+
+    // Carry and overflow are set when the product does not fit in 64 bits
+    fn imul(&mut self, x0: usize, x1: u64) {
+        let res = self.xs[x0] as u128 * x1 as u128;
+        self.clear_flags(&[F_OVERFLOW, F_CARRY]);
+        self.set_flag(F_OVERFLOW, res >> 64 != 0);
+        self.set_flag(F_CARRY, res >> 64 != 0);
+        self.set_int(x0, res as u64);
+    }
+
+    fn shift(&mut self, x0: usize, amount: u64, left: bool) {
+        let a = self.xs[x0];
+        let res = match (amount < 64, left) {
+            (true, true) => a << amount,
+            (true, false) => a >> amount,
+            (false, _) => 0,
+        } | self.get_flag(F_CARRY) as u64;
+
+        self.clear_flags(&[F_CARRY]);
+        if amount == 1 {
+            self.set_flag(F_CARRY, if left { a >> 63 != 0 } else { a & 1 != 0 });
+        }
+        self.set_int(x0, res);
+    }
+
+    fn push(&mut self, data: u64) -> Result<(), InvalidMemoryAccess> {
+        for i in (0..8).rev() {
+            self.write(self.xs[R_SP], data >> (i * 8), 1)?;
+            self.xs[R_SP] = self.xs[R_SP].wrapping_sub(1);
+        }
+        Ok(())
+    }
+
+    fn pop_base(&mut self) -> Result<u64, InvalidMemoryAccess> {
+        let mut data = 0;
+        for i in 0..8 {
+            self.xs[R_BASE] = self.xs[R_BASE].wrapping_add(1);
+            data |= self.read(self.xs[R_BASE], 1)? << (8 * i);
+        }
+        Ok(data)
+    }
+
+    fn privileged(&self) -> Result<(), InvalidMemoryAccess> {
+        if self.get_flag(F_USER_RING) {
+            Err(InvalidMemoryAccess::UnprivilegedOpcode)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), InvalidMemoryAccess> {
+        let opcode = instruction.opcode;
+        match instruction.operands {
+            Operands::None => match opcode {
+                0x10 | 0x11 => {
+                    self.clear_flags(&[F_CARRY]);
+                    self.set_flag(F_CARRY, opcode == 0x11);
+                }
+                0x12..=0x15 | 0x17 => {
+                    self.privileged()?;
+                    let flag = match opcode {
+                        0x12 | 0x13 => F_MEMMAP_ENABLE,
+                        0x14 | 0x15 => F_INTERRUPT_ENABLE,
+                        _ => F_USER_RING,
+                    };
+                    self.clear_flags(&[flag]);
+                    self.set_flag(flag, opcode & 1 != 0);
+                }
+                0x19 => {
+                    self.xs[R_PC] = self.pop_base()?;
+                    let base = self.pop_base()?;
+                    self.xs[R_SP] = self.xs[R_BASE];
+                    self.xs[R_BASE] = base;
+                }
+                _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
+            },
+
+            Operands::Word(addr) => match opcode {
+                0x00..=0x0f => {
+                    let flag = [
+                        F_ZERO,
+                        F_OVERFLOW,
+                        F_CARRY,
+                        F_NEGATIVE,
+                        F_PARITY,
+                        F_NAN,
+                        F_INFINITE,
+                        F_MEMMAP_ENABLE,
+                    ][opcode as usize & 0x07];
+                    if self.get_flag(flag) == (opcode < 0x08) {
+                        self.xs[R_PC] = addr;
+                    }
+                }
+                0x18 => {
+                    self.push(self.xs[R_BASE])?;
+                    self.push(self.xs[R_PC])?;
+                    self.xs[R_BASE] = self.xs[R_SP];
+                    self.xs[R_PC] = addr;
+                }
+                _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
+            },
+
+            Operands::RegisterWord(r, word) => match opcode & 0xf0 {
+                0x40 => self.set_int(r, word),
+                0x50 => self.set_float(r, f32::from_bits(word as u32)),
+                0x60 => {
+                    let data = self.read(word, 8)?;
+                    self.set_int(r, data);
+                }
+                0x70 => {
+                    let data = self.read(word, 4)?;
+                    self.set_float(r, f32::from_bits(data as u32));
+                }
+                0xc0 => self.write(word, self.xs[r], 8)?,
+                0xd0 => self.write(word, self.xs[r], 2)?,
+                0xe0 => self.write(word, self.xs[r], 1)?,
+                0xf0 => self.write(word, self.fs[r].to_bits() as u64, 4)?,
+                _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
+            },
+
+            Operands::Registers(fst, snd) => {
+                let (x, f) = (self.xs[snd], self.fs[snd]);
+                match opcode {
+                    0x80 => self.iadd(fst, x),
+                    0x81 => self.iadd(fst, !x),
+                    0x82 => self.imul(fst, x),
+                    0x83 | 0x84 if x == 0 => return Err(InvalidMemoryAccess::DivideByZero),
+                    0x83 => self.set_int(fst, self.xs[fst] / x),
+                    0x84 => self.set_int(fst, self.xs[fst] % x),
+
+                    0x85 => self.set_float(fst, self.fs[fst] + f),
+                    0x86 => self.set_float(fst, self.fs[fst] - f),
+                    0x87 => self.set_float(fst, self.fs[fst] * f),
+                    0x88 => self.set_float(fst, self.fs[fst] / f),
+
+                    0x89 => self.shift(fst, x, true),
+                    0x8a => self.shift(fst, x, false),
+                    0x8b => self.set_int(fst, self.xs[fst] & x),
+                    0x8c => self.set_int(fst, self.xs[fst] | x),
+                    0x8d => self.set_int(fst, self.xs[fst] ^ x),
+
+                    0x8e => self.set_int(fst, x),
+                    0x8f => self.set_float(fst, f),
+                    0x90 => self.set_int(fst, f as i64 as u64),
+                    0x91 => self.set_float(fst, x as i64 as f32),
+                    0x92 => self.set_int(fst, f.to_bits() as u64),
+                    0x93 => self.set_float(fst, f32::from_bits(x as u32)),
+
+                    0x94 => {
+                        let data = self.read(x, 8)?;
+                        self.set_int(fst, data);
+                    }
+                    0x95 => {
+                        let data = self.read(x, 4)?;
+                        self.set_float(fst, f32::from_bits(data as u32));
+                    }
+                    0x96 => self.write(self.xs[snd], self.xs[fst], 8)?,
+                    0x97 => self.write(self.xs[snd], self.xs[fst], 2)?,
+                    0x98 => self.write(self.xs[snd], self.xs[fst], 1)?,
+                    0x99 => self.write(self.xs[snd], self.fs[fst].to_bits() as u64, 4)?,
+
+                    0x9a => {
+                        self.privileged()?;
+                        match snd {
+                            0 => self.flags = self.xs[fst] as u32,
+                            1 => self.memmap = self.xs[fst],
+                            2 => self.interrupt_mask = self.xs[fst] as u8,
+                            _ => return Err(InvalidMemoryAccess::InvalidRegister(snd)),
+                        }
+                    }
+                    0x9b => match fst {
+                        0 => self.xs[snd] = self.flags as u64,
+                        1 => self.xs[snd] = self.memmap,
+                        2 => self.xs[snd] = self.interrupt_mask as u64,
+                        _ => return Err(InvalidMemoryAccess::InvalidRegister(fst)),
+                    },
+
+                    _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
+                }
+            }
+
+            Operands::Extended(..) => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
+        }
+
+        Ok(())
+    }
+
+    // Executes one instruction
+    // A faulting instruction leaves the program counter wherever decoding stopped
+    pub fn step(&mut self) -> Result<(), InvalidMemoryAccess> {
+        let instruction = decode::decode(8, || self.exec())?;
+        self.execute(instruction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{conformance, Cpu, StepOutcome};
+
+    fn machine(program: &[u8]) -> Cpu64<SimpleAddress> {
+        let mut memory = SimpleAddress::default();
+        memory.load(0, program);
+        Cpu64::new(memory)
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut program = vec![0x40];
+        program.extend_from_slice(&0xffff_ffff_ffff_ffffu64.to_le_bytes());
+        program.push(0x41);
+        program.extend_from_slice(&1u64.to_le_bytes());
+        program.extend_from_slice(&[0x80, 0x01]);
+
+        let mut cpu = machine(&program);
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let state = cpu.state();
+        assert_eq!(state.xs[0], 0);
+        assert!(state.flags & 1 << F_CARRY != 0);
+        assert!(state.flags & 1 << F_ZERO != 0);
+        assert!(state.flags & 1 << F_OVERFLOW == 0);
+
+        cpu.xs[0] = 0x7fff_ffff_ffff_ffff;
+        cpu.flags = 0;
+        cpu.iadd(0, 1);
+        assert_eq!(cpu.xs[0], 0x8000_0000_0000_0000);
+        assert!(cpu.get_flag(F_OVERFLOW));
+        assert!(cpu.get_flag(F_NEGATIVE));
+
+        // Products that do not fit in 64 bits set carry and overflow
+        cpu.xs[0] = 1 << 32;
+        cpu.flags = 0;
+        cpu.imul(0, 1 << 32);
+        assert_eq!(cpu.xs[0], 0);
+        assert!(cpu.get_flag(F_CARRY) && cpu.get_flag(F_OVERFLOW));
+    }
+
+    #[test]
+    fn call_and_return() {
+        // call 0x100; at 0x100: ret
+        let mut program = vec![0x18];
+        program.extend_from_slice(&0x100u64.to_le_bytes());
+        let mut cpu = machine(&program);
+        cpu.addressing.memory[0x100] = 0x19;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_BASE] = 0x1234_5678_9abc;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.xs[R_PC], 0x100);
+        assert_eq!(cpu.xs[R_SP], 0x8000 - 16);
+        cpu.step().unwrap();
+        assert_eq!(cpu.xs[R_PC], 9);
+        assert_eq!(cpu.xs[R_SP], 0x8000);
+        assert_eq!(cpu.xs[R_BASE], 0x1234_5678_9abc);
+    }
+
+    #[test]
+    fn wide_memory() {
+        let mut cpu = machine(&[]);
+        cpu.xs[0] = 0x0102_0304_0506_0708;
+        cpu.xs[1] = 0x2000;
+        cpu.execute(Instruction {
+            opcode: 0x96,
+            operands: Operands::Registers(0, 1),
+        })
+        .unwrap();
+        assert_eq!(cpu.addressing.memory[0x2000], 0x08);
+        assert_eq!(cpu.addressing.memory[0x2007], 0x01);
+
+        cpu.execute(Instruction {
+            opcode: 0x62,
+            operands: Operands::RegisterWord(2, 0x2000),
+        })
+        .unwrap();
+        assert_eq!(cpu.xs[2], 0x0102_0304_0506_0708);
+    }
+
+    #[test]
+    fn page_table() {
+        let mut cpu = machine(&[]);
+
+        // Map the virtual page 0x0000_1234_5678_9000 to the physical page 0x5000 as read only
+        let virt: u64 = 0x0000_1234_5678_9000;
+        let tables = [0x1000u64, 0x2000, 0x3000, 0x4000, 0x5000];
+        for level in 0..4 {
+            let index = virt >> (PAGE_BITS + LEVEL_BITS * (3 - level as u32)) & 0x1ff;
+            let perms = if level == 3 { 0x0c } else { 0x08 };
+            let entry = (tables[level + 1] | perms).to_le_bytes();
+            let addr = (tables[level] + index * 8) as usize;
+            cpu.addressing.memory[addr..addr + 8].copy_from_slice(&entry);
+        }
+        cpu.addressing.memory[0x5abc] = 0x42;
+        cpu.memmap = 0x1000;
+        cpu.flags = 1 << F_MEMMAP_ENABLE;
+
+        assert_eq!(cpu.read(virt | 0xabc, 1).unwrap(), 0x42);
+        assert!(matches!(
+            cpu.write(virt, 0, 1),
+            Err(InvalidMemoryAccess::InvalidPermissions(0x0c, WRITE))
+        ));
+        assert!(matches!(
+            cpu.read(virt + 0x1000, 1),
+            Err(InvalidMemoryAccess::UsedFreePage)
+        ));
+        assert!(matches!(
+            cpu.read(1 << 48, 1),
+            Err(InvalidMemoryAccess::UsedFreePage)
+        ));
+    }
+
+    #[test]
+    fn unimplemented_opcodes() {
+        // hlt, cas x0, x1, push x1, and mrs x0, s3
+        for (program, fault) in [
+            (&[0x1b][..], InvalidMemoryAccess::IllegalOpcode(0x1b)),
+            (&[0x3f, 0x38, 0x01][..], InvalidMemoryAccess::IllegalOpcode(0x3f)),
+            (&[0xb0, 0x10][..], InvalidMemoryAccess::IllegalOpcode(0xb0)),
+            (&[0x9b, 0x30][..], InvalidMemoryAccess::InvalidRegister(3)),
+        ]
+        .iter()
+        {
+            let mut cpu = machine(program);
+            assert_eq!(cpu.step(), Err(*fault));
+        }
+    }
+
+    // Re-encodes a 32 bit program with 8 byte immediates, along with the address of each
+    // instruction in both programs
+    fn widen(program: &[u8]) -> (Vec<u8>, Vec<(u64, u64)>) {
+        let (mut wide, mut addrs) = (vec![], vec![]);
+        let mut rest = program;
+        while !rest.is_empty() {
+            addrs.push(((program.len() - rest.len()) as u64, wide.len() as u64));
+            let instruction = decode::decode(4, || -> Result<u8, ()> {
+                let (&byte, tail) = rest.split_first().ok_or(())?;
+                rest = tail;
+                Ok(byte)
+            })
+            .unwrap();
+            instruction.encode_words(8, &mut wide);
+        }
+        addrs.push((program.len() as u64, wide.len() as u64));
+        (wide, addrs)
+    }
+
+    // Runs the conformance cases on both machines and compares them wherever the 64 bit one
+    // implements every instruction of the case. The widths are meant to differ once an integer
+    // register holds a negative or 32 bit value, so cases are skipped from then on.
+    #[test]
+    fn agrees_with_cpu() {
+        let mut compared = 0;
+        'cases: for case in conformance::generate() {
+            let (program, addrs) = widen(&case.program);
+            let mut cpu = Cpu::new(SimpleAddress::default());
+            cpu.addressing.load(0, &case.program);
+            let mut cpu64 = machine(&program);
+            for _ in 0..case.steps {
+                let outcome = cpu.step();
+                match cpu64.step() {
+                    Ok(()) => {
+                        assert!(
+                            matches!(outcome, StepOutcome::Executed { .. }),
+                            "{}: {:?}",
+                            case.name,
+                            outcome
+                        );
+                        if (0..16).any(|r| r != R_PC && cpu64.xs[r] >> 31 != 0) {
+                            continue 'cases;
+                        }
+                    }
+                    Err(InvalidMemoryAccess::IllegalOpcode(_))
+                    | Err(InvalidMemoryAccess::InvalidRegister(_)) => continue 'cases,
+                    Err(e) => {
+                        assert_eq!(outcome, StepOutcome::Faulted(e), "{}", case.name);
+                        compared += 1;
+                        continue 'cases;
+                    }
+                }
+            }
+
+            let state = cpu64.state();
+            let pc = cpu.xs[R_PC] as u64;
+            let pc = addrs
+                .iter()
+                .find(|&&(narrow, _)| narrow == pc)
+                .map_or(pc, |&(_, wide)| wide);
+            assert_eq!(state.xs[R_PC], pc, "{}: pc", case.name);
+            for r in (0..16).filter(|&r| r != R_PC) {
+                assert_eq!(state.xs[r] as u32, cpu.xs[r], "{}: x{}", case.name, r);
+            }
+            for r in 0..16 {
+                let (a, b) = (state.fs[r].to_bits(), cpu.fs[r].to_bits());
+                assert_eq!(a, b, "{}: f{}", case.name, r);
+            }
+            assert_eq!(state.flags, cpu.flags, "{}: flags", case.name);
+            compared += 1;
+        }
+        assert!(compared > 500, "only {} cases compared", compared);
+    }
+}
@@ -0,0 +1,159 @@
+// Memory access for debuggers and other host tooling
+// Peeking and poking go through the memory map when it is enabled but ignore permissions, never
+// fault, and leave the fault registers alone. Peeks use read_debug so they do not disturb devices.
+// The physical and virtual accessors are real accesses with the same effect on devices as guest
+// accesses, and return the fault a guest access would have raised. They are not seen by analyses
+// and do not change the fault registers either.
+
+use crate::{Address, Cpu, InvalidMemoryAccess, F_MEMMAP_ENABLE, READ, WRITE};
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    fn debug_translate(&mut self, addr: u32) -> Option<u32> {
+        if self.get_flag(F_MEMMAP_ENABLE) {
+            self.mmu
+                .walk(&mut self.addressing, addr, true)
+                .map(|(_, addr)| addr)
+        } else {
+            Some(addr)
+        }
+    }
+
+    // Reads a byte at a virtual address, None if it is not mapped
+    pub fn peek(&mut self, addr: u32) -> Option<u8> {
+        let addr = self.debug_translate(addr)?;
+        Some(self.addressing.read_debug(addr))
+    }
+
+    // Reads len bytes starting at a virtual address, None if any of them are not mapped
+    pub fn peek_range(&mut self, addr: u32, len: u32) -> Option<Vec<u8>> {
+        (0..len).map(|i| self.peek(addr.wrapping_add(i))).collect()
+    }
+
+    // Writes a byte at a virtual address, returning whether it is mapped
+    pub fn poke(&mut self, addr: u32, data: u8) -> bool {
+        match self.debug_translate(addr) {
+            Some(addr) => {
+                self.addressing.write(addr, data);
+                self.on_phys_write(addr);
+
+                // The cpu did not make the access so it must not fault on it
+                self.addressing.bus_error();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Writes bytes starting at a virtual address, returning whether all of them are mapped
+    // Bytes that are mapped are written even if others are not
+    pub fn poke_range(&mut self, addr: u32, data: &[u8]) -> bool {
+        data.iter().enumerate().fold(true, |mapped, (i, byte)| {
+            self.poke(addr.wrapping_add(i as u32), *byte) && mapped
+        })
+    }
+
+    fn host_bus_error(&mut self) -> Result<(), InvalidMemoryAccess> {
+        match self.addressing.bus_error() {
+            Some(addr) => Err(InvalidMemoryAccess::BusError(addr)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn read_phys(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess> {
+        let data = self.addressing.read(addr);
+        self.host_bus_error()?;
+        Ok(data)
+    }
+
+    pub fn write_phys(&mut self, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.addressing.write(addr, data);
+        self.on_phys_write(addr);
+        self.host_bus_error()
+    }
+
+    // Translates a virtual address as the guest would, without touching the fault registers
+    fn host_translate(&mut self, addr: u32, permissions: u8) -> Result<u32, InvalidMemoryAccess> {
+        let fault_address = self.fault_address;
+        let result = self.check_memory(addr, permissions);
+        self.fault_address = fault_address;
+        result
+    }
+
+    // Memory map entries do not distinguish rings, so virtual accesses are the same from both
+    pub fn read_virt(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess> {
+        let addr = self.host_translate(addr, READ)?;
+        self.read_phys(addr)
+    }
+
+    pub fn write_virt(&mut self, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        let addr = self.host_translate(addr, WRITE)?;
+        self.write_phys(addr, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Rng;
+    use crate::{OutOfRange, SimpleAddress, R_PC};
+
+    #[test]
+    fn mapped() {
+        let mut cpu = Cpu::new(SimpleAddress::new(0x10000).out_of_range(OutOfRange::BusError));
+
+        // Map virtual 0x01000000-0x0100ffff read only to 0x8000
+        cpu.mmu.set_root(0x100);
+        cpu.addressing.load(0x101, &0x200u32.to_le_bytes());
+        cpu.addressing.load(0x200, &0xc0008000u32.to_le_bytes());
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+
+        assert!(cpu.poke_range(0x01000010, b"uwu"));
+        assert_eq!(cpu.addressing.dump(0x8010, 3), b"uwu");
+        assert_eq!(cpu.peek_range(0x01000010, 3), Some(b"uwu".to_vec()));
+        assert_eq!(cpu.peek(0x02000000), None);
+        assert!(!cpu.poke(0x02000000, 1));
+        assert_eq!(cpu.fault_address, 0);
+
+        // Past the end of physical memory
+        assert!(cpu.poke(0x0100ffff, 1));
+        assert_eq!(cpu.addressing.bus_error(), None);
+        assert_eq!(cpu.xs[R_PC], 0);
+    }
+
+    #[test]
+    fn physical_and_virtual() {
+        let mut cpu = Cpu::new(SimpleAddress::new(0x10000).out_of_range(OutOfRange::BusError));
+        cpu.mmu.set_root(0x100);
+        cpu.addressing.load(0x101, &0x200u32.to_le_bytes());
+        cpu.addressing.load(0x200, &0xc0008000u32.to_le_bytes());
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+
+        cpu.write_phys(0x8004, 0x42).unwrap();
+        assert_eq!(cpu.read_virt(0x01000004).unwrap(), 0x42);
+        assert_eq!(
+            cpu.read_phys(0x01000004).unwrap_err(),
+            InvalidMemoryAccess::BusError(0x01000004)
+        );
+        assert_eq!(
+            cpu.write_virt(0x01000004, 1).unwrap_err(),
+            InvalidMemoryAccess::InvalidPermissions(0x0c, WRITE)
+        );
+        assert_eq!(
+            cpu.read_virt(0x05000000).unwrap_err(),
+            InvalidMemoryAccess::UsedFreePage
+        );
+        assert_eq!(cpu.fault_address, 0);
+    }
+
+    #[test]
+    fn devices() {
+        let mut cpu = Cpu::new(Rng::new(7));
+        let first = cpu.peek(0);
+        assert_eq!(cpu.peek(0), first);
+        cpu.addressing.read(0);
+        assert_ne!(cpu.peek(0), first);
+    }
+}
@@ -0,0 +1,579 @@
+// Instruction decoding and encoding shared by every word size
+// The opcode map is the same for all machines, only the width of immediate operands (literals,
+// addresses, and branch targets) changes with the word size.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::opcodes::{self, OpcodeInfo, OperandKind};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operands {
+    None,
+
+    // Branch or call target
+    Word(u64),
+
+    // Register and a literal or memory address
+    RegisterWord(usize, u64),
+
+    Registers(usize, usize),
+
+    // Opcode in the escaped page and its register pair
+    Extended(u8, usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub opcode: u8,
+    pub operands: Operands,
+}
+
+impl Instruction {
+    // Appends the instruction with 4 byte immediates, the inverse of decode(4, ...)
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        self.encode_words(4, out);
+    }
+
+    // Appends the instruction with immediates word_bytes long
+    // Registers 16-31 are reached with the register extension prefix, which decode reads as an
+    // instruction of its own
+    // Panics if the operands do not fit the opcode or a register is above 31
+    pub fn encode_words(&self, word_bytes: u32, out: &mut Vec<u8>) {
+        let word = |out: &mut Vec<u8>, word: u64| {
+            out.extend((0..word_bytes).map(|i| (word >> (8 * i)) as u8));
+        };
+        let prefix = |out: &mut Vec<u8>, fst: usize, snd: usize| {
+            assert!(fst < 32 && snd < 32, "registers must be below 32");
+            if fst > 15 || snd > 15 {
+                out.extend(&[0x3e, (fst >> 4 | (snd >> 4) << 1) as u8]);
+            }
+        };
+
+        match (self.opcode & 0xc0, self.operands) {
+            (0x00, Operands::Extended(func, fst, snd)) if self.opcode == opcodes::ESCAPE => {
+                prefix(out, fst, snd);
+                out.extend(&[self.opcode, func, ((fst & 0x0f) << 4 | snd & 0x0f) as u8]);
+            }
+            (0x00, Operands::None) if !has_word(self.opcode) && !has_registers(self.opcode) => {
+                out.push(self.opcode)
+            }
+            (0x00, Operands::Word(addr)) if has_word(self.opcode) => {
+                out.push(self.opcode);
+                word(out, addr);
+            }
+            (0x40, Operands::RegisterWord(r, data)) | (0xc0, Operands::RegisterWord(r, data)) => {
+                prefix(out, r, 0);
+                out.push(self.opcode & 0xf0 | (r & 0x0f) as u8);
+                word(out, data);
+            }
+            (0x80, Operands::RegisterWord(r, data)) if has_immediate(self.opcode) => {
+                prefix(out, r, 0);
+                out.extend(&[self.opcode, ((r & 0x0f) << 4) as u8]);
+                word(out, data);
+            }
+            (0x00, Operands::Registers(fst, snd)) if has_registers(self.opcode) => {
+                prefix(out, fst, snd);
+                out.extend(&[self.opcode, ((fst & 0x0f) << 4 | snd & 0x0f) as u8]);
+            }
+            (0x80, Operands::Registers(fst, snd)) if !has_immediate(self.opcode) => {
+                prefix(out, fst, snd);
+                out.extend(&[self.opcode, ((fst & 0x0f) << 4 | snd & 0x0f) as u8]);
+            }
+            _ => panic!("operands do not fit opcode {:#04x}", self.opcode),
+        }
+    }
+
+    // Builds an instruction for fuzzing from the front of data, which it consumes the way
+    // arbitrary::Unstructured does: two bytes pick an entry of the opcode table, either page, then
+    // each operand takes a byte for a register or four for a word
+    // Registers go up to 31, so they may be past the end of a cpu's registers, but every
+    // instruction built can be encoded. Returns None once data runs out
+    pub fn arbitrary(data: &mut &[u8]) -> Option<Instruction> {
+        let mut byte = || {
+            let (&byte, rest) = data.split_first()?;
+            *data = rest;
+            Some(byte)
+        };
+
+        // Every instruction of both pages, without the prefix and escape bytes themselves
+        static TABLE: OnceLock<Vec<(&OpcodeInfo, bool)>> = OnceLock::new();
+        let table = TABLE.get_or_init(|| {
+            opcodes::OPCODES
+                .iter()
+                .filter(|info| {
+                    info.operands != [OperandKind::RegisterExtension]
+                        && info.operands != [OperandKind::ExtendedOpcode]
+                })
+                .map(|info| (info, false))
+                .chain(opcodes::EXTENDED_OPCODES.iter().map(|info| (info, true)))
+                .collect()
+        });
+        let index = u16::from_le_bytes([byte()?, byte()?]) as usize % table.len();
+        let (info, extended) = table[index];
+
+        let mut values = vec![];
+        for kind in info.operands {
+            let value = match kind {
+                OperandKind::IntRegister
+                | OperandKind::FloatRegister
+                | OperandKind::DoubleRegister
+                | OperandKind::VectorRegister
+                | OperandKind::SystemRegister => (byte()? % 32) as u64,
+                _ => u32::from_le_bytes([byte()?, byte()?, byte()?, byte()?]) as u64,
+            };
+            values.push(value);
+        }
+        Some(Instruction::from_values(info, extended, &values))
+    }
+
+    // The instruction for an entry of the opcode table and one value per operand of the entry
+    fn from_values(info: &OpcodeInfo, extended: bool, values: &[u64]) -> Instruction {
+        let operands = match info.operands {
+            _ if extended => {
+                Operands::Extended(info.opcode, values[0] as usize, values[1] as usize)
+            }
+            [] => Operands::None,
+            [OperandKind::IntRegister] | [OperandKind::FloatRegister] => {
+                Operands::Registers(values[0] as usize, 0)
+            }
+            [_] => Operands::Word(values[0]),
+            [_, OperandKind::Literal] | [_, OperandKind::Address] => {
+                Operands::RegisterWord(values[0] as usize, values[1])
+            }
+            _ => Operands::Registers(values[0] as usize, values[1] as usize),
+        };
+        let opcode = match operands {
+            Operands::Extended(..) => opcodes::ESCAPE,
+            Operands::RegisterWord(r, _) if info.mask != 0xff => info.opcode | (r & 0x0f) as u8,
+            _ => info.opcode,
+        };
+        Instruction { opcode, operands }
+    }
+}
+
+// Disassembly using the mnemonics of the opcode table
+// Unassigned opcodes are shown as a .byte directive
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        let info = match self.operands {
+            Operands::Extended(func, _, _) => opcodes::lookup_extended(func),
+            _ => opcodes::lookup(self.opcode),
+        };
+        let info = match info {
+            Some(info) => info,
+            None => return write!(f, ".byte {:#04x}", self.opcode),
+        };
+        let values = match self.operands {
+            Operands::None => vec![],
+            Operands::Word(word) => vec![word],
+            Operands::RegisterWord(r, word) => vec![r as u64, word],
+            Operands::Registers(fst, snd) | Operands::Extended(_, fst, snd) => {
+                vec![fst as u64, snd as u64]
+            }
+        };
+
+        write!(f, "{}", info.mnemonic)?;
+        for (i, (kind, value)) in info.operands.iter().zip(values).enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+            match kind {
+                OperandKind::IntRegister => write!(f, "x{}", value)?,
+                OperandKind::FloatRegister => write!(f, "f{}", value)?,
+                OperandKind::DoubleRegister => write!(f, "d{}", value)?,
+                OperandKind::VectorRegister => write!(f, "v{}", value)?,
+                OperandKind::SystemRegister => write!(f, "s{}", value)?,
+                OperandKind::Address => write!(f, "[{:#x}]", value)?,
+                OperandKind::Offset if value as i32 >= 0 => write!(f, "{:#x}", value as i32)?,
+                OperandKind::Offset => write!(f, "-{:#x}", (value as i32).unsigned_abs())?,
+                _ => write!(f, "{:#x}", value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmError {
+    UnknownMnemonic(String),
+
+    // Expected and given number of operands
+    OperandCount(usize, usize),
+
+    BadOperand(String),
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            AsmError::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic {}", mnemonic),
+            AsmError::OperandCount(expected, given) => {
+                write!(f, "expected {} operands, got {}", expected, given)
+            }
+            AsmError::BadOperand(operand) => write!(f, "bad operand {}", operand),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Assembles one line in the syntax the disassembly uses, eg "ldi x3, 0x10" or "ld x1, [0x2000]"
+// Numbers are decimal or hex with 0x, and literals may be negative. Registers 16-31 are allowed
+// and encode picks the extension prefix for them, so the prefix itself cannot be assembled.
+impl FromStr for Instruction {
+    type Err = AsmError;
+
+    fn from_str(line: &str) -> Result<Instruction, AsmError> {
+        let line = line.trim();
+        let (mnemonic, rest) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let (info, extended) = match opcodes::lookup_mnemonic(mnemonic) {
+            Some(info) if info.operands == [OperandKind::RegisterExtension] => (None, false),
+            Some(info) if info.operands == [OperandKind::ExtendedOpcode] => (None, false),
+            Some(info) => (Some(info), false),
+            None => (opcodes::lookup_extended_mnemonic(mnemonic), true),
+        };
+        let info = match info {
+            Some(info) => info,
+            None => return Err(AsmError::UnknownMnemonic(mnemonic.to_string())),
+        };
+        let operands: Vec<&str> = if rest.is_empty() {
+            vec![]
+        } else {
+            rest.split(',').map(str::trim).collect()
+        };
+        if operands.len() != info.operands.len() {
+            return Err(AsmError::OperandCount(info.operands.len(), operands.len()));
+        }
+
+        let mut values = vec![];
+        for (kind, operand) in info.operands.iter().zip(operands) {
+            let bad = || AsmError::BadOperand(operand.to_string());
+            let value = match kind {
+                OperandKind::IntRegister => parse_register(operand, 'x'),
+                OperandKind::FloatRegister => parse_register(operand, 'f'),
+                OperandKind::DoubleRegister => parse_register(operand, 'd'),
+                OperandKind::VectorRegister => parse_register(operand, 'v'),
+                OperandKind::SystemRegister => parse_register(operand, 's'),
+                OperandKind::Address => operand
+                    .strip_prefix('[')
+                    .and_then(|o| o.strip_suffix(']'))
+                    .and_then(|o| parse_number(o.trim())),
+                _ => parse_number(operand),
+            };
+            values.push(value.ok_or_else(bad)?);
+        }
+        Ok(Instruction::from_values(info, extended, &values))
+    }
+}
+
+fn parse_register(operand: &str, prefix: char) -> Option<u64> {
+    let index: u64 = operand.strip_prefix(prefix)?.parse().ok()?;
+    if index < 32 {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+// Literals are 32 bits, and negative ones are stored in two's complement
+pub(crate) fn parse_number(operand: &str) -> Option<u64> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, operand),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    let value = if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    };
+    Some(value as u64)
+}
+
+// Whether an opcode in the no register page is followed by an immediate word
+pub(crate) fn has_word(opcode: u8) -> bool {
+    matches!(opcode, 0x00..=0x0f | 0x18 | 0x22..=0x39)
+}
+
+// Whether an opcode in the no register page takes a register pair byte after all, as the two
+// register page is full
+pub(crate) fn has_registers(opcode: u8) -> bool {
+    matches!(opcode, 0x3a..=0x3d)
+}
+
+// Whether an opcode in the two register page takes one register and an immediate word instead
+// The register byte is kept, with the second register bits unused
+pub(crate) fn has_immediate(opcode: u8) -> bool {
+    matches!(opcode, 0xa9..=0xaf | 0xb6..=0xb9)
+}
+
+// Decodes one instruction from bytes in execution order, with immediates word_bytes long
+pub fn decode<F, E>(word_bytes: u32, mut fetch: F) -> Result<Instruction, E>
+where
+    F: FnMut() -> Result<u8, E>,
+{
+    let word = |fetch: &mut F| -> Result<u64, E> {
+        let mut word = 0;
+        for i in 0..word_bytes {
+            word |= (fetch()? as u64) << (8 * i);
+        }
+        Ok(word)
+    };
+
+    let opcode = fetch()?;
+    let operands = match opcode & 0xc0 {
+        0x00 if opcode == opcodes::ESCAPE => {
+            let func = fetch()?;
+            let data = fetch()?;
+            Operands::Extended(func, (data >> 4) as usize, (data & 0x0f) as usize)
+        }
+        0x00 if has_word(opcode) => Operands::Word(word(&mut fetch)?),
+        0x00 if has_registers(opcode) => {
+            let data = fetch()?;
+            Operands::Registers((data >> 4) as usize, (data & 0x0f) as usize)
+        }
+        0x00 => Operands::None,
+        0x40 | 0xc0 => Operands::RegisterWord(opcode as usize & 0x0f, word(&mut fetch)?),
+        0x80 => {
+            let data = fetch()?;
+            if has_immediate(opcode) {
+                Operands::RegisterWord((data >> 4) as usize, word(&mut fetch)?)
+            } else {
+                Operands::Registers((data >> 4) as usize, (data & 0x0f) as usize)
+            }
+        }
+        _ => unreachable!("nya :("),
+    };
+
+    Ok(Instruction { opcode, operands })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_bytes(word_bytes: u32, bytes: &[u8]) -> Result<Instruction, ()> {
+        let mut bytes = bytes.iter();
+        decode(word_bytes, || bytes.next().copied().ok_or(()))
+    }
+
+    #[test]
+    fn forms() {
+        assert_eq!(
+            decode_bytes(4, &[0x19]),
+            Ok(Instruction {
+                opcode: 0x19,
+                operands: Operands::None
+            })
+        );
+        assert_eq!(
+            decode_bytes(4, &[0x18, 0x78, 0x56, 0x34, 0x12]),
+            Ok(Instruction {
+                opcode: 0x18,
+                operands: Operands::Word(0x12345678)
+            })
+        );
+        assert_eq!(
+            decode_bytes(8, &[0x43, 1, 2, 3, 4, 5, 6, 7, 8]),
+            Ok(Instruction {
+                opcode: 0x43,
+                operands: Operands::RegisterWord(3, 0x0807060504030201)
+            })
+        );
+        assert_eq!(
+            decode_bytes(4, &[0x80, 0x1f]),
+            Ok(Instruction {
+                opcode: 0x80,
+                operands: Operands::Registers(1, 15)
+            })
+        );
+        assert_eq!(decode_bytes(4, &[0xc0, 0, 0]), Err(()));
+    }
+
+    #[test]
+    fn encoding() {
+        let program = [
+            Instruction {
+                opcode: 0x43,
+                operands: Operands::RegisterWord(3, 0x12345678),
+            },
+            Instruction {
+                opcode: 0x96,
+                operands: Operands::Registers(1, 2),
+            },
+            Instruction {
+                opcode: 0x18,
+                operands: Operands::Word(0x2000),
+            },
+            Instruction {
+                opcode: 0x19,
+                operands: Operands::None,
+            },
+        ];
+        let mut bytes = vec![];
+        for instruction in program.iter() {
+            instruction.encode(&mut bytes);
+        }
+        assert_eq!(
+            bytes,
+            [0x43, 0x78, 0x56, 0x34, 0x12, 0x96, 0x12, 0x18, 0x00, 0x20, 0x00, 0x00, 0x19]
+        );
+
+        let mut fetch = bytes.iter();
+        let decoded: Vec<String> = program
+            .iter()
+            .map(|_| {
+                decode::<_, ()>(4, || fetch.next().copied().ok_or(()))
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            decoded,
+            vec!["ldi x3, 0x12345678", "str x1, x2", "call 0x2000", "ret"]
+        );
+        let assembled: Vec<Instruction> = decoded.iter().map(|l| l.parse().unwrap()).collect();
+        assert_eq!(assembled, program);
+
+        // High registers need the extension prefix
+        let mut bytes = vec![];
+        Instruction {
+            opcode: 0x80,
+            operands: Operands::Registers(17, 2),
+        }
+        .encode(&mut bytes);
+        assert_eq!(bytes, [0x3e, 0x01, 0x80, 0x12]);
+
+        // Escaped opcodes take their second byte, then the register pair
+        let mut bytes = vec![];
+        let fmax: Instruction = "fmax f1, f2".parse().unwrap();
+        fmax.encode(&mut bytes);
+        assert_eq!(bytes, [0x3f, 0x09, 0x12]);
+        assert_eq!(decode_bytes(4, &bytes).unwrap(), fmax);
+        assert_eq!(fmax.to_string(), "fmax f1, f2");
+
+        // Immediate forms keep the register byte, then take a word
+        let mut bytes = vec![];
+        let addi: Instruction = "addi x17, 5".parse().unwrap();
+        assert_eq!(
+            "fpop f3".parse(),
+            Ok(Instruction {
+                opcode: 0xb3,
+                operands: Operands::Registers(3, 0),
+            })
+        );
+        addi.encode(&mut bytes);
+        assert_eq!(bytes, [0x3e, 0x01, 0xa9, 0x10, 5, 0, 0, 0]);
+        assert_eq!(
+            decode_bytes(4, &bytes[2..]).unwrap().to_string(),
+            "addi x1, 0x5"
+        );
+    }
+
+    #[test]
+    fn assembly() {
+        assert_eq!(
+            "ld  x20, [ 0x2000 ]".parse(),
+            Ok(Instruction {
+                opcode: 0x64,
+                operands: Operands::RegisterWord(20, 0x2000),
+            })
+        );
+        assert_eq!(
+            "ldi x1, -1".parse::<Instruction>().unwrap().operands,
+            Operands::RegisterWord(1, 0xffffffff)
+        );
+        assert_eq!(
+            "msr x2, s6".parse::<Instruction>().unwrap().operands,
+            Operands::Registers(2, 6)
+        );
+        assert_eq!(
+            "bnz.r -0x10".parse(),
+            Ok(Instruction {
+                opcode: 0x30,
+                operands: Operands::Word(0xfffffff0),
+            })
+        );
+        assert_eq!(
+            "bnz.r -0x10".parse::<Instruction>().unwrap().to_string(),
+            "bnz.r -0x10"
+        );
+        assert_eq!(
+            "sxth x1, x18".parse(),
+            Ok(Instruction {
+                opcode: 0x3b,
+                operands: Operands::Registers(1, 18),
+            })
+        );
+        assert_eq!(
+            "fcvt.rd x1, f17".parse(),
+            Ok(Instruction {
+                opcode: 0x3f,
+                operands: Operands::Extended(0x06, 1, 17),
+            })
+        );
+        assert_eq!(
+            "ldrd d2, x3".parse::<Instruction>().unwrap().operands,
+            Operands::Extended(0x18, 2, 3)
+        );
+        assert_eq!(
+            "vcmplt.f v7, v0".parse::<Instruction>().unwrap().operands,
+            Operands::Extended(0x2c, 7, 0)
+        );
+        assert_eq!(
+            "esc 0".parse::<Instruction>(),
+            Err(AsmError::UnknownMnemonic("esc".to_string()))
+        );
+        assert_eq!(
+            "fence.rel".parse(),
+            Ok(Instruction {
+                opcode: 0x1d,
+                operands: Operands::None,
+            })
+        );
+        assert_eq!(
+            "nop".parse::<Instruction>(),
+            Err(AsmError::UnknownMnemonic("nop".to_string()))
+        );
+        assert_eq!(
+            "ret x1".parse::<Instruction>(),
+            Err(AsmError::OperandCount(0, 1))
+        );
+        assert_eq!(
+            "mov x1, f2".parse::<Instruction>(),
+            Err(AsmError::BadOperand("f2".to_string()))
+        );
+    }
+
+    #[test]
+    fn arbitrary_instructions() {
+        let bytes = crate::xorshift_bytes(&mut 0x9e37_79b9, 4096);
+
+        // Every instruction built assembles back from its disassembly and encodes, and those
+        // below register 16 decode back from the encoding
+        let mut data = &bytes[..];
+        let mut program = vec![];
+        let mut count = 0;
+        while let Some(instruction) = Instruction::arbitrary(&mut data) {
+            assert_eq!(instruction.to_string().parse(), Ok(instruction));
+            let mut bytes = vec![];
+            instruction.encode(&mut bytes);
+            if bytes[0] != 0x3e {
+                assert_eq!(decode_bytes(4, &bytes), Ok(instruction));
+            }
+            program.extend(bytes);
+            count += 1;
+        }
+        assert!(count > 500);
+        assert_eq!(Instruction::arbitrary(&mut &[0x01][..]), None);
+
+        // Running the stream is how a fuzz target would use it
+        crate::Cpu::run_fuzz(&program, 1000);
+    }
+}
@@ -0,0 +1,257 @@
+// Incremental snapshots
+// Memory is wrapped in DirtyTracking, which records the 4K pages written since it was last asked.
+// The first snapshot copies all of memory and every later one only copies the pages dirtied since
+// the snapshot before it, so taking snapshots often on a long run stays cheap. Restoring rewinds
+// to a snapshot by rewriting only the pages that have changed since it was taken.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{Address, Cpu, CpuState, ReadEffect, Width};
+
+pub const PAGE_BITS: u32 = 12;
+pub const PAGE_SIZE: u32 = 1 << PAGE_BITS;
+
+// Records which pages of the inner memory have been written
+pub struct DirtyTracking<T> {
+    inner: T,
+    dirty: BTreeSet<u32>,
+}
+
+impl<T> DirtyTracking<T>
+where
+    T: Address,
+{
+    pub fn new(inner: T) -> DirtyTracking<T> {
+        DirtyTracking {
+            inner,
+            dirty: BTreeSet::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    // Accesses through the inner memory are not tracked
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn is_dirty(&self, page: u32) -> bool {
+        self.dirty.contains(&page)
+    }
+
+    // Page numbers written since the last call, in ascending order
+    pub fn take_dirty(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.dirty).into_iter().collect()
+    }
+
+    fn read_page(&mut self, page: u32) -> Box<[u8]> {
+        let start = page << PAGE_BITS;
+        (0..PAGE_SIZE)
+            .map(|i| self.inner.read_debug(start.wrapping_add(i)))
+            .collect()
+    }
+
+    fn write_page(&mut self, page: u32, data: Option<&[u8]>) {
+        let start = page << PAGE_BITS;
+        for i in 0..PAGE_SIZE {
+            let byte = data.map_or(0, |data| data[i as usize]);
+            self.inner.write(start.wrapping_add(i), byte);
+        }
+    }
+}
+
+impl<T> Address for DirtyTracking<T>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        self.inner.read(addr)
+    }
+
+    fn fetch(&mut self, addr: u32) -> u8 {
+        self.inner.fetch(addr)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        self.inner.read_effect(addr)
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        self.inner.read_debug(addr)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.inner.size()
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        self.dirty.insert(addr >> PAGE_BITS);
+        self.inner.write(addr, data);
+    }
+
+    fn read_width(&mut self, addr: u32, width: Width) -> u32 {
+        self.inner.read_width(addr, width)
+    }
+
+    fn write_width(&mut self, addr: u32, width: Width, data: u32) {
+        for i in 0..width.bytes() {
+            self.dirty.insert(addr.wrapping_add(i) >> PAGE_BITS);
+        }
+        self.inner.write_width(addr, width, data);
+    }
+
+    fn bus_error(&mut self) -> Option<u32> {
+        self.inner.bus_error()
+    }
+}
+
+struct Delta<const N: usize> {
+    state: CpuState<N>,
+
+    // Contents of the pages dirtied since the previous snapshot
+    pages: HashMap<u32, Box<[u8]>>,
+}
+
+pub struct DeltaSnapshots<const N: usize = 16> {
+    // The first snapshot holds every non-zero page
+    deltas: Vec<Delta<N>>,
+}
+
+impl<const N: usize> DeltaSnapshots<N> {
+    // Takes a full snapshot of the first size bytes of memory
+    pub fn new<T>(cpu: &mut Cpu<DirtyTracking<T>, N>, size: u32) -> DeltaSnapshots<N>
+    where
+        T: Address,
+    {
+        let memory = cpu.addressing();
+        memory.take_dirty();
+        let mut pages = HashMap::new();
+        for page in 0..(size + PAGE_SIZE - 1) >> PAGE_BITS {
+            let data = memory.read_page(page);
+            if data.iter().any(|&b| b != 0) {
+                pages.insert(page, data);
+            }
+        }
+
+        DeltaSnapshots {
+            deltas: vec![Delta {
+                state: cpu.state(),
+                pages,
+            }],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    // Never true, since the full snapshot is always kept
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    // Number of pages stored by a snapshot
+    pub fn pages(&self, index: usize) -> usize {
+        self.deltas[index].pages.len()
+    }
+
+    pub fn state(&self, index: usize) -> &CpuState<N> {
+        &self.deltas[index].state
+    }
+
+    // Records the pages dirtied since the last snapshot and returns the new snapshot's index
+    pub fn take<T>(&mut self, cpu: &mut Cpu<DirtyTracking<T>, N>) -> usize
+    where
+        T: Address,
+    {
+        let memory = cpu.addressing();
+        let pages = memory
+            .take_dirty()
+            .into_iter()
+            .map(|page| (page, memory.read_page(page)))
+            .collect();
+        self.deltas.push(Delta {
+            state: cpu.state(),
+            pages,
+        });
+        self.deltas.len() - 1
+    }
+
+    // Contents of a page as of a snapshot, None if it was all zeros
+    fn page_at(&self, index: usize, page: u32) -> Option<&[u8]> {
+        self.deltas[..=index]
+            .iter()
+            .rev()
+            .find_map(|delta| delta.pages.get(&page))
+            .map(|data| &data[..])
+    }
+
+    // Rewinds the machine to a snapshot and discards every snapshot taken after it
+    // Panics if there is no such snapshot
+    pub fn restore<T>(&mut self, index: usize, cpu: &mut Cpu<DirtyTracking<T>, N>)
+    where
+        T: Address,
+    {
+        assert!(index < self.deltas.len(), "no snapshot {}", index);
+
+        let memory = cpu.addressing();
+        let mut changed: BTreeSet<u32> = memory.take_dirty().into_iter().collect();
+        for delta in self.deltas[index + 1..].iter() {
+            changed.extend(delta.pages.keys());
+        }
+        for page in changed {
+            memory.write_page(page, self.page_at(index, page));
+        }
+
+        self.deltas.truncate(index + 1);
+        cpu.set_state(&self.deltas[index].state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, R_PC};
+
+    #[test]
+    fn rewind() {
+        // x1 = 0x2000; loop: x0 += x2; store x0 -> [x1]; x1 += x3; jmp loop
+        let mut memory = SimpleAddress::new(0x10000);
+        memory.load(
+            0,
+            &[
+                0x41, 0x00, 0x20, 0, 0, 0x80, 0x02, 0x96, 0x01, 0x80, 0x13, 0x0f, 5, 0, 0, 0,
+            ],
+        );
+        let mut cpu = Cpu::new(DirtyTracking::new(memory));
+        cpu.xs[2] = 1;
+        cpu.xs[3] = 0x1000;
+
+        let mut snapshots = DeltaSnapshots::new(&mut cpu, 0x10000);
+        assert_eq!(snapshots.pages(0), 1);
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(snapshots.take(&mut cpu), 1);
+        assert_eq!(snapshots.pages(1), 1);
+        let pc = cpu.xs[R_PC];
+        for _ in 0..8 {
+            cpu.step();
+        }
+        snapshots.take(&mut cpu);
+        assert_eq!(snapshots.pages(2), 2);
+        cpu.step();
+
+        snapshots.restore(1, &mut cpu);
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(cpu.xs[R_PC], pc);
+        assert_eq!(cpu.xs[0], 1);
+        let memory = cpu.addressing().inner_mut();
+        assert_eq!(memory.read(0x2000), 1);
+        assert_eq!(memory.read(0x3000), 0);
+        assert_eq!(memory.read(0x4000), 0);
+        assert!(!cpu.addressing().is_dirty(3));
+    }
+}
@@ -0,0 +1,229 @@
+// Bank switching controller
+// Maps one bank of a large host buffer into a window of the address space, letting programs reach
+// far more memory than the address space holds without enabling paging. Unlike the other devices
+// it wraps the rest of memory, since the window and the bank select register are placed at
+// absolute addresses. Accesses outside both are passed to the inner memory.
+//
+// Registers:
+// select+0x0-0x3 BANK - Bank mapped into the window, taken modulo the number of banks. Each byte
+//                       written takes effect immediately
+
+use crate::{Address, ReadEffect, Width};
+
+#[derive(Debug, Clone)]
+pub struct BankSwitch<T> {
+    inner: T,
+    window: u32,
+    window_size: u32,
+    select: u32,
+    bank: u32,
+    banks: Vec<u8>,
+}
+
+impl<T> BankSwitch<T>
+where
+    T: Address,
+{
+    // Panics if the window is empty or there are no banks
+    pub fn new(inner: T, window: u32, window_size: u32, select: u32, banks: u32) -> BankSwitch<T> {
+        assert!(
+            window_size != 0 && banks != 0,
+            "bank switching needs a window and banks"
+        );
+        BankSwitch {
+            inner,
+            window,
+            window_size,
+            select,
+            bank: 0,
+            banks: vec![0; window_size as usize * banks as usize],
+        }
+    }
+
+    pub fn bank(&self) -> u32 {
+        self.bank
+    }
+
+    pub fn set_bank(&mut self, bank: u32) {
+        self.bank = bank;
+    }
+
+    pub fn bank_count(&self) -> u32 {
+        (self.banks.len() / self.window_size as usize) as u32
+    }
+
+    // Contents of one bank, for loading programs and data from the host
+    pub fn bank_memory(&self, bank: u32) -> &[u8] {
+        let start = (bank % self.bank_count()) as usize * self.window_size as usize;
+        &self.banks[start..start + self.window_size as usize]
+    }
+
+    pub fn bank_memory_mut(&mut self, bank: u32) -> &mut [u8] {
+        let start = (bank % self.bank_count()) as usize * self.window_size as usize;
+        &mut self.banks[start..start + self.window_size as usize]
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn banked(&self, addr: u32) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.window);
+        if offset < self.window_size {
+            let bank = (self.bank % self.bank_count()) as usize;
+            Some(bank * self.window_size as usize + offset as usize)
+        } else {
+            None
+        }
+    }
+
+    fn select_byte(&self, addr: u32) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.select);
+        if offset < 4 {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // Whether a wider access only touches the inner memory, so it can be passed on whole
+    fn passes_through(&self, addr: u32, width: Width) -> bool {
+        (0..width.bytes()).all(|i| {
+            let addr = addr.wrapping_add(i);
+            self.select_byte(addr).is_none() && self.banked(addr).is_none()
+        })
+    }
+}
+
+impl<T> Address for BankSwitch<T>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        if let Some(byte) = self.select_byte(addr) {
+            (self.bank >> (8 * byte)) as u8
+        } else if let Some(i) = self.banked(addr) {
+            self.banks[i]
+        } else {
+            self.inner.read(addr)
+        }
+    }
+
+    fn fetch(&mut self, addr: u32) -> u8 {
+        if self.select_byte(addr).is_some() || self.banked(addr).is_some() {
+            self.read(addr)
+        } else {
+            self.inner.fetch(addr)
+        }
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        if self.select_byte(addr).is_some() || self.banked(addr).is_some() {
+            ReadEffect::Pure
+        } else {
+            self.inner.read_effect(addr)
+        }
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        if self.select_byte(addr).is_some() || self.banked(addr).is_some() {
+            self.read(addr)
+        } else {
+            self.inner.read_debug(addr)
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        if let Some(byte) = self.select_byte(addr) {
+            let shift = 8 * byte;
+            self.bank = (self.bank & !(0xff << shift)) | ((data as u32) << shift);
+        } else if let Some(i) = self.banked(addr) {
+            self.banks[i] = data;
+        } else {
+            self.inner.write(addr, data);
+        }
+    }
+
+    fn read_width(&mut self, addr: u32, width: Width) -> u32 {
+        if self.passes_through(addr, width) {
+            self.inner.read_width(addr, width)
+        } else {
+            (0..width.bytes()).fold(0, |acc, i| {
+                acc | (self.read(addr.wrapping_add(i)) as u32) << (8 * i)
+            })
+        }
+    }
+
+    fn write_width(&mut self, addr: u32, width: Width, data: u32) {
+        if self.passes_through(addr, width) {
+            self.inner.write_width(addr, width, data);
+        } else {
+            for i in 0..width.bytes() {
+                self.write(addr.wrapping_add(i), (data >> (8 * i)) as u8);
+            }
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.inner.size()
+    }
+
+    fn bus_error(&mut self) -> Option<u32> {
+        self.inner.bus_error()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, SimpleAddress};
+
+    #[test]
+    fn switching() {
+        let mut memory = BankSwitch::new(SimpleAddress::default(), 0x8000, 0x1000, 0x7ffc, 4);
+        memory.bank_memory_mut(2)[0x10] = 0xaa;
+        memory.write(0x8010, 0x55);
+        assert_eq!(memory.bank_memory(0)[0x10], 0x55);
+
+        memory.write(0x7ffc, 2);
+        assert_eq!(memory.bank(), 2);
+        assert_eq!(memory.read(0x8010), 0xaa);
+        assert_eq!(memory.read(0x7ffc), 2);
+
+        // Banks wrap around
+        memory.write(0x7ffc, 4);
+        assert_eq!(memory.read(0x8010), 0x55);
+
+        // Outside the window goes to the inner memory
+        memory.write(0x9000, 0x12);
+        assert_eq!(memory.inner_mut().read(0x9000), 0x12);
+    }
+
+    #[test]
+    fn beyond_address_space() {
+        // 1024 banks of 64K is 64M of memory behind a single window
+        let mut memory = BankSwitch::new(SimpleAddress::default(), 0x10000, 0x10000, 0x100, 1024);
+        memory.bank_memory_mut(1000)[0] = 0x42;
+
+        // x0 = 1000; store x0 -> [0x100]; load x1 <- [0x10000]
+        let program = [
+            0x40, 0xe8, 0x03, 0, 0, 0xc0, 0x00, 0x01, 0, 0, 0x61, 0x00, 0x00, 0x01, 0,
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            memory.write(i as u32, *byte);
+        }
+        let mut cpu = Cpu::new(memory);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.xs[1] & 0xff, 0x42);
+    }
+}
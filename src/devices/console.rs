@@ -0,0 +1,268 @@
+// Interactive console
+// Bridges the guest to a host terminal without ever blocking the cpu: input is read by a
+// background thread and handed over through a channel, which the machine drains with poll between
+// steps. poll also says when to raise CONSOLE_IRQ, so a guest shell can sleep until a key arrives
+// instead of spinning on STATUS. Output is written and flushed a byte at a time.
+//
+// Registers:
+// 0x0 DATA    - Reading pops a byte of input, 0 when there is none. Writing outputs a byte.
+// 0x1 STATUS  - Bit 0 is set while input is waiting, bit 1 is always set as output never blocks
+// 0x2 CONTROL - Bit 0 enables the input interrupt
+
+use std::collections::VecDeque;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::{Address, ReadEffect};
+
+pub const CONSOLE_DATA: u32 = 0x0;
+pub const CONSOLE_STATUS: u32 = 0x1;
+pub const CONSOLE_CONTROL: u32 = 0x2;
+
+pub const STATUS_INPUT: u8 = 1 << 0;
+pub const STATUS_OUTPUT: u8 = 1 << 1;
+
+pub const CONTROL_INPUT_IRQ: u8 = 1 << 0;
+
+// Interrupt line the machine raises when input arrives
+pub const CONSOLE_IRQ: u8 = 4;
+
+pub struct Console {
+    input: Option<Receiver<u8>>,
+    buffer: VecDeque<u8>,
+    output: Box<dyn Write>,
+    control: u8,
+    escape: Option<u8>,
+    escaped: bool,
+}
+
+impl Console {
+    pub fn new(input: Receiver<u8>, output: Box<dyn Write>) -> Console {
+        Console {
+            input: Some(input),
+            buffer: VecDeque::new(),
+            output,
+            control: 0,
+            escape: None,
+            escaped: false,
+        }
+    }
+
+    // Reads any host stream on a background thread, eg a pipe or a socket, so that a stream that
+    // blocks never holds up the cpu
+    pub fn from_reader<R>(input: R, output: Box<dyn Write>) -> Console
+    where
+        R: Read + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::BufReader::new(input).bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => (),
+                    _ => return,
+                }
+            }
+        });
+        Console::new(receiver, output)
+    }
+
+    // Reads the host's stdin and writes to its stdout
+    pub fn stdio() -> Console {
+        Console::from_reader(io::stdin(), Box::new(io::stdout()))
+    }
+
+    // Keeps a byte from the guest and sets escaped when it is typed instead, eg so that the host
+    // can still stop a machine whose terminal is in raw mode
+    pub fn escape(mut self, byte: u8) -> Console {
+        self.escape = Some(byte);
+        self
+    }
+
+    pub fn escaped(&self) -> bool {
+        self.escaped
+    }
+
+    // Whether the input has ended and everything in it has been read
+    pub fn closed(&self) -> bool {
+        self.input.is_none() && self.buffer.is_empty()
+    }
+
+    // Takes the input that has arrived since the last call, returning whether the input interrupt
+    // should be raised
+    pub fn poll(&mut self) -> bool {
+        let mut arrived = false;
+        while let Some(input) = &self.input {
+            match input.try_recv() {
+                Ok(byte) if Some(byte) == self.escape => self.escaped = true,
+                Ok(byte) => {
+                    self.buffer.push_back(byte);
+                    arrived = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.input = None,
+            }
+        }
+        arrived && self.control & CONTROL_INPUT_IRQ != 0
+    }
+
+    fn status(&self) -> u8 {
+        STATUS_OUTPUT
+            | if self.buffer.is_empty() {
+                0
+            } else {
+                STATUS_INPUT
+            }
+    }
+}
+
+impl Address for Console {
+    fn read(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0 => self.buffer.pop_front().unwrap_or(0),
+            0x1 => self.status(),
+            0x2 => self.control,
+            _ => 0,
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(3)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        if addr == CONSOLE_DATA {
+            ReadEffect::Pop
+        } else {
+            ReadEffect::Pure
+        }
+    }
+
+    // Shows the next byte of input without taking it
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0 => self.buffer.front().copied().unwrap_or(0),
+            _ => self.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        match addr {
+            // A terminal that cannot be written to drops the output
+            0x0 => {
+                let _ = self.output.write_all(&[data]);
+                let _ = self.output.flush();
+            }
+            0x2 => self.control = data,
+            _ => (),
+        }
+    }
+}
+
+// Puts the host terminal in raw mode, so that keys reach the guest as they are typed without being
+// echoed or turned into signals, and puts it back as it was when dropped
+pub struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    // Does nothing and returns None when stdin is not a terminal
+    pub fn enable() -> io::Result<Option<RawMode>> {
+        if !io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(Some(RawMode {
+            saved: saved.trim().to_string(),
+        }))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+// stty acts on the terminal it is given as stdin
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn input_and_output() {
+        let (sender, receiver) = mpsc::channel();
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut console = Console::new(receiver, Box::new(Output(output.clone()))).escape(0x1d);
+
+        // Nothing waiting, and no interrupt until enabled
+        assert_eq!(console.read(CONSOLE_STATUS), STATUS_OUTPUT);
+        sender.send(b'a').unwrap();
+        assert!(!console.poll());
+        console.write(CONSOLE_CONTROL, CONTROL_INPUT_IRQ);
+        sender.send(b'b').unwrap();
+        sender.send(0x1d).unwrap();
+        assert!(console.poll());
+        assert!(!console.poll());
+        assert!(console.escaped());
+
+        assert_eq!(console.read(CONSOLE_STATUS), STATUS_OUTPUT | STATUS_INPUT);
+        assert_eq!(console.read_debug(CONSOLE_DATA), b'a');
+        assert_eq!(console.read(CONSOLE_DATA), b'a');
+        assert_eq!(console.read(CONSOLE_DATA), b'b');
+        assert_eq!(console.read(CONSOLE_DATA), 0);
+
+        drop(sender);
+        console.poll();
+        assert!(console.closed());
+
+        console.write(CONSOLE_DATA, b'!');
+        assert_eq!(*output.borrow(), b"!");
+    }
+
+    #[test]
+    fn host_stream() {
+        let output = Rc::new(RefCell::new(vec![]));
+        let input = io::Cursor::new(b"hi".to_vec());
+        let mut console = Console::from_reader(input, Box::new(Output(output)));
+
+        // The reader thread hands the input over in its own time
+        let mut input = vec![];
+        while !console.closed() {
+            console.poll();
+            while console.read(CONSOLE_STATUS) & STATUS_INPUT != 0 {
+                input.push(console.read(CONSOLE_DATA));
+            }
+        }
+        assert_eq!(input, b"hi");
+    }
+}
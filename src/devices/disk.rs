@@ -0,0 +1,255 @@
+// Block storage
+// A disk of DISK_SECTOR byte sectors kept in a host file or any other seekable stream, eg a
+// Cursor<Vec<u8>> for tests. Transfers copy whole sectors between the disk and a buffer in guest
+// memory, which the disk reaches through its own handle to that memory (usually an Rc<RefCell<_>>
+// shared with the bus). A command completes before the store that issues it does, and poll says
+// when to raise DISK_IRQ for it, so a guest can either wait on STATUS or sleep until the interrupt.
+//
+// Registers:
+// 0x00-0x03 SECTOR  - First sector of the transfer
+// 0x04-0x07 BUFFER  - Physical address of the buffer in guest memory
+// 0x08-0x0b COUNT   - Number of sectors to transfer
+// 0x0c      COMMAND - Writing 1 reads the sectors into the buffer, writing 2 writes the buffer to
+//                     the sectors
+// 0x0d      STATUS  - Bit 0 is set once the last command is done, bit 1 if it failed (sectors past
+//                     the end of the disk, an unknown command, or a host I/O error), writing 1 to a
+//                     bit clears it
+// 0x0e      CONTROL - Bit 0 enables the completion interrupt
+// 0x10-0x13 SECTORS - Number of sectors on the disk, read only
+
+use std::fs::OpenOptions;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::Address;
+
+pub const DISK_SECTOR_REGISTER: u32 = 0x00;
+pub const DISK_BUFFER: u32 = 0x04;
+pub const DISK_COUNT: u32 = 0x08;
+pub const DISK_COMMAND: u32 = 0x0c;
+pub const DISK_STATUS: u32 = 0x0d;
+pub const DISK_CONTROL: u32 = 0x0e;
+pub const DISK_SECTORS: u32 = 0x10;
+
+pub const COMMAND_READ: u8 = 1;
+pub const COMMAND_WRITE: u8 = 2;
+
+pub const STATUS_DONE: u8 = 1 << 0;
+pub const STATUS_ERROR: u8 = 1 << 1;
+
+pub const CONTROL_DONE_IRQ: u8 = 1 << 0;
+
+pub const DISK_SECTOR: u32 = 512;
+
+// Interrupt line the machine raises when a command completes
+pub const DISK_IRQ: u8 = 3;
+
+// Anything a disk can be kept in
+pub trait Storage: Read + Write + Seek {}
+
+impl<S> Storage for S where S: Read + Write + Seek {}
+
+fn write_byte(word: &mut u32, index: u32, data: u8) {
+    let shift = 8 * index;
+    *word = *word & !(0xff << shift) | (data as u32) << shift;
+}
+
+pub struct Disk<M>
+where
+    M: Address,
+{
+    storage: Box<dyn Storage>,
+    memory: M,
+    sectors: u32,
+    sector: u32,
+    buffer: u32,
+    count: u32,
+    status: u8,
+    control: u8,
+    completed: bool,
+}
+
+impl<M> Disk<M>
+where
+    M: Address,
+{
+    // A partial sector at the end of the storage is not part of the disk
+    pub fn new(mut storage: Box<dyn Storage>, memory: M) -> io::Result<Disk<M>> {
+        let len = storage.seek(SeekFrom::End(0))?;
+        Ok(Disk {
+            storage,
+            memory,
+            sectors: (len / DISK_SECTOR as u64).min(u32::MAX as u64) as u32,
+            sector: 0,
+            buffer: 0,
+            count: 0,
+            status: 0,
+            control: 0,
+            completed: false,
+        })
+    }
+
+    // Opens an existing image for reading and writing
+    pub fn open<P>(path: P, memory: M) -> io::Result<Disk<M>>
+    where
+        P: AsRef<Path>,
+    {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Disk::new(Box::new(file), memory)
+    }
+
+    pub fn from_vec(image: Vec<u8>, memory: M) -> Disk<M> {
+        // Seeking in memory cannot fail
+        Disk::new(Box::new(Cursor::new(image)), memory).unwrap()
+    }
+
+    pub fn sectors(&self) -> u32 {
+        self.sectors
+    }
+
+    // Returns whether the completion interrupt should be raised for a command that has completed
+    // since the last call
+    pub fn poll(&mut self) -> bool {
+        std::mem::take(&mut self.completed) && self.control & CONTROL_DONE_IRQ != 0
+    }
+
+    fn run(&mut self, command: u8) {
+        let ok = match command {
+            COMMAND_READ | COMMAND_WRITE
+                if self.sector as u64 + self.count as u64 <= self.sectors as u64 =>
+            {
+                self.transfer(command == COMMAND_WRITE).is_ok()
+            }
+            _ => false,
+        };
+        self.status = STATUS_DONE | if ok { 0 } else { STATUS_ERROR };
+        self.completed = true;
+    }
+
+    fn transfer(&mut self, write: bool) -> io::Result<()> {
+        let len = self.count as usize * DISK_SECTOR as usize;
+        let mut data = vec![0; len];
+        self.storage
+            .seek(SeekFrom::Start(self.sector as u64 * DISK_SECTOR as u64))?;
+        if write {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = self.memory.read(self.buffer.wrapping_add(i as u32));
+            }
+            self.storage.write_all(&data)?;
+            self.storage.flush()
+        } else {
+            self.storage.read_exact(&mut data)?;
+            for (i, byte) in data.iter().enumerate() {
+                self.memory.write(self.buffer.wrapping_add(i as u32), *byte);
+            }
+            Ok(())
+        }
+    }
+}
+
+impl<M> Address for Disk<M>
+where
+    M: Address,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        let (word, index) = match addr {
+            0x00..=0x03 => (self.sector, addr),
+            0x04..=0x07 => (self.buffer, addr - 0x04),
+            0x08..=0x0b => (self.count, addr - 0x08),
+            0x0d => (self.status as u32, 0),
+            0x0e => (self.control as u32, 0),
+            0x10..=0x13 => (self.sectors, addr - 0x10),
+            _ => return 0,
+        };
+        (word >> (8 * index)) as u8
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(0x14)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        match addr {
+            0x00..=0x03 => write_byte(&mut self.sector, addr, data),
+            0x04..=0x07 => write_byte(&mut self.buffer, addr - 0x04, data),
+            0x08..=0x0b => write_byte(&mut self.count, addr - 0x08, data),
+            0x0c => self.run(data),
+            0x0d => self.status &= !data,
+            0x0e => self.control = data,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::{Cpu, SimpleAddress, Width};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn sector_transfers() {
+        let ram = Rc::new(RefCell::new(SimpleAddress::new(0x2000)));
+        let mut image = vec![0; 4 * DISK_SECTOR as usize];
+        image[DISK_SECTOR as usize..][..4].copy_from_slice(b"boot");
+        let mut disk = Disk::from_vec(image, ram.clone());
+        assert_eq!(disk.read_width(DISK_SECTORS, Width::Word), 4);
+
+        // Read sector 1 into 0x1000 and copy it to sector 3
+        disk.write_width(DISK_SECTOR_REGISTER, Width::Word, 1);
+        disk.write_width(DISK_BUFFER, Width::Word, 0x1000);
+        disk.write_width(DISK_COUNT, Width::Word, 1);
+        disk.write(DISK_CONTROL, CONTROL_DONE_IRQ);
+        disk.write(DISK_COMMAND, COMMAND_READ);
+        assert_eq!(disk.read(DISK_STATUS), STATUS_DONE);
+        assert!(disk.poll());
+        assert!(!disk.poll());
+        assert_eq!(
+            ram.borrow_mut().read_width(0x1000, Width::Word),
+            0x746f_6f62
+        );
+
+        disk.write_width(DISK_SECTOR_REGISTER, Width::Word, 3);
+        disk.write(DISK_COMMAND, COMMAND_WRITE);
+        ram.borrow_mut().write(0x1000, 0);
+        disk.write(DISK_COMMAND, COMMAND_READ);
+        assert_eq!(ram.borrow_mut().read(0x1000), b'b');
+
+        // Transfers past the end fail without touching memory
+        disk.write(DISK_STATUS, STATUS_DONE);
+        disk.write_width(DISK_COUNT, Width::Word, 2);
+        ram.borrow_mut().write(0x1000, 0);
+        disk.write(DISK_COMMAND, COMMAND_READ);
+        assert_eq!(disk.read(DISK_STATUS), STATUS_DONE | STATUS_ERROR);
+        assert_eq!(ram.borrow_mut().read(0x1000), 0);
+    }
+
+    #[test]
+    fn guest_commands() {
+        // A guest reads sector 0 into 0x800 by writing the registers and the command
+        // ldi x1, 0x800; st x1, BUFFER; ldi x1, 1; st x1, COUNT; stb x1, COMMAND
+        const BASE: u32 = 0x4000;
+        let ram = Rc::new(RefCell::new(SimpleAddress::new(0x1000)));
+        let mut program = vec![0x41, 0x00, 0x08, 0x00, 0x00, 0xc1];
+        program.extend_from_slice(&(BASE + DISK_BUFFER).to_le_bytes());
+        program.extend_from_slice(&[0x41, 0x01, 0x00, 0x00, 0x00, 0xc1]);
+        program.extend_from_slice(&(BASE + DISK_COUNT).to_le_bytes());
+        program.push(0xe1);
+        program.extend_from_slice(&(BASE + DISK_COMMAND).to_le_bytes());
+        ram.borrow_mut().load(0, &program);
+
+        let disk = Disk::from_vec(vec![0x5a; DISK_SECTOR as usize], ram.clone());
+        let mut bus = Bus::new();
+        bus.map(0, 0x1000, Box::new(ram.clone()));
+        bus.map_mmio(BASE, 0x14, Box::new(disk));
+        let mut cpu = Cpu::new(bus);
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(ram.borrow_mut().read(0x800), 0x5a);
+        assert_eq!(ram.borrow_mut().read(0x9ff), 0x5a);
+        assert_eq!(cpu.peek(BASE + DISK_STATUS), Some(STATUS_DONE));
+    }
+}
@@ -0,0 +1,139 @@
+// Keyboard
+// Queues key codes for the guest to read. What a code means is up to the host feeding it, eg ASCII
+// bytes, or scan codes with the top bit marking a release. The queue holds KEYBOARD_FIFO codes,
+// later keys are dropped until the guest catches up, and the guest is told that it missed some.
+//
+// Registers:
+// 0x0 DATA    - Reading pops the oldest key code, 0 when there is none
+// 0x1 STATUS  - Bit 0 is set while a key is waiting, bit 1 is set once a key has been dropped and
+//               stays set until 1 is written to it
+// 0x2 CONTROL - Bit 0 enables the key interrupt
+
+use std::collections::VecDeque;
+
+use crate::{Address, ReadEffect};
+
+pub const KEYBOARD_DATA: u32 = 0x0;
+pub const KEYBOARD_STATUS: u32 = 0x1;
+pub const KEYBOARD_CONTROL: u32 = 0x2;
+
+pub const STATUS_KEY: u8 = 1 << 0;
+pub const STATUS_DROPPED: u8 = 1 << 1;
+
+pub const CONTROL_KEY_IRQ: u8 = 1 << 0;
+
+pub const KEYBOARD_FIFO: usize = 16;
+
+// Interrupt line the machine raises when a key is pushed
+pub const KEYBOARD_IRQ: u8 = 5;
+
+#[derive(Debug, Clone, Default)]
+pub struct Keyboard {
+    keys: VecDeque<u8>,
+    dropped: bool,
+    control: u8,
+}
+
+impl Keyboard {
+    pub fn new() -> Keyboard {
+        Keyboard::default()
+    }
+
+    // Queues a key code, returning whether the key interrupt should be raised
+    pub fn push_key(&mut self, code: u8) -> bool {
+        if self.keys.len() == KEYBOARD_FIFO {
+            self.dropped = true;
+            return false;
+        }
+        self.keys.push_back(code);
+        self.control & CONTROL_KEY_IRQ != 0
+    }
+
+    // Queues every byte of a string, eg to type a command in a test
+    pub fn push_str(&mut self, keys: &str) -> bool {
+        keys.bytes()
+            .fold(false, |raise, code| self.push_key(code) | raise)
+    }
+
+    // Key codes waiting to be read
+    pub fn pending(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn status(&self) -> u8 {
+        let key = if self.keys.is_empty() { 0 } else { STATUS_KEY };
+        let dropped = if self.dropped { STATUS_DROPPED } else { 0 };
+        key | dropped
+    }
+}
+
+impl Address for Keyboard {
+    fn read(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0 => self.keys.pop_front().unwrap_or(0),
+            0x1 => self.status(),
+            0x2 => self.control,
+            _ => 0,
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(3)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        if addr == KEYBOARD_DATA {
+            ReadEffect::Pop
+        } else {
+            ReadEffect::Pure
+        }
+    }
+
+    // Shows the next key without taking it
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0 => self.keys.front().copied().unwrap_or(0),
+            _ => self.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        match addr {
+            0x1 if data & STATUS_DROPPED != 0 => self.dropped = false,
+            0x2 => self.control = data,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_fifo() {
+        let mut keyboard = Keyboard::new();
+        assert!(!keyboard.push_key(b'a'));
+        keyboard.write(KEYBOARD_CONTROL, CONTROL_KEY_IRQ);
+        assert!(keyboard.push_str("bc"));
+        assert_eq!(keyboard.read(KEYBOARD_STATUS), STATUS_KEY);
+        assert_eq!(keyboard.read_debug(KEYBOARD_DATA), b'a');
+        assert_eq!(keyboard.read(KEYBOARD_DATA), b'a');
+        assert_eq!(keyboard.pending(), 2);
+
+        // Keys past the end of the queue are dropped
+        for code in 0..KEYBOARD_FIFO as u8 {
+            keyboard.push_key(code);
+        }
+        assert_eq!(keyboard.pending(), KEYBOARD_FIFO);
+        assert_eq!(keyboard.read(KEYBOARD_STATUS), STATUS_KEY | STATUS_DROPPED);
+        assert_eq!(keyboard.read(KEYBOARD_DATA), b'b');
+        keyboard.write(KEYBOARD_STATUS, STATUS_DROPPED);
+        assert_eq!(keyboard.read(KEYBOARD_STATUS), STATUS_KEY);
+
+        while keyboard.read(KEYBOARD_STATUS) & STATUS_KEY != 0 {
+            keyboard.read(KEYBOARD_DATA);
+        }
+        assert_eq!(keyboard.read(KEYBOARD_DATA), 0);
+    }
+}
@@ -0,0 +1,155 @@
+// Per-core local interrupt controller
+// Every core of a cluster (see cluster.rs) has its own controller at the same address, holding the
+// sources that belong to that core alone: a preemption timer and inter-processor interrupts. It
+// only decides when they fire, the cluster raises the interrupts on the core and carries IPIs
+// between controllers.
+//
+// Registers:
+// 0x00-0x03 ID          - Index of the core, read only
+// 0x04      MASK        - Bit 0 enables the timer interrupt, bit 1 enables IPIs
+// 0x08-0x0b TIMER       - Cycles until the timer fires, counting down once per cycle of the core,
+//                         0 when stopped
+// 0x0c-0x0f RELOAD      - Loaded into TIMER when it fires, 0 for a one-shot timer
+// 0x10-0x13 IPI_SEND    - Writing byte 0x13 sends an IPI to every core whose bit is set in the
+//                         word written
+// 0x14-0x17 IPI_PENDING - Bit n is set while an IPI from core n is unacknowledged, writing 1 to a
+//                         bit clears it
+
+use crate::Address;
+
+pub const LOCAL_ID: u32 = 0x00;
+pub const LOCAL_MASK: u32 = 0x04;
+pub const LOCAL_TIMER: u32 = 0x08;
+pub const LOCAL_RELOAD: u32 = 0x0c;
+pub const LOCAL_IPI_SEND: u32 = 0x10;
+pub const LOCAL_IPI_PENDING: u32 = 0x14;
+
+pub const MASK_TIMER: u8 = 1 << 0;
+pub const MASK_IPI: u8 = 1 << 1;
+
+// Interrupt lines of the core the sources are raised on
+pub const LOCAL_TIMER_IRQ: u8 = 6;
+pub const LOCAL_IPI_IRQ: u8 = 7;
+
+fn write_byte(word: &mut u32, index: u32, data: u8) {
+    let shift = 8 * index;
+    *word = *word & !(0xff << shift) | (data as u32) << shift;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalController {
+    id: u32,
+    mask: u8,
+    timer: u32,
+    reload: u32,
+    ipi_send: u32,
+    ipi_pending: u32,
+    ipi_targets: u32,
+}
+
+impl LocalController {
+    pub fn new(id: u32) -> LocalController {
+        LocalController {
+            id,
+            ..LocalController::default()
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn ipi_pending(&self) -> u32 {
+        self.ipi_pending
+    }
+
+    // Advances the timer by a cycle, returning whether the timer interrupt should be raised
+    pub fn tick(&mut self) -> bool {
+        if self.timer == 0 {
+            return false;
+        }
+        self.timer -= 1;
+        if self.timer != 0 {
+            return false;
+        }
+        self.timer = self.reload;
+        self.mask & MASK_TIMER != 0
+    }
+
+    // Takes the cores sent IPIs since the last call, as a bit per core
+    pub fn take_ipis(&mut self) -> u32 {
+        std::mem::take(&mut self.ipi_targets)
+    }
+
+    // Marks an IPI from the source core as pending, returning whether the IPI interrupt should be
+    // raised
+    pub fn receive_ipi(&mut self, source: u32) -> bool {
+        self.ipi_pending |= 1 << source;
+        self.mask & MASK_IPI != 0
+    }
+}
+
+impl Address for LocalController {
+    fn read(&mut self, addr: u32) -> u8 {
+        let (word, index) = match addr {
+            0x00..=0x03 => (self.id, addr),
+            0x04 => (self.mask as u32, 0),
+            0x08..=0x0b => (self.timer, addr - 0x08),
+            0x0c..=0x0f => (self.reload, addr - 0x0c),
+            0x10..=0x13 => (self.ipi_send, addr - 0x10),
+            0x14..=0x17 => (self.ipi_pending, addr - 0x14),
+            _ => return 0,
+        };
+        (word >> (8 * index)) as u8
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(0x18)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        match addr {
+            0x04 => self.mask = data,
+            0x08..=0x0b => write_byte(&mut self.timer, addr - 0x08, data),
+            0x0c..=0x0f => write_byte(&mut self.reload, addr - 0x0c, data),
+            0x10..=0x13 => {
+                write_byte(&mut self.ipi_send, addr - 0x10, data);
+                if addr == 0x13 {
+                    self.ipi_targets |= self.ipi_send;
+                }
+            }
+            0x14..=0x17 => self.ipi_pending &= !((data as u32) << (8 * (addr - 0x14))),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timer_and_ipis() {
+        let mut local = LocalController::new(2);
+        assert_eq!(local.read(LOCAL_ID), 2);
+
+        // Periodic timer every 3 cycles, first firing after 2
+        local.write(LOCAL_RELOAD, 3);
+        local.write(LOCAL_TIMER, 2);
+        local.write(LOCAL_MASK, MASK_TIMER);
+        let fired: Vec<bool> = (0..6).map(|_| local.tick()).collect();
+        assert_eq!(fired, vec![false, true, false, false, true, false]);
+
+        for (i, byte) in 0b1001u32.to_le_bytes().iter().enumerate() {
+            local.write(LOCAL_IPI_SEND + i as u32, *byte);
+        }
+        assert_eq!(local.take_ipis(), 0b1001);
+        assert_eq!(local.take_ipis(), 0);
+
+        // IPIs are masked, but still pending until acknowledged
+        assert!(!local.receive_ipi(3));
+        assert_eq!(local.read(LOCAL_IPI_PENDING), 0b1000);
+        local.write(LOCAL_IPI_PENDING, 0b1000);
+        assert_eq!(local.ipi_pending(), 0);
+    }
+}
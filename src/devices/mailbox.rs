@@ -0,0 +1,135 @@
+// Core bring-up mailbox
+// Shared by every core of a cluster (see cluster.rs), it lets the guest start parked cores and
+// park running ones. Only core 0 runs after reset, so the boot core decides when and where the
+// others start. The mailbox only records requests, the cluster carries them out after the step
+// that made them.
+//
+// Registers:
+// 0x00-0x03 CORE    - Core the other registers refer to
+// 0x04-0x07 ENTRY   - Address the selected core starts at when it is released
+// 0x08      CONTROL - Writing 1 releases the selected core at ENTRY, writing 0 parks it. Reads 1
+//                     while the selected core is running.
+// 0x0c-0x0f COUNT   - Number of cores, read only
+
+use crate::Address;
+
+pub const MAILBOX_CORE: u32 = 0x00;
+pub const MAILBOX_ENTRY: u32 = 0x04;
+pub const MAILBOX_CONTROL: u32 = 0x08;
+pub const MAILBOX_COUNT: u32 = 0x0c;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MailboxRequest {
+    Start { core: u32, entry: u32 },
+    Park { core: u32 },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Mailbox {
+    cores: u32,
+    core: u32,
+    entries: Vec<u32>,
+    running: u32,
+    requests: Vec<MailboxRequest>,
+}
+
+impl Mailbox {
+    pub fn new(cores: u32) -> Mailbox {
+        Mailbox {
+            cores,
+            entries: vec![0; cores as usize],
+            ..Mailbox::default()
+        }
+    }
+
+    // Takes the requests made since the last call, oldest first
+    pub fn take_requests(&mut self) -> Vec<MailboxRequest> {
+        std::mem::take(&mut self.requests)
+    }
+
+    // Updates which cores the guest sees as running, a bit per core
+    pub fn set_running(&mut self, running: u32) {
+        self.running = running;
+    }
+
+    fn entry(&self) -> u32 {
+        self.entries.get(self.core as usize).copied().unwrap_or(0)
+    }
+}
+
+impl Address for Mailbox {
+    fn read(&mut self, addr: u32) -> u8 {
+        let (word, index) = match addr {
+            0x00..=0x03 => (self.core, addr),
+            0x04..=0x07 => (self.entry(), addr - 0x04),
+            0x08 => ((self.running >> self.core & 1), 0),
+            0x0c..=0x0f => (self.cores, addr - 0x0c),
+            _ => return 0,
+        };
+        (word >> (8 * index)) as u8
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(0x10)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        let shift = 8 * (addr & 3);
+        let replace = |word: u32| word & !(0xff << shift) | (data as u32) << shift;
+        match addr {
+            0x00..=0x03 => self.core = replace(self.core),
+            0x04..=0x07 => {
+                if let Some(entry) = self.entries.get_mut(self.core as usize) {
+                    *entry = replace(*entry);
+                }
+            }
+
+            // Requests for cores that do not exist are ignored
+            0x08 if self.core < self.cores => {
+                let core = self.core;
+                self.requests.push(match data {
+                    0 => MailboxRequest::Park { core },
+                    _ => MailboxRequest::Start {
+                        core,
+                        entry: self.entry(),
+                    },
+                });
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let mut mailbox = Mailbox::new(2);
+        assert_eq!(mailbox.read(MAILBOX_COUNT), 2);
+        mailbox.write(MAILBOX_CORE, 1);
+        for (i, byte) in 0x1234u32.to_le_bytes().iter().enumerate() {
+            mailbox.write(MAILBOX_ENTRY + i as u32, *byte);
+        }
+        mailbox.write(MAILBOX_CONTROL, 1);
+        mailbox.write(MAILBOX_CONTROL, 0);
+        mailbox.write(MAILBOX_CORE, 2);
+        mailbox.write(MAILBOX_CONTROL, 1);
+        assert_eq!(
+            mailbox.take_requests(),
+            vec![
+                MailboxRequest::Start {
+                    core: 1,
+                    entry: 0x1234
+                },
+                MailboxRequest::Park { core: 1 },
+            ]
+        );
+
+        mailbox.set_running(0b10);
+        mailbox.write(MAILBOX_CORE, 1);
+        assert_eq!(mailbox.read(MAILBOX_CONTROL), 1);
+        assert_eq!(mailbox.read(MAILBOX_ENTRY + 1), 0x12);
+    }
+}
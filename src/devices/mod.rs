@@ -0,0 +1,21 @@
+// Memory mapped peripherals
+// Every device implements Address with addresses relative to the start of its register block, so
+// it can be mapped at any base address.
+
+pub mod bank;
+pub mod console;
+pub mod disk;
+pub mod keyboard;
+pub mod local;
+pub mod mailbox;
+pub mod net;
+pub mod rng;
+
+pub use bank::BankSwitch;
+pub use console::Console;
+pub use disk::Disk;
+pub use keyboard::Keyboard;
+pub use local::LocalController;
+pub use mailbox::Mailbox;
+pub use net::{Frame, NetPort};
+pub use rng::Rng;
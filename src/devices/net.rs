@@ -0,0 +1,159 @@
+// Network port
+// Sends and receives whole frames between machines. The port only queues frames, moving them
+// between machines is up to the host, eg a Lockstep group (see lockstep.rs), which delivers them
+// at fixed points so that runs are reproducible.
+//
+// Registers:
+// 0x0-0x3 RX_LEN  - Bytes left in the oldest received frame, 0 if there is none
+// 0x4     RX_DATA - Reading takes the next byte of the oldest frame, which is dropped once it has
+//                   all been read
+// 0x8-0xb TX_DEST - Port the next frame is sent to
+// 0xc     TX_DATA - Writing appends a byte to the frame being built
+// 0xd     TX_SEND - Writing sends the frame being built, empty frames are not sent
+
+use std::collections::VecDeque;
+
+use crate::{Address, ReadEffect};
+
+pub const NET_RX_LEN: u32 = 0x0;
+pub const NET_RX_DATA: u32 = 0x4;
+pub const NET_TX_DEST: u32 = 0x8;
+pub const NET_TX_DATA: u32 = 0xc;
+pub const NET_TX_SEND: u32 = 0xd;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub source: u32,
+    pub dest: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetPort {
+    received: VecDeque<Frame>,
+    read: usize,
+    dest: u32,
+    building: Vec<u8>,
+    sent: Vec<Frame>,
+}
+
+impl NetPort {
+    pub fn new() -> NetPort {
+        NetPort::default()
+    }
+
+    // Queues a frame for the guest to read
+    pub fn deliver(&mut self, frame: Frame) {
+        if !frame.data.is_empty() {
+            self.received.push_back(frame);
+        }
+    }
+
+    // Frames received but not yet fully read
+    pub fn pending(&self) -> usize {
+        self.received.len()
+    }
+
+    // Takes the frames the guest has sent since the last call, oldest first
+    // Their source is left as 0 for the host to fill in
+    pub fn take_sent(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.sent)
+    }
+
+    fn remaining(&self) -> u32 {
+        self.received
+            .front()
+            .map_or(0, |frame| (frame.data.len() - self.read) as u32)
+    }
+}
+
+impl Address for NetPort {
+    fn read(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0..=0x3 => (self.remaining() >> (8 * addr)) as u8,
+            0x4 => {
+                let frame = match self.received.front() {
+                    Some(frame) => frame,
+                    None => return 0,
+                };
+                let byte = frame.data[self.read];
+                self.read += 1;
+                if self.read == frame.data.len() {
+                    self.received.pop_front();
+                    self.read = 0;
+                }
+                byte
+            }
+            0x8..=0xb => (self.dest >> (8 * (addr - 8))) as u8,
+            _ => 0,
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(0x10)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        if addr == NET_RX_DATA {
+            ReadEffect::Pop
+        } else {
+            ReadEffect::Pure
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        match addr {
+            0x8..=0xb => {
+                let shift = 8 * (addr - 8);
+                self.dest = self.dest & !(0xff << shift) | (data as u32) << shift;
+            }
+            0xc => self.building.push(data),
+            0xd if !self.building.is_empty() => self.sent.push(Frame {
+                source: 0,
+                dest: self.dest,
+                data: std::mem::take(&mut self.building),
+            }),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let mut port = NetPort::new();
+        port.write(NET_TX_DEST, 3);
+        port.write(NET_TX_SEND, 0);
+        port.write(NET_TX_DATA, 0xaa);
+        port.write(NET_TX_DATA, 0xbb);
+        port.write(NET_TX_SEND, 0);
+        assert_eq!(
+            port.take_sent(),
+            vec![Frame {
+                source: 0,
+                dest: 3,
+                data: vec![0xaa, 0xbb]
+            }]
+        );
+        assert!(port.take_sent().is_empty());
+
+        for data in [vec![1, 2], vec![3]] {
+            port.deliver(Frame {
+                source: 1,
+                dest: 0,
+                data,
+            });
+        }
+        assert_eq!(port.read(NET_RX_LEN), 2);
+        assert_eq!(port.read(NET_RX_DATA), 1);
+        assert_eq!(port.read(NET_RX_LEN), 1);
+        assert_eq!(port.read(NET_RX_DATA), 2);
+        assert_eq!(port.read(NET_RX_LEN), 1);
+        assert_eq!(port.read(NET_RX_DATA), 3);
+        assert_eq!(port.read(NET_RX_LEN), 0);
+        assert_eq!(port.pending(), 0);
+    }
+}
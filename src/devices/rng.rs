@@ -0,0 +1,129 @@
+// Deterministic random number generator
+// The stream is derived entirely from the seed, so runs can be replayed and compared exactly.
+//
+// Registers:
+// 0x0-0x3 DATA - Reading byte 0 latches a new random word, bytes 1-3 read the rest of that word
+// 0x4-0x7 SEED - Writing byte 7 reseeds the generator with the 32 bit value written to 0x4-0x7
+
+use crate::{Address, ReadEffect};
+
+pub const RNG_DATA: u32 = 0x0;
+pub const RNG_SEED: u32 = 0x4;
+
+#[derive(Debug, Clone)]
+pub struct Rng {
+    seed: u64,
+    state: u64,
+    latch: u32,
+    seed_latch: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            seed,
+            state: seed,
+            latch: 0,
+            seed_latch: 0,
+        }
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.state = seed;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // SplitMix64, keeping the high half of each output
+    pub fn next_u32(&mut self) -> u32 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((z ^ (z >> 31)) >> 32) as u32
+    }
+}
+
+impl Address for Rng {
+    fn read(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0 => {
+                self.latch = self.next_u32();
+                self.latch as u8
+            }
+            0x1..=0x3 => (self.latch >> (8 * addr)) as u8,
+            0x4..=0x7 => (self.seed >> (8 * (addr - 4))) as u8,
+            _ => 0,
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(8)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        if addr == RNG_DATA {
+            ReadEffect::Pop
+        } else {
+            ReadEffect::Pure
+        }
+    }
+
+    // Shows the latched word without generating a new one
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        match addr {
+            0x0..=0x3 => (self.latch >> (8 * addr)) as u8,
+            _ => self.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        if let 0x4..=0x7 = addr {
+            let shift = 8 * (addr - 4);
+            self.seed_latch = self.seed_latch & !(0xff << shift) | (data as u32) << shift;
+            if addr == 0x7 {
+                self.reseed(self.seed_latch as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_word(rng: &mut Rng, addr: u32) -> u32 {
+        (0..4).fold(0, |acc, i| acc | (rng.read(addr + i) as u32) << (8 * i))
+    }
+
+    #[test]
+    fn deterministic_stream() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let mut c = Rng::new(43);
+        let stream: Vec<u32> = (0..16).map(|_| a.next_u32()).collect();
+        assert_eq!(stream, (0..16).map(|_| b.next_u32()).collect::<Vec<_>>());
+        assert_ne!(stream, (0..16).map(|_| c.next_u32()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn guest_registers() {
+        let mut host = Rng::new(1234);
+        let expected = [host.next_u32(), host.next_u32()];
+
+        // Reseed from the guest side
+        let mut rng = Rng::new(0);
+        for (i, byte) in 1234u32.to_le_bytes().iter().enumerate() {
+            rng.write(RNG_SEED + i as u32, *byte);
+        }
+        assert_eq!(rng.seed(), 1234);
+        assert_eq!(read_word(&mut rng, RNG_SEED), 1234);
+
+        // A word read only advances the generator once
+        assert_eq!(read_word(&mut rng, RNG_DATA), expected[0]);
+        assert_eq!(read_word(&mut rng, RNG_DATA), expected[1]);
+    }
+}
@@ -0,0 +1,233 @@
+// Differential testing helpers
+// Runs the same program on two machines (or against a previously recorded golden trace) and
+// compares their architectural state after every instruction, reporting the first divergence.
+
+use std::fmt;
+
+use crate::vector::Vector;
+use crate::{Address, Cpu, CpuState};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Difference {
+    Int(usize, u32, u32),
+    Float(usize, f32, f32),
+    Vector(usize, Vector, Vector),
+    Flags(u32, u32),
+    InterruptMask(u8, u8),
+    Memmap(u32, u32),
+    SystemSp(u32, u32),
+
+    // Other system registers, by their privileged move index
+    SystemRegister(u8, u32, u32),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Difference::Int(r, a, b) => write!(f, "x{}: {:#010x} != {:#010x}", r, a, b),
+            Difference::Float(r, a, b) => {
+                write!(f, "f{}: {} ({:#010x}) != {} ({:#010x})", r, a, a.to_bits(), b, b.to_bits())
+            }
+            Difference::Vector(r, a, b) => write!(f, "v{}: {:08x?} != {:08x?}", r, a, b),
+            Difference::Flags(a, b) => write!(f, "flags: {:#015b} != {:#015b}", a, b),
+            Difference::InterruptMask(a, b) => write!(f, "mask: {:#010b} != {:#010b}", a, b),
+            Difference::Memmap(a, b) => write!(f, "memmap: {:#010x} != {:#010x}", a, b),
+            Difference::SystemSp(a, b) => write!(f, "system_sp: {:#010x} != {:#010x}", a, b),
+            Difference::SystemRegister(r, a, b) => {
+                write!(f, "system register {}: {:#010x} != {:#010x}", r, a, b)
+            }
+        }
+    }
+}
+
+// Lists every register that differs between the two states
+// Floats are compared bitwise so that identical NaNs are not reported
+pub fn diff_states(a: &CpuState, b: &CpuState) -> Vec<Difference> {
+    let mut diffs = vec![];
+
+    for (i, (x, y)) in a.xs.iter().zip(b.xs.iter()).enumerate() {
+        if x != y {
+            diffs.push(Difference::Int(i, *x, *y));
+        }
+    }
+
+    for (i, (x, y)) in a.fs.iter().zip(b.fs.iter()).enumerate() {
+        if x.to_bits() != y.to_bits() {
+            diffs.push(Difference::Float(i, *x, *y));
+        }
+    }
+
+    for (i, (x, y)) in a.vs.iter().zip(b.vs.iter()).enumerate() {
+        if x != y {
+            diffs.push(Difference::Vector(i, *x, *y));
+        }
+    }
+
+    if a.flags != b.flags {
+        diffs.push(Difference::Flags(a.flags, b.flags));
+    }
+    if a.interrupt_mask != b.interrupt_mask {
+        diffs.push(Difference::InterruptMask(a.interrupt_mask, b.interrupt_mask));
+    }
+    if a.memmap != b.memmap {
+        diffs.push(Difference::Memmap(a.memmap, b.memmap));
+    }
+    if a.system_sp != b.system_sp {
+        diffs.push(Difference::SystemSp(a.system_sp, b.system_sp));
+    }
+
+    let system = [
+        (4, a.vector_base, b.vector_base),
+        (5, a.fault_address, b.fault_address),
+        (6, a.fault_cause, b.fault_cause),
+        (9, a.asid, b.asid),
+        (10, a.shadow_sp, b.shadow_sp),
+    ];
+    for &(r, x, y) in system.iter() {
+        if x != y {
+            diffs.push(Difference::SystemRegister(r, x, y));
+        }
+    }
+
+    diffs
+}
+
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    // Number of instructions executed before the states diverged (0 means the initial states
+    // already differ)
+    pub step: usize,
+
+    // Program counter of the instruction that caused the divergence
+    pub pc: u32,
+
+    pub left: CpuState,
+    pub right: CpuState,
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(f, "traces diverged after step {} (instruction at {:#010x}):", self.step, self.pc)?;
+        for diff in self.differences.iter() {
+            writeln!(f, "    {}", diff)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+fn check_step(step: usize, pc: u32, left: CpuState, right: CpuState) -> Result<(), Box<Divergence>> {
+    let differences = diff_states(&left, &right);
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(Box::new(Divergence {
+            step,
+            pc,
+            left,
+            right,
+            differences,
+        }))
+    }
+}
+
+// Records the state before the first step and after each of the given number of steps
+pub fn record<T>(cpu: &mut Cpu<T>, steps: usize) -> Vec<CpuState>
+where
+    T: Address,
+{
+    let mut trace = Vec::with_capacity(steps + 1);
+    trace.push(cpu.state());
+    for _ in 0..steps {
+        cpu.step();
+        trace.push(cpu.state());
+    }
+    trace
+}
+
+// Replays a cpu against a golden trace produced by `record`
+pub fn check<T>(cpu: &mut Cpu<T>, golden: &[CpuState]) -> Result<(), Box<Divergence>>
+where
+    T: Address,
+{
+    let mut pc = cpu.xs[crate::R_PC];
+    for (step, expected) in golden.iter().enumerate() {
+        if step != 0 {
+            pc = cpu.xs[crate::R_PC];
+            cpu.step();
+        }
+        check_step(step, pc, cpu.state(), expected.clone())?;
+    }
+    Ok(())
+}
+
+// Steps two cpus in lockstep, comparing their states after every instruction
+pub fn compare<A, B>(a: &mut Cpu<A>, b: &mut Cpu<B>, steps: usize) -> Result<(), Box<Divergence>>
+where
+    A: Address,
+    B: Address,
+{
+    let mut pc = a.xs[crate::R_PC];
+    check_step(0, pc, a.state(), b.state())?;
+    for step in 1..=steps {
+        pc = a.xs[crate::R_PC];
+        a.step();
+        b.step();
+        check_step(step, pc, a.state(), b.state())?;
+    }
+    Ok(())
+}
+
+pub fn assert_match<A, B>(a: &mut Cpu<A>, b: &mut Cpu<B>, steps: usize)
+where
+    A: Address,
+    B: Address,
+{
+    if let Err(e) = compare(a, b, steps) {
+        panic!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x0 = 5; x1 = 7; x0 += x1
+    static PROGRAM: [u8; 12] = [0x40, 5, 0, 0, 0, 0x41, 7, 0, 0, 0, 0x80, 0x01];
+
+    #[test]
+    fn matching_machines() {
+        let mut a = Cpu::with_program(&PROGRAM);
+        let mut b = Cpu::with_program(&PROGRAM);
+        assert!(compare(&mut a, &mut b, 3).is_ok());
+        assert_eq!(a.xs[0], 12);
+    }
+
+    #[test]
+    fn first_divergence() {
+        let mut patched = PROGRAM;
+        patched[6] = 9;
+
+        let mut a = Cpu::with_program(&PROGRAM);
+        let mut b = Cpu::with_program(&patched);
+        let e = compare(&mut a, &mut b, 3).unwrap_err();
+        assert_eq!(e.step, 2);
+        assert_eq!(e.pc, 5);
+        assert_eq!(e.differences, vec![Difference::Int(1, 7, 9)]);
+    }
+
+    #[test]
+    fn golden_trace() {
+        let golden = record(&mut Cpu::with_program(&PROGRAM), 3);
+        assert_eq!(golden.len(), 4);
+        assert!(check(&mut Cpu::with_program(&PROGRAM), &golden).is_ok());
+
+        let mut patched = PROGRAM;
+        patched[1] = 7;
+        let e = check(&mut Cpu::with_program(&patched), &golden).unwrap_err();
+        assert_eq!(e.step, 1);
+        assert_eq!(e.differences, vec![Difference::Int(0, 7, 5)]);
+    }
+}
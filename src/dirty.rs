@@ -0,0 +1,77 @@
+// Dirty page tracking for frontends
+// Records the physical pages the cpu writes to, so that a display only redraws the parts of a
+// framebuffer that changed and snapshot or migration code only copies the memory that was touched.
+// This is the host's own view and has nothing to do with the memory map, which has no dirty bits.
+// Pages can be any power of two size, eg a framebuffer row or a 4K page.
+//
+// Every write the cpu makes is seen, including trap frames and debugger pokes. Writes made by the
+// host through addressing or by other bus masters are not, as the host knows about those already.
+// Unlike DirtyTracking (see delta.rs) the memory does not need to be wrapped, and taking the pages
+// here does not disturb incremental snapshots.
+
+use std::collections::BTreeSet;
+
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone)]
+pub(crate) struct DirtyPages {
+    page_bits: u32,
+    pages: BTreeSet<u32>,
+}
+
+impl DirtyPages {
+    pub(crate) fn on_write(&mut self, addr: u32) {
+        self.pages.insert(addr >> self.page_bits);
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // Starts recording writes in pages of page_size bytes, or stops with None
+    // Changing the page size forgets what has been recorded so far
+    pub fn set_dirty_tracking(&mut self, page_size: Option<u32>) {
+        self.dirty = page_size.map(|page_size| {
+            assert!(
+                page_size.is_power_of_two(),
+                "dirty page size must be a power of two"
+            );
+            DirtyPages {
+                page_bits: page_size.trailing_zeros(),
+                pages: BTreeSet::new(),
+            }
+        });
+    }
+
+    // Start addresses of the pages written since the last call, in ascending order
+    pub fn take_dirty_pages(&mut self) -> Vec<u32> {
+        match self.dirty.as_mut() {
+            Some(dirty) => std::mem::take(&mut dirty.pages)
+                .into_iter()
+                .map(|page| page << dirty.page_bits)
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, Width};
+
+    #[test]
+    fn dirty_pages() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        assert_eq!(cpu.write_sized(0x10, Width::Byte, 1), Ok(()));
+        assert_eq!(cpu.take_dirty_pages(), vec![]);
+
+        // A word straddling two pages dirties both
+        cpu.set_dirty_tracking(Some(0x100));
+        assert_eq!(cpu.write_sized(0x2fe, Width::Word, 1), Ok(()));
+        assert_eq!(cpu.write_sized(0x10, Width::Byte, 1), Ok(()));
+        assert_eq!(cpu.take_dirty_pages(), vec![0x0, 0x200, 0x300]);
+        assert_eq!(cpu.take_dirty_pages(), vec![]);
+    }
+}
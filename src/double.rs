@@ -0,0 +1,156 @@
+// Double precision extension
+// Double registers are pairs of float registers, so the extension adds no state: dN is fN holding
+// the low word and fN+1 the high word, and N must be even. The operations are in the escaped page
+// and only decode with the double feature enabled. Soft float mode only covers single precision,
+// doubles always use the host FPU.
+//
+// Loads and stores take the address from an integer register, as there is no room for an
+// immediate in the escaped page.
+
+use crate::{
+    Address, Cpu, InvalidMemoryAccess, Width, F_CARRY, F_INFINITE, F_NAN, F_NEGATIVE, F_OVERFLOW,
+    F_ZERO,
+};
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    fn double_register(&self, d: usize) -> Result<usize, InvalidMemoryAccess> {
+        if d & 1 == 0 && d + 1 < N {
+            Ok(d)
+        } else {
+            Err(InvalidMemoryAccess::InvalidRegister(d))
+        }
+    }
+
+    pub fn get_double(&self, d: usize) -> f64 {
+        let bits = (self.fs[d + 1].to_bits() as u64) << 32 | self.fs[d].to_bits() as u64;
+        f64::from_bits(bits)
+    }
+
+    pub fn set_double(&mut self, d: usize, x: f64) {
+        self.fs[d] = f32::from_bits(x.to_bits() as u32);
+        self.fs[d + 1] = f32::from_bits((x.to_bits() >> 32) as u32);
+    }
+
+    fn update_flags_double(&mut self, x: f64) {
+        self.flags &= !(1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_NAN | 1 << F_INFINITE);
+        self.set_flag(F_ZERO, x == 0.0);
+        self.set_flag(F_NEGATIVE, x.is_sign_negative());
+        self.set_flag(F_NAN, x.is_nan());
+        self.set_flag(F_INFINITE, x.is_infinite());
+    }
+
+    // Executes a double precision instruction from the escaped page
+    // 0x10-0x13 - add, subtract, multiply, divide
+    // 0x14 - compare, setting the flags like fcmp
+    // 0x18, 0x19 - load and store at the address in an integer register
+    // 0x1c-0x1f - conversions from double to float, float to double, double to int, int to double
+    pub(crate) fn double_op(
+        &mut self,
+        func: u8,
+        fst: usize,
+        snd: usize,
+    ) -> Result<(), InvalidMemoryAccess> {
+        match func {
+            0x10..=0x13 => {
+                let (d0, d1) = (self.double_register(fst)?, self.double_register(snd)?);
+                let (a, b) = (self.get_double(d0), self.get_double(d1));
+                let res = match func {
+                    0x10 => a + b,
+                    0x11 => a - b,
+                    0x12 => a * b,
+                    _ => a / b,
+                };
+                self.set_double(d0, res);
+                self.update_flags_double(res);
+            }
+            0x14 => {
+                let (d0, d1) = (self.double_register(fst)?, self.double_register(snd)?);
+                let (a, b) = (self.get_double(d0), self.get_double(d1));
+                self.flags &= !(1 << F_ZERO
+                    | 1 << F_NEGATIVE
+                    | 1 << F_NAN
+                    | 1 << F_INFINITE
+                    | 1 << F_OVERFLOW
+                    | 1 << F_CARRY);
+                self.set_flag(F_ZERO, a == b);
+                self.set_flag(F_NEGATIVE, a < b);
+                self.set_flag(F_CARRY, a.partial_cmp(&b) != Some(std::cmp::Ordering::Less));
+                self.set_flag(F_NAN, a.is_nan() || b.is_nan());
+            }
+            0x18 => {
+                let d0 = self.double_register(fst)?;
+                let addr = self.xs[snd];
+                let low = self.read_sized(addr, Width::Word)?;
+                let high = self.read_sized(addr.wrapping_add(4), Width::Word)?;
+                let res = f64::from_bits((high as u64) << 32 | low as u64);
+                self.set_double(d0, res);
+                self.update_flags_double(res);
+            }
+            0x19 => {
+                let d0 = self.double_register(fst)?;
+                let (addr, bits) = (self.xs[snd], self.get_double(d0).to_bits());
+                self.write_sized(addr, Width::Word, bits as u32)?;
+                self.write_sized(addr.wrapping_add(4), Width::Word, (bits >> 32) as u32)?;
+            }
+            0x1c => {
+                let res = self.get_double(self.double_register(snd)?) as f32;
+                self.fs[fst] = res;
+                self.update_flags_float(res);
+            }
+            0x1d => {
+                let res = self.fs[snd] as f64;
+                self.set_double(self.double_register(fst)?, res);
+                self.update_flags_double(res);
+            }
+            0x1e => {
+                let res = self.get_double(self.double_register(snd)?) as i32 as u32;
+                self.xs[fst] = res;
+                self.update_flags_int(res);
+            }
+            _ => {
+                let res = self.xs[snd] as i32 as f64;
+                self.set_double(self.double_register(fst)?, res);
+                self.update_flags_double(res);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cpu, InvalidMemoryAccess, SimpleAddress, StepOutcome};
+
+    #[test]
+    fn double_precision() {
+        // itod d2, x1; ddiv d2, d4; strd d2, x0; ldrd d6, x0
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [
+            0x3f, 0x1f, 0x21, 0x3f, 0x13, 0x24, 0x3f, 0x19, 0x20, 0x3f, 0x18, 0x60,
+        ];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        cpu.xs[0] = 0x100;
+        cpu.xs[1] = 1;
+        cpu.set_double(4, 3.0);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.get_double(6), 1.0 / 3.0);
+        assert_eq!(
+            cpu.addressing.memory[0x100..0x108],
+            (1.0f64 / 3.0).to_le_bytes()
+        );
+
+        // Odd registers are not doubles
+        cpu.addressing.memory[0..3].copy_from_slice(&[0x3f, 0x10, 0x13]);
+        cpu.xs[crate::R_PC] = 0;
+        cpu.xs[crate::R_SP] = 0x8000;
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Faulted(InvalidMemoryAccess::InvalidRegister(1))
+        );
+    }
+}
@@ -0,0 +1,238 @@
+// Round robin executor for many machines
+// Runs batches of independent machines on a pool of threads. Each worker keeps a few machines
+// active at once and gives them turns of a fixed number of cycles, taking new jobs from a shared
+// queue as machines finish, so thousands of short or long runs share the threads fairly without
+// each machine needing a thread of its own.
+//
+// A machine that executes a pause hint is spinning, so its turn ends early to let the others run.
+//
+// Machines are built on the worker that runs them, since a cpu with memory hooks cannot be sent
+// between threads. Completion callbacks run on that worker too, with the finished machine.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::{Address, Cpu, StepOutcome};
+
+pub const DEFAULT_QUANTUM: u64 = 10_000;
+pub const DEFAULT_MAX_ACTIVE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Finish {
+    Shutdown,
+
+    // Ran for its whole cycle budget without shutting down
+    Budget,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Completion {
+    pub finish: Finish,
+
+    // Cycles run by the executor, not counting any the machine had when it was built
+    pub cycles: u64,
+}
+
+type Build<'a, T, const N: usize> = Box<dyn FnOnce() -> Cpu<T, N> + Send + 'a>;
+type Complete<'a, T, const N: usize> = Box<dyn FnOnce(Cpu<T, N>, Completion) + Send + 'a>;
+
+pub struct Job<'a, T, const N: usize = 16>
+where
+    T: Address,
+{
+    build: Build<'a, T, N>,
+    on_complete: Complete<'a, T, N>,
+    budget: Option<u64>,
+    quantum: Option<u64>,
+}
+
+impl<'a, T, const N: usize> Job<'a, T, N>
+where
+    T: Address,
+{
+    // Runs the machine until it shuts down
+    pub fn new<B, C>(build: B, on_complete: C) -> Job<'a, T, N>
+    where
+        B: FnOnce() -> Cpu<T, N> + Send + 'a,
+        C: FnOnce(Cpu<T, N>, Completion) + Send + 'a,
+    {
+        Job {
+            build: Box::new(build),
+            on_complete: Box::new(on_complete),
+            budget: None,
+            quantum: None,
+        }
+    }
+
+    // Stops the machine after this many cycles if it has not shut down
+    pub fn budget(mut self, cycles: u64) -> Job<'a, T, N> {
+        self.budget = Some(cycles);
+        self
+    }
+
+    // Cycles per turn for this machine, instead of the executor's quantum
+    pub fn quantum(mut self, cycles: u64) -> Job<'a, T, N> {
+        self.quantum = Some(cycles.max(1));
+        self
+    }
+}
+
+struct Active<'a, T, const N: usize>
+where
+    T: Address,
+{
+    cpu: Cpu<T, N>,
+    start: u64,
+    on_complete: Complete<'a, T, N>,
+    budget: Option<u64>,
+    quantum: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Executor {
+    threads: usize,
+    quantum: u64,
+    max_active: usize,
+}
+
+impl Executor {
+    pub fn new(threads: usize) -> Executor {
+        Executor {
+            threads: threads.max(1),
+            quantum: DEFAULT_QUANTUM,
+            max_active: DEFAULT_MAX_ACTIVE,
+        }
+    }
+
+    // Cycles per turn for machines that do not set their own
+    pub fn quantum(mut self, cycles: u64) -> Executor {
+        self.quantum = cycles.max(1);
+        self
+    }
+
+    // Machines each worker keeps built and running at once
+    pub fn max_active(mut self, machines: usize) -> Executor {
+        self.max_active = machines.max(1);
+        self
+    }
+
+    // Runs every job to completion, returning once all of their callbacks have run
+    pub fn run<'a, T, const N: usize>(&self, jobs: Vec<Job<'a, T, N>>)
+    where
+        T: Address,
+    {
+        let queue = Mutex::new(jobs.into_iter().collect::<VecDeque<_>>());
+        thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| self.work(&queue));
+            }
+        });
+    }
+
+    fn work<'a, T, const N: usize>(&self, queue: &Mutex<VecDeque<Job<'a, T, N>>>)
+    where
+        T: Address,
+    {
+        let mut active: VecDeque<Active<'a, T, N>> = VecDeque::new();
+        loop {
+            while active.len() < self.max_active {
+                let job = match queue.lock().unwrap().pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+                let cpu = (job.build)();
+                active.push_back(Active {
+                    start: cpu.cycles(),
+                    cpu,
+                    on_complete: job.on_complete,
+                    budget: job.budget,
+                    quantum: job.quantum.unwrap_or(self.quantum),
+                });
+            }
+
+            let mut machine = match active.pop_front() {
+                Some(machine) => machine,
+                None => return,
+            };
+            match run_turn(&mut machine) {
+                Some(finish) => {
+                    let completion = Completion {
+                        finish,
+                        cycles: machine.cpu.cycles() - machine.start,
+                    };
+                    (machine.on_complete)(machine.cpu, completion);
+                }
+                None => active.push_back(machine),
+            }
+        }
+    }
+}
+
+// Runs a machine for one quantum, returning how it finished if it did
+fn run_turn<T, const N: usize>(machine: &mut Active<'_, T, N>) -> Option<Finish>
+where
+    T: Address,
+{
+    let turn_start = machine.cpu.cycles();
+    while machine.cpu.cycles() - turn_start < machine.quantum {
+        if machine
+            .budget
+            .is_some_and(|budget| machine.cpu.cycles() - machine.start >= budget)
+        {
+            return Some(Finish::Budget);
+        }
+        if machine.cpu.step() == StepOutcome::Shutdown {
+            return Some(Finish::Shutdown);
+        }
+        if machine.cpu.paused() {
+            break;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn runs_every_job() {
+        let results = Mutex::new(vec![]);
+        let jobs = (0..50u32)
+            .map(|i| {
+                let results = &results;
+                Job::new(
+                    move || {
+                        // ldi x1, i, then zeroed memory decodes as bz 0x0, which loops forever
+                        let mut cpu = Cpu::new(SimpleAddress::new(0x100));
+                        let mut program = vec![0x41];
+                        program.extend_from_slice(&i.to_le_bytes());
+                        cpu.poke_range(0, &program);
+                        cpu
+                    },
+                    move |cpu, completion| {
+                        results.lock().unwrap().push((cpu.xs[1], completion));
+                    },
+                )
+                .budget(100 + i as u64)
+            })
+            .collect();
+        Executor::new(4).quantum(7).max_active(3).run(jobs);
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|&(x, _)| x);
+        assert_eq!(results.len(), 50);
+        for (i, &(x, completion)) in results.iter().enumerate() {
+            assert_eq!(x, i as u32);
+            assert_eq!(
+                completion,
+                Completion {
+                    finish: Finish::Budget,
+                    cycles: 100 + i as u64
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,332 @@
+// Host side firmware services
+// Early guest software gets a BIOS-like set of services before it has drivers of its own. The
+// fwcall instruction (0x20, privileged) passes the service number in x0 and up to three arguments
+// in x1-x3 to the firmware installed on the cpu, which returns results in x0 and x1. Services that
+// do not exist, and every service when no firmware is installed, return FW_ERROR in x0.
+//
+// Services of the default firmware:
+// 0 FW_INFO       x0 = firmware version, x1 = number of services
+// 1 FW_WRITE      writes x2 bytes at x1 to the console, x0 = bytes written
+// 2 FW_READ       reads up to x2 bytes of console input to x1 without waiting, x0 = bytes read
+// 3 FW_MEMORY_MAP x0 = start, x1 = length of memory region x1, FW_ERROR past the last region
+// 4 FW_SHUTDOWN   shuts the machine down
+// 5 FW_REBOOT     resets the registers, see Cpu::reset, memory is kept
+// 6 FW_TIME       x0 = low word, x1 = high word of the time in milliseconds
+// 7-11            host file access when a sandbox is given, see semihosting.rs
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::semihosting::HostFiles;
+use crate::{Address, Cpu, InvalidMemoryAccess, Width, F_USER_RING, R_PC};
+
+pub const FW_INFO: u32 = 0;
+pub const FW_WRITE: u32 = 1;
+pub const FW_READ: u32 = 2;
+pub const FW_MEMORY_MAP: u32 = 3;
+pub const FW_SHUTDOWN: u32 = 4;
+pub const FW_REBOOT: u32 = 5;
+pub const FW_TIME: u32 = 6;
+
+pub const FW_ERROR: u32 = u32::MAX;
+
+pub const DEFAULT_FIRMWARE_VERSION: u32 = 1;
+const DEFAULT_SERVICES: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FirmwareAction {
+    // Values for x0 and x1
+    Return(u32, u32),
+
+    Shutdown,
+    Reboot,
+}
+
+// Guest memory as seen by the instruction making the call, with the usual permission checks
+pub trait GuestMemory {
+    fn read(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess>;
+
+    fn write(&mut self, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess>;
+
+    fn cycles(&self) -> u64;
+}
+
+pub trait Firmware {
+    // Errors fault the calling instruction, eg when a buffer is not mapped
+    fn call(
+        &mut self,
+        service: u32,
+        args: [u32; 3],
+        memory: &mut dyn GuestMemory,
+    ) -> Result<FirmwareAction, InvalidMemoryAccess>;
+}
+
+type Clock = Box<dyn FnMut() -> u64>;
+
+pub struct DefaultFirmware {
+    output: Box<dyn Write>,
+    input: VecDeque<u8>,
+    regions: Vec<(u32, u32)>,
+    clock: Clock,
+    files: Option<HostFiles>,
+}
+
+impl DefaultFirmware {
+    // Writes the console to stdout, has no memory regions, and tells the host's time
+    pub fn new() -> DefaultFirmware {
+        DefaultFirmware {
+            output: Box::new(io::stdout()),
+            input: VecDeque::new(),
+            regions: vec![],
+            clock: Box::new(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |time| time.as_millis() as u64)
+            }),
+            files: None,
+        }
+    }
+
+    pub fn output(mut self, output: Box<dyn Write>) -> DefaultFirmware {
+        self.output = output;
+        self
+    }
+
+    // Adds a region to the memory map, regions are reported in the order they are added
+    pub fn region(mut self, start: u32, len: u32) -> DefaultFirmware {
+        self.regions.push((start, len));
+        self
+    }
+
+    // Replaces the time source, eg to make runs reproducible
+    pub fn clock<F>(mut self, clock: F) -> DefaultFirmware
+    where
+        F: FnMut() -> u64 + 'static,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    // Gives the guest access to the files in a sandbox directory
+    pub fn files(mut self, files: HostFiles) -> DefaultFirmware {
+        self.files = Some(files);
+        self
+    }
+
+    // Queues bytes for the guest to read from the console
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.input.extend(input);
+    }
+}
+
+impl Default for DefaultFirmware {
+    fn default() -> DefaultFirmware {
+        DefaultFirmware::new()
+    }
+}
+
+impl Firmware for DefaultFirmware {
+    fn call(
+        &mut self,
+        service: u32,
+        args: [u32; 3],
+        memory: &mut dyn GuestMemory,
+    ) -> Result<FirmwareAction, InvalidMemoryAccess> {
+        if let Some(result) = self
+            .files
+            .as_mut()
+            .and_then(|f| f.call(service, args, memory))
+        {
+            return result;
+        }
+
+        let [addr, len, _] = args;
+        Ok(match service {
+            FW_INFO => FirmwareAction::Return(DEFAULT_FIRMWARE_VERSION, DEFAULT_SERVICES),
+            FW_WRITE => {
+                let data = (0..len)
+                    .map(|i| memory.read(addr.wrapping_add(i)))
+                    .collect::<Result<Vec<u8>, _>>()?;
+
+                // A console that cannot be written to drops the output
+                let written = match self.output.write_all(&data) {
+                    Ok(()) => len,
+                    Err(_) => 0,
+                };
+                let _ = self.output.flush();
+                FirmwareAction::Return(written, 0)
+            }
+            FW_READ => {
+                let mut read = 0;
+                while read < len {
+                    let byte = match self.input.front() {
+                        Some(&byte) => byte,
+                        None => break,
+                    };
+                    memory.write(addr.wrapping_add(read), byte)?;
+                    self.input.pop_front();
+                    read += 1;
+                }
+                FirmwareAction::Return(read, 0)
+            }
+            FW_MEMORY_MAP => match self.regions.get(addr as usize) {
+                Some(&(start, len)) => FirmwareAction::Return(start, len),
+                None => FirmwareAction::Return(FW_ERROR, 0),
+            },
+            FW_SHUTDOWN => FirmwareAction::Shutdown,
+            FW_REBOOT => FirmwareAction::Reboot,
+            FW_TIME => {
+                let time = (self.clock)();
+                FirmwareAction::Return(time as u32, (time >> 32) as u32)
+            }
+            _ => FirmwareAction::Return(FW_ERROR, 0),
+        })
+    }
+}
+
+impl<T, const N: usize> GuestMemory for Cpu<T, N>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess> {
+        Ok(self.read_sized(addr, Width::Byte)? as u8)
+    }
+
+    fn write(&mut self, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.write_sized(addr, Width::Byte, data as u32)
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn set_firmware(&mut self, firmware: Option<Box<dyn Firmware>>) {
+        self.firmware = firmware;
+    }
+
+    pub fn firmware_mut(&mut self) -> Option<&mut (dyn Firmware + 'static)> {
+        self.firmware.as_deref_mut()
+    }
+
+    // Puts the registers and pending interrupts back as they were at power on, memory is kept
+    pub fn reset(&mut self) {
+        self.xs = [0; N];
+        self.fs = [0.0; N];
+        self.vs = [[0; 4]; crate::vector::VECTOR_REGISTERS];
+        self.flags = 0;
+        self.interrupt_mask = 0xff;
+        self.mmu.set_root(0);
+        self.mmu.set_asid(0);
+        self.system_sp = 0;
+        self.vector_base = 0;
+        self.fault_address = 0;
+        self.fault_cause = 0;
+        self.shadow_sp = 0;
+        self.traps = crate::isa::OpcodeTraps::default();
+        self.trapped_instruction = 0;
+        self.halted = false;
+        self.interrupts.clear_edges();
+        self.interrupts.reset_priorities();
+    }
+
+    pub(crate) fn firmware_call(&mut self) -> Result<(), InvalidMemoryAccess> {
+        if self.get_flag(F_USER_RING) {
+            return Err(InvalidMemoryAccess::UnprivilegedOpcode);
+        }
+
+        // The firmware is taken out while it runs so that it can be given the cpu's memory
+        let mut firmware = match self.firmware.take() {
+            Some(firmware) => firmware,
+            None => {
+                self.xs[0] = FW_ERROR;
+                return Ok(());
+            }
+        };
+        let args = [self.xs[1], self.xs[2], self.xs[3]];
+        let action = firmware.call(self.xs[0], args, self);
+        self.firmware = Some(firmware);
+
+        match action? {
+            FirmwareAction::Return(x0, x1) => {
+                self.xs[0] = x0;
+                self.xs[1] = x1;
+            }
+            FirmwareAction::Shutdown => self.shutdown = true,
+            FirmwareAction::Reboot => {
+                self.reset();
+                self.xs[R_PC] = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, StepOutcome};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Console(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Console {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn services() {
+        // fwcall, over and over
+        let mut memory = SimpleAddress::new(0x1000);
+        memory.load(0, &[0x20; 8]);
+        memory.load(0x100, b"hi!");
+        let mut cpu = Cpu::new(memory);
+
+        const EXECUTED: StepOutcome = StepOutcome::Executed {
+            opcode: 0x20,
+            cycles: 1,
+        };
+        let call = |cpu: &mut Cpu<SimpleAddress>, service, x1, x2| {
+            cpu.xs[0] = service;
+            cpu.xs[1] = x1;
+            cpu.xs[2] = x2;
+            let outcome = cpu.step();
+            (outcome, cpu.xs[0], cpu.xs[1])
+        };
+        assert_eq!(call(&mut cpu, FW_INFO, 0, 0).1, FW_ERROR);
+
+        let console = Rc::new(RefCell::new(vec![]));
+        let mut firmware = DefaultFirmware::new()
+            .output(Box::new(Console(console.clone())))
+            .region(0, 0x1000)
+            .clock(|| 0x1_0000_0002);
+        firmware.push_input(b"ok");
+        cpu.set_firmware(Some(Box::new(firmware)));
+
+        assert_eq!(call(&mut cpu, FW_WRITE, 0x100, 3), (EXECUTED, 3, 0));
+        assert_eq!(*console.borrow(), b"hi!");
+        assert_eq!(call(&mut cpu, FW_READ, 0x200, 8).1, 2);
+        assert_eq!(cpu.peek_range(0x200, 2), Some(b"ok".to_vec()));
+        assert_eq!(call(&mut cpu, FW_MEMORY_MAP, 0, 0).1, 0);
+        assert_eq!(call(&mut cpu, FW_MEMORY_MAP, 0, 0).2, 0x1000);
+        assert_eq!(call(&mut cpu, FW_MEMORY_MAP, 1, 0).1, FW_ERROR);
+        assert_eq!(call(&mut cpu, FW_TIME, 0, 0), (EXECUTED, 2, 1));
+
+        assert_eq!(call(&mut cpu, FW_REBOOT, 0, 0), (EXECUTED, 0, 0));
+        assert_eq!(cpu.xs[R_PC], 0);
+        assert_eq!(call(&mut cpu, FW_SHUTDOWN, 0, 0).0, StepOutcome::Shutdown);
+    }
+}
@@ -0,0 +1,261 @@
+// Pending interrupt lines
+// Each of the IRQ_LINES maskable interrupts is a line into the cpu, and what is pending is a
+// bitmask rather than a queue, as in hardware. Lines are either edge triggered, where irq latches a
+// request that is cleared when its handler is entered, or level triggered, where the line is
+// pending for as long as the device holds it asserted and the device must deassert it once the
+// handler has dealt with the cause. Requesting a line that is already pending has no further
+// effect, so devices cannot flood the cpu.
+//
+// Each line has a priority level from 0, the highest, to PRIORITY_LEVELS - 1, which starts out as
+// the line number. When interrupts are enabled the pending line that is not masked with the highest
+// priority is taken, the lowest numbered one among lines of the same level. Besides the per line
+// mask, the priority threshold masks every line whose level is not above it, so lowering it shuts
+// out whole classes of devices at once. Non-maskable interrupts never wait and are delivered as
+// they happen.
+//
+// A line is in service from when its handler is entered until the handler returns with iret. With
+// the nested interrupts flag set, entering a handler leaves interrupts enabled and only lines of a
+// higher priority than every line in service can be taken, so a timer at level 0 can preempt a
+// slow device handler but not the other way around.
+
+pub const IRQ_LINES: usize = 8;
+pub const PRIORITY_LEVELS: u8 = 16;
+
+#[derive(Debug, Clone)]
+pub(crate) struct PendingInterrupts {
+    edge: u8,
+    level: u8,
+    in_service: u8,
+
+    // Priority level of each line, lower is more urgent
+    priorities: [u8; IRQ_LINES],
+
+    // Lines are only taken if their level is below this
+    threshold: u8,
+
+    // Cycle each line was raised at, for latency statistics
+    raised: [u64; IRQ_LINES],
+
+    // Cycle each line became the next to be taken at
+    queued: [Option<u64>; IRQ_LINES],
+}
+
+impl Default for PendingInterrupts {
+    fn default() -> PendingInterrupts {
+        PendingInterrupts {
+            edge: 0,
+            level: 0,
+            in_service: 0,
+            priorities: [0, 1, 2, 3, 4, 5, 6, 7],
+            threshold: PRIORITY_LEVELS,
+            raised: [0; IRQ_LINES],
+            queued: [None; IRQ_LINES],
+        }
+    }
+}
+
+impl PendingInterrupts {
+    pub(crate) fn pending(&self) -> u8 {
+        self.edge | self.level
+    }
+
+    pub(crate) fn raise(&mut self, line: usize, cycle: u64) {
+        if self.pending() & 1 << line == 0 {
+            self.raised[line] = cycle;
+        }
+        self.edge |= 1 << line;
+    }
+
+    pub(crate) fn set_level(&mut self, line: usize, asserted: bool, cycle: u64) {
+        if asserted {
+            if self.pending() & 1 << line == 0 {
+                self.raised[line] = cycle;
+            }
+            self.level |= 1 << line;
+        } else {
+            self.level &= !(1 << line);
+            if self.edge & 1 << line == 0 {
+                self.queued[line] = None;
+            }
+        }
+    }
+
+    pub(crate) fn priority(&self, line: usize) -> u8 {
+        self.priorities[line]
+    }
+
+    pub(crate) fn set_priority(&mut self, line: usize, level: u8) {
+        self.priorities[line] = level.min(PRIORITY_LEVELS - 1);
+    }
+
+    // The priorities of every line packed into a system register, four bits per line with line 0
+    // in the lowest bits
+    pub(crate) fn priorities(&self) -> u32 {
+        self.priorities
+            .iter()
+            .enumerate()
+            .fold(0, |word, (line, &level)| {
+                word | (level as u32) << (4 * line)
+            })
+    }
+
+    pub(crate) fn set_priorities(&mut self, word: u32) {
+        for (line, level) in self.priorities.iter_mut().enumerate() {
+            *level = (word >> (4 * line) & 0xf) as u8;
+        }
+    }
+
+    pub(crate) fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    pub(crate) fn set_threshold(&mut self, threshold: u8) {
+        self.threshold = threshold.min(PRIORITY_LEVELS);
+    }
+
+    // Puts the priorities and threshold back as they were at power on
+    pub(crate) fn reset_priorities(&mut self) {
+        let PendingInterrupts {
+            priorities,
+            threshold,
+            ..
+        } = PendingInterrupts::default();
+        self.priorities = priorities;
+        self.threshold = threshold;
+    }
+
+    // Lines with a priority above level
+    fn above(&self, level: u8) -> u8 {
+        (0..IRQ_LINES)
+            .filter(|&line| self.priorities[line] < level)
+            .fold(0, |mask, line| mask | 1 << line)
+    }
+
+    // The line that would be taken next
+    pub(crate) fn next(&self, mask: u8) -> Option<usize> {
+        let pending = self.pending() & mask & self.above(self.threshold);
+        (0..IRQ_LINES)
+            .filter(|&line| pending & 1 << line != 0)
+            .min_by_key(|&line| self.priorities[line])
+    }
+
+    pub(crate) fn in_service(&self) -> u8 {
+        self.in_service
+    }
+
+    // Lines of a higher priority than everything in service
+    pub(crate) fn priority_mask(&self) -> u8 {
+        let highest = (0..IRQ_LINES)
+            .filter(|&line| self.in_service & 1 << line != 0)
+            .map(|line| self.priorities[line])
+            .min();
+        match highest {
+            Some(level) => self.above(level),
+            None => 0xff,
+        }
+    }
+
+    // A handler returned
+    pub(crate) fn end(&mut self, line: usize) {
+        self.in_service &= !(1 << line);
+    }
+
+    // Notes the cycle the next line started waiting to be taken at
+    pub(crate) fn mark_queued(&mut self, mask: u8, cycle: u64) {
+        if let Some(line) = self.next(mask) {
+            self.queued[line].get_or_insert(cycle);
+        }
+    }
+
+    // Takes a line to handle, returning the cycles it was raised and queued at
+    // A level triggered line that is still asserted is raised again straight away
+    pub(crate) fn take(&mut self, line: usize, cycle: u64) -> (u64, Option<u64>) {
+        let times = (self.raised[line], self.queued[line].take());
+        self.edge &= !(1 << line);
+        self.in_service |= 1 << line;
+        self.raised[line] = cycle;
+        times
+    }
+
+    // Drops latched requests and forgets the handlers in service, level triggered lines keep
+    // following their devices
+    pub(crate) fn clear_edges(&mut self) {
+        self.in_service = 0;
+        for line in 0..IRQ_LINES {
+            if self.level & 1 << line == 0 {
+                self.queued[line] = None;
+            }
+        }
+        self.edge = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_and_triggers() {
+        let mut pending = PendingInterrupts::default();
+        pending.raise(5, 0);
+        pending.raise(5, 1);
+        pending.set_level(2, true, 2);
+        assert_eq!(pending.pending(), 0b100100);
+        assert_eq!(pending.next(0xff), Some(2));
+        assert_eq!(pending.next(0b11111011), Some(5));
+
+        // Level triggered lines stay pending until deasserted, edges are cleared when taken
+        assert_eq!(pending.take(2, 3), (2, None));
+        assert_eq!(pending.next(0xff), Some(2));
+        pending.set_level(2, false, 4);
+        assert_eq!(pending.take(5, 5), (0, None));
+        assert_eq!(pending.next(0xff), None);
+    }
+
+    #[test]
+    fn in_service() {
+        let mut pending = PendingInterrupts::default();
+        assert_eq!(pending.priority_mask(), 0xff);
+        pending.raise(3, 0);
+        pending.take(3, 1);
+        pending.raise(1, 2);
+        pending.take(1, 3);
+        assert_eq!(pending.in_service(), 0b1010);
+        assert_eq!(pending.priority_mask(), 0b1);
+
+        pending.end(1);
+        assert_eq!(pending.priority_mask(), 0b111);
+    }
+
+    #[test]
+    fn priority_levels() {
+        let mut pending = PendingInterrupts::default();
+        assert_eq!(pending.priorities(), 0x76543210);
+        pending.set_priority(6, 0);
+        pending.set_priority(0, 3);
+        pending.raise(0, 0);
+        pending.raise(3, 0);
+        pending.raise(6, 0);
+        assert_eq!(pending.next(0xff), Some(6));
+
+        // Lines of the same level are taken lowest number first
+        assert_eq!(pending.next(0b10111111), Some(0));
+
+        // The threshold masks every line that is not above it
+        pending.set_threshold(3);
+        assert_eq!(pending.next(0b10111111), None);
+        pending.set_threshold(4);
+        assert_eq!(pending.next(0b10111111), Some(0));
+
+        // Nesting goes by level, so line 6 can preempt line 0 but not the other way around
+        pending.take(6, 1);
+        assert_eq!(pending.priority_mask(), 0);
+        pending.end(6);
+        pending.take(0, 2);
+        assert_eq!(pending.priority_mask(), 0b01000110);
+
+        pending.reset_priorities();
+        assert_eq!(pending.priorities(), 0x76543210);
+        assert_eq!(pending.threshold(), PRIORITY_LEVELS);
+    }
+}
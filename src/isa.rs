@@ -0,0 +1,341 @@
+// ISA versioning and optional opcode groups
+// Each group of opcodes added on top of the base instruction set has a feature bit. A cpu only
+// decodes the groups that are enabled, so binaries written for an older machine can be run against
+// exactly the instruction set they expect. Disabled opcodes behave like unassigned ones and raise
+// the illegal opcode fault. Guests read the enabled features and the ISA version through system
+// registers 11 and 12.
+//
+// The system ring can also make opcodes, one at a time or a whole group, trap to the handler for
+// the trapped opcode fault instead of executing, to emulate instructions it does not want to allow
+// or that the machine lacks. Trapping takes precedence over a group being disabled. The fault
+// returns to the trapped instruction, whose first bytes are kept in system register 15:
+// 0x00eerroo
+// e - byte following the register extension prefix, if the instruction had one
+// r - register byte of two register opcodes
+// o - opcode
+
+use std::ops::{BitOr, Not};
+
+use crate::{Address, Cpu, InvalidMemoryAccess};
+
+// Bumped whenever an opcode group is added
+pub const ISA_VERSION: u32 = 23;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IsaFeatures(u32);
+
+impl IsaFeatures {
+    pub const NONE: IsaFeatures = IsaFeatures(0);
+
+    // Floating point arithmetic, moves, loads, and stores
+    pub const FLOAT: IsaFeatures = IsaFeatures(1 << 0);
+
+    // The 0x3e prefix for reaching registers 16-31
+    pub const REGISTER_EXTENSION: IsaFeatures = IsaFeatures(1 << 1);
+
+    // Memory fences, added in version 2
+    pub const FENCE: IsaFeatures = IsaFeatures(1 << 2);
+
+    // The pause hint, added in version 3
+    pub const PAUSE: IsaFeatures = IsaFeatures(1 << 3);
+
+    // The firmware call, added in version 4
+    pub const FIRMWARE: IsaFeatures = IsaFeatures(1 << 4);
+
+    // The system call, added in version 5
+    pub const SYSCALL: IsaFeatures = IsaFeatures(1 << 5);
+
+    // Halting until an interrupt, added in version 6
+    pub const HALT: IsaFeatures = IsaFeatures(1 << 6);
+
+    // The widening multiply, added in version 7
+    pub const WIDE_MULTIPLY: IsaFeatures = IsaFeatures(1 << 7);
+
+    // Signed multiply, divide, and modulo, added in version 8
+    pub const SIGNED_ARITHMETIC: IsaFeatures = IsaFeatures(1 << 8);
+
+    // Integer and float comparisons, added in version 9
+    pub const COMPARE: IsaFeatures = IsaFeatures(1 << 9);
+
+    // Branches on signed and unsigned comparisons, added in version 10
+    pub const COMPARE_BRANCH: IsaFeatures = IsaFeatures(1 << 10);
+
+    // Complement and negation, added in version 11
+    pub const NEGATE: IsaFeatures = IsaFeatures(1 << 11);
+
+    // Rotates, added in version 12
+    pub const ROTATE: IsaFeatures = IsaFeatures(1 << 12);
+
+    // Arithmetic and logic with an immediate operand, added in version 13
+    pub const IMMEDIATE: IsaFeatures = IsaFeatures(1 << 13);
+
+    // Pushing and popping single registers, added in version 14
+    pub const PUSH_POP: IsaFeatures = IsaFeatures(1 << 14);
+
+    // Calls and jumps to an address in a register, added in version 15
+    pub const INDIRECT_BRANCH: IsaFeatures = IsaFeatures(1 << 15);
+
+    // Branches, calls, and jumps to an offset from the next instruction, added in version 16
+    pub const RELATIVE_BRANCH: IsaFeatures = IsaFeatures(1 << 16);
+
+    // Byte and halfword loads, added in version 17
+    pub const NARROW_LOAD: IsaFeatures = IsaFeatures(1 << 17);
+
+    // Sign and zero extension of bytes and halfwords, added in version 18
+    pub const EXTEND: IsaFeatures = IsaFeatures(1 << 18);
+
+    // Float square root and absolute value, added in version 19
+    pub const FLOAT_MATH: IsaFeatures = IsaFeatures(1 << 19);
+
+    // Float rounding, conversions with a rounding mode, minimum, and maximum, the first opcodes
+    // of the escaped page, added in version 20
+    pub const FLOAT_ROUNDING: IsaFeatures = IsaFeatures(1 << 20);
+
+    // Double precision arithmetic on pairs of float registers, added in version 21
+    pub const DOUBLE: IsaFeatures = IsaFeatures(1 << 21);
+
+    // Packed integer and float arithmetic on the vector registers, added in version 22
+    pub const VECTOR: IsaFeatures = IsaFeatures(1 << 22);
+
+    // Compare-and-swap, exchange, and fetch-and-add on memory, added in version 23
+    pub const ATOMIC: IsaFeatures = IsaFeatures(1 << 23);
+
+    pub const ALL: IsaFeatures = IsaFeatures(0b1111_1111_1111_1111_1111_1111);
+
+    pub fn from_bits(bits: u32) -> IsaFeatures {
+        IsaFeatures(bits & IsaFeatures::ALL.0)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: IsaFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: IsaFeatures) -> IsaFeatures {
+        IsaFeatures(self.0 | other.0)
+    }
+
+    pub fn without(self, other: IsaFeatures) -> IsaFeatures {
+        IsaFeatures(self.0 & !other.0)
+    }
+
+    // Features needed to decode an opcode, NONE for the base instruction set
+    pub fn required(opcode: u8) -> IsaFeatures {
+        match opcode {
+            0x1b => IsaFeatures::HALT,
+            0x1c..=0x1e => IsaFeatures::FENCE,
+            0x1f => IsaFeatures::PAUSE,
+            0x20 => IsaFeatures::FIRMWARE,
+            0x21 => IsaFeatures::SYSCALL,
+            0x22..=0x27 => IsaFeatures::COMPARE_BRANCH,
+            0x28..=0x39 => IsaFeatures::RELATIVE_BRANCH,
+            0x3a..=0x3d => IsaFeatures::EXTEND,
+            0x3e => IsaFeatures::REGISTER_EXTENSION,
+            0x50..=0x5f | 0x70..=0x7f | 0xf0..=0xff => IsaFeatures::FLOAT,
+            0x85..=0x88 | 0x8f..=0x93 | 0x95 | 0x99 => IsaFeatures::FLOAT,
+            0x9c => IsaFeatures::WIDE_MULTIPLY,
+            0x9d..=0x9f => IsaFeatures::SIGNED_ARITHMETIC,
+            0xa0 | 0xa1 => IsaFeatures::COMPARE,
+            0xa2..=0xa4 => IsaFeatures::NEGATE,
+            0xa5..=0xa8 => IsaFeatures::ROTATE,
+            0xa9..=0xaf => IsaFeatures::IMMEDIATE,
+            0xb0..=0xb3 => IsaFeatures::PUSH_POP,
+            0xb4 | 0xb5 => IsaFeatures::INDIRECT_BRANCH,
+            0xb6..=0xbd => IsaFeatures::NARROW_LOAD,
+            0xbe | 0xbf => IsaFeatures::FLOAT_MATH,
+            _ => IsaFeatures::NONE,
+        }
+    }
+
+    // Features needed to decode an opcode in the escaped page, given the byte after the escape
+    pub fn required_extended(func: u8) -> IsaFeatures {
+        match func {
+            0x00..=0x09 => IsaFeatures::FLOAT_ROUNDING,
+            0x10..=0x14 | 0x18 | 0x19 | 0x1c..=0x1f => IsaFeatures::DOUBLE,
+            0x20..=0x24 | 0x28..=0x2c | 0x30 | 0x31 => IsaFeatures::VECTOR,
+            0x38..=0x3a => IsaFeatures::ATOMIC,
+            _ => IsaFeatures::NONE,
+        }
+    }
+}
+
+impl Default for IsaFeatures {
+    fn default() -> IsaFeatures {
+        IsaFeatures::ALL
+    }
+}
+
+impl BitOr for IsaFeatures {
+    type Output = IsaFeatures;
+
+    fn bitor(self, other: IsaFeatures) -> IsaFeatures {
+        self.with(other)
+    }
+}
+
+impl Not for IsaFeatures {
+    type Output = IsaFeatures;
+
+    fn not(self) -> IsaFeatures {
+        IsaFeatures::ALL.without(self)
+    }
+}
+
+// Opcodes that fault rather than execute
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodeTraps {
+    opcodes: [u64; 4],
+    groups: IsaFeatures,
+}
+
+impl Default for OpcodeTraps {
+    fn default() -> OpcodeTraps {
+        OpcodeTraps {
+            opcodes: [0; 4],
+            groups: IsaFeatures::NONE,
+        }
+    }
+}
+
+impl OpcodeTraps {
+    pub fn set_opcode(&mut self, opcode: u8, trapped: bool) {
+        let bit = 1 << (opcode & 0x3f);
+        if trapped {
+            self.opcodes[opcode as usize >> 6] |= bit;
+        } else {
+            self.opcodes[opcode as usize >> 6] &= !bit;
+        }
+    }
+
+    pub fn groups(&self) -> IsaFeatures {
+        self.groups
+    }
+
+    pub fn set_groups(&mut self, groups: IsaFeatures) {
+        self.groups = groups;
+    }
+
+    // Whether an opcode traps, either on its own or through its group
+    pub fn contains(&self, opcode: u8) -> bool {
+        let required = IsaFeatures::required(opcode);
+        self.opcodes[opcode as usize >> 6] & 1 << (opcode & 0x3f) != 0
+            || required != IsaFeatures::NONE && self.groups.contains(required)
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn opcode_traps(&self) -> OpcodeTraps {
+        self.traps
+    }
+
+    pub fn set_opcode_traps(&mut self, traps: OpcodeTraps) {
+        self.traps = traps;
+    }
+
+    // Faults with the first bytes of a trapped instruction
+    pub(crate) fn trap_opcode(&mut self, opcode: u8, ext: Option<u8>) -> InvalidMemoryAccess {
+        // Fetching the register byte can fault in turn
+        let regs = if opcode & 0xc0 == 0x80 {
+            match self.exec() {
+                Ok(regs) => regs,
+                Err(e) => return e,
+            }
+        } else {
+            0
+        };
+        self.trapped_instruction =
+            (ext.unwrap_or(0) as u32) << 16 | (regs as u32) << 8 | opcode as u32;
+        InvalidMemoryAccess::TrappedOpcode(opcode)
+    }
+
+    pub fn isa_features(&self) -> IsaFeatures {
+        self.features
+    }
+
+    pub fn set_isa_features(&mut self, features: IsaFeatures) {
+        self.features = features;
+    }
+
+    pub(crate) fn opcode_enabled(&self, opcode: u8) -> bool {
+        self.features.contains(IsaFeatures::required(opcode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, R_SP};
+
+    #[test]
+    fn features() {
+        let features = IsaFeatures::ALL.without(IsaFeatures::FLOAT);
+        assert!(features.contains(IsaFeatures::REGISTER_EXTENSION));
+        assert!(!features.contains(IsaFeatures::FLOAT));
+        assert_eq!(!features, IsaFeatures::FLOAT);
+        assert_eq!(IsaFeatures::from_bits(u32::MAX), IsaFeatures::ALL);
+        assert_eq!(IsaFeatures::required(0x86), IsaFeatures::FLOAT);
+        assert_eq!(IsaFeatures::required(0x80), IsaFeatures::NONE);
+        assert_eq!(IsaFeatures::required(0x1d), IsaFeatures::FENCE);
+    }
+
+    #[test]
+    fn disabled_opcodes() {
+        // move x2 <- features; move x3 <- version; load f0 <- 1.5
+        let mut memory = SimpleAddress::default();
+        memory.load(0, &[0x9b, 0xb2, 0x9b, 0xc3, 0x50]);
+        memory.load(5, &1.5f32.to_bits().to_le_bytes());
+
+        let mut cpu = Cpu::new(memory);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.set_isa_features(IsaFeatures::REGISTER_EXTENSION);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.xs[2], IsaFeatures::REGISTER_EXTENSION.bits());
+        assert_eq!(cpu.xs[3], ISA_VERSION);
+        assert_eq!(
+            cpu.last_fault(),
+            Some(InvalidMemoryAccess::IllegalOpcode(0x50))
+        );
+        assert_eq!(cpu.fs[0], 0.0);
+        assert_eq!(cpu.current_trap_frame().unwrap().pc, 4);
+    }
+
+    #[test]
+    fn trapped_opcodes() {
+        let mut traps = OpcodeTraps::default();
+        traps.set_opcode(0x82, true);
+        traps.set_groups(IsaFeatures::FENCE);
+        assert!(traps.contains(0x82));
+        assert!(traps.contains(0x1d));
+        assert!(!traps.contains(0x83));
+        traps.set_opcode(0x82, false);
+        assert!(!traps.contains(0x82));
+
+        // move p13 <- x1 (trap imul); imul x2, x3
+        let mut memory = SimpleAddress::default();
+        memory.load(0, &[0x9a, 0x1d, 0x82, 0x23]);
+        let mut cpu = Cpu::new(memory);
+        cpu.xs[1] = 0x182;
+        cpu.xs[2] = 6;
+        cpu.xs[3] = 7;
+        cpu.xs[crate::R_SP] = 0x8000;
+        cpu.step();
+        assert!(cpu.opcode_traps().contains(0x82));
+
+        cpu.step();
+        assert_eq!(
+            cpu.last_fault(),
+            Some(InvalidMemoryAccess::TrappedOpcode(0x82))
+        );
+        assert_eq!(cpu.xs[2], 6);
+        assert_eq!(cpu.trapped_instruction, 0x2382);
+        assert_eq!(cpu.current_trap_frame().unwrap().pc, 2);
+    }
+}
@@ -1,4 +1,45 @@
-use std::collections::VecDeque;
+pub mod analysis;
+pub mod backtrace;
+pub mod bus;
+pub mod checkpoint;
+pub mod cluster;
+pub mod compressed;
+pub mod conformance;
+pub mod contention;
+pub mod cow;
+pub mod cpu64;
+pub mod debug;
+pub mod decode;
+pub mod delta;
+pub mod devices;
+pub mod difftest;
+pub mod dirty;
+pub mod double;
+pub mod executor;
+pub mod firmware;
+pub mod interrupts;
+pub mod isa;
+pub mod lockstep;
+pub mod mmu;
+pub mod monitor;
+pub mod object;
+pub mod opcodes;
+pub mod pagetable;
+pub mod prefetch;
+pub mod remote;
+pub mod savestate;
+pub mod script;
+pub mod semihosting;
+pub mod server;
+pub mod snapshot;
+pub mod softfloat;
+pub mod stepping;
+pub mod symbols;
+pub mod test_machine;
+pub mod translate;
+pub mod trap;
+pub mod vector;
+pub mod walkcache;
 
 /*
 - interrupts
@@ -9,15 +50,24 @@ use std::collections::VecDeque;
 
 */
 
-const READ:  u8 = 0b100;
-const WRITE: u8 = 0b010;
-const EXEC:  u8 = 0b001;
+pub const READ:  u8 = 0b100;
+pub const WRITE: u8 = 0b010;
+pub const EXEC:  u8 = 0b001;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InvalidMemoryAccess {
     UsedFreePage,
     InvalidPermissions(u8, u8),
-    UnprivilegedOpcode
+    UnprivilegedOpcode,
+    DivideByZero,
+    UninitializedRead(u32),
+    TaintedData,
+    InvalidRegister(usize),
+    ShadowStackMismatch,
+    CorruptedFrame,
+    BusError(u32),
+    TrappedOpcode(u8),
+    IllegalOpcode(u8),
 }
 
 impl std::fmt::Display for InvalidMemoryAccess {
@@ -28,43 +78,231 @@ impl std::fmt::Display for InvalidMemoryAccess {
 
 impl std::error::Error for InvalidMemoryAccess {}
 
+// Size of a data access
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+}
+
+// What reading a byte does besides returning it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadEffect {
+    // Plain memory or registers, reading them changes nothing
+    Pure,
+
+    // Status bits that are cleared once read
+    ClearOnRead,
+
+    // Queues that return a different entry on every read
+    Pop,
+
+    // Anything else that changes the device
+    Other,
+}
+
+impl ReadEffect {
+    pub fn has_side_effects(self) -> bool {
+        self != ReadEffect::Pure
+    }
+}
+
 pub trait Address {
     fn read(&mut self, addr: u32) -> u8;
 
     fn write(&mut self, addr: u32, data: u8);
+
+    // Little endian accesses wider than a byte, which the cpu makes for loads and stores of
+    // halves and words that are not split across pages
+    // Devices where the width matters (eg a FIFO register popped once per read) override these,
+    // by default they are split into byte accesses
+    fn read_width(&mut self, addr: u32, width: Width) -> u32 {
+        (0..width.bytes())
+            .fold(0, |acc, i| acc | (self.read(addr.wrapping_add(i)) as u32) << (8 * i))
+    }
+
+    fn write_width(&mut self, addr: u32, width: Width, data: u32) {
+        for i in 0..width.bytes() {
+            self.write(addr.wrapping_add(i), (data >> (8 * i)) as u8);
+        }
+    }
+
+    // Replaces a word with the value op gives for it, returning the old value, for the atomic
+    // instructions. op returns None to leave the word as it is
+    // Memory shared with other agents overrides this to keep them out between the read and the
+    // write, by default it is a read followed by a write
+    fn read_modify_write(&mut self, addr: u32, op: &mut dyn FnMut(u32) -> Option<u32>) -> u32 {
+        let old = self.read_width(addr, Width::Word);
+        if let Some(new) = op(old) {
+            self.write_width(addr, Width::Word, new);
+        }
+        old
+    }
+
+    // Reads a byte of an instruction
+    // Memory that treats instruction fetches differently from data reads overrides this
+    fn fetch(&mut self, addr: u32) -> u8 {
+        self.read(addr)
+    }
+
+    // Declares which bytes have side effects when read
+    fn read_effect(&self, _addr: u32) -> ReadEffect {
+        ReadEffect::Pure
+    }
+
+    // Reads without side effects on behalf of debuggers, tracing, and snapshots
+    // Bytes with side effects read as zero unless the device overrides this to show their value
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        if self.read_effect(addr).has_side_effects() {
+            0
+        } else {
+            self.read(addr)
+        }
+    }
+
+    // Number of bytes of physical memory, if known
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    // Takes the address of an access the memory could not complete since this was last called
+    // The cpu checks after every access and faults with a bus error
+    fn bus_error(&mut self) -> Option<u32> {
+        None
+    }
 }
 
 const SIMPLE_ADDRESS_SIZE: usize = 0x1000000;
 
+// What a SimpleAddress does with accesses past the end of its memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutOfRange {
+    // Wrap around to the start of memory
+    Mirror,
+
+    // Read as zero and ignore writes
+    Zero,
+
+    // Read as zero, ignore writes, and report a bus error
+    BusError,
+}
+
+#[derive(Clone)]
 pub struct SimpleAddress {
     memory: Vec<u8>,
+    out_of_range: OutOfRange,
+    bus_error: Option<u32>,
 }
 
 impl Default for SimpleAddress {
     fn default() -> SimpleAddress {
+        SimpleAddress::new(SIMPLE_ADDRESS_SIZE)
+    }
+}
+
+impl SimpleAddress {
+    // Panics if size is 0
+    pub fn new(size: usize) -> SimpleAddress {
+        assert!(size != 0, "memory must not be empty");
         SimpleAddress {
-            memory: vec![0; SIMPLE_ADDRESS_SIZE],
+            memory: vec![0; size],
+            out_of_range: OutOfRange::Zero,
+            bus_error: None,
+        }
+    }
+
+    pub fn out_of_range(mut self, out_of_range: OutOfRange) -> SimpleAddress {
+        self.out_of_range = out_of_range;
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.memory.len()
+    }
+
+    // Copies data into memory starting at addr, going through the out of range policy
+    pub fn load(&mut self, addr: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            self.write(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+
+    // Copies len bytes out of memory starting at addr, going through the out of range policy
+    pub fn dump(&mut self, addr: u32, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.read(addr.wrapping_add(i as u32))).collect()
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn contents_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    fn index(&mut self, addr: u64) -> Option<usize> {
+        let size = self.memory.len() as u64;
+        if addr < size {
+            Some(addr as usize)
+        } else {
+            match self.out_of_range {
+                OutOfRange::Mirror => Some((addr % size) as usize),
+                OutOfRange::Zero => None,
+                OutOfRange::BusError => {
+                    self.bus_error = Some(addr as u32);
+                    None
+                }
+            }
         }
     }
 }
 
 impl Address for SimpleAddress {
     fn read(&mut self, addr: u32) -> u8 {
-        if addr < 0x1000000 {
-            self.memory[addr as usize]
-        } else {
-            0
+        match self.index(addr as u64) {
+            Some(i) => self.memory[i],
+            None => 0,
         }
     }
 
     fn write(&mut self, addr: u32, data: u8) {
-        if addr < 0x1000000 {
-            self.memory[addr as usize] = data;
+        if let Some(i) = self.index(addr as u64) {
+            self.memory[i] = data;
+        }
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.memory.len() as u64)
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        let size = self.memory.len() as u64;
+        match self.out_of_range {
+            _ if (addr as u64) < size => self.memory[addr as usize],
+            OutOfRange::Mirror => self.memory[(addr as u64 % size) as usize],
+            OutOfRange::Zero | OutOfRange::BusError => 0,
         }
     }
+
+    fn bus_error(&mut self) -> Option<u32> {
+        self.bus_error.take()
+    }
 }
 
-pub struct Cpu<T>
+// N is the number of integer and float registers, 16 by default or up to 32
+// Registers above x15/f15 are encoded with the register extension prefix (0x3e)
+pub struct Cpu<T, const N: usize = 16>
 where
     T: Address,
 {
@@ -73,13 +311,16 @@ where
     // Program counter is x13
     // Stack base pointer is x14
     // Stack pointer is x15
-    xs: [u32; 16],
+    xs: [u32; N],
 
     // General purpose floating point registers
-    fs: [f32; 16],
+    fs: [f32; N],
+
+    // Vector registers, see vector.rs
+    vs: [vector::Vector; vector::VECTOR_REGISTERS],
 
     // Flags
-    //                      MRFAN PCVZQLLL
+    //                   ISMRFAN PCVZQLLL
     // 10987654 32109876 54321098 76543210
     // 33222222 22221111 111111
     // LLL      - Last interrupt
@@ -94,45 +335,178 @@ where
     // R        - user Ring (if enabled, certain features will be locked down until an interrupt
     //            occurs)
     // M        - Memory map enable
+    // S        - Shadow stack enable
+    // I        - nested Interrupts, see interrupts.rs
     flags: u32,
 
-    // Bits that are marked as 0 disable those interrupts from being added to the queue and being
-    // handled
+    // Bits that are marked as 0 disable those interrupts from being requested and being handled
     interrupt_mask: u8,
 
-    // Memory map register
-    memmap: u32,
+    // Memory map register (the root of the tables), address space id, and translation cache
+    mmu: mmu::Mmu,
+
+    // Instruction bytes fetched ahead of pc, see prefetch.rs
+    prefetch: prefetch::PrefetchBuffer,
+
+    // Optional record of the pages written, see dirty.rs
+    dirty: Option<dirty::DirtyPages>,
 
     // System ring stack pointer (saved from x15 when switching to the user ring)
     system_sp: u32,
 
-    // Queue of previously requested interrupts
-    interrupt_queue: VecDeque<u32>,
+    // Base address of the interrupt vector table
+    // Each entry is the 4 byte address of a handler. Vectors 0-31 are used by non-maskable
+    // interrupts (faults) and vectors 32 onwards by irqs
+    vector_base: u32,
+
+    // Virtual address of the last memory access that faulted
+    fault_address: u32,
+
+    // Non-maskable interrupt number of the last fault
+    fault_cause: u32,
+
+    // Fault raised by the last step, if any
+    last_fault: Option<InvalidMemoryAccess>,
+
+    // Whether the last step executed a pause hint
+    paused: bool,
+
+    // Set by the halt instruction until an interrupt is delivered
+    halted: bool,
+
+    // Shadow stack pointer, pointing at the last return address pushed
+    shadow_sp: u32,
+
+    // Use the original stack convention, see set_legacy_stack
+    legacy_stack: bool,
+
+    // Do float arithmetic in software, see softfloat.rs
+    soft_float: bool,
+
+    // Optional opcode groups that are decoded
+    features: isa::IsaFeatures,
+
+    // Opcodes that fault instead of executing, and the first bytes of the last one that did
+    traps: isa::OpcodeTraps,
+    trapped_instruction: u32,
+
+    // Set when a double fault could not be delivered, after which the cpu stops executing
+    shutdown: bool,
+
+    // Interrupt lines waiting to be handled, see interrupts.rs
+    interrupts: interrupts::PendingInterrupts,
+
+    // Number of steps executed
+    cycles: u64,
+
+    // Optional interrupt latency statistics
+    latency: Option<analysis::LatencyStats>,
+
+    // Address of the instruction currently being executed
+    instruction_pc: u32,
+
+    // Optional detection of reads from never written memory
+    uninit: Option<analysis::UninitChecker>,
+
+    // Optional taint tracking
+    taint: Option<analysis::TaintTracker>,
+
+    // Optional stack frame integrity checking
+    frames: Option<analysis::FrameChecker>,
+
+    // User analyses called on every data read and write
+    memory_hooks: Vec<Box<dyn analysis::MemoryHook>>,
+
+    // Optional history of privileged state changes
+    audit: Option<analysis::AuditTrail>,
+
+    // Optional call graph profiling
+    profile: Option<analysis::CallGraphProfile>,
+
+    // Optional count of executions of each instruction
+    histogram: Option<analysis::PcHistogram>,
+
+    // Services for the fwcall instruction
+    firmware: Option<Box<dyn firmware::Firmware>>,
 
     addressing: T,
 }
 
+// Architectural state of a cpu, independent of its memory
+#[derive(Clone, Debug)]
+pub struct CpuState<const N: usize = 16> {
+    pub xs: [u32; N],
+    pub fs: [f32; N],
+    pub vs: [vector::Vector; vector::VECTOR_REGISTERS],
+    pub flags: u32,
+    pub interrupt_mask: u8,
+    pub memmap: u32,
+    pub system_sp: u32,
+    pub vector_base: u32,
+    pub fault_address: u32,
+    pub fault_cause: u32,
+    pub asid: u32,
+    pub shadow_sp: u32,
+}
+
+// What a step did
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepOutcome {
+    // An instruction ran to completion, every instruction takes a single cycle
+    Executed { opcode: u8, cycles: u64 },
+
+    // An irq line was taken instead of executing an instruction
+    Interrupted(u32),
+
+    // The instruction faulted and the fault was delivered as a non-maskable interrupt
+    Faulted(InvalidMemoryAccess),
+
+    // The cpu is halted and waiting for an interrupt
+    Halted,
+
+    // The cpu is shut down after a fault while delivering a double fault
+    Shutdown,
+}
+
+// Interrupt numbers with this bit set are non-maskable interrupts
+pub const NMI_BIT: u32 = 0x80000000;
+
+// Non-maskable interrupt raised by the syscall instruction
+pub const SYSCALL: u32 = 0x1e;
+
+// Non-maskable interrupt raised when delivering another interrupt faults
+pub const DOUBLE_FAULT: u32 = 0x1f;
+
+static IRQ_VECTORS: u32 = 32;
+
 // Flags
-static F_INTERRUPT_ENABLE: u32 = 3;
-static F_ZERO: u32 = 4;
-static F_OVERFLOW: u32 = 5;
-static F_CARRY: u32 = 6;
-static F_PARITY: u32 = 7;
-static F_NEGATIVE: u32 = 8;
-static F_NAN: u32 = 9;
-static F_INFINITE: u32 = 10;
-static F_USER_RING: u32 = 11;
-static F_MEMMAP_ENABLE: u32 = 12;
+// Mask of the LLL bits rather than a bit number
+const F_LAST_INTERRUPT: u32 = 0b111;
+const F_INTERRUPT_ENABLE: u32 = 3;
+const F_ZERO: u32 = 4;
+const F_OVERFLOW: u32 = 5;
+const F_CARRY: u32 = 6;
+const F_PARITY: u32 = 7;
+const F_NEGATIVE: u32 = 8;
+const F_NAN: u32 = 9;
+const F_INFINITE: u32 = 10;
+const F_USER_RING: u32 = 11;
+const F_MEMMAP_ENABLE: u32 = 12;
+const F_SHADOW_STACK: u32 = 13;
+const F_NESTED_INTERRUPTS: u32 = 14;
 
 // Registers
-static R_INT: usize = 12;
-static R_PC: usize = 13;
-static R_BASE: usize = 14;
-static R_SP: usize = 15;
+pub const R_INT: usize = 12;
+pub const R_PC: usize = 13;
+pub const R_BASE: usize = 14;
+pub const R_SP: usize = 15;
+
+// Number of system registers, see privileged_move
+pub const SYSTEM_REGISTERS: usize = 19;
 
 macro_rules! clear_flags {
     ($self: ident, $($flags: ident),*) => {
-        $self.flags &= !($((1 << $flags))|*);
+        $self.flags &= !($(1 << $flags)|*);
     }
 }
 
@@ -141,49 +515,144 @@ where
     T: Address,
 {
     pub fn new(t: T) -> Cpu<T> {
+        Cpu::with_registers(t)
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn with_registers(t: T) -> Cpu<T, N> {
+        assert!(
+            (16..=32).contains(&N),
+            "register file must have between 16 and 32 registers"
+        );
+
         Cpu {
-            xs: [0; 16],
-            fs: [0.0; 16],
+            xs: [0; N],
+            fs: [0.0; N],
+            vs: [[0; 4]; vector::VECTOR_REGISTERS],
             flags: 0,
             interrupt_mask: 0xff,
-            memmap: 0,
+            mmu: mmu::Mmu::default(),
+            prefetch: prefetch::PrefetchBuffer::new(),
+            dirty: None,
             system_sp: 0,
-            interrupt_queue: VecDeque::new(),
+            vector_base: 0,
+            fault_address: 0,
+            fault_cause: 0,
+            last_fault: None,
+            paused: false,
+            halted: false,
+            shadow_sp: 0,
+            legacy_stack: false,
+            soft_float: false,
+            features: isa::IsaFeatures::ALL,
+            traps: isa::OpcodeTraps::default(),
+            trapped_instruction: 0,
+            shutdown: false,
+            interrupts: interrupts::PendingInterrupts::default(),
+            cycles: 0,
+            latency: None,
+            instruction_pc: 0,
+            uninit: None,
+            taint: None,
+            frames: None,
+            audit: None,
+            profile: None,
+            histogram: None,
+            firmware: None,
+            memory_hooks: vec![],
             addressing: t,
         }
     }
 
-    fn check_memory(&mut self, addr: u32, permissions: u8) -> Result<u32, InvalidMemoryAccess> {
-        if self.flags & (1 << F_MEMMAP_ENABLE) != 0 {
-            let table_addr = self.memmap;
-            let table_addr = self.addressing.read(table_addr + (addr >> 24)) as u32
-                | (self.addressing.read(table_addr + (addr >> 24) + 1) as u32) << 8
-                | (self.addressing.read(table_addr + (addr >> 24) + 2) as u32) << 16
-                | (self.addressing.read(table_addr + (addr >> 24) + 3) as u32) << 24;
-
-            if table_addr == 0 {
-                return Err(InvalidMemoryAccess::UsedFreePage);
-            }
+    pub fn state(&self) -> CpuState<N> {
+        CpuState {
+            xs: self.xs,
+            fs: self.fs,
+            vs: self.vs,
+            flags: self.flags,
+            interrupt_mask: self.interrupt_mask,
+            memmap: self.mmu.root(),
+            system_sp: self.system_sp,
+            vector_base: self.vector_base,
+            fault_address: self.fault_address,
+            fault_cause: self.fault_cause,
+            asid: self.mmu.asid(),
+            shadow_sp: self.shadow_sp,
+        }
+    }
 
-            let addr = (self.addressing.read(table_addr + (addr >> 16 & 0xff)) as u32
-                | (self.addressing.read(table_addr + (addr >> 16 & 0xff) + 1) as u32) << 8
-                | (self.addressing.read(table_addr + (addr >> 16 & 0xff) + 2) as u32) << 16
-                | (self.addressing.read(table_addr + (addr >> 16 & 0xff) + 3) as u32) << 24)
-                + (addr & 0xffff);
-            let (p, addr) = (((addr & 0xf0000000) >> 28) as u8, addr & 0x0fffffff);
+    // Restores the architectural state, leaving memory and pending interrupts alone
+    pub fn set_state(&mut self, state: &CpuState<N>) {
+        self.xs = state.xs;
+        self.fs = state.fs;
+        self.vs = state.vs;
+        self.flags = state.flags;
+        self.interrupt_mask = state.interrupt_mask;
+        self.mmu.set_root(state.memmap);
+        self.flush_translations();
+        self.system_sp = state.system_sp;
+        self.vector_base = state.vector_base;
+        self.fault_address = state.fault_address;
+        self.fault_cause = state.fault_cause;
+        self.mmu.set_asid(state.asid);
+        self.shadow_sp = state.shadow_sp;
+    }
 
-            if p & 0x08 == 0 {
-                Err(InvalidMemoryAccess::UsedFreePage)
-            } else if p & permissions != permissions {
-                Err(InvalidMemoryAccess::InvalidPermissions(p, permissions))
-            } else {
-                Ok(addr)
+    // The host may change anything in memory, including code and page tables
+    pub fn addressing(&mut self) -> &mut T {
+        self.flush_translations();
+        &mut self.addressing
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Counts cycles spent waiting on something outside the cpu, eg a memory bank that another bus
+    // master is using
+    pub fn stall(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    pub fn set_uninit_checker(&mut self, checker: Option<analysis::UninitChecker>) {
+        self.uninit = checker;
+    }
+
+    pub fn uninit_checker(&self) -> Option<&analysis::UninitChecker> {
+        self.uninit.as_ref()
+    }
+
+    fn check_memory(&mut self, addr: u32, permissions: u8) -> Result<u32, InvalidMemoryAccess> {
+        if self.flags & (1 << F_MEMMAP_ENABLE) != 0 {
+            let result = self.mmu.check(&mut self.addressing, addr, permissions);
+            if result.is_err() {
+                self.fault_address = addr;
             }
+            result
         } else {
             Ok(addr)
         }
     }
 
+    // Drops everything cached about the memory map and the code it maps
+    fn flush_translations(&mut self) {
+        self.mmu.flush();
+        self.prefetch.invalidate();
+    }
+
+    // Keeps the caches in step with a write to physical memory
+    fn on_phys_write(&mut self, addr: u32) {
+        self.mmu.on_write(addr);
+        self.prefetch.on_write(addr);
+        if let Some(dirty) = self.dirty.as_mut() {
+            dirty.on_write(addr);
+        }
+    }
+
     fn set_flag(&mut self, flag: u32, val: bool) {
         self.flags |= (val as u32) << flag;
     }
@@ -199,6 +668,9 @@ where
 
     fn set_user_ring(&mut self, val: bool) -> Result<(), InvalidMemoryAccess> {
         if !self.get_flag(F_USER_RING) {
+            if val {
+                self.system_sp = self.xs[R_SP];
+            }
             clear_flags!(self, F_USER_RING);
             self.set_flag(F_USER_RING, val);
             Ok(())
@@ -211,6 +683,7 @@ where
         if !self.get_flag(F_USER_RING) {
             clear_flags!(self, F_MEMMAP_ENABLE);
             self.set_flag(F_MEMMAP_ENABLE, val);
+            self.flush_translations();
             Ok(())
         } else {
             Err(InvalidMemoryAccess::UnprivilegedOpcode)
@@ -232,40 +705,139 @@ where
             | (self.exec()? as u32) << 8
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
+        self.call_to(addr)
+    }
 
-        let data = self.xs[R_BASE];
-        for i in (0..4).rev() {
-            self.write(self.xs[R_SP], (data >> (i * 8)) as u8)?;
-            self.xs[R_SP] -= 1;
-        }
-
-        let data = self.xs[R_PC];
-        for i in (0..4).rev() {
-            self.write(self.xs[R_SP], (data >> (i * 8)) as u8)?;
-            self.xs[R_SP] -= 1;
+    // Saves the return frame and jumps to addr, for both call and callr
+    fn call_to(&mut self, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        let base = self.xs[R_BASE];
+        self.push_word(base)?;
+        self.push_word(self.xs[R_PC])?;
+        if self.get_flag(F_SHADOW_STACK) {
+            self.shadow_push(self.xs[R_PC])?;
         }
 
         self.xs[R_BASE] = self.xs[R_SP];
+        let frame = self.stack_top(self.xs[R_BASE]);
+        if let Some(frames) = self.frames.as_mut() {
+            frames.on_call(self.instruction_pc, self.xs[R_BASE], frame, self.xs[R_PC], base);
+        }
+        if let Some(profile) = self.profile.as_mut() {
+            profile.on_call(addr);
+        }
         self.xs[R_PC] = addr;
         Ok(())
     }
 
-    fn ret(&mut self) -> Result<(), InvalidMemoryAccess> {
-        self.xs[R_PC] = 0;
+    // The shadow stack only needs to be readable, so mapping it read only protects it from
+    // ordinary stores while calls can still push to it
+    fn shadow_push(&mut self, data: u32) -> Result<(), InvalidMemoryAccess> {
+        self.shadow_sp = self.shadow_sp.wrapping_sub(4);
         for i in 0..4 {
-            self.xs[R_BASE] += 1;
-            self.xs[R_PC] |= (self.read(self.xs[R_BASE])? as u32) << (8 * i);
+            let addr = self.check_memory(self.shadow_sp.wrapping_add(i), READ)?;
+            self.addressing.write(addr, (data >> (8 * i)) as u8);
+            self.on_phys_write(addr);
         }
+        Ok(())
+    }
 
+    fn shadow_pop(&mut self) -> Result<u32, InvalidMemoryAccess> {
         let mut data = 0;
         for i in 0..4 {
-            self.xs[R_BASE] += 1;
-            data |= (self.read(self.xs[R_BASE])? as u32) << (8 * i);
+            let addr = self.check_memory(self.shadow_sp.wrapping_add(i), READ)?;
+            data |= (self.addressing.read(addr) as u32) << (8 * i);
+        }
+        self.shadow_sp = self.shadow_sp.wrapping_add(4);
+        Ok(data)
+    }
+
+    // Stacks are full descending: the stack pointer points at the last word pushed, and pushing
+    // decrements it by 4 before writing the word
+    // The legacy convention writes each byte before decrementing instead, leaving the stack
+    // pointer one byte below the last word pushed
+    pub fn set_legacy_stack(&mut self, legacy: bool) {
+        self.legacy_stack = legacy;
+    }
+
+    // Lowest address of the words pushed last, given the stack pointer after pushing them
+    pub(crate) fn stack_top(&self, sp: u32) -> u32 {
+        if self.legacy_stack {
+            sp.wrapping_add(1)
+        } else {
+            sp
+        }
+    }
+
+    fn push_word(&mut self, data: u32) -> Result<(), InvalidMemoryAccess> {
+        if self.legacy_stack {
+            for i in (0..4).rev() {
+                self.write(self.xs[R_SP], (data >> (i * 8)) as u8)?;
+                self.xs[R_SP] = self.xs[R_SP].wrapping_sub(1);
+            }
+        } else {
+            let sp = self.xs[R_SP].wrapping_sub(4);
+            self.write_sized(sp, Width::Word, data)?;
+            self.xs[R_SP] = sp;
+        }
+        Ok(())
+    }
+
+    fn pop_word(&mut self) -> Result<u32, InvalidMemoryAccess> {
+        let data = self.read_sized(self.stack_top(self.xs[R_SP]), Width::Word)?;
+        self.xs[R_SP] = self.xs[R_SP].wrapping_add(4);
+        Ok(data)
+    }
+
+    // Pops a trap frame and restores the interrupted state
+    // Returning to the user ring saves the system stack pointer for the next interrupt
+    fn iret(&mut self) -> Result<(), InvalidMemoryAccess> {
+        if self.get_flag(F_USER_RING) {
+            return Err(InvalidMemoryAccess::UnprivilegedOpcode);
         }
 
-        self.xs[R_SP] = self.xs[R_BASE];
+        let mut words = [0; 5];
+        for word in words.iter_mut() {
+            *word = self.pop_word()?;
+        }
+        let [pc, int, flags, base, sp] = words;
+
+        // x12 still holds the number of the interrupt being returned from
+        let current = self.xs[R_INT];
+        if (current as usize) < interrupts::IRQ_LINES {
+            self.interrupts.end(current as usize);
+        }
+
+        if flags & (1 << F_USER_RING) != 0 {
+            self.system_sp = self.xs[R_SP];
+        }
+        self.xs[R_PC] = pc;
+        self.xs[R_INT] = int;
+        self.flags = flags;
+        self.xs[R_BASE] = base;
+        self.xs[R_SP] = sp;
+        Ok(())
+    }
+
+    fn ret(&mut self) -> Result<(), InvalidMemoryAccess> {
+        let frame = self.xs[R_BASE];
+        self.xs[R_SP] = frame;
+        self.xs[R_PC] = self.pop_word()?;
+        let data = self.pop_word()?;
         self.xs[R_BASE] = data;
 
+        if let Some(frames) = self.frames.as_mut() {
+            if frames.on_ret(self.instruction_pc, frame, self.xs[R_PC], data) {
+                return Err(InvalidMemoryAccess::CorruptedFrame);
+            }
+        }
+        if let Some(profile) = self.profile.as_mut() {
+            profile.on_ret();
+        }
+
+        if self.get_flag(F_SHADOW_STACK) && self.shadow_pop()? != self.xs[R_PC] {
+            return Err(InvalidMemoryAccess::ShadowStackMismatch);
+        }
+
         Ok(())
     }
 
@@ -291,6 +863,70 @@ where
         Ok(())
     }
 
+    // Branches on one of the flags the absolute branches test, to an offset from the next
+    // instruction
+    // 0-7 branch when zero, overflow, carry, negative, parity, nan, infinite, or memmap enable is
+    // set, and 8-15 when it is clear
+    fn branch_relative(&mut self, condition: u8) -> Result<(), InvalidMemoryAccess> {
+        let offset = (self.exec()? as u32)
+            | (self.exec()? as u32) << 8
+            | (self.exec()? as u32) << 16
+            | (self.exec()? as u32) << 24;
+        let flag = [
+            F_ZERO,
+            F_OVERFLOW,
+            F_CARRY,
+            F_NEGATIVE,
+            F_PARITY,
+            F_NAN,
+            F_INFINITE,
+            F_MEMMAP_ENABLE,
+        ][condition as usize & 7];
+        if self.get_flag(flag) == (condition & 8 == 0) {
+            self.xs[R_PC] = self.xs[R_PC].wrapping_add(offset);
+        }
+        Ok(())
+    }
+
+    // Calls to an offset from the next instruction, or jumps there without a return frame
+    fn call_relative(&mut self, call: bool) -> Result<(), InvalidMemoryAccess> {
+        let offset = (self.exec()? as u32)
+            | (self.exec()? as u32) << 8
+            | (self.exec()? as u32) << 16
+            | (self.exec()? as u32) << 24;
+        let addr = self.xs[R_PC].wrapping_add(offset);
+        if call {
+            self.call_to(addr)
+        } else {
+            self.xs[R_PC] = addr;
+            Ok(())
+        }
+    }
+
+    // Branches after a cmp, where carry means there was no borrow
+    // 0 - less, 1 - less or equal, 2 - greater, 3 - greater or equal (signed)
+    // 4 - lower, 5 - higher or same (unsigned)
+    fn branch_compare(&mut self, condition: u8) -> Result<(), InvalidMemoryAccess> {
+        let addr = (self.exec()? as u32)
+            | (self.exec()? as u32) << 8
+            | (self.exec()? as u32) << 16
+            | (self.exec()? as u32) << 24;
+        let less = self.get_flag(F_NEGATIVE) != self.get_flag(F_OVERFLOW);
+        let zero = self.get_flag(F_ZERO);
+        let taken = match condition {
+            0 => less,
+            1 => less || zero,
+            2 => !less && !zero,
+            3 => !less,
+            4 => !self.get_flag(F_CARRY),
+            _ => self.get_flag(F_CARRY),
+        };
+        if taken {
+            self.xs[R_PC] = addr;
+        }
+        Ok(())
+    }
+
     fn load_lit_int(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
         let data = (self.exec()? as u32)
             | (self.exec()? as u32) << 8
@@ -317,10 +953,7 @@ where
             | (self.exec()? as u32) << 8
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+        let data = self.read_sized(addr, Width::Word)?;
         self.xs[x0] = data;
         self.update_flags_int(data);
         Ok(())
@@ -331,29 +964,129 @@ where
             | (self.exec()? as u32) << 8
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+        let data = self.read_sized(addr, Width::Word)?;
         let data = f32::from_bits(data);
         self.fs[f0] = data;
         self.update_flags_float(data);
         Ok(())
     }
 
-    fn iadd(&mut self, x0: usize, x1: usize) {
-        let res = self.xs[x0] as u64 + self.xs[x1] as u64 + self.get_flag(F_CARRY) as u64;
+    // Adds with a carry in, setting every integer flag
+    fn add_with_flags(&mut self, a: u32, b: u32, carry: bool) -> u32 {
+        let res = a as u64 + b as u64 + carry as u64;
         clear_flags!(self, F_ZERO, F_OVERFLOW, F_CARRY, F_NEGATIVE, F_PARITY);
         self.set_flag(F_ZERO, res as u32 == 0);
         self.set_flag(F_NEGATIVE, res & 0x80000000 != 0);
         self.set_flag(F_CARRY, res & 0x100000000 != 0);
         self.set_flag(
             F_OVERFLOW,
-            self.xs[x0] & 0x80000000 == self.xs[x1] & 0x80000000
-                && self.xs[x0] & 0x80000000 != res as u32 & 0x80000000,
+            a & 0x80000000 == b & 0x80000000 && a & 0x80000000 != res as u32 & 0x80000000,
         );
         self.set_flag(F_PARITY, res & 1 != 0);
-        self.xs[x0] = res as u32;
+        res as u32
+    }
+
+    fn iadd(&mut self, x0: usize, x1: usize) {
+        self.xs[x0] = self.add_with_flags(self.xs[x0], self.xs[x1], self.get_flag(F_CARRY));
+    }
+
+    // Sets the flags of x0 - x1 without a borrow, leaving both registers alone
+    // Carry is set when there is no borrow, ie x0 >= x1 unsigned
+    fn cmp(&mut self, x0: usize, x1: usize) {
+        self.add_with_flags(self.xs[x0], !self.xs[x1], true);
+    }
+
+    fn not(&mut self, x0: usize, x1: usize) {
+        self.xs[x0] = !self.xs[x1];
+        self.update_flags_int(self.xs[x0]);
+    }
+
+    // 0 - x1, so carry is only set for 0 and overflow only for INT_MIN
+    fn neg(&mut self, x0: usize, x1: usize) {
+        self.xs[x0] = self.add_with_flags(0, !self.xs[x1], true);
+    }
+
+    // Flips the sign bit, NaNs included
+    fn fneg(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = f32::from_bits(self.fs[f1].to_bits() ^ 0x80000000);
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    // Negative values other than -0 have no root and give nan
+    fn fsqrt(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = if self.soft_float {
+            f32::from_bits(softfloat::sqrt(self.fs[f1].to_bits()))
+        } else {
+            self.fs[f1].sqrt()
+        };
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    fn fabs(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = f32::from_bits(self.fs[f1].to_bits() & 0x7fffffff);
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    // Rounds to an integral value
+    // 0 - to nearest with ties to even, 1 - towards zero, 2 - down, 3 - up
+    fn round_float(x: f32, mode: u8) -> f32 {
+        match mode {
+            0 => x.round_ties_even(),
+            1 => x.trunc(),
+            2 => x.floor(),
+            _ => x.ceil(),
+        }
+    }
+
+    fn fround(&mut self, f0: usize, f1: usize, mode: u8) {
+        self.fs[f0] = Self::round_float(self.fs[f1], mode);
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    // Unlike ftoi, rounds with the mode given and sets nan for nan, which converts to 0, and
+    // overflow for values out of range, which saturate
+    fn fcvt(&mut self, x0: usize, f1: usize, mode: u8) {
+        let x = Self::round_float(self.fs[f1], mode);
+        let res = x as i32 as u32;
+        clear_flags!(self, F_OVERFLOW, F_NAN);
+        self.update_flags_int(res);
+        self.set_flag(
+            F_OVERFLOW,
+            !x.is_nan() && !(-2147483648.0..2147483648.0).contains(&x),
+        );
+        self.set_flag(F_NAN, x.is_nan());
+        self.xs[x0] = res;
+    }
+
+    // A number is chosen over nan, and -0 is less than +0
+    fn fmin_max(&mut self, f0: usize, f1: usize, max: bool) {
+        let (a, b) = (self.fs[f0], self.fs[f1]);
+        self.fs[f0] = if a.is_nan() {
+            b
+        } else if b.is_nan() {
+            a
+        } else if a == b && max {
+            f32::from_bits(a.to_bits() & b.to_bits())
+        } else if a == b {
+            f32::from_bits(a.to_bits() | b.to_bits())
+        } else if (a < b) != max {
+            a
+        } else {
+            b
+        };
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    // Zero when equal, negative when f0 is less, and nan when the two are unordered. Overflow is
+    // cleared and carry set when f0 is not less, like cmp, so the signed and unsigned branches
+    // both see the ordering
+    fn fcmp(&mut self, f0: usize, f1: usize) {
+        let (a, b) = (self.fs[f0], self.fs[f1]);
+        clear_flags!(self, F_ZERO, F_NEGATIVE, F_NAN, F_INFINITE, F_OVERFLOW, F_CARRY);
+        self.set_flag(F_ZERO, a == b);
+        self.set_flag(F_NEGATIVE, a < b);
+        self.set_flag(F_CARRY, a.partial_cmp(&b) != Some(std::cmp::Ordering::Less));
+        self.set_flag(F_NAN, a.is_nan() || b.is_nan());
     }
 
     fn isub(&mut self, x0: usize, x1: usize) {
@@ -369,19 +1102,83 @@ where
         self.set_flag(F_PARITY, x & 1 != 0);
     }
 
+    // Full product of two registers
+    // Carry and overflow are set when the high word is not zero, ie the product does not fit in
+    // the low word alone
+    fn multiply(&mut self, x0: usize, x1: usize) -> u64 {
+        let res = self.xs[x0] as u64 * self.xs[x1] as u64;
+        clear_flags!(self, F_OVERFLOW, F_CARRY);
+        self.update_flags_int(res as u32);
+        self.set_flag(F_OVERFLOW, res >> 32 != 0);
+        self.set_flag(F_CARRY, res >> 32 != 0);
+        res
+    }
+
     fn imul(&mut self, x0: usize, x1: usize) {
-        self.xs[x0] *= self.xs[x1];
-        self.update_flags_int(self.xs[x0]);
+        self.xs[x0] = self.multiply(x0, x1) as u32;
+    }
+
+    fn imul_wide(&mut self, x0: usize, x1: usize) {
+        let res = self.multiply(x0, x1);
+        self.xs[x0] = res as u32;
+        self.xs[x1] = (res >> 32) as u32;
     }
 
-    fn idiv(&mut self, x0: usize, x1: usize) {
+    fn idiv(&mut self, x0: usize, x1: usize) -> Result<(), InvalidMemoryAccess> {
+        if self.xs[x1] == 0 {
+            return Err(InvalidMemoryAccess::DivideByZero);
+        }
+
         self.xs[x0] /= self.xs[x1];
         self.update_flags_int(self.xs[x0]);
+        Ok(())
     }
 
-    fn imod(&mut self, x0: usize, x1: usize) {
+    fn imod(&mut self, x0: usize, x1: usize) -> Result<(), InvalidMemoryAccess> {
+        if self.xs[x1] == 0 {
+            return Err(InvalidMemoryAccess::DivideByZero);
+        }
+
         self.xs[x0] %= self.xs[x1];
         self.update_flags_int(self.xs[x0]);
+        Ok(())
+    }
+
+    // Signed arithmetic sets overflow (and carry, as for imul) when the result does not fit
+    fn update_flags_signed(&mut self, res: i64) {
+        clear_flags!(self, F_OVERFLOW, F_CARRY);
+        self.update_flags_int(res as u32);
+        self.set_flag(F_OVERFLOW, res != res as i32 as i64);
+        self.set_flag(F_CARRY, res != res as i32 as i64);
+    }
+
+    fn imul_signed(&mut self, x0: usize, x1: usize) {
+        let res = self.xs[x0] as i32 as i64 * self.xs[x1] as i32 as i64;
+        self.update_flags_signed(res);
+        self.xs[x0] = res as u32;
+    }
+
+    // The only quotient that does not fit is INT_MIN / -1, which wraps to INT_MIN
+    fn idiv_signed(&mut self, x0: usize, x1: usize) -> Result<(), InvalidMemoryAccess> {
+        if self.xs[x1] == 0 {
+            return Err(InvalidMemoryAccess::DivideByZero);
+        }
+
+        let res = self.xs[x0] as i32 as i64 / self.xs[x1] as i32 as i64;
+        self.update_flags_signed(res);
+        self.xs[x0] = res as u32;
+        Ok(())
+    }
+
+    // The remainder takes the sign of the dividend
+    fn imod_signed(&mut self, x0: usize, x1: usize) -> Result<(), InvalidMemoryAccess> {
+        if self.xs[x1] == 0 {
+            return Err(InvalidMemoryAccess::DivideByZero);
+        }
+
+        self.xs[x0] = (self.xs[x0] as i32).wrapping_rem(self.xs[x1] as i32) as u32;
+        self.update_flags_int(self.xs[x0]);
+        Ok(())
     }
 
     fn update_flags_float(&mut self, x: f32) {
@@ -392,24 +1189,106 @@ where
         self.set_flag(F_INFINITE, x.is_infinite());
     }
 
-    fn fadd(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] += self.fs[f1];
+    // Applies either the host or the software version of an operation
+    fn float_op(
+        &mut self,
+        f0: usize,
+        f1: usize,
+        host: fn(f32, f32) -> f32,
+        soft: fn(u32, u32) -> u32,
+    ) {
+        self.fs[f0] = if self.soft_float {
+            f32::from_bits(soft(self.fs[f0].to_bits(), self.fs[f1].to_bits()))
+        } else {
+            host(self.fs[f0], self.fs[f1])
+        };
         self.update_flags_float(self.fs[f0]);
     }
 
+    fn fadd(&mut self, f0: usize, f1: usize) {
+        self.float_op(f0, f1, |a, b| a + b, softfloat::add);
+    }
+
     fn fsub(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] -= self.fs[f1];
-        self.update_flags_float(self.fs[f0]);
+        self.float_op(f0, f1, |a, b| a - b, softfloat::sub);
     }
 
     fn fmul(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] *= self.fs[f1];
-        self.update_flags_float(self.fs[f0]);
+        self.float_op(f0, f1, |a, b| a * b, softfloat::mul);
     }
 
     fn fdiv(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] /= self.fs[f1];
-        self.update_flags_float(self.fs[f0]);
+        self.float_op(f0, f1, |a, b| a / b, softfloat::div);
+    }
+
+    // Rotates x0 left by x1 bits, or right with right set
+    // Carry is the last bit rotated around, and is left alone when the count is a multiple of 32
+    fn rotate(&mut self, x0: usize, x1: usize, right: bool) {
+        let count = self.xs[x1] % 32;
+        let res = if right {
+            self.xs[x0].rotate_right(count)
+        } else {
+            self.xs[x0].rotate_left(count)
+        };
+        if count != 0 {
+            clear_flags!(self, F_CARRY);
+            self.set_flag(F_CARRY, res & if right { 0x80000000 } else { 1 } != 0);
+        }
+        self.update_flags_int(res);
+        self.xs[x0] = res;
+    }
+
+    // Rotates x0 and carry as a 33 bit value, carry above bit 31
+    fn rotate_carry(&mut self, x0: usize, x1: usize, right: bool) {
+        let count = self.xs[x1] as u64 % 33;
+        let count = if right { (33 - count) % 33 } else { count };
+        let value = (self.get_flag(F_CARRY) as u64) << 32 | self.xs[x0] as u64;
+        let res = if count == 0 {
+            value
+        } else {
+            (value << count | value >> (33 - count)) & 0x1_ffff_ffff
+        };
+        clear_flags!(self, F_CARRY);
+        self.set_flag(F_CARRY, res >> 32 != 0);
+        self.update_flags_int(res as u32);
+        self.xs[x0] = res as u32;
+    }
+
+    // Applies an operation with the literal following the register byte
+    // Unlike iadd and isub there is no carry in or borrow, and shifts set carry to the last bit
+    // shifted out
+    fn alu_immediate(&mut self, x0: usize, opcode: u8) -> Result<(), InvalidMemoryAccess> {
+        let imm = (self.exec()? as u32)
+            | (self.exec()? as u32) << 8
+            | (self.exec()? as u32) << 16
+            | (self.exec()? as u32) << 24;
+        let x = self.xs[x0];
+        let (res, carry) = match opcode & 0x3f {
+            0x29 => (self.add_with_flags(x, imm, false), None),
+            0x2a => (self.add_with_flags(x, !imm, true), None),
+            0x2b => (x & imm, None),
+            0x2c => (x | imm, None),
+            0x2d => (x ^ imm, None),
+            0x2e => match imm {
+                0 => (x, None),
+                1..=32 => (((x as u64) << imm) as u32, Some(x >> (32 - imm) & 1 != 0)),
+                _ => (0, Some(false)),
+            },
+            _ => match imm {
+                0 => (x, None),
+                1..=32 => ((x as u64 >> imm) as u32, Some(x >> (imm - 1) & 1 != 0)),
+                _ => (0, Some(false)),
+            },
+        };
+        if !matches!(opcode & 0x3f, 0x29 | 0x2a) {
+            self.update_flags_int(res);
+        }
+        if let Some(carry) = carry {
+            clear_flags!(self, F_CARRY);
+            self.set_flag(F_CARRY, carry);
+        }
+        self.xs[x0] = res;
+        Ok(())
     }
 
     fn bsl(&mut self, x0: usize, x1: usize) {
@@ -472,12 +1351,20 @@ where
     }
 
     fn move_int_float(&mut self, x0: usize, f1: usize) {
-        self.xs[x0] = (self.fs[f1] as i32) as u32;
+        self.xs[x0] = if self.soft_float {
+            softfloat::to_i32(self.fs[f1].to_bits()) as u32
+        } else {
+            (self.fs[f1] as i32) as u32
+        };
         self.update_flags_int(self.xs[x0]);
     }
 
     fn move_float_int(&mut self, f0: usize, x1: usize) {
-        self.fs[f0] = (self.xs[x1] as i32) as f32;
+        self.fs[f0] = if self.soft_float {
+            f32::from_bits(softfloat::from_i32(self.xs[x1] as i32))
+        } else {
+            (self.xs[x1] as i32) as f32
+        };
         self.update_flags_float(self.fs[f0]);
     }
 
@@ -493,21 +1380,56 @@ where
 
     fn load_indirect_int(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+        let data = self.read_sized(addr, Width::Word)?;
+        self.xs[x0] = data;
+        self.update_flags_int(data);
+        Ok(())
+    }
+
+    // Loads a byte or halfword, extending it with its top bit when signed
+    // 0-3 read from the literal address and 4-7 from the address in addr, with the low two bits
+    // picking a byte, signed byte, halfword, or signed halfword
+    fn load_narrow(
+        &mut self,
+        x0: usize,
+        addr: usize,
+        opcode: u8,
+    ) -> Result<(), InvalidMemoryAccess> {
+        let addr = if opcode & 4 == 0 {
+            (self.exec()? as u32)
+                | (self.exec()? as u32) << 8
+                | (self.exec()? as u32) << 16
+                | (self.exec()? as u32) << 24
+        } else {
+            self.xs[addr]
+        };
+        let width = if opcode & 2 == 0 { Width::Byte } else { Width::Half };
+        let data = self.read_sized(addr, width)?;
+        let data = match opcode & 3 {
+            1 => data as u8 as i8 as u32,
+            3 => data as u16 as i16 as u32,
+            _ => data,
+        };
         self.xs[x0] = data;
         self.update_flags_int(data);
         Ok(())
     }
 
+    fn extend(&mut self, x0: usize, x1: usize, opcode: u8) {
+        let x = self.xs[x1];
+        let res = match opcode {
+            0x3a => x as u8 as i8 as u32,
+            0x3b => x as u16 as i16 as u32,
+            0x3c => x as u8 as u32,
+            _ => x as u16 as u32,
+        };
+        self.update_flags_int(res);
+        self.xs[x0] = res;
+    }
+
     fn load_indirect_float(&mut self, f0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+        let data = self.read_sized(addr, Width::Word)?;
         let data = f32::from_bits(data);
         self.fs[f0] = data;
         self.update_flags_float(data);
@@ -516,30 +1438,23 @@ where
 
     fn store_indirect_int(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)?;
-        self.write(addr + 2, (self.xs[x0] >> 16) as u8)?;
-        self.write(addr + 3, (self.xs[x0] >> 24) as u8)
+        self.write_sized(addr, Width::Word, self.xs[x0])
     }
 
     fn store_indirect_short(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)
+        self.write_sized(addr, Width::Half, self.xs[x0])
     }
 
     fn store_indirect_byte(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        self.write(addr, self.xs[x0] as u8)
+        self.write_sized(addr, Width::Byte, self.xs[x0])
     }
 
     fn store_indirect_float(&mut self, f0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
         let data = self.fs[f0].to_bits();
-        self.write(addr, data as u8)?;
-        self.write(addr + 1, (data >> 8) as u8)?;
-        self.write(addr + 2, (data >> 16) as u8)?;
-        self.write(addr + 3, (data >> 24) as u8)
+        self.write_sized(addr, Width::Word, data)
     }
 
     fn store_int(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
@@ -547,10 +1462,7 @@ where
             | (self.exec()? as u32) << 8
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)?;
-        self.write(addr + 2, (self.xs[x0] >> 16) as u8)?;
-        self.write(addr + 3, (self.xs[x0] >> 24) as u8)
+        self.write_sized(addr, Width::Word, self.xs[x0])
     }
 
     fn store_short(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
@@ -558,8 +1470,7 @@ where
             | (self.exec()? as u32) << 8
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)
+        self.write_sized(addr, Width::Half, self.xs[x0])
     }
 
     fn store_byte(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
@@ -567,7 +1478,7 @@ where
             | (self.exec()? as u32) << 8
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
-        self.write(addr, self.xs[x0] as u8)
+        self.write_sized(addr, Width::Byte, self.xs[x0])
     }
 
     fn store_float(&mut self, f0: usize) -> Result<(), InvalidMemoryAccess> {
@@ -576,21 +1487,56 @@ where
             | (self.exec()? as u32) << 16
             | (self.exec()? as u32) << 24;
         let data = self.fs[f0].to_bits();
-        self.write(addr, data as u8)?;
-        self.write(addr + 1, (data >> 8) as u8)?;
-        self.write(addr + 2, (data >> 16) as u8)?;
-        self.write(addr + 3, (data >> 24) as u8)
-    }
-
+        self.write_sized(addr, Width::Word, data)
+    }
+
+    // System registers
+    // 0 - flags
+    // 1 - memory map
+    // 2 - interrupt mask
+    // 3 - system stack pointer
+    // 4 - interrupt vector base
+    // 5 - fault address (virtual address of the last memory access that faulted)
+    // 6 - fault cause (non-maskable interrupt number of the last fault)
+    // 7 - cycle counter, low word (read only)
+    // 8 - cycle counter, high word (read only)
+    // 9 - address space id
+    // 10 - shadow stack pointer
+    // 11 - enabled ISA features (read only)
+    // 12 - ISA version (read only)
+    // 13 - opcode trap control (write only), 0x1oo traps opcode o and 0x0oo stops trapping it
+    // 14 - trapped ISA feature groups
+    // 15 - first bytes of the last trapped instruction (read only), see isa.rs
+    // 16 - irq lines in service, a bit per line (read only)
+    // 17 - irq line priority levels, four bits per line with line 0 in the lowest bits
+    // 18 - irq priority threshold, lines are only taken if their level is below it
+    // The indices past 15 need the register extension prefix, whatever the size of the register
+    // file, and moves naming one past the last fault with an invalid register
     fn privileged_move(&mut self, x0: usize, p: usize) -> Result<(), InvalidMemoryAccess> {
-        if p as u32 & F_USER_RING != 0 {
+        if self.get_flag(F_USER_RING) {
             return Err(InvalidMemoryAccess::UnprivilegedOpcode);
         }
 
         match p {
-            0 => self.flags = self.xs[x0],
-            1 => self.memmap = self.xs[x0],
+            0 => {
+                self.flags = self.xs[x0];
+                self.flush_translations();
+            }
+            1 => {
+                self.mmu.set_root(self.xs[x0]);
+                self.flush_translations();
+            }
             2 => self.interrupt_mask = self.xs[x0] as u8,
+            3 => self.system_sp = self.xs[x0],
+            4 => self.vector_base = self.xs[x0],
+            5 => self.fault_address = self.xs[x0],
+            6 => self.fault_cause = self.xs[x0],
+            9 => self.mmu.set_asid(self.xs[x0]),
+            10 => self.shadow_sp = self.xs[x0],
+            13 => self.traps.set_opcode(self.xs[x0] as u8, self.xs[x0] & 0x100 != 0),
+            14 => self.traps.set_groups(isa::IsaFeatures::from_bits(self.xs[x0])),
+            17 => self.interrupts.set_priorities(self.xs[x0]),
+            18 => self.interrupts.set_threshold(self.xs[x0].min(0xff) as u8),
 
             _ => ()
         }
@@ -601,36 +1547,292 @@ where
     fn unprivileged_move(&mut self, p: usize, x0: usize) {
         match p {
             0 => self.xs[x0] = self.flags,
-            1 => self.xs[x0] = self.memmap,
+            1 => self.xs[x0] = self.mmu.root(),
             2 => self.xs[x0] = self.interrupt_mask as u32,
+            3 => self.xs[x0] = self.system_sp,
+            4 => self.xs[x0] = self.vector_base,
+            5 => self.xs[x0] = self.fault_address,
+            6 => self.xs[x0] = self.fault_cause,
+            7 => self.xs[x0] = self.cycles as u32,
+            8 => self.xs[x0] = (self.cycles >> 32) as u32,
+            9 => self.xs[x0] = self.mmu.asid(),
+            10 => self.xs[x0] = self.shadow_sp,
+            11 => self.xs[x0] = self.features.bits(),
+            12 => self.xs[x0] = isa::ISA_VERSION,
+            14 => self.xs[x0] = self.traps.groups().bits(),
+            15 => self.xs[x0] = self.trapped_instruction,
+            16 => self.xs[x0] = self.interrupts.in_service() as u32,
+            17 => self.xs[x0] = self.interrupts.priorities(),
+            18 => self.xs[x0] = self.interrupts.threshold() as u32,
 
             _ => ()
         }
     }
 
     fn exec(&mut self) -> Result<u8, InvalidMemoryAccess> {
-        let addr = self.check_memory(self.xs[R_PC], EXEC)?;
-        let res = self.addressing.read(addr);
-        self.xs[R_PC] += 1;
+        let pc = self.xs[R_PC];
+        let res = match self.prefetch.get(pc) {
+            Some(res) => res,
+            None => {
+                let addr = self.check_memory(pc, EXEC)?;
+                match self.fill_prefetch(pc, addr) {
+                    Some(res) => res,
+                    None => {
+                        let res = self.addressing.fetch(addr);
+                        self.check_bus(pc)?;
+                        res
+                    }
+                }
+            }
+        };
+        self.xs[R_PC] = pc.wrapping_add(1);
         Ok(res)
     }
 
-    fn read(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess> {
-        let addr = self.check_memory(addr, READ)?;
-        Ok(self.addressing.read(addr))
+    fn write(&mut self, virt: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
+        self.write_sized(virt, Width::Byte, data as u32)
     }
 
-    fn write(&mut self, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
-        let addr = self.check_memory(addr, WRITE)?;
-        self.addressing.write(addr, data);
+    // Translates every byte of an access, returning the physical addresses and whether they are
+    // consecutive so the memory can see a single access
+    fn translate_sized(
+        &mut self,
+        virt: u32,
+        width: Width,
+        permissions: u8,
+    ) -> Result<([u32; 4], bool), InvalidMemoryAccess> {
+        let mut phys = [0; 4];
+        for i in 0..width.bytes() {
+            phys[i as usize] = self.check_memory(virt.wrapping_add(i), permissions)?;
+        }
+        let contiguous = (1..width.bytes()).all(|i| phys[i as usize] == phys[0].wrapping_add(i));
+        Ok((phys, contiguous))
+    }
+
+    // Little endian data read
+    fn read_sized(&mut self, virt: u32, width: Width) -> Result<u32, InvalidMemoryAccess> {
+        let (phys, contiguous) = self.translate_sized(virt, width, READ)?;
+        let phys = &phys[..width.bytes() as usize];
+        for &addr in phys.iter() {
+            if let Some(checker) = self.uninit.as_mut() {
+                if checker.check_read(self.instruction_pc, addr) {
+                    return Err(InvalidMemoryAccess::UninitializedRead(addr));
+                }
+            }
+            if let Some(taint) = self.taint.as_mut() {
+                taint.on_read(addr);
+            }
+        }
+
+        let data = if contiguous {
+            self.addressing.read_width(phys[0], width)
+        } else {
+            let memory = &mut self.addressing;
+            phys.iter()
+                .enumerate()
+                .fold(0, |acc, (i, &addr)| acc | (memory.read(addr) as u32) << (8 * i))
+        };
+        self.check_bus(virt)?;
+
+        for (i, &addr) in phys.iter().enumerate() {
+            for hook in self.memory_hooks.iter_mut() {
+                hook.read(self.instruction_pc, addr, (data >> (8 * i)) as u8)?;
+            }
+        }
+        Ok(data)
+    }
+
+    // Little endian data write
+    // Nothing is written unless every byte can be
+    fn write_sized(
+        &mut self,
+        virt: u32,
+        width: Width,
+        data: u32,
+    ) -> Result<(), InvalidMemoryAccess> {
+        if let Some(frames) = self.frames.as_mut() {
+            for i in 0..width.bytes() {
+                frames.on_write(self.instruction_pc, virt.wrapping_add(i));
+            }
+        }
+        let (phys, contiguous) = self.translate_sized(virt, width, WRITE)?;
+        let phys = &phys[..width.bytes() as usize];
+        for (i, &addr) in phys.iter().enumerate() {
+            self.on_phys_write(addr);
+            if let Some(checker) = self.uninit.as_mut() {
+                checker.mark_written(addr);
+            }
+            if let Some(taint) = self.taint.as_mut() {
+                taint.on_write(addr);
+            }
+            for hook in self.memory_hooks.iter_mut() {
+                hook.write(self.instruction_pc, addr, (data >> (8 * i)) as u8)?;
+            }
+        }
+
+        if contiguous {
+            self.addressing.write_width(phys[0], width, data);
+        } else {
+            for (i, &addr) in phys.iter().enumerate() {
+                self.addressing.write(addr, (data >> (8 * i)) as u8);
+            }
+        }
+        self.check_bus(virt)
+    }
+
+    // Atomic read-modify-write of a word, returning the old value
+    // Both permissions are checked before anything is read. A word within a page is handed to the
+    // memory as a single access, a word split across pages is not atomic
+    fn atomic_word(
+        &mut self,
+        virt: u32,
+        op: &mut dyn FnMut(u32) -> Option<u32>,
+    ) -> Result<u32, InvalidMemoryAccess> {
+        let (phys, contiguous) = self.translate_sized(virt, Width::Word, READ | WRITE)?;
+        if !contiguous {
+            let old = self.read_sized(virt, Width::Word)?;
+            if let Some(new) = op(old) {
+                self.write_sized(virt, Width::Word, new)?;
+            }
+            return Ok(old);
+        }
+
+        for &addr in phys.iter() {
+            if let Some(checker) = self.uninit.as_mut() {
+                if checker.check_read(self.instruction_pc, addr) {
+                    return Err(InvalidMemoryAccess::UninitializedRead(addr));
+                }
+            }
+            if let Some(taint) = self.taint.as_mut() {
+                taint.on_read(addr);
+            }
+        }
+
+        let mut written = None;
+        let old = self.addressing.read_modify_write(phys[0], &mut |old| {
+            written = op(old);
+            written
+        });
+        self.check_bus(virt)?;
+
+        for (i, &addr) in phys.iter().enumerate() {
+            for hook in self.memory_hooks.iter_mut() {
+                hook.read(self.instruction_pc, addr, (old >> (8 * i)) as u8)?;
+            }
+        }
+        if let Some(new) = written {
+            for (i, &addr) in phys.iter().enumerate() {
+                if let Some(frames) = self.frames.as_mut() {
+                    frames.on_write(self.instruction_pc, virt.wrapping_add(i as u32));
+                }
+                self.on_phys_write(addr);
+                if let Some(checker) = self.uninit.as_mut() {
+                    checker.mark_written(addr);
+                }
+                if let Some(taint) = self.taint.as_mut() {
+                    taint.on_write(addr);
+                }
+                for hook in self.memory_hooks.iter_mut() {
+                    hook.write(self.instruction_pc, addr, (new >> (8 * i)) as u8)?;
+                }
+            }
+        }
+        Ok(old)
+    }
+
+    // Executes an atomic instruction from the escaped page, taking the address from x1
+    // 0x38 - compare-and-swap: if the word equals x0 it is replaced by the register after x0, and
+    //        the zero flag is set
+    // 0x39 - exchange the word with x0
+    // 0x3a - add x0 to the word
+    // x0 receives the old value of the word in each case
+    fn atomic(&mut self, func: u8, x0: usize, x1: usize) -> Result<(), InvalidMemoryAccess> {
+        let addr = self.xs[x1];
+        let value = self.xs[x0];
+        let old = match func {
+            0x38 => {
+                let new = self.xs[self.register(x0 + 1)?];
+                let old = self.atomic_word(addr, &mut |old| Some(new).filter(|_| old == value))?;
+                clear_flags!(self, F_ZERO);
+                self.set_flag(F_ZERO, old == value);
+                old
+            }
+            0x39 => self.atomic_word(addr, &mut |_| Some(value))?,
+            _ => self.atomic_word(addr, &mut |old| Some(old.wrapping_add(value)))?,
+        };
+        self.xs[x0] = old;
         Ok(())
     }
 
-    fn decode_instruction(&mut self) -> Result<(), InvalidMemoryAccess> {
-        let opcode = self.exec()?;
+    // Faults if the memory could not complete the last access
+    fn check_bus(&mut self, virt: u32) -> Result<(), InvalidMemoryAccess> {
+        match self.addressing.bus_error() {
+            Some(addr) => {
+                self.fault_address = virt;
+                Err(InvalidMemoryAccess::BusError(addr))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn system_register(&self, p: usize) -> Result<usize, InvalidMemoryAccess> {
+        if p < SYSTEM_REGISTERS {
+            Ok(p)
+        } else {
+            Err(InvalidMemoryAccess::InvalidRegister(p))
+        }
+    }
+
+    fn register(&self, r: usize) -> Result<usize, InvalidMemoryAccess> {
+        if r < N {
+            Ok(r)
+        } else {
+            Err(InvalidMemoryAccess::InvalidRegister(r))
+        }
+    }
+
+    // Executes one instruction, returning its opcode
+    fn decode_instruction(&mut self) -> Result<u8, InvalidMemoryAccess> {
+        self.instruction_pc = self.xs[R_PC];
+        let mut opcode = self.exec()?;
+
+        // Register extension prefix
+        // 0x3e 0b000000ba -> a and b are the high bits of the first and second register arguments
+        // of the following instruction
+        let mut ext = 0;
+        if self.traps.contains(opcode) {
+            return Err(self.trap_opcode(opcode, None));
+        }
+        if opcode == 0x3e && self.opcode_enabled(opcode) {
+            ext = self.exec()? as usize;
+            opcode = self.exec()?;
+            if self.traps.contains(opcode) {
+                return Err(self.trap_opcode(opcode, Some(ext as u8)));
+            }
+        }
+
+        if !self.opcode_enabled(opcode) {
+            return Err(InvalidMemoryAccess::IllegalOpcode(opcode));
+        }
+
         match opcode & 0xc0 {
-            // 0b00xxxxxx -> no arguments
+            // 0b00xxxxxx -> no arguments, apart from the extensions which take two registers
+            // 0x3f 0bffffffff 0bxxxxyyyy -> escape to the second page, f picks the instruction
             0x00 => {
+                let func = if opcode == opcodes::ESCAPE {
+                    self.exec()?
+                } else {
+                    0
+                };
+                let (fst, snd) = if decode::has_registers(opcode) || opcode == opcodes::ESCAPE {
+                    let data = self.exec()?;
+                    let fst = self.register(((data & 0xf0) >> 4) as usize | (ext & 1) << 4)?;
+                    (fst, self.register((data & 0x0f) as usize | (ext & 2) << 3)?)
+                } else {
+                    (0, 0)
+                };
+
+                self.taint_before(opcode, fst, snd)?;
                 match opcode & 0x3f {
                     // Branches
                     // Jumping is just mov x13, addr
@@ -663,14 +1865,44 @@ where
 
                     0x18 => self.call()?,
                     0x19 => self.ret()?,
+                    0x1a => self.iret()?,
+                    0x1b => self.halt()?,
+
+                    // Fences
+                    // Every access is complete before the next instruction starts, so the order
+                    // they guarantee always holds already
+                    0x1c..=0x1e => self.prefetch.invalidate(),
+
+                    // Spin wait hint
+                    0x1f => self.paused = true,
+
+                    0x20 => self.firmware_call()?,
+
+                    // Enters the system ring like an interrupt, returning past the syscall
+                    0x21 => self.nmi(SYSCALL),
 
-                    _ => (),
+                    // Comparison branches
+                    0x22..=0x27 => self.branch_compare(opcode - 0x22)?,
+
+                    // Relative branches, call, and jump
+                    0x28..=0x37 => self.branch_relative(opcode - 0x28)?,
+                    0x38 => self.call_relative(true)?,
+                    0x39 => self.call_relative(false)?,
+
+                    // Sign and zero extension
+                    0x3a..=0x3d => self.extend(fst, snd, opcode),
+
+                    0x3f => self.decode_extended(func, fst, snd)?,
+
+                    _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
                 }
+                self.taint_after(opcode, fst, snd)?;
             }
 
             // 0b01xxyyyy data -> one register argument and 32 bit data
             0x40 => {
-                let data = opcode as usize & 0x0f;
+                let data = self.register(opcode as usize & 0x0f | (ext & 1) << 4)?;
+                self.taint_before(opcode, data, 0)?;
                 match opcode & 0x30 {
                     // Load literal
                     0x00 => self.load_lit_int(data)?,
@@ -682,20 +1914,30 @@ where
 
                     _ => unreachable!("nya :("),
                 }
+                self.taint_after(opcode, data, 0)?;
             }
 
             // 0b10xxxxxx 0byyyyzzzz -> two register arguments
             0x80 => {
                 let data = self.exec()?;
-                let (fst, snd) = (((data & 0xf0) >> 4) as usize, (data & 0x0f) as usize);
+                let fst = ((data & 0xf0) >> 4) as usize | (ext & 1) << 4;
+                let snd = (data & 0x0f) as usize | (ext & 2) << 3;
 
+                // The privileged moves name a system register instead of one of the file
+                let (fst, snd) = match opcode & 0x3f {
+                    0x1a => (self.register(fst)?, self.system_register(snd)?),
+                    0x1b => (self.system_register(fst)?, self.register(snd)?),
+                    _ => (self.register(fst)?, self.register(snd)?),
+                };
+
+                self.taint_before(opcode, fst, snd)?;
                 match opcode & 0x3f {
                     // Integer arithmetic
                     0x00 => self.iadd(fst, snd),
                     0x01 => self.isub(fst, snd),
                     0x02 => self.imul(fst, snd),
-                    0x03 => self.idiv(fst, snd),
-                    0x04 => self.imod(fst, snd),
+                    0x03 => self.idiv(fst, snd)?,
+                    0x04 => self.imod(fst, snd)?,
 
                     // Floating point arithmetic
                     0x05 => self.fadd(fst, snd),
@@ -732,78 +1974,380 @@ where
                     0x1a => self.privileged_move(fst, snd)?,
                     0x1b => self.unprivileged_move(fst, snd),
 
-                    _ => (),
-                }
-            }
+                    0x1c => self.imul_wide(fst, snd),
+                    0x1d => self.imul_signed(fst, snd),
+                    0x1e => self.idiv_signed(fst, snd)?,
+                    0x1f => self.imod_signed(fst, snd)?,
+
+                    // Comparisons
+                    0x20 => self.cmp(fst, snd),
+                    0x21 => self.fcmp(fst, snd),
+
+                    // Complement and negation
+                    0x22 => self.not(fst, snd),
+                    0x23 => self.neg(fst, snd),
+                    0x24 => self.fneg(fst, snd),
+
+                    // Rotates
+                    0x25 => self.rotate(fst, snd, false),
+                    0x26 => self.rotate(fst, snd, true),
+                    0x27 => self.rotate_carry(fst, snd, false),
+                    0x28 => self.rotate_carry(fst, snd, true),
+
+                    // Immediate operations
+                    0x29..=0x2f => self.alu_immediate(fst, opcode)?,
+
+                    // Stack operations
+                    0x30 => self.push_word(self.xs[fst])?,
+                    0x31 => self.xs[fst] = self.pop_word()?,
+                    0x32 => self.push_word(self.fs[fst].to_bits())?,
+                    0x33 => self.fs[fst] = f32::from_bits(self.pop_word()?),
+
+                    // Indirect branches
+                    0x34 => self.call_to(self.xs[fst])?,
+                    0x35 => self.xs[R_PC] = self.xs[fst],
+
+                    // Byte and halfword loads
+                    0x36..=0x3d => self.load_narrow(fst, snd, opcode - 0xb6)?,
+
+                    // Float square root and absolute value
+                    0x3e => self.fsqrt(fst, snd),
+                    0x3f => self.fabs(fst, snd),
+
+                    _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcode)),
+                }
+                self.taint_after(opcode, fst, snd)?;
+            }
+
+            // 0b11xxyyyy data -> one register argument and 32 bit data
+            0xc0 => {
+                let data = self.register(opcode as usize & 0x0f | (ext & 1) << 4)?;
+                self.taint_before(opcode, data, 0)?;
+                match opcode & 0x30 {
+                    // Store at memory address
+                    0x00 => self.store_int(data)?,
+                    0x10 => self.store_short(data)?,
+                    0x20 => self.store_byte(data)?,
+                    0x30 => self.store_float(data)?,
+
+                    _ => unreachable!("nya :("),
+                }
+            }
+
+            _ => unreachable!("nya :("),
+        }
+
+        Ok(opcode)
+    }
+
+    // Executes an instruction in the escaped page
+    fn decode_extended(
+        &mut self,
+        func: u8,
+        fst: usize,
+        snd: usize,
+    ) -> Result<(), InvalidMemoryAccess> {
+        if !self.features.contains(isa::IsaFeatures::required_extended(func)) {
+            return Err(InvalidMemoryAccess::IllegalOpcode(opcodes::ESCAPE));
+        }
+
+        self.taint_before_extended(func, fst);
+        match func {
+            // Float rounding
+            0x00..=0x03 => self.fround(fst, snd, func),
+            0x04..=0x07 => self.fcvt(fst, snd, func & 3),
+
+            // Float minimum and maximum
+            0x08 => self.fmin_max(fst, snd, false),
+            0x09 => self.fmin_max(fst, snd, true),
+
+            // Double precision, see double.rs
+            0x10..=0x14 | 0x18 | 0x19 | 0x1c..=0x1f => self.double_op(func, fst, snd)?,
+
+            // Vectors, see vector.rs
+            0x20..=0x24 | 0x28..=0x2c | 0x30 | 0x31 => self.vector_op(func, fst, snd)?,
+
+            // Atomics
+            0x38..=0x3a => self.atomic(func, fst, snd)?,
+
+            _ => return Err(InvalidMemoryAccess::IllegalOpcode(opcodes::ESCAPE)),
+        }
+        self.taint_after_extended(func, fst, snd);
+        Ok(())
+    }
+
+    fn read_vector(&mut self, vector: u32) -> Result<u32, InvalidMemoryAccess> {
+        let addr = self.vector_base.wrapping_add(vector * 4);
+        let mut handler = 0;
+        for i in 0..4 {
+            let addr = self.check_memory(addr.wrapping_add(i), READ)?;
+            handler |= (self.addressing.read(addr) as u32) << (8 * i);
+        }
+        Ok(handler)
+    }
+
+    // Pushes the interrupted state to the system stack as a trap frame and jumps to the handler
+    fn call_interrupt(&mut self, interrupt: u32) -> Result<(), InvalidMemoryAccess> {
+        let frame = trap::TrapFrame {
+            pc: self.xs[R_PC],
+            int: self.xs[R_INT],
+            flags: self.flags,
+            base: self.xs[R_BASE],
+            sp: self.xs[R_SP],
+        };
+
+        if self.get_flag(F_USER_RING) {
+            self.xs[R_SP] = self.system_sp;
+            clear_flags!(self, F_USER_RING);
+        }
+
+        for data in frame.words().iter().rev() {
+            self.push_word(*data)?;
+        }
+
+        let vector = if interrupt & NMI_BIT != 0 {
+            interrupt & (IRQ_VECTORS - 1)
+        } else {
+            IRQ_VECTORS + interrupt
+        };
+        let handler = self.read_vector(vector)?;
+
+        // The low bits of the number are kept in LLL too, the full number is in x12
+        // Nested irq handlers can be interrupted by lines of a higher priority
+        if interrupt & NMI_BIT != 0 || !self.get_flag(F_NESTED_INTERRUPTS) {
+            clear_flags!(self, F_INTERRUPT_ENABLE);
+        }
+        self.flags = self.flags & !F_LAST_INTERRUPT | interrupt & F_LAST_INTERRUPT;
+        self.xs[R_INT] = interrupt;
+        self.xs[R_BASE] = self.xs[R_SP];
+        self.xs[R_PC] = handler;
+        Ok(())
+    }
+
+    // Delivers an interrupt, escalating to a double fault if delivery itself faults and shutting
+    // the cpu down if the double fault cannot be delivered either
+    fn deliver(&mut self, interrupt: u32) {
+        self.halted = false;
+        if let Some(taint) = self.taint.as_mut() {
+            taint.clear_store();
+        }
+        let (xs, flags) = (self.xs, self.flags);
+        if self.call_interrupt(interrupt).is_ok() {
+            return;
+        }
+
+        self.xs = xs;
+        self.flags = flags;
+        let double_fault = DOUBLE_FAULT | NMI_BIT;
+        self.fault_cause = DOUBLE_FAULT;
+        if interrupt != double_fault && self.call_interrupt(double_fault).is_ok() {
+            return;
+        }
 
-            // 0b11xxyyyy data -> one register argument and 32 bit data
-            0xc0 => {
-                let data = opcode as usize & 0x0f;
-                match opcode & 0x30 {
-                    // Store at memory address
-                    0x00 => self.store_int(data)?,
-                    0x10 => self.store_short(data)?,
-                    0x20 => self.store_byte(data)?,
-                    0x30 => self.store_float(data)?,
+        self.xs = xs;
+        self.flags = flags;
+        self.shutdown = true;
+    }
 
-                    _ => unreachable!("nya :("),
-                }
-            }
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
 
-            _ => unreachable!("nya :("),
-        }
+    // Whether the cpu is waiting for an interrupt after a halt instruction
+    // With interrupts disabled only a non-maskable interrupt from the host can wake it
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
 
+    fn halt(&mut self) -> Result<(), InvalidMemoryAccess> {
+        if self.get_flag(F_USER_RING) {
+            return Err(InvalidMemoryAccess::UnprivilegedOpcode);
+        }
+        self.halted = true;
         Ok(())
     }
 
-    fn call_interrupt(&mut self, interrupt: u32) {
-        let flags = self.flags;
-        let int = self.xs[R_INT];
-        let pc = self.xs[R_PC];
-        self.xs[R_INT] = interrupt;
+    // Fault raised by the instruction executed in the last step
+    pub fn last_fault(&self) -> Option<InvalidMemoryAccess> {
+        self.last_fault
+    }
 
-        if self.get_flag(F_USER_RING) {
-            let sp = self.xs[R_SP];
-            let base = self.xs[R_BASE];
-            self.xs[R_SP] = self.system_sp;
-            self.xs[R_BASE] = 0;
+    // Whether the instruction executed in the last step was a pause hint, ie the guest is spinning
+    // and other work could be run instead
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
 
-            for i in 4..8 {
+    // Steps until shutdown, a halt with interrupts disabled, or max_steps, returning the number of
+    // steps taken
+    // Pause hints yield the host thread
+    pub fn run(&mut self, max_steps: u64) -> u64 {
+        for steps in 0..max_steps {
+            match self.step() {
+                StepOutcome::Shutdown => return steps + 1,
+                StepOutcome::Halted if !self.get_flag(F_INTERRUPT_ENABLE) => return steps + 1,
+                _ => (),
+            }
+            if self.paused {
+                std::thread::yield_now();
             }
-        } else {
         }
+        max_steps
     }
 
-    pub fn step(&mut self) {
-        if !self.interrupt_queue.is_empty() && self.flags & F_INTERRUPT_ENABLE != 0 {
-            let interrupt = self.interrupt_queue.pop_front().unwrap();
-            self.call_interrupt(interrupt);
+    pub fn step(&mut self) -> StepOutcome {
+        if self.shutdown {
+            return StepOutcome::Shutdown;
+        }
 
+        let cycle = self.cycles;
+        self.cycles += 1;
+        self.last_fault = None;
+        self.paused = false;
+        let audit = self.start_audit();
+        if let Some(profile) = self.profile.as_mut() {
+            profile.on_step(self.xs[R_PC]);
+        }
+        let mask = if self.get_flag(F_NESTED_INTERRUPTS) {
+            self.interrupt_mask & self.interrupts.priority_mask()
         } else {
+            self.interrupt_mask
+        };
+        self.interrupts.mark_queued(mask, cycle);
+
+        let next = self.interrupts.next(mask);
+        let outcome = if let (Some(line), true) = (next, self.get_flag(F_INTERRUPT_ENABLE)) {
+            let (raised, queued) = self.interrupts.take(line, cycle);
+            if let Some(latency) = self.latency.as_mut() {
+                latency.record(analysis::LatencySample {
+                    id: line as u32,
+                    raised,
+                    queued: queued.unwrap_or(cycle),
+                    started: cycle,
+                });
+            }
+            self.deliver(line as u32);
+            StepOutcome::Interrupted(line as u32)
+        } else if self.halted {
+            StepOutcome::Halted
+        } else {
+            if let Some(histogram) = self.histogram.as_mut() {
+                histogram.record(self.xs[R_PC]);
+            }
             match self.decode_instruction() {
-                Ok(_) => (),
+                Ok(opcode) => StepOutcome::Executed { opcode, cycles: 1 },
                 Err(e) => {
-                    self.nmi(match e {
+                    // Faults return to the faulting instruction
+                    self.last_fault = Some(e);
+                    self.xs[R_PC] = self.instruction_pc;
+                    self.fault_cause = match e {
                         InvalidMemoryAccess::UsedFreePage => 0x00000000,
                         InvalidMemoryAccess::InvalidPermissions(_, _) => 0x00000001,
                         InvalidMemoryAccess::UnprivilegedOpcode => 0x00000002,
-                    })
+                        InvalidMemoryAccess::DivideByZero => 0x00000003,
+                        InvalidMemoryAccess::UninitializedRead(_) => 0x00000004,
+                        InvalidMemoryAccess::TaintedData => 0x00000005,
+                        InvalidMemoryAccess::InvalidRegister(_) => 0x00000006,
+                        InvalidMemoryAccess::ShadowStackMismatch => 0x00000007,
+                        InvalidMemoryAccess::CorruptedFrame => 0x00000008,
+                        InvalidMemoryAccess::BusError(_) => 0x00000009,
+                        InvalidMemoryAccess::TrappedOpcode(_) => 0x0000000a,
+                        InvalidMemoryAccess::IllegalOpcode(_) => 0x0000000b,
+                    };
+                    self.nmi(self.fault_cause);
+                    StepOutcome::Faulted(e)
                 }
             }
+        };
+        self.finish_audit(cycle, audit);
+
+        if self.shutdown {
+            StepOutcome::Shutdown
+        } else {
+            outcome
         }
     }
 
+    // Requests an edge triggered interrupt, which is dropped if it is masked
+    // Lines past the last one do not exist and are ignored. How urgent it is depends on the
+    // priority level of the line, see interrupts.rs
     pub fn irq(&mut self, id: u8) {
-        if 1 << id & self.interrupt_mask != 0 {
-            self.interrupt_queue.push_back(id as u32);
+        if (id as usize) < interrupts::IRQ_LINES && 1 << id & self.interrupt_mask != 0 {
+            self.interrupts.raise(id as usize, self.cycles);
+        }
+    }
+
+    // Asserts or deasserts a level triggered interrupt line
+    pub fn set_irq_level(&mut self, id: u8, asserted: bool) {
+        if (id as usize) < interrupts::IRQ_LINES {
+            self.interrupts.set_level(id as usize, asserted, self.cycles);
         }
     }
 
+    // Sets the priority level of an interrupt line, 0 being the highest, as system register 17 does
+    pub fn set_irq_priority(&mut self, id: u8, level: u8) {
+        if (id as usize) < interrupts::IRQ_LINES {
+            self.interrupts.set_priority(id as usize, level);
+        }
+    }
+
+    pub fn irq_priority(&self, id: u8) -> Option<u8> {
+        if (id as usize) < interrupts::IRQ_LINES {
+            Some(self.interrupts.priority(id as usize))
+        } else {
+            None
+        }
+    }
+
+    // Interrupt lines waiting to be handled, a bit per line
+    pub fn pending_irqs(&self) -> u8 {
+        self.interrupts.pending()
+    }
+
+    // Non-maskable interrupts are delivered immediately, even with interrupts disabled
     pub fn nmi(&mut self, id: u32) {
-        // self.interrupt_queue.push_back(id | 0x80000000);
+        self.deliver(id | NMI_BIT);
+    }
+}
+
+impl Cpu<SimpleAddress> {
+    // A cpu with a program loaded at address 0, for the tests of every module
+    #[cfg(test)]
+    pub(crate) fn with_program(program: &[u8]) -> Cpu<SimpleAddress> {
+        let mut memory = SimpleAddress::default();
+        memory.load(0, program);
+        Cpu::new(memory)
+    }
+
+    // Runs an arbitrary byte string as a program loaded at address 0 for at most max_steps steps
+    // Guest programs can never panic the host, so this is safe to call directly from fuzz targets
+    pub fn run_fuzz(bytes: &[u8], max_steps: usize) -> Cpu<SimpleAddress> {
+        let mut memory = SimpleAddress::default();
+        for (addr, byte) in bytes.iter().take(SIMPLE_ADDRESS_SIZE).enumerate() {
+            memory.write(addr as u32, *byte);
+        }
+
+        let mut cpu = Cpu::new(memory);
+        for _ in 0..max_steps {
+            cpu.step();
+        }
+        cpu
     }
 }
 
+// Pseudorandom bytes for tests that need arbitrary input, continuing from seed
+#[cfg(test)]
+pub(crate) fn xorshift_bytes(seed: &mut u32, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 17;
+            *seed ^= *seed << 5;
+            *seed as u8
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1068,7 +2612,7 @@ mod tests {
     fn cpu_memmap() {
         let mut cpu = Cpu::new(SimpleAddress::default());
         cpu.flags |= 1 << F_MEMMAP_ENABLE;
-        cpu.memmap = 0x1234;
+        cpu.mmu.set_root(0x1234);
         cpu.addressing.memory[0x1234] = 0x0a;
         cpu.addressing.memory[0x1235] = 0x0b;
         cpu.addressing.memory[0x1236] = 0x00;
@@ -1079,7 +2623,847 @@ mod tests {
         cpu.addressing.memory[0x0b0d] = 0xe0;
         cpu.write(0x000000bc, 0x42).unwrap();
         assert_eq!(cpu.addressing.memory[0x0000eebc], 0x42);
-        assert_eq!(cpu.read(0xbc).unwrap(), 0x42);
+        assert_eq!(cpu.read_sized(0xbc, Width::Byte).unwrap(), 0x42);
         assert!(cpu.exec().is_err());
     }
+
+    #[test]
+    fn cpu_multiply() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[0] = 0x1_0001;
+        cpu.xs[1] = 0x1_0000;
+        cpu.imul(0, 1);
+        assert_eq!(cpu.xs[0], 0x1_0000);
+        assert!(cpu.get_flag(F_CARRY) && cpu.get_flag(F_OVERFLOW));
+
+        cpu.xs[0] = 0xffff_ffff;
+        cpu.xs[1] = 0xffff_ffff;
+        cpu.imul_wide(0, 1);
+        assert_eq!((cpu.xs[0], cpu.xs[1]), (1, 0xffff_fffe));
+
+        cpu.xs[0] = 6;
+        cpu.xs[1] = 7;
+        cpu.imul(0, 1);
+        assert_eq!(cpu.xs[0], 42);
+        assert!(!cpu.get_flag(F_CARRY) && !cpu.get_flag(F_OVERFLOW));
+    }
+
+    #[test]
+    fn cpu_signed_arithmetic() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[0] = -6i32 as u32;
+        cpu.xs[1] = 7;
+        cpu.imul_signed(0, 1);
+        assert_eq!(cpu.xs[0] as i32, -42);
+        assert!(cpu.get_flag(F_NEGATIVE) && !cpu.get_flag(F_OVERFLOW));
+
+        cpu.xs[1] = -5i32 as u32;
+        cpu.imod_signed(0, 1).unwrap();
+        assert_eq!(cpu.xs[0] as i32, -2);
+        cpu.xs[0] = -42i32 as u32;
+        cpu.idiv_signed(0, 1).unwrap();
+        assert_eq!(cpu.xs[0], 8);
+        assert!(!cpu.get_flag(F_NEGATIVE));
+
+        cpu.xs[0] = i32::MIN as u32;
+        cpu.xs[1] = -1i32 as u32;
+        cpu.idiv_signed(0, 1).unwrap();
+        assert_eq!(cpu.xs[0], i32::MIN as u32);
+        assert!(cpu.get_flag(F_OVERFLOW));
+        cpu.xs[1] = 0;
+        assert_eq!(cpu.idiv_signed(0, 1), Err(InvalidMemoryAccess::DivideByZero));
+    }
+
+    #[test]
+    fn cpu_compare() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[0] = 3;
+        cpu.xs[1] = 5;
+        cpu.set_carry(true);
+        cpu.cmp(0, 1);
+        assert_eq!((cpu.xs[0], cpu.xs[1]), (3, 5));
+        assert!(cpu.get_flag(F_NEGATIVE) && !cpu.get_flag(F_CARRY) && !cpu.get_flag(F_ZERO));
+        cpu.cmp(1, 0);
+        assert!(!cpu.get_flag(F_NEGATIVE) && cpu.get_flag(F_CARRY));
+        cpu.cmp(0, 0);
+        assert!(cpu.get_flag(F_ZERO) && cpu.get_flag(F_CARRY));
+
+        // -1 is less than 1 signed but higher unsigned
+        cpu.xs[0] = -1i32 as u32;
+        cpu.xs[1] = 1;
+        cpu.cmp(0, 1);
+        for (condition, taken) in [true, true, false, false, false, true].iter().enumerate() {
+            cpu.addressing.memory[..4].copy_from_slice(&0x100u32.to_le_bytes());
+            cpu.xs[R_PC] = 0;
+            cpu.branch_compare(condition as u8).unwrap();
+            assert_eq!(cpu.xs[R_PC] == 0x100, *taken, "{}", condition);
+        }
+
+        cpu.fs[0] = -1.0;
+        cpu.fs[1] = 2.5;
+        cpu.fcmp(0, 1);
+        assert!(cpu.get_flag(F_NEGATIVE) && !cpu.get_flag(F_ZERO));
+        cpu.fs[1] = f32::NAN;
+        cpu.fcmp(0, 1);
+        assert!(cpu.get_flag(F_NAN) && !cpu.get_flag(F_NEGATIVE));
+
+        // An overflow left by an earlier add doesn't flip the signed branches after fcmp
+        cpu.fs[0] = 1.0;
+        cpu.fs[1] = 2.0;
+        for (condition, taken) in [true, true, false, false, true, false].iter().enumerate() {
+            cpu.set_flag(F_OVERFLOW, true);
+            cpu.fcmp(0, 1);
+            cpu.addressing.memory[..4].copy_from_slice(&0x100u32.to_le_bytes());
+            cpu.xs[R_PC] = 0;
+            cpu.branch_compare(condition as u8).unwrap();
+            assert_eq!(cpu.xs[R_PC] == 0x100, *taken, "{}", condition);
+        }
+    }
+
+    #[test]
+    fn cpu_negate() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[1] = 5;
+        cpu.not(0, 1);
+        assert_eq!(cpu.xs[0], 0xffff_fffa);
+        assert!(cpu.get_flag(F_NEGATIVE));
+        cpu.neg(0, 1);
+        assert_eq!(cpu.xs[0] as i32, -5);
+        assert!(!cpu.get_flag(F_CARRY) && !cpu.get_flag(F_OVERFLOW));
+
+        cpu.xs[1] = 0x8000_0000;
+        cpu.neg(1, 1);
+        assert_eq!(cpu.xs[1], 0x8000_0000);
+        assert!(cpu.get_flag(F_OVERFLOW));
+
+        cpu.fs[1] = 0.0;
+        cpu.fneg(0, 1);
+        assert_eq!(cpu.fs[0].to_bits(), 0x8000_0000);
+        assert!(cpu.get_flag(F_ZERO) && cpu.get_flag(F_NEGATIVE));
+    }
+
+    #[test]
+    fn cpu_float_math() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.fs[1] = -2.25;
+        cpu.fabs(0, 1);
+        assert_eq!(cpu.fs[0], 2.25);
+        assert!(!cpu.get_flag(F_NEGATIVE));
+        cpu.fsqrt(0, 0);
+        assert_eq!(cpu.fs[0], 1.5);
+
+        // No root, in either mode
+        for soft_float in [false, true] {
+            cpu.set_soft_float(soft_float);
+            cpu.fsqrt(0, 1);
+            assert!(cpu.fs[0].is_nan() && cpu.get_flag(F_NAN));
+        }
+        cpu.fs[1] = f32::INFINITY;
+        cpu.fsqrt(0, 1);
+        assert!(cpu.get_flag(F_INFINITE));
+    }
+
+    #[test]
+    fn cpu_rotate() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[0] = 0x8000_0001;
+        cpu.xs[1] = 4;
+        cpu.rotate(0, 1, false);
+        assert_eq!(cpu.xs[0], 0x18);
+        assert!(!cpu.get_flag(F_CARRY));
+        cpu.rotate(0, 1, true);
+        assert_eq!(cpu.xs[0], 0x8000_0001);
+        assert!(cpu.get_flag(F_CARRY));
+
+        // Through carry, which is set
+        cpu.xs[1] = 1;
+        cpu.rotate_carry(0, 1, false);
+        assert_eq!(cpu.xs[0], 3);
+        assert!(cpu.get_flag(F_CARRY));
+        cpu.rotate_carry(0, 1, true);
+        assert_eq!(cpu.xs[0], 0x8000_0001);
+        assert!(cpu.get_flag(F_CARRY));
+        cpu.xs[1] = 33;
+        cpu.rotate_carry(0, 1, true);
+        assert_eq!(cpu.xs[0], 0x8000_0001);
+    }
+
+    #[test]
+    fn cpu_immediates() {
+        // addi x2, 3; subi x2, 4; shri x2, 28; shli x2, 33; xori x2, 0xff
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [
+            0xa9, 0x20, 3, 0, 0, 0, 0xaa, 0x20, 4, 0, 0, 0, 0xaf, 0x20, 28, 0, 0, 0, 0xae, 0x20, 33,
+            0, 0, 0, 0xad, 0x20, 0xff, 0, 0, 0,
+        ];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        cpu.set_flag(F_CARRY, true);
+        cpu.step();
+        assert_eq!(cpu.xs[2], 3);
+        cpu.step();
+        assert_eq!(cpu.xs[2], 0xffff_ffff);
+        assert!(!cpu.get_flag(F_CARRY) && cpu.get_flag(F_NEGATIVE));
+        cpu.step();
+        assert_eq!(cpu.xs[2], 0xf);
+        assert!(cpu.get_flag(F_CARRY));
+        cpu.step();
+        assert_eq!(cpu.xs[2], 0);
+        assert!(!cpu.get_flag(F_CARRY) && cpu.get_flag(F_ZERO));
+        cpu.step();
+        assert_eq!(cpu.xs[2], 0xff);
+        assert_eq!(cpu.xs[R_PC], program.len() as u32);
+    }
+
+    #[test]
+    fn cpu_push_pop() {
+        // push x1; fpush f2; pop x3; fpop f4
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..8]
+            .copy_from_slice(&[0xb0, 0x10, 0xb2, 0x20, 0xb1, 0x30, 0xb3, 0x40]);
+        cpu.xs[1] = 0x1234_5678;
+        cpu.fs[2] = 1.5;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.xs[R_SP], 0x7ff8);
+        assert_eq!(cpu.read_sized(0x7ffc, Width::Word), Ok(0x1234_5678));
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.xs[3], 1.5f32.to_bits());
+        assert_eq!(cpu.fs[4].to_bits(), 0x1234_5678);
+        assert_eq!(cpu.xs[R_SP], 0x8000);
+    }
+
+    #[test]
+    fn cpu_indirect_branch() {
+        // callr x1; and at 0x100, jmpr x2
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..2].copy_from_slice(&[0xb4, 0x10]);
+        cpu.addressing.memory[0x100..0x102].copy_from_slice(&[0xb5, 0x20]);
+        cpu.xs[1] = 0x100;
+        cpu.xs[2] = 0x200;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_BASE] = 0x1234;
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x100);
+        assert_eq!(cpu.xs[R_BASE], 0x7ff8);
+        assert_eq!(cpu.read_sized(0x7ff8, Width::Word), Ok(2));
+        assert_eq!(cpu.read_sized(0x7ffc, Width::Word), Ok(0x1234));
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x200);
+        assert_eq!(cpu.xs[R_SP], 0x7ff8);
+    }
+
+    #[test]
+    fn cpu_relative_branch() {
+        // call.r 0x100; and at 0x105, bz.r -0x20 and bnz.r -0x20
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..5].copy_from_slice(&[0x38, 0x00, 0x01, 0x00, 0x00]);
+        cpu.addressing.memory[0x105..0x10f]
+            .copy_from_slice(&[0x28, 0xe0, 0xff, 0xff, 0xff, 0x30, 0xe0, 0xff, 0xff, 0xff]);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x105);
+        assert_eq!(cpu.read_sized(0x7ff8, Width::Word), Ok(5));
+
+        // Zero is clear, so only the second branch is taken
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x10a);
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0xef);
+    }
+
+    #[test]
+    fn cpu_narrow_loads() {
+        // ldb x1, [0x100]; ldbs x2, [0x100]; ldrh x3, x0; ldrhs x4, x0
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [
+            0xb6, 0x10, 0x00, 0x01, 0x00, 0x00, 0xb7, 0x20, 0x00, 0x01, 0x00, 0x00, 0xbc, 0x30,
+            0xbd, 0x40,
+        ];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        cpu.addressing.memory[0x100..0x102].copy_from_slice(&[0x80, 0xff]);
+        cpu.xs[0] = 0x100;
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.xs[1..5], [0x80, 0xffff_ff80, 0xff80, 0xffff_ff80]);
+        assert!(cpu.get_flag(F_NEGATIVE));
+        assert_eq!(cpu.xs[R_PC], program.len() as u32);
+    }
+
+    #[test]
+    fn cpu_extend() {
+        // sxtb x1, x0; sxth x2, x0; uxtb x3, x0; uxth x4, x0
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..8]
+            .copy_from_slice(&[0x3a, 0x10, 0x3b, 0x20, 0x3c, 0x30, 0x3d, 0x40]);
+        cpu.xs[0] = 0x1234_80f0;
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.xs[1..5], [0xffff_fff0, 0xffff_80f0, 0xf0, 0x80f0]);
+        assert!(!cpu.get_flag(F_NEGATIVE));
+    }
+
+    #[test]
+    fn cpu_float_rounding() {
+        // fround, ftrunc, ffloor, and fceil of -2.5 into f1-f4, then fcvt.ru x1, f0
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [
+            0x3f, 0x00, 0x10, 0x3f, 0x01, 0x20, 0x3f, 0x02, 0x30, 0x3f, 0x03, 0x40, 0x3f, 0x07,
+            0x10,
+        ];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        cpu.fs[0] = -2.5;
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(cpu.fs[1..5], [-2.0, -2.0, -3.0, -2.0]);
+        assert_eq!(cpu.xs[1] as i32, -2);
+        assert!(cpu.get_flag(F_NEGATIVE) && !cpu.get_flag(F_OVERFLOW));
+
+        cpu.fs[0] = f32::NAN;
+        cpu.fcvt(1, 0, 0);
+        assert_eq!(cpu.xs[1], 0);
+        assert!(cpu.get_flag(F_NAN) && !cpu.get_flag(F_OVERFLOW));
+        cpu.fs[0] = 3e9;
+        cpu.fcvt(1, 0, 0);
+        assert_eq!(cpu.xs[1], i32::MAX as u32);
+        assert!(cpu.get_flag(F_OVERFLOW));
+
+        // Minimum and maximum
+        cpu.fs[0] = 0.0;
+        cpu.fs[1] = -0.0;
+        cpu.fmin_max(0, 1, false);
+        assert_eq!(cpu.fs[0].to_bits(), 0x8000_0000);
+        cpu.fs[1] = f32::NAN;
+        cpu.fmin_max(0, 1, true);
+        assert_eq!(cpu.fs[0].to_bits(), 0x8000_0000);
+        cpu.fs[1] = 1.0;
+        cpu.fmin_max(0, 1, true);
+        assert_eq!(cpu.fs[0], 1.0);
+    }
+
+    #[test]
+    fn cpu_atomics() {
+        // cas x2, x4 (succeeds), cas x6, x4 (fails), xchg x8, x4, xadd x10, x4
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [
+            0x3f, 0x38, 0x24, 0x3f, 0x38, 0x64, 0x3f, 0x39, 0x84, 0x3f, 0x3a, 0xa4,
+        ];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        cpu.addressing.memory[0x100] = 5;
+        cpu.xs[2] = 5;
+        cpu.xs[3] = 9;
+        cpu.xs[4] = 0x100;
+        cpu.xs[6] = 5;
+        cpu.xs[7] = 1;
+        cpu.xs[8] = 0x20;
+        cpu.xs[10] = 3;
+
+        cpu.step();
+        assert_eq!(read_word(&cpu, 0x100), 9);
+        assert_eq!(cpu.xs[2], 5);
+        assert!(cpu.get_flag(F_ZERO));
+        cpu.step();
+        assert_eq!(read_word(&cpu, 0x100), 9);
+        assert_eq!(cpu.xs[6], 9);
+        assert!(!cpu.get_flag(F_ZERO));
+        cpu.step();
+        cpu.step();
+        assert_eq!(read_word(&cpu, 0x100), 0x23);
+        assert_eq!(cpu.xs[8..11], [9, 0, 0x20]);
+
+        // Compare-and-swap needs the register after the expected value
+        cpu.addressing.memory[0..3].copy_from_slice(&[0x3f, 0x38, 0xf4]);
+        cpu.xs[R_PC] = 0;
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Faulted(InvalidMemoryAccess::InvalidRegister(16))
+        );
+    }
+
+    #[test]
+    fn cpu_divide_by_zero() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[0] = 42;
+        cpu.xs[1] = 0;
+        assert!(matches!(cpu.idiv(0, 1), Err(InvalidMemoryAccess::DivideByZero)));
+        assert!(matches!(cpu.imod(0, 1), Err(InvalidMemoryAccess::DivideByZero)));
+        assert_eq!(cpu.xs[0], 42);
+
+        // Division is unsigned, so INT_MIN / -1 cannot overflow
+        cpu.xs[0] = 0x8000_0000;
+        cpu.xs[1] = 0xffff_ffff;
+        assert_eq!(cpu.idiv(0, 1), Ok(()));
+        assert_eq!(cpu.xs[0], 0);
+        assert!(cpu.get_flag(F_ZERO));
+
+        // Float division by zero is infinite rather than a fault
+        cpu.fs[0] = -1.0;
+        cpu.fs[1] = 0.0;
+        cpu.fdiv(0, 1);
+        assert_eq!(cpu.fs[0], f32::NEG_INFINITY);
+        assert!(cpu.get_flag(F_INFINITE));
+    }
+
+    #[test]
+    fn cpu_run_fuzz() {
+        // Divide by zero
+        let cpu = Cpu::run_fuzz(&[0x41, 0xff, 0xff, 0xff, 0xff, 0x83, 0x02], 16);
+        assert_eq!(cpu.xs[1], 0xffffffff);
+
+        // Run off the end of the address space
+        let cpu = Cpu::run_fuzz(&[0x4d, 0xfe, 0xff, 0xff, 0xff], 4);
+        assert!(cpu.xs[R_PC] < 0x10);
+
+        // Random programs must never panic
+        let mut seed = 0x12345678;
+        for _ in 0..64 {
+            Cpu::run_fuzz(&xorshift_bytes(&mut seed, 256), 1000);
+        }
+    }
+
+    #[test]
+    fn cpu_uninit_reads() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.set_uninit_checker(Some(analysis::UninitChecker::default().trap(true)));
+
+        // Reading before writing traps
+        cpu.xs[1] = 0x2000;
+        assert!(matches!(
+            cpu.load_indirect_int(0, 1),
+            Err(InvalidMemoryAccess::UninitializedRead(0x2000))
+        ));
+
+        // Written memory can be read back
+        cpu.xs[1] = 0x3000;
+        cpu.store_indirect_int(0, 1).unwrap();
+        cpu.load_indirect_int(0, 1).unwrap();
+        assert_eq!(cpu.uninit_checker().unwrap().reads().len(), 1);
+    }
+
+    // Memory hook that makes the first n writes fault
+    struct FailWrites(usize);
+
+    impl analysis::MemoryHook for FailWrites {
+        fn write(&mut self, _pc: u32, _addr: u32, _data: u8) -> Result<(), InvalidMemoryAccess> {
+            if self.0 == 0 {
+                Ok(())
+            } else {
+                self.0 -= 1;
+                Err(InvalidMemoryAccess::UsedFreePage)
+            }
+        }
+    }
+
+    // Divides by zero with a vector table at 0x1000
+    fn faulting_cpu() -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0] = 0x83;
+        cpu.addressing.memory[1] = 0x01;
+        cpu.addressing.memory[0x100c..0x1010].copy_from_slice(&0x2000u32.to_le_bytes());
+        cpu.addressing.memory[0x107c..0x1080].copy_from_slice(&0x3000u32.to_le_bytes());
+        cpu.vector_base = 0x1000;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_BASE] = 0x8000;
+        cpu.flags = 1 << F_INTERRUPT_ENABLE;
+        cpu
+    }
+
+    fn read_word(cpu: &Cpu<SimpleAddress>, addr: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&cpu.addressing.memory[addr..addr + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    #[test]
+    fn cpu_fault_delivery() {
+        let mut cpu = faulting_cpu();
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Faulted(InvalidMemoryAccess::DivideByZero)
+        );
+        assert_eq!(cpu.xs[R_PC], 0x2000);
+        assert_eq!(cpu.xs[R_INT], 3 | NMI_BIT);
+        assert_eq!(cpu.xs[R_SP], 0x8000 - 20);
+        assert!(!cpu.get_flag(F_INTERRUPT_ENABLE));
+
+        // Saved pc, x12, flags, base, and sp
+        assert_eq!(read_word(&cpu, 0x8000 - 20), 0);
+        assert_eq!(read_word(&cpu, 0x8000 - 16), 0);
+        assert_eq!(read_word(&cpu, 0x8000 - 12), 1 << F_INTERRUPT_ENABLE);
+        assert_eq!(read_word(&cpu, 0x8000 - 8), 0x8000);
+        assert_eq!(read_word(&cpu, 0x8000 - 4), 0x8000);
+    }
+
+    #[test]
+    fn cpu_double_fault() {
+        let mut cpu = faulting_cpu();
+        cpu.add_memory_hook(Box::new(FailWrites(1)));
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Faulted(InvalidMemoryAccess::DivideByZero)
+        );
+        assert_eq!(cpu.xs[R_PC], 0x3000);
+        assert_eq!(cpu.xs[R_INT], DOUBLE_FAULT | NMI_BIT);
+        assert_eq!(read_word(&cpu, 0x8000 - 12), 1 << F_INTERRUPT_ENABLE);
+    }
+
+    #[test]
+    fn cpu_shutdown() {
+        let mut cpu = faulting_cpu();
+        cpu.add_memory_hook(Box::new(FailWrites(usize::MAX)));
+        assert_eq!(cpu.step(), StepOutcome::Shutdown);
+        assert!(cpu.is_shutdown());
+        assert_eq!(cpu.step(), StepOutcome::Shutdown);
+
+        // The state is left as it was at the faulting instruction
+        assert_eq!(cpu.xs[R_PC], 0);
+        assert_eq!(cpu.xs[R_SP], 0x8000);
+        assert_eq!(cpu.flags, 1 << F_INTERRUPT_ENABLE);
+    }
+
+    #[test]
+    fn cpu_pause() {
+        // pause; clc
+        let mut memory = SimpleAddress::default();
+        memory.load(0, &[0x1f, 0x10]);
+        let mut cpu = Cpu::new(memory);
+        cpu.step();
+        assert!(cpu.paused());
+        cpu.step();
+        assert!(!cpu.paused());
+
+        assert_eq!(cpu.run(3), 3);
+        assert_eq!(cpu.cycles(), 5);
+    }
+
+    #[test]
+    fn cpu_system_registers() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        for p in [1, 3, 4, 5, 6, 9].iter() {
+            cpu.xs[0] = 0x1000 + *p as u32;
+            cpu.privileged_move(0, *p).unwrap();
+            cpu.unprivileged_move(*p, 1);
+            assert_eq!(cpu.xs[1], 0x1000 + *p as u32);
+        }
+        assert_eq!(cpu.system_sp, 0x1003);
+        assert_eq!(cpu.vector_base, 0x1004);
+
+        // The cycle counter is read only
+        cpu.cycles = 0x1_0000_0002;
+        cpu.privileged_move(0, 7).unwrap();
+        cpu.unprivileged_move(7, 1);
+        cpu.unprivileged_move(8, 2);
+        assert_eq!((cpu.xs[1], cpu.xs[2]), (2, 1));
+
+        // System registers can only be written from the system ring
+        cpu.set_flag(F_USER_RING, true);
+        assert!(matches!(
+            cpu.privileged_move(0, 3),
+            Err(InvalidMemoryAccess::UnprivilegedOpcode)
+        ));
+        assert_eq!(cpu.system_sp, 0x1003);
+    }
+
+    #[test]
+    fn cpu_fault_registers() {
+        let mut cpu = faulting_cpu();
+        cpu.step();
+        assert_eq!(cpu.fault_cause, 3);
+
+        // Accessing an unmapped page records the faulting address
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+        cpu.mmu.set_root(0x100);
+        cpu.xs[1] = 0x1234_5678;
+        assert!(cpu.load_indirect_int(0, 1).is_err());
+        assert_eq!(cpu.fault_address, 0x1234_5678);
+    }
+
+    #[test]
+    fn simple_address_out_of_range() {
+        let mut memory = SimpleAddress::new(0x100).out_of_range(OutOfRange::Mirror);
+        memory.load(0xfe, &[1, 2, 3]);
+        assert_eq!(memory.contents()[0], 3);
+        assert_eq!(memory.dump(0x1fe, 3), vec![1, 2, 3]);
+        assert_eq!(memory.bus_error(), None);
+
+        let mut memory = SimpleAddress::new(0x100);
+        memory.write(0x100, 1);
+        assert_eq!(memory.read(0x100), 0);
+        assert_eq!(memory.bus_error(), None);
+
+        let mut memory = SimpleAddress::new(0x100).out_of_range(OutOfRange::BusError);
+        assert_eq!(memory.read(0x104), 0);
+        assert_eq!(memory.bus_error(), Some(0x104));
+        assert_eq!(memory.bus_error(), None);
+    }
+
+    #[test]
+    fn cpu_bus_error() {
+        // load x0 <- [0x20000]
+        let mut memory = SimpleAddress::new(0x10000).out_of_range(OutOfRange::BusError);
+        memory.load(0, &[0x60, 0x00, 0x00, 0x02, 0x00]);
+        memory.load(0x1024, &0x2000u32.to_le_bytes());
+        let mut cpu = Cpu::new(memory);
+        cpu.vector_base = 0x1000;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.step();
+        assert_eq!(cpu.fault_cause, 9);
+        assert_eq!(cpu.fault_address, 0x20000);
+        assert_eq!(cpu.xs[R_PC], 0x2000);
+    }
+
+    #[test]
+    fn cpu_stack_switching() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+
+        // Kernel: enter the user ring with the system stack at 0x8000
+        cpu.addressing.memory[0] = 0x17;
+        cpu.xs[R_SP] = 0x8000;
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Executed {
+                opcode: 0x17,
+                cycles: 1
+            }
+        );
+        assert_eq!(cpu.system_sp, 0x8000);
+        assert!(cpu.get_flag(F_USER_RING));
+
+        // User code on its own stack is interrupted
+        cpu.addressing.memory[0x80..0x84].copy_from_slice(&0x3000u32.to_le_bytes());
+        cpu.addressing.memory[0x3000] = 0x1a;
+        cpu.xs[R_SP] = 0x6000;
+        cpu.set_flag(F_INTERRUPT_ENABLE, true);
+        cpu.irq(0);
+        assert_eq!(cpu.step(), StepOutcome::Interrupted(0));
+        assert_eq!(cpu.xs[R_PC], 0x3000);
+        assert_eq!(cpu.xs[R_SP], 0x8000 - trap::TrapFrame::SIZE);
+        assert!(!cpu.get_flag(F_USER_RING));
+        assert_eq!(cpu.current_trap_frame().unwrap().sp, 0x6000);
+
+        // Returning restores the user stack and ring
+        cpu.system_sp = 0;
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 1);
+        assert_eq!(cpu.xs[R_SP], 0x6000);
+        assert_eq!(cpu.system_sp, 0x8000);
+        assert!(cpu.get_flag(F_USER_RING));
+
+        // Returning is privileged
+        cpu.addressing.memory[1] = 0x1a;
+        assert!(matches!(
+            cpu.decode_instruction(),
+            Err(InvalidMemoryAccess::UnprivilegedOpcode)
+        ));
+    }
+
+    #[test]
+    fn cpu_syscall() {
+        // usr; syscall; and a handler at 0x3000 that returns straight away
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0..2].copy_from_slice(&[0x17, 0x21]);
+        cpu.addressing.memory[0x78..0x7c].copy_from_slice(&0x3000u32.to_le_bytes());
+        cpu.addressing.memory[0x3000] = 0x1a;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.step();
+        cpu.xs[R_SP] = 0x6000;
+
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x3000);
+        assert_eq!(cpu.xs[R_INT], SYSCALL | NMI_BIT);
+        assert_eq!(cpu.xs[R_SP], 0x8000 - trap::TrapFrame::SIZE);
+        assert!(!cpu.get_flag(F_USER_RING));
+        assert_eq!(cpu.last_fault(), None);
+
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 2);
+        assert_eq!(cpu.xs[R_SP], 0x6000);
+        assert!(cpu.get_flag(F_USER_RING));
+    }
+
+    #[test]
+    fn cpu_nested_interrupts() {
+        // Handlers for lines 1, 3, and 5 at 0x4000, 0x3000, and 0x5000
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        for (line, handler) in [(1, 0x4000u32), (3, 0x3000), (5, 0x5000)].iter() {
+            let vector = (IRQ_VECTORS + line) as usize * 4;
+            cpu.addressing.memory[vector..vector + 4].copy_from_slice(&handler.to_le_bytes());
+        }
+        cpu.addressing.memory[0x3000] = 0x10;
+        cpu.addressing.memory[0x4000] = 0x1a;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.set_flag(F_INTERRUPT_ENABLE, true);
+        cpu.set_flag(F_NESTED_INTERRUPTS, true);
+
+        cpu.irq(3);
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x3000);
+        assert!(cpu.get_flag(F_INTERRUPT_ENABLE));
+
+        // A lower priority line waits, a higher one preempts the handler
+        cpu.irq(5);
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x3001);
+        cpu.irq(1);
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x4000);
+        assert_eq!(cpu.interrupts.in_service(), 0b1010);
+
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x3001);
+        assert_eq!(cpu.xs[R_INT], 3);
+        assert_eq!(cpu.interrupts.in_service(), 0b1000);
+        assert_eq!(cpu.pending_irqs(), 0b100000);
+
+        // The handler reads the lines in service with ext 0b01; mov x1, s16
+        cpu.addressing.memory[0x3001..0x3005].copy_from_slice(&[0x3e, 0x01, 0x9b, 0x01]);
+        assert_eq!(cpu.step(), StepOutcome::Executed { opcode: 0x9b, cycles: 1 });
+        assert_eq!(cpu.xs[1], 0b1000);
+
+        // There is no system register 19
+        cpu.addressing.memory[0x3005..0x3009].copy_from_slice(&[0x3e, 0x01, 0x9b, 0x31]);
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Faulted(InvalidMemoryAccess::InvalidRegister(19))
+        );
+    }
+
+    #[test]
+    fn cpu_irq_priorities() {
+        // ext 0b10; mov s17, x1; ext 0b10; mov s18, x2, and handlers for lines 1 and 5
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [0x3e, 0x02, 0x9a, 0x11, 0x3e, 0x02, 0x9a, 0x22];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        for (line, handler) in [(1, 0x4000u32), (5, 0x5000)].iter() {
+            let vector = (IRQ_VECTORS + line) as usize * 4;
+            cpu.addressing.memory[vector..vector + 4].copy_from_slice(&handler.to_le_bytes());
+        }
+        cpu.addressing.memory[0x5000] = 0x10;
+        cpu.xs[R_SP] = 0x8000;
+
+        // Line 5 goes to level 0 and the threshold only lets level 0 through
+        cpu.xs[1] = 0x76043210;
+        cpu.xs[2] = 1;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.irq_priority(5), Some(0));
+        assert_eq!(cpu.irq_priority(8), None);
+
+        cpu.set_flag(F_INTERRUPT_ENABLE, true);
+        cpu.irq(1);
+        cpu.irq(5);
+        assert_eq!(cpu.step(), StepOutcome::Interrupted(5));
+        cpu.set_flag(F_INTERRUPT_ENABLE, true);
+        cpu.step();
+        assert_eq!(cpu.xs[R_PC], 0x5001);
+        assert_eq!(cpu.pending_irqs(), 0b10);
+
+        cpu.set_irq_priority(1, 0);
+        assert_eq!(cpu.step(), StepOutcome::Interrupted(1));
+    }
+
+    #[test]
+    fn cpu_halt() {
+        // hlt; clc, and a handler for line 2 at 0x3000
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0..2].copy_from_slice(&[0x1b, 0x10]);
+        cpu.addressing.memory[0x88..0x8c].copy_from_slice(&0x3000u32.to_le_bytes());
+        cpu.xs[R_SP] = 0x8000;
+        cpu.set_flag(F_INTERRUPT_ENABLE, true);
+        cpu.step();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.step(), StepOutcome::Halted);
+        assert_eq!(cpu.xs[R_PC], 1);
+
+        cpu.irq(2);
+        assert_eq!(cpu.step(), StepOutcome::Interrupted(2));
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.current_trap_frame().unwrap().pc, 1);
+
+        // Halting with interrupts disabled ends a run
+        cpu.xs[R_PC] = 0;
+        assert_eq!(cpu.run(100), 2);
+    }
+
+    #[test]
+    fn cpu_shadow_stack() {
+        // call 0x100; at 0x100: ret
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0..5].copy_from_slice(&[0x18, 0x00, 0x01, 0x00, 0x00]);
+        cpu.addressing.memory[0x100] = 0x19;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.shadow_sp = 0x9000;
+        cpu.set_flag(F_SHADOW_STACK, true);
+
+        cpu.decode_instruction().unwrap();
+        assert_eq!(cpu.shadow_sp, 0x8ffc);
+        assert_eq!(read_word(&cpu, 0x8ffc), 5);
+        cpu.decode_instruction().unwrap();
+        assert_eq!(cpu.xs[R_PC], 5);
+        assert_eq!(cpu.shadow_sp, 0x9000);
+
+        // Overwriting the return address on the stack is caught by ret
+        cpu.xs[R_PC] = 0;
+        cpu.decode_instruction().unwrap();
+        cpu.addressing.memory[0x8000 - 8] = 0x42;
+        assert!(matches!(
+            cpu.decode_instruction(),
+            Err(InvalidMemoryAccess::ShadowStackMismatch)
+        ));
+    }
+
+    #[test]
+    fn cpu_legacy_stack() {
+        // call 0x100; at 0x100: ret
+        for &legacy in [false, true].iter() {
+            let mut cpu = Cpu::new(SimpleAddress::default());
+            cpu.addressing.memory[0..5].copy_from_slice(&[0x18, 0x00, 0x01, 0x00, 0x00]);
+            cpu.addressing.memory[0x100] = 0x19;
+            cpu.xs[R_SP] = 0x8000;
+            cpu.xs[R_BASE] = 0x1234;
+            cpu.set_legacy_stack(legacy);
+
+            cpu.decode_instruction().unwrap();
+            assert_eq!(cpu.xs[R_SP], 0x7ff8);
+            assert_eq!(cpu.xs[R_BASE], 0x7ff8);
+            let frame = if legacy { 0x7ff9 } else { 0x7ff8 };
+            assert_eq!(read_word(&cpu, frame), 5);
+            assert_eq!(read_word(&cpu, frame + 4), 0x1234);
+
+            cpu.decode_instruction().unwrap();
+            assert_eq!(cpu.xs[R_PC], 5);
+            assert_eq!(cpu.xs[R_SP], 0x8000);
+            assert_eq!(cpu.xs[R_BASE], 0x1234);
+        }
+    }
+
+    #[test]
+    fn cpu_register_extension() {
+        let program = [
+            0x3e, 0x01, 0x43, 0x05, 0, 0, 0, // x19 = 5
+            0x3e, 0x03, 0x80, 0x33, // x19 += x19
+            0x3e, 0x02, 0x8e, 0x03, // mov x0, x19
+        ];
+        let mut memory = SimpleAddress::default();
+        memory.load(0, &program);
+        let mut cpu = Cpu::<_, 32>::with_registers(memory);
+        for _ in 0..3 {
+            cpu.decode_instruction().unwrap();
+        }
+        assert_eq!(cpu.xs[19], 10);
+        assert_eq!(cpu.xs[0], 10);
+        assert_eq!(cpu.state().xs.len(), 32);
+
+        // The default register file has no registers above x15
+        let mut cpu = Cpu::with_program(&program);
+        assert!(matches!(
+            cpu.decode_instruction(),
+            Err(InvalidMemoryAccess::InvalidRegister(19))
+        ));
+    }
 }
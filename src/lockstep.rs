@@ -0,0 +1,190 @@
+// Deterministic lock-step execution of several machines
+// Machines advance together in quanta of a fixed number of cycles, always in the order they were
+// added. Frames sent between their network ports (see devices/net.rs) are only exchanged between
+// quanta, in order of the sending machine and then of sending, so a frame becomes visible at the
+// start of the next quantum no matter when in the quantum it was sent. Runs of the same group are
+// therefore reproducible, cycle for cycle.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::devices::{Frame, NetPort};
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+    // Quantum the frame was sent in
+    pub quantum: u64,
+    pub frame: Frame,
+}
+
+struct Member<T, const N: usize>
+where
+    T: Address,
+{
+    cpu: Cpu<T, N>,
+    port: Option<Rc<RefCell<NetPort>>>,
+}
+
+pub struct Lockstep<T, const N: usize = 16>
+where
+    T: Address,
+{
+    members: Vec<Member<T, N>>,
+    quantum: u64,
+    quanta: u64,
+    deliveries: Vec<Delivery>,
+}
+
+impl<T, const N: usize> Lockstep<T, N>
+where
+    T: Address,
+{
+    // Panics if quantum is 0
+    pub fn new(quantum: u64) -> Lockstep<T, N> {
+        assert!(quantum > 0, "quantum must be at least one cycle");
+        Lockstep {
+            members: vec![],
+            quantum,
+            quanta: 0,
+            deliveries: vec![],
+        }
+    }
+
+    // Adds a machine, returning its index, which is also the address of its port
+    // The port should be mapped into the machine's memory, the group keeps a handle to it
+    pub fn add(&mut self, cpu: Cpu<T, N>, port: Option<Rc<RefCell<NetPort>>>) -> u32 {
+        self.members.push(Member { cpu, port });
+        self.members.len() as u32 - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn cpu(&self, index: u32) -> Option<&Cpu<T, N>> {
+        self.members.get(index as usize).map(|member| &member.cpu)
+    }
+
+    pub fn cpu_mut(&mut self, index: u32) -> Option<&mut Cpu<T, N>> {
+        self.members
+            .get_mut(index as usize)
+            .map(|member| &mut member.cpu)
+    }
+
+    // Quanta run so far
+    pub fn quanta(&self) -> u64 {
+        self.quanta
+    }
+
+    // Every frame delivered so far, in delivery order
+    pub fn deliveries(&self) -> &[Delivery] {
+        &self.deliveries
+    }
+
+    // Runs every machine for one quantum, then exchanges frames
+    // Machines that have shut down stay stopped but still receive frames
+    pub fn run_quantum(&mut self) {
+        for member in self.members.iter_mut() {
+            for _ in 0..self.quantum {
+                if member.cpu.is_shutdown() {
+                    break;
+                }
+                member.cpu.step();
+            }
+        }
+
+        let mut sent = vec![];
+        for (source, member) in self.members.iter().enumerate() {
+            if let Some(port) = member.port.as_ref() {
+                for mut frame in port.borrow_mut().take_sent() {
+                    frame.source = source as u32;
+                    sent.push(frame);
+                }
+            }
+        }
+
+        // Frames to machines without a port, or to no machine, are dropped
+        for frame in sent {
+            let port = self
+                .members
+                .get(frame.dest as usize)
+                .and_then(|member| member.port.as_ref());
+            if let Some(port) = port {
+                port.borrow_mut().deliver(frame.clone());
+                self.deliveries.push(Delivery {
+                    quantum: self.quanta,
+                    frame,
+                });
+            }
+        }
+        self.quanta += 1;
+    }
+
+    // Runs quanta until every machine has shut down or the limit is reached, returning how many
+    // were run
+    pub fn run(&mut self, max_quanta: u64) -> u64 {
+        let mut run = 0;
+        while run < max_quanta && !self.members.iter().all(|member| member.cpu.is_shutdown()) {
+            self.run_quantum();
+            run += 1;
+        }
+        run
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::SimpleAddress;
+
+    fn machine(program: &[u8]) -> (Cpu<Bus>, Rc<RefCell<NetPort>>) {
+        let port = Rc::new(RefCell::new(NetPort::new()));
+        let mut bus = Bus::new();
+        bus.map(0, 0x1000, Box::new(SimpleAddress::new(0x1000)));
+        bus.map_mmio(0x10000, 0x10, Box::new(port.clone()));
+        let mut cpu = Cpu::new(bus);
+        cpu.poke_range(0, program);
+        (cpu, port)
+    }
+
+    #[test]
+    fn frames_between_machines() {
+        let mut group = Lockstep::new(10);
+
+        // ldi x2, 1; st x2, TX_DEST; ldi x1, 0x2a; stb x1, TX_DATA; stb x1, TX_SEND
+        let (cpu, port) = machine(&[
+            0x42, 0x01, 0x00, 0x00, 0x00, 0xc2, 0x08, 0x00, 0x01, 0x00, 0x41, 0x2a, 0x00, 0x00,
+            0x00, 0xe1, 0x0c, 0x00, 0x01, 0x00, 0xe1, 0x0d, 0x00, 0x01, 0x00,
+        ]);
+        assert_eq!(group.add(cpu, Some(port)), 0);
+
+        // Polls RX_DATA: ld x3, RX_DATA; bz 0x0
+        let (cpu, port) = machine(&[0x63, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(group.add(cpu, Some(port)), 1);
+
+        // The frame is sent in the first quantum but only arrives after it
+        group.run_quantum();
+        assert_eq!(group.cpu(1).unwrap().xs[3], 0);
+        assert_eq!(
+            group.deliveries(),
+            &[Delivery {
+                quantum: 0,
+                frame: Frame {
+                    source: 0,
+                    dest: 1,
+                    data: vec![0x2a]
+                }
+            }]
+        );
+
+        assert_eq!(group.run(1), 1);
+        assert_eq!(group.cpu(1).unwrap().xs[3], 0x2a);
+        assert_eq!(group.quanta(), 2);
+    }
+}
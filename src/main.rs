@@ -0,0 +1,154 @@
+// Command line interface
+//
+// cpuwu monitor [file] [base]
+//     Loads a raw binary at base (hex, 0 by default) or an object file at its own base, points pc
+//     at it, and reads monitor commands from stdin
+// cpuwu serve <address> [file] [base]
+//     Loads a program the same way and serves the remote control protocol on address, see
+//     remote.rs
+// cpuwu server <address>
+//     Serves any number of named machines, each with 16MB of memory, see server.rs
+// cpuwu run [file] [base]
+//     Loads a program the same way and runs it with the terminal attached to a console device at
+//     CONSOLE_BASE, see devices/console.rs, until it shuts down or ctrl-] is typed
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use cpuwu::bus::Bus;
+use cpuwu::devices::console::{Console, RawMode, CONSOLE_IRQ};
+use cpuwu::monitor::Monitor;
+use cpuwu::object::{self, Object};
+use cpuwu::remote::Session;
+use cpuwu::server::Server;
+use cpuwu::{Address, Cpu, SimpleAddress, StepOutcome};
+
+const CONSOLE_BASE: u32 = 0xffff_0000;
+
+// Typed to stop a run, as ctrl-c reaches the guest in raw mode
+const ESCAPE: u8 = 0x1d;
+
+fn usage() -> ! {
+    eprintln!("usage: cpuwu monitor [file] [base]");
+    eprintln!("       cpuwu serve <address> [file] [base]");
+    eprintln!("       cpuwu server <address>");
+    eprintln!("       cpuwu run [file] [base]");
+    process::exit(2);
+}
+
+// Loads [file] [base] from args into a machine
+fn load<T: Address>(mut cpu: Cpu<T>, args: &[String]) -> Result<Cpu<T>, String> {
+    if let Some(path) = args.first() {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let object = if bytes.starts_with(object::MAGIC) {
+            Object::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e))?
+        } else {
+            let base = match args.get(1) {
+                Some(base) => u32::from_str_radix(base.trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("bad base {}", base))?,
+                None => 0,
+            };
+            let mut object = Object::new(base);
+            object.push_bytes(&bytes);
+            object
+        };
+        if !cpu.poke_range(object.base, &object.code) {
+            return Err(format!("{} does not fit in memory", path));
+        }
+        let mut state = cpu.state();
+        state.xs[cpuwu::R_PC] = object.base;
+        cpu.set_state(&state);
+    }
+    Ok(cpu)
+}
+
+fn monitor(args: &[String]) -> Result<(), String> {
+    let mut cpu = load(Cpu::new(SimpleAddress::default()), args)?;
+    let mut monitor = Monitor::new();
+    let stdin = io::stdin();
+    loop {
+        print!("\\ ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Ok(());
+        }
+        match line.trim() {
+            "q" | "quit" => return Ok(()),
+            line => match monitor.execute(&mut cpu, line) {
+                Ok(out) => print!("{}", out),
+                Err(e) => println!("? {}", e),
+            },
+        }
+    }
+}
+
+fn serve(args: &[String]) -> Result<(), String> {
+    let address = args.first().unwrap_or_else(|| usage());
+    let mut session = Session::new(load(Cpu::new(SimpleAddress::default()), &args[1..])?);
+    session
+        .listen(address.as_str())
+        .map_err(|e| format!("{}: {}", address, e))
+}
+
+fn server(args: &[String]) -> Result<(), String> {
+    let address = args.first().unwrap_or_else(|| usage());
+    Server::new(|| Cpu::new(SimpleAddress::default()))
+        .listen(address.as_str())
+        .map_err(|e| format!("{}: {}", address, e))
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let memory = SimpleAddress::default();
+    let len = memory.size() as u32;
+    let console = Rc::new(RefCell::new(Console::stdio().escape(ESCAPE)));
+    let mut bus = Bus::new();
+    bus.map(0, len, Box::new(memory));
+    bus.map_mmio(CONSOLE_BASE, 3, Box::new(console.clone()));
+    let mut cpu = load(Cpu::new(bus), args)?;
+
+    let _raw = RawMode::enable().map_err(|e| format!("terminal: {}", e))?;
+    loop {
+        let mut console = console.borrow_mut();
+        if console.poll() {
+            cpu.irq(CONSOLE_IRQ);
+        }
+        if console.escaped() {
+            return Ok(());
+        }
+        drop(console);
+        match cpu.step() {
+            StepOutcome::Shutdown => return Ok(()),
+
+            // Waiting for the console
+            StepOutcome::Halted => thread::sleep(Duration::from_millis(1)),
+            _ => (),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("monitor") => monitor(&args[1..]),
+        Some("serve") => serve(&args[1..]),
+        Some("server") => server(&args[1..]),
+        Some("run") => run(&args[1..]),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("cpuwu: {}", e);
+        process::exit(1);
+    }
+}
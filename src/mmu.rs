@@ -0,0 +1,210 @@
+// Memory management unit
+// Translates virtual addresses through the memory map, a two level table in physical memory. The
+// root (the memmap register) holds a 4 byte entry for every 16M of address space, indexed by the
+// top byte of the address, pointing at a second level table or 0 if there is none. Second level
+// entries, indexed by the next byte, map 64K pages:
+// 0xpaaaaaaa
+// p - 1 if present, then the read, write, and execute permissions
+// a - physical address the page starts at
+//
+// The cpu owns one and uses it for every access while the memory map is enabled, but it only
+// depends on the memory the tables are read from, so other bus masters (eg a DMA engine working
+// on virtual addresses) and tools can use their own. Entries carry no ring bits, so both rings see
+// the same permissions. Top level entries are cached, see walkcache.rs.
+
+use crate::translate::Access;
+use crate::walkcache::{TopLevelCache, WalkStats};
+use crate::{Address, Cpu, InvalidMemoryAccess};
+
+#[derive(Debug, Clone)]
+pub struct Mmu {
+    root: u32,
+    asid: u32,
+    cache: TopLevelCache,
+}
+
+impl Default for Mmu {
+    fn default() -> Mmu {
+        Mmu::new(0)
+    }
+}
+
+impl Mmu {
+    pub fn new(root: u32) -> Mmu {
+        Mmu {
+            root,
+            asid: 0,
+            cache: TopLevelCache::new(),
+        }
+    }
+
+    pub fn root(&self) -> u32 {
+        self.root
+    }
+
+    // Switches to another memory map, emptying the cache
+    pub fn set_root(&mut self, root: u32) {
+        self.root = root;
+        self.cache.clear();
+    }
+
+    // Address space id of the current memory map, kept for software and not used in translation
+    pub fn asid(&self) -> u32 {
+        self.asid
+    }
+
+    pub fn set_asid(&mut self, asid: u32) {
+        self.asid = asid;
+    }
+
+    // Forgets everything read from the tables
+    pub fn flush(&mut self) {
+        self.cache.clear();
+    }
+
+    // Keeps the cache in step with a write to physical memory, which may be to the tables
+    pub fn on_write(&mut self, addr: u32) {
+        self.cache.on_write(addr);
+    }
+
+    pub fn stats(&self) -> WalkStats {
+        self.cache.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.cache.stats = WalkStats::default();
+    }
+
+    // Translates a virtual address for an access, returning the physical address
+    pub fn translate<A>(
+        &mut self,
+        memory: &mut A,
+        addr: u32,
+        access: Access,
+    ) -> Result<u32, InvalidMemoryAccess>
+    where
+        A: Address + ?Sized,
+    {
+        self.check(memory, addr, access.permissions())
+    }
+
+    // Translates an address for an access needing all of the permission bits given
+    pub(crate) fn check<A>(
+        &mut self,
+        memory: &mut A,
+        addr: u32,
+        permissions: u8,
+    ) -> Result<u32, InvalidMemoryAccess>
+    where
+        A: Address + ?Sized,
+    {
+        match self.walk(memory, addr, false) {
+            None => Err(InvalidMemoryAccess::UsedFreePage),
+            Some((p, _)) if p & permissions != permissions => {
+                Err(InvalidMemoryAccess::InvalidPermissions(p, permissions))
+            }
+            Some((_, addr)) => Ok(addr),
+        }
+    }
+
+    // Looks up a virtual address, returning the permission bits and physical address, or None if
+    // it is not mapped
+    // Debug walks read the tables with read_debug and bypass the cache, guest walks take the top
+    // level entry from the cache when they can
+    pub fn walk<A>(&mut self, memory: &mut A, addr: u32, debug: bool) -> Option<(u8, u32)>
+    where
+        A: Address + ?Sized,
+    {
+        let root = self.root;
+        let cache = &mut self.cache;
+        let mut read_word = |addr: u32| {
+            (0..4).fold(0, |acc, i| {
+                let addr = addr.wrapping_add(i);
+                let byte = if debug {
+                    memory.read_debug(addr)
+                } else {
+                    memory.read(addr)
+                };
+                acc | (byte as u32) << (8 * i)
+            })
+        };
+
+        let index = addr >> 24;
+        let table_addr = if debug {
+            read_word(root.wrapping_add(index))
+        } else {
+            cache.stats.walks += 1;
+            match cache.get(root, index) {
+                Some(table_addr) => {
+                    cache.stats.reads_saved += 4;
+                    table_addr
+                }
+                None => {
+                    let table_addr = read_word(root.wrapping_add(index));
+                    cache.insert(index, table_addr);
+                    cache.stats.table_reads += 4;
+                    table_addr
+                }
+            }
+        };
+        if table_addr == 0 {
+            return None;
+        }
+
+        if !debug {
+            cache.stats.table_reads += 4;
+        }
+        let entry =
+            read_word(table_addr.wrapping_add(addr >> 16 & 0xff)).wrapping_add(addr & 0xffff);
+        let (p, phys) = (((entry & 0xf0000000) >> 28) as u8, entry & 0x0fffffff);
+        if p & 0x08 == 0 {
+            None
+        } else {
+            Some((p, phys))
+        }
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // The cpu's memory map is changed through its registers, see set_state
+    pub fn mmu(&self) -> &Mmu {
+        &self.mmu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn standalone() {
+        // Map virtual 0x01020000-0x0102ffff read only to 0x80000
+        let mut memory = SimpleAddress::new(0x1000);
+        memory.load(0x101, &0x200u32.to_le_bytes());
+        memory.load(0x202, &0xc0080000u32.to_le_bytes());
+
+        let mut mmu = Mmu::new(0x100);
+        assert_eq!(
+            mmu.translate(&mut memory, 0x01020010, Access::Read),
+            Ok(0x80010)
+        );
+        assert_eq!(
+            mmu.translate(&mut memory, 0x01020010, Access::Write),
+            Err(InvalidMemoryAccess::InvalidPermissions(0x0c, 0b010))
+        );
+        assert_eq!(
+            mmu.translate(&mut memory, 0x01030000, Access::Read),
+            Err(InvalidMemoryAccess::UsedFreePage)
+        );
+
+        mmu.set_root(0x800);
+        assert_eq!(
+            mmu.translate(&mut memory, 0x01020010, Access::Read),
+            Err(InvalidMemoryAccess::UsedFreePage)
+        );
+    }
+}
@@ -0,0 +1,285 @@
+// Machine code monitor
+// A small line based interface for poking at a machine, in the spirit of WozMon. Addresses and
+// bytes are hex, with or without 0x. Memory goes through peek and poke, so it is virtual when the
+// memory map is enabled and devices are not disturbed by examining them.
+//
+// 1000            examine a byte
+// 1000.101f       examine a range
+// 1000: 41 05 00  store bytes
+// r               show the registers
+// r x3 10         set a register (x0-x31, f0-f31, or flags), the value is hex or a float
+// a 1000 ldi x1, 5
+//                 assemble a line in place, operands as in the disassembly
+// l 1000 [n]      disassemble n instructions, 8 by default
+// b [1000]        set a breakpoint, or list them
+// d 1000          delete a breakpoint
+// s [n]           single step n times, once by default
+// c [n]           continue until a breakpoint, shutdown, or n steps (1000000 by default)
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::decode::{self, Instruction};
+use crate::{Address, Cpu, StepOutcome, R_PC};
+
+const DEFAULT_CONTINUE_STEPS: u64 = 1_000_000;
+
+#[derive(Debug, Default, Clone)]
+pub struct Monitor {
+    breakpoints: BTreeSet<u32>,
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    // Runs one command, returning what to print or why it failed
+    pub fn execute<T, const N: usize>(
+        &mut self,
+        cpu: &mut Cpu<T, N>,
+        line: &str,
+    ) -> Result<String, String>
+    where
+        T: Address,
+    {
+        let line = line.trim();
+        let (command, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let words: Vec<&str> = args.split_whitespace().collect();
+
+        match command {
+            "" => Ok(String::new()),
+            "r" if words.is_empty() => Ok(registers(cpu)),
+            "r" => set_register(cpu, &words),
+            "a" => {
+                let (addr, source) = match args.find(char::is_whitespace) {
+                    Some(i) => (hex(&args[..i])?, &args[i..]),
+                    None => return Err("usage: a <addr> <instruction>".to_string()),
+                };
+                let instruction: Instruction = source.parse().map_err(|e| format!("{}", e))?;
+                let mut bytes = vec![];
+                instruction.encode(&mut bytes);
+                if !cpu.poke_range(addr, &bytes) {
+                    return Err(format!("{:x} is not mapped", addr));
+                }
+                Ok(listing(cpu, addr, 1))
+            }
+            "l" => {
+                let addr = words.first().map_or(Ok(cpu.xs[R_PC]), |w| hex(w))?;
+                let count = words.get(1).map_or(Ok(8), |w| decimal(w))?;
+                Ok(listing(cpu, addr, count))
+            }
+            "b" if words.is_empty() => Ok(self
+                .breakpoints
+                .iter()
+                .map(|addr| format!("{:08x}\n", addr))
+                .collect()),
+            "b" => {
+                self.breakpoints.insert(hex(words[0])?);
+                Ok(String::new())
+            }
+            "d" => match self.breakpoints.remove(&hex(words.first().unwrap_or(&""))?) {
+                true => Ok(String::new()),
+                false => Err("no breakpoint there".to_string()),
+            },
+            "s" => {
+                let steps = words.first().map_or(Ok(1), |w| decimal(w))?;
+                for _ in 0..steps {
+                    if cpu.step() == StepOutcome::Shutdown {
+                        return Ok("shutdown\n".to_string());
+                    }
+                }
+                Ok(listing(cpu, cpu.xs[R_PC], 1))
+            }
+            "c" => {
+                let steps = words
+                    .first()
+                    .map_or(Ok(DEFAULT_CONTINUE_STEPS), |w| decimal(w))?;
+                for _ in 0..steps {
+                    if cpu.step() == StepOutcome::Shutdown {
+                        return Ok("shutdown\n".to_string());
+                    }
+                    if self.breakpoints.contains(&cpu.xs[R_PC]) {
+                        return Ok(format!("break\n{}", listing(cpu, cpu.xs[R_PC], 1)));
+                    }
+                }
+                Ok(listing(cpu, cpu.xs[R_PC], 1))
+            }
+            _ => examine(cpu, line),
+        }
+    }
+}
+
+fn hex(word: &str) -> Result<u32, String> {
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u32::from_str_radix(digits, 16).map_err(|_| format!("bad number {}", word))
+}
+
+fn decimal(word: &str) -> Result<u64, String> {
+    word.parse().map_err(|_| format!("bad count {}", word))
+}
+
+fn registers<T, const N: usize>(cpu: &Cpu<T, N>) -> String
+where
+    T: Address,
+{
+    let mut out = String::new();
+    for (i, x) in cpu.xs.iter().enumerate() {
+        let end = if i % 4 == 3 { "\n" } else { "  " };
+        write!(out, "x{:<2} {:08x}{}", i, x, end).unwrap();
+    }
+    for (i, f) in cpu.fs.iter().enumerate() {
+        let end = if i % 4 == 3 { "\n" } else { "  " };
+        write!(out, "f{:<2} {:<12}{}", i, f, end).unwrap();
+    }
+    writeln!(out, "flags {:08x}", cpu.flags).unwrap();
+    out
+}
+
+fn set_register<T, const N: usize>(cpu: &mut Cpu<T, N>, words: &[&str]) -> Result<String, String>
+where
+    T: Address,
+{
+    let (name, value) = match words {
+        [name, value] => (*name, *value),
+        _ => return Err("usage: r <register> <value>".to_string()),
+    };
+    let index = |prefix| {
+        name.strip_prefix(prefix)
+            .and_then(|i| i.parse::<usize>().ok())
+            .filter(|&i| i < N)
+    };
+
+    if name == "flags" {
+        cpu.flags = hex(value)?;
+    } else if let Some(i) = index('x') {
+        cpu.xs[i] = hex(value)?;
+    } else if let Some(i) = index('f') {
+        cpu.fs[i] = value.parse().map_err(|_| format!("bad float {}", value))?;
+    } else {
+        return Err(format!("no register {}", name));
+    }
+    Ok(String::new())
+}
+
+fn listing<T, const N: usize>(cpu: &mut Cpu<T, N>, mut addr: u32, count: u64) -> String
+where
+    T: Address,
+{
+    let mut out = String::new();
+    for _ in 0..count {
+        let start = addr;
+        let mut bytes = vec![];
+        let instruction = decode::decode(4, || {
+            let byte = cpu.peek(addr).ok_or(())?;
+            bytes.push(byte);
+            addr = addr.wrapping_add(1);
+            Ok(byte)
+        });
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        match instruction {
+            Ok(instruction) => {
+                writeln!(out, "{:08x}: {:<15}  {}", start, hex.join(" "), instruction).unwrap()
+            }
+            Err(()) => {
+                writeln!(out, "{:08x}: not mapped", start).unwrap();
+                break;
+            }
+        }
+    }
+    out
+}
+
+// "1000", "1000.101f", or "1000: 01 02"
+fn examine<T, const N: usize>(cpu: &mut Cpu<T, N>, line: &str) -> Result<String, String>
+where
+    T: Address,
+{
+    if let Some(i) = line.find(':') {
+        let addr = hex(line[..i].trim())?;
+        let data = line[i + 1..]
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("bad byte {}", b)))
+            .collect::<Result<Vec<u8>, String>>()?;
+        return match cpu.poke_range(addr, &data) {
+            true => Ok(String::new()),
+            false => Err(format!("{:x} is not mapped", addr)),
+        };
+    }
+
+    let (start, end) = match line.find('.') {
+        Some(i) => (hex(&line[..i])?, hex(&line[i + 1..])?),
+        None => (hex(line)?, hex(line)?),
+    };
+    if end < start {
+        return Err("range ends before it starts".to_string());
+    }
+
+    let mut out = String::new();
+    let mut addr = start;
+    loop {
+        if addr == start || addr % 8 == 0 {
+            if addr != start {
+                out.push('\n');
+            }
+            write!(out, "{:08x}:", addr).unwrap();
+        }
+        match cpu.peek(addr) {
+            Some(byte) => write!(out, " {:02x}", byte).unwrap(),
+            None => out.push_str(" --"),
+        }
+        if addr == end {
+            break;
+        }
+        addr += 1;
+    }
+    out.push('\n');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn session() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let mut monitor = Monitor::new();
+        let mut run = |line| monitor.execute(&mut cpu, line);
+
+        assert_eq!(
+            run("a 0 ldi x1, 0x2a"),
+            Ok("00000000: 41 2a 00 00 00   ldi x1, 0x2a\n".to_string())
+        );
+        assert_eq!(run("5: 96 12"), Ok(String::new()));
+        assert_eq!(
+            run("0.8"),
+            Ok("00000000: 41 2a 00 00 00 96 12 00\n00000008: 00\n".to_string())
+        );
+        assert_eq!(run("r x2 100"), Ok(String::new()));
+        assert_eq!(run("b 7"), Ok(String::new()));
+        assert_eq!(
+            run("c"),
+            Ok("break\n00000007: 00 00 00 00 00   bz 0x0\n".to_string())
+        );
+        assert_eq!(run("100.103"), Ok("00000100: 2a 00 00 00\n".to_string()));
+        assert!(run("r x3").is_err());
+        assert!(run("zz").is_err());
+    }
+}
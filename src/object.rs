@@ -0,0 +1,571 @@
+// Relocatable object files
+// An object holds machine code built to run at a base address, along with relocation records for
+// every absolute 32 bit operand that points into the object. Relocating an object moves it to a
+// new base by adding the difference between the bases to each of those operands, so a program can
+// be built once and loaded wherever there is room for it.
+//
+// All integers are little endian. The file layout follows save states:
+//
+// Header:
+// 0x00 magic "CPUWUOBJ"
+// 0x08 u16 major version
+// 0x0a u16 minor version
+// 0x0c u32 base address
+// 0x10 u32 number of sections
+//
+// Section:
+// 0x00 4 byte tag
+// 0x04 u32 flags, bit 0 set if a reader must understand the section to load the file
+// 0x08 u64 payload length
+// 0x10 payload
+//
+// Sections:
+// "CODE" machine code
+// "RELO" u32 offset into the code of each relocated operand
+// "LINE" optional line table:
+//     u32 number of files, then for each a u32 length and the file name in UTF-8
+//     u32 number of rows, then for each a u32 code offset, u32 file index, and u32 line
+//     A row covers the code from its offset up to the next row
+// "SYMS" optional symbols:
+//     u32 number of symbols, then for each a u32 offset from the base, u32 length, and the name
+//     in UTF-8
+
+use std::fmt;
+
+use crate::decode::{self, Instruction};
+use crate::savestate::SECTION_REQUIRED;
+use crate::symbols::SymbolTable;
+use crate::{Address, Cpu};
+
+pub const MAGIC: &[u8; 8] = b"CPUWUOBJ";
+pub const MAJOR_VERSION: u16 = 1;
+pub const MINOR_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectError {
+    BadMagic,
+    UnsupportedVersion(u16, u16),
+    Truncated,
+
+    // A relocation does not leave room for a 32 bit operand inside the code
+    RelocationOutOfRange(u32),
+
+    UnknownRequiredSection([u8; 4]),
+
+    // A line table row names a file that is not in the table, or a file name is not UTF-8
+    BadLineTable,
+
+    // A symbol name is not UTF-8
+    BadSymbolTable,
+}
+
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            ObjectError::BadMagic => write!(f, "not an object file"),
+            ObjectError::UnsupportedVersion(major, minor) => {
+                write!(f, "unsupported object version {}.{}", major, minor)
+            }
+            ObjectError::Truncated => write!(f, "object file is truncated"),
+            ObjectError::RelocationOutOfRange(offset) => {
+                write!(f, "relocation at {:#x} is outside the code", offset)
+            }
+            ObjectError::UnknownRequiredSection(tag) => write!(
+                f,
+                "unknown required section {:?}",
+                String::from_utf8_lossy(tag)
+            ),
+            ObjectError::BadLineTable => write!(f, "line table is malformed"),
+            ObjectError::BadSymbolTable => write!(f, "symbol table is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub base: u32,
+    pub code: Vec<u8>,
+
+    // Offsets into the code of absolute 32 bit operands that point into the object
+    pub relocations: Vec<u32>,
+
+    pub lines: LineTable,
+
+    // Addresses of symbols move with the object when it is relocated
+    pub symbols: SymbolTable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// Maps code offsets to the source lines they were assembled from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineTable {
+    files: Vec<String>,
+
+    // (offset, file index, line) sorted by offset
+    rows: Vec<(u32, u32, u32)>,
+}
+
+impl LineTable {
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    // Code from offset on comes from a source line, until the next row
+    // A row at the same offset as the last one replaces it, so empty lines leave no rows
+    // Panics if offset is before the last row
+    pub fn add(&mut self, offset: u32, file: &str, line: u32) {
+        let file = match self.files.iter().position(|f| f == file) {
+            Some(i) => i,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        } as u32;
+
+        match self.rows.last_mut() {
+            Some(last) if last.0 == offset => *last = (offset, file, line),
+            Some(last) => {
+                assert!(last.0 < offset, "line table rows must be added in order");
+                self.rows.push((offset, file, line));
+            }
+            None => self.rows.push((offset, file, line)),
+        }
+    }
+
+    pub fn lookup(&self, offset: u32) -> Option<SourceLocation<'_>> {
+        let i = self
+            .rows
+            .partition_point(|row| row.0 <= offset)
+            .checked_sub(1)?;
+        let (_, file, line) = self.rows[i];
+        Some(SourceLocation {
+            file: &self.files[file as usize],
+            line,
+        })
+    }
+
+    // Offsets at which the code for a source line starts
+    pub fn offsets(&self, file: &str, line: u32) -> Vec<u32> {
+        self.rows
+            .iter()
+            .filter(|row| row.2 == line && self.files[row.1 as usize] == file)
+            .map(|row| row.0)
+            .collect()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = (self.files.len() as u32).to_le_bytes().to_vec();
+        for file in self.files.iter() {
+            out.extend_from_slice(&(file.len() as u32).to_le_bytes());
+            out.extend_from_slice(file.as_bytes());
+        }
+        out.extend_from_slice(&(self.rows.len() as u32).to_le_bytes());
+        for &(offset, file, line) in self.rows.iter() {
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&file.to_le_bytes());
+            out.extend_from_slice(&line.to_le_bytes());
+        }
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<LineTable, ObjectError> {
+        let mut r = Reader { bytes, pos: 0 };
+        let mut table = LineTable::default();
+        for _ in 0..r.u32()? {
+            let len = r.u32()? as usize;
+            let name = std::str::from_utf8(r.take(len)?).map_err(|_| ObjectError::BadLineTable)?;
+            table.files.push(name.to_string());
+        }
+        for _ in 0..r.u32()? {
+            let row = (r.u32()?, r.u32()?, r.u32()?);
+            let ordered = table.rows.last().is_none_or(|last| last.0 < row.0);
+            if row.1 as usize >= table.files.len() || !ordered {
+                return Err(ObjectError::BadLineTable);
+            }
+            table.rows.push(row);
+        }
+        Ok(table)
+    }
+}
+
+impl Object {
+    pub fn new(base: u32) -> Object {
+        Object {
+            base,
+            code: vec![],
+            relocations: vec![],
+            lines: LineTable::default(),
+            symbols: SymbolTable::new(),
+        }
+    }
+
+    // Names the current address, returning it
+    pub fn define(&mut self, name: &str) -> u32 {
+        let addr = self.address();
+        self.symbols.insert(name, addr);
+        addr
+    }
+
+    // Code appended from now on was assembled from a source line
+    pub fn set_line(&mut self, file: &str, line: u32) {
+        self.lines.add(self.code.len() as u32, file, line);
+    }
+
+    // Source line of the code at an address, None outside the object or without line information
+    pub fn location(&self, addr: u32) -> Option<SourceLocation<'_>> {
+        let offset = addr.wrapping_sub(self.base);
+        if offset as usize >= self.code.len() {
+            return None;
+        }
+        self.lines.lookup(offset)
+    }
+
+    // Listing of the code with the source line shown above the instructions assembled from it
+    // Data is disassembled as if it were code
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        let mut bytes = self.code.iter().copied().enumerate().peekable();
+        let mut last = None;
+        while let Some(&(offset, _)) = bytes.peek() {
+            let addr = self.base.wrapping_add(offset as u32);
+            let location = self.location(addr);
+            if let Some(location) = location.filter(|_| location != last) {
+                out += &format!("{}:\n", location);
+            }
+            last = location;
+
+            match decode::decode(4, || bytes.next().map(|(_, b)| b).ok_or(())) {
+                Ok(instruction) => out += &format!("{:#010x}  {}\n", addr, instruction),
+                Err(()) => {
+                    for (i, byte) in self.code.iter().enumerate().skip(offset) {
+                        let addr = self.base.wrapping_add(i as u32);
+                        out += &format!("{:#010x}  .byte {:#04x}\n", addr, byte);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    // Address the next instruction or data is placed at
+    pub fn address(&self) -> u32 {
+        self.base.wrapping_add(self.code.len() as u32)
+    }
+
+    // Appends an instruction whose operands are used as is, returning its address
+    pub fn push(&mut self, instruction: &Instruction) -> u32 {
+        let addr = self.address();
+        instruction.encode(&mut self.code);
+        addr
+    }
+
+    // Appends an instruction whose word operand is an address inside the object
+    // Panics if the instruction has no word operand
+    pub fn push_relocated(&mut self, instruction: &Instruction) -> u32 {
+        let addr = self.push(instruction);
+        assert!(
+            self.code.len() - (addr - self.base) as usize > 4,
+            "instruction has no word operand"
+        );
+        self.relocations.push(self.code.len() as u32 - 4);
+        addr
+    }
+
+    pub fn push_bytes(&mut self, data: &[u8]) -> u32 {
+        let addr = self.address();
+        self.code.extend_from_slice(data);
+        addr
+    }
+
+    // Appends a word holding an address inside the object, eg an entry of a jump table
+    pub fn push_pointer(&mut self, target: u32) -> u32 {
+        let addr = self.push_bytes(&target.to_le_bytes());
+        self.relocations.push(addr - self.base);
+        addr
+    }
+
+    // Moves the object to a new base address in place
+    pub fn relocate(&mut self, new_base: u32) {
+        let delta = new_base.wrapping_sub(self.base);
+        for &offset in self.relocations.iter() {
+            let word = &mut self.code[offset as usize..offset as usize + 4];
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(word);
+            let value = u32::from_le_bytes(bytes).wrapping_add(delta);
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+        self.symbols.shift(delta);
+        self.base = new_base;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        out.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.base.to_le_bytes());
+        let sections = 2 + !self.lines.is_empty() as u32 + !self.symbols.is_empty() as u32;
+        out.extend_from_slice(&sections.to_le_bytes());
+
+        write_section(&mut out, b"CODE", SECTION_REQUIRED, &self.code);
+        let relocations: Vec<u8> = self
+            .relocations
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes().to_vec())
+            .collect();
+        write_section(&mut out, b"RELO", SECTION_REQUIRED, &relocations);
+        if !self.lines.is_empty() {
+            write_section(&mut out, b"LINE", 0, &self.lines.to_bytes());
+        }
+        if !self.symbols.is_empty() {
+            let mut symbols = (self.symbols.len() as u32).to_le_bytes().to_vec();
+            for symbol in self.symbols.iter() {
+                symbols.extend_from_slice(&symbol.addr.wrapping_sub(self.base).to_le_bytes());
+                symbols.extend_from_slice(&(symbol.name.len() as u32).to_le_bytes());
+                symbols.extend_from_slice(symbol.name.as_bytes());
+            }
+            write_section(&mut out, b"SYMS", 0, &symbols);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Object, ObjectError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(8)? != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        let major = r.u16()?;
+        let minor = r.u16()?;
+        if major != MAJOR_VERSION {
+            return Err(ObjectError::UnsupportedVersion(major, minor));
+        }
+
+        let mut object = Object::new(r.u32()?);
+        for _ in 0..r.u32()? {
+            let mut tag = [0; 4];
+            tag.copy_from_slice(r.take(4)?);
+            let flags = r.u32()?;
+            let len = r.u64()?;
+            if len > (bytes.len() - r.pos) as u64 {
+                return Err(ObjectError::Truncated);
+            }
+            let payload = r.take(len as usize)?;
+
+            match &tag {
+                b"CODE" => object.code = payload.to_vec(),
+                b"RELO" => {
+                    let mut s = Reader {
+                        bytes: payload,
+                        pos: 0,
+                    };
+                    while s.pos < payload.len() {
+                        object.relocations.push(s.u32()?);
+                    }
+                }
+                b"LINE" => object.lines = LineTable::from_bytes(payload)?,
+                b"SYMS" => {
+                    let mut s = Reader {
+                        bytes: payload,
+                        pos: 0,
+                    };
+                    for _ in 0..s.u32()? {
+                        let addr = object.base.wrapping_add(s.u32()?);
+                        let len = s.u32()? as usize;
+                        let name = std::str::from_utf8(s.take(len)?)
+                            .map_err(|_| ObjectError::BadSymbolTable)?;
+                        object.symbols.insert(name, addr);
+                    }
+                }
+                _ if flags & SECTION_REQUIRED != 0 => {
+                    return Err(ObjectError::UnknownRequiredSection(tag));
+                }
+                _ => (),
+            }
+        }
+
+        for &offset in object.relocations.iter() {
+            if offset as u64 + 4 > object.code.len() as u64 {
+                return Err(ObjectError::RelocationOutOfRange(offset));
+            }
+        }
+        Ok(object)
+    }
+}
+
+// Copy of an object moved to a new base address
+pub fn relocate(object: &Object, new_base: u32) -> Object {
+    let mut object = object.clone();
+    object.relocate(new_base);
+    object
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // Source line of the instruction executed last, or of the faulting instruction after a fault
+    pub fn source_location<'a>(&self, object: &'a Object) -> Option<SourceLocation<'a>> {
+        object.location(self.instruction_pc)
+    }
+}
+
+fn write_section(out: &mut Vec<u8>, tag: &[u8; 4], flags: u32, payload: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ObjectError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(ObjectError::Truncated);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, ObjectError> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64, ObjectError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::Operands;
+    use crate::{Cpu, SimpleAddress};
+
+    // Calls a function that loads a word stored after it, from an address outside the object
+    fn program() -> Object {
+        let mut object = Object::new(0x1000);
+        let call = object.define("_start");
+        object.push_relocated(&Instruction {
+            opcode: 0x18,
+            operands: Operands::Word(0),
+        });
+        let function = object.push_relocated(&Instruction {
+            opcode: 0x61,
+            operands: Operands::RegisterWord(1, 0),
+        });
+        object.push(&Instruction {
+            opcode: 0xc1,
+            operands: Operands::RegisterWord(1, 0x8000),
+        });
+        let data = object.push_bytes(&0x1234u32.to_le_bytes());
+
+        // Patch the targets now that they are known
+        object.code[(call - object.base) as usize + 1..][..4]
+            .copy_from_slice(&function.to_le_bytes());
+        object.code[(function - object.base) as usize + 1..][..4]
+            .copy_from_slice(&data.to_le_bytes());
+        object.push_pointer(function);
+        object
+    }
+
+    #[test]
+    fn relocation() {
+        let object = relocate(&program(), 0x3000);
+        assert_eq!(object.relocations, vec![1, 6, 19]);
+        assert_eq!(object.symbols.get("_start"), Some(0x3000));
+        assert_eq!(object.code[19..23], 0x3005u32.to_le_bytes());
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[crate::R_SP] = 0x7000;
+        cpu.xs[crate::R_PC] = object.base;
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.addressing.dump(0x8000, 4), 0x1234u32.to_le_bytes());
+    }
+
+    #[test]
+    fn file_format() {
+        let object = program();
+        let bytes = object.to_bytes();
+        assert_eq!(Object::from_bytes(&bytes), Ok(object));
+        assert_eq!(
+            Object::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(ObjectError::Truncated)
+        );
+
+        // First relocation of the RELO section
+        let mut bad = bytes.clone();
+        let relo = bytes.windows(4).position(|w| w == b"RELO").unwrap() + 16;
+        bad[relo..relo + 4].copy_from_slice(&100u32.to_le_bytes());
+        assert_eq!(
+            Object::from_bytes(&bad),
+            Err(ObjectError::RelocationOutOfRange(100))
+        );
+    }
+
+    #[test]
+    fn line_table() {
+        // kernel.s:
+        // 10  ldi x1, 0
+        // 11
+        // 12  msr x1, s0 ; faults in the user ring
+        let mut object = Object::new(0x1000);
+        object.set_line("kernel.s", 10);
+        object.push(&Instruction {
+            opcode: 0x41,
+            operands: Operands::RegisterWord(1, 0),
+        });
+        object.set_line("kernel.s", 11);
+        object.set_line("kernel.s", 12);
+        object.push(&Instruction {
+            opcode: 0x9a,
+            operands: Operands::Registers(1, 0),
+        });
+        let object = relocate(&Object::from_bytes(&object.to_bytes()).unwrap(), 0x4000);
+        assert_eq!(object.lines.offsets("kernel.s", 12), vec![5]);
+        assert_eq!(object.location(0x4007), None);
+        assert_eq!(
+            object.disassemble(),
+            "kernel.s:10:\n0x00004000  ldi x1, 0x0\nkernel.s:12:\n0x00004005  msr x1, s0\n"
+        );
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[crate::R_PC] = object.base;
+        cpu.set_flag(crate::F_USER_RING, true);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.fault_cause, 2);
+        let location = cpu.source_location(&object).unwrap();
+        assert_eq!(location.to_string(), "kernel.s:12");
+    }
+}
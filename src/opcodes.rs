@@ -0,0 +1,903 @@
+// Machine readable opcode map
+// Describes every instruction the interpreter decodes, for assemblers, fuzzers, and documentation
+// generators. Tests check the table against the decoder so the two cannot drift apart.
+//
+// Encoding patterns give the bits of each byte, with r for a register in the opcode byte, xxxx and
+// yyyy for the first and second register of a register pair, ab for the high register bits set by
+// the extension prefix, imm32/addr32 for little endian 32 bit operands, and rel32 for signed
+// offsets from the end of the instruction.
+//
+// The first page is full, so opcode 0x3f escapes to a second page of up to 256 instructions, in
+// EXTENDED_OPCODES. The byte after the escape picks the instruction and the one after that is a
+// register pair, as in the two register page.
+
+use std::fmt::Write;
+
+use crate::isa::IsaFeatures;
+use crate::{
+    F_CARRY, F_INFINITE, F_INTERRUPT_ENABLE, F_MEMMAP_ENABLE, F_NAN, F_NEGATIVE, F_OVERFLOW,
+    F_PARITY, F_USER_RING, F_ZERO,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    IntRegister,
+    FloatRegister,
+
+    // Pair of float registers holding a double, starting at an even register
+    DoubleRegister,
+
+    // 128 bit register of four lanes, see vector.rs
+    VectorRegister,
+
+    // System register index, see the privileged move instructions
+    SystemRegister,
+
+    // 32 bit literal
+    Literal,
+
+    // 32 bit memory address
+    Address,
+
+    // 32 bit branch or call target
+    Target,
+
+    // Signed 32 bit offset of a target from the next instruction
+    Offset,
+
+    // High bits of the registers of the following instruction
+    RegisterExtension,
+
+    // Instruction in the escaped page
+    ExtendedOpcode,
+}
+
+impl OperandKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            OperandKind::IntRegister => "int_register",
+            OperandKind::FloatRegister => "float_register",
+            OperandKind::DoubleRegister => "double_register",
+            OperandKind::VectorRegister => "vector_register",
+            OperandKind::SystemRegister => "system_register",
+            OperandKind::Literal => "literal",
+            OperandKind::Address => "address",
+            OperandKind::Target => "target",
+            OperandKind::Offset => "offset",
+            OperandKind::RegisterExtension => "register_extension",
+            OperandKind::ExtendedOpcode => "extended_opcode",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpcodeInfo {
+    pub opcode: u8,
+
+    // Bits of the opcode byte that pick the instruction, the rest select a register
+    pub mask: u8,
+
+    pub mnemonic: &'static str,
+    pub pattern: &'static str,
+    pub operands: &'static [OperandKind],
+
+    // Bits of the flags register the instruction may change
+    pub flags: u32,
+
+    // Faults in the user ring
+    pub privileged: bool,
+
+    pub cycles: u32,
+    pub features: IsaFeatures,
+}
+
+impl OpcodeInfo {
+    pub fn matches(&self, opcode: u8) -> bool {
+        opcode & self.mask == self.opcode
+    }
+}
+
+const INT_FLAGS: u32 = 1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_PARITY;
+const ADD_FLAGS: u32 = INT_FLAGS | 1 << F_OVERFLOW | 1 << F_CARRY;
+const SHIFT_FLAGS: u32 = INT_FLAGS | 1 << F_CARRY;
+const MUL_FLAGS: u32 = INT_FLAGS | 1 << F_OVERFLOW | 1 << F_CARRY;
+const FLOAT_FLAGS: u32 = 1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_NAN | 1 << F_INFINITE;
+const ALL_FLAGS: u32 = u32::MAX;
+const CONVERT_FLAGS: u32 = INT_FLAGS | 1 << F_OVERFLOW | 1 << F_NAN;
+const FLOAT_COMPARE_FLAGS: u32 = FLOAT_FLAGS | 1 << F_OVERFLOW | 1 << F_CARRY;
+
+pub const ESCAPE: u8 = 0x3f;
+
+// Names of the flags register bits, in the order they are listed by flag_names
+const FLAG_NAMES: [(u32, &str); 10] = [
+    (F_INTERRUPT_ENABLE, "interrupt_enable"),
+    (F_ZERO, "zero"),
+    (F_OVERFLOW, "overflow"),
+    (F_CARRY, "carry"),
+    (F_PARITY, "parity"),
+    (F_NEGATIVE, "negative"),
+    (F_NAN, "nan"),
+    (F_INFINITE, "infinite"),
+    (F_USER_RING, "user_ring"),
+    (F_MEMMAP_ENABLE, "memmap_enable"),
+];
+
+pub fn flag_names(flags: u32) -> Vec<&'static str> {
+    FLAG_NAMES
+        .iter()
+        .filter(|&&(bit, _)| flags & 1 << bit != 0)
+        .map(|&(_, name)| name)
+        .collect()
+}
+
+use OperandKind::*;
+
+const fn op(
+    opcode: u8,
+    mask: u8,
+    mnemonic: &'static str,
+    pattern: &'static str,
+    operands: &'static [OperandKind],
+    flags: u32,
+) -> OpcodeInfo {
+    OpcodeInfo {
+        opcode,
+        mask,
+        mnemonic,
+        pattern,
+        operands,
+        flags,
+        privileged: false,
+        cycles: 1,
+        features: IsaFeatures::NONE,
+    }
+}
+
+const fn privileged(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        privileged: true,
+        ..info
+    }
+}
+
+const fn float(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::FLOAT,
+        ..info
+    }
+}
+
+const fn fence(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::FENCE,
+        ..info
+    }
+}
+
+const fn pause(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::PAUSE,
+        ..info
+    }
+}
+
+const fn firmware(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::FIRMWARE,
+        ..info
+    }
+}
+
+const fn halt(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::HALT,
+        ..info
+    }
+}
+
+const fn wide_multiply(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::WIDE_MULTIPLY,
+        ..info
+    }
+}
+
+const fn signed(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::SIGNED_ARITHMETIC,
+        ..info
+    }
+}
+
+const fn compare(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::COMPARE,
+        ..info
+    }
+}
+
+const fn compare_branch(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::COMPARE_BRANCH,
+        ..info
+    }
+}
+
+const fn negate(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::NEGATE,
+        ..info
+    }
+}
+
+const fn rotate(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::ROTATE,
+        ..info
+    }
+}
+
+const fn immediate(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::IMMEDIATE,
+        ..info
+    }
+}
+
+const fn push_pop(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::PUSH_POP,
+        ..info
+    }
+}
+
+const fn indirect_branch(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::INDIRECT_BRANCH,
+        ..info
+    }
+}
+
+const fn relative_branch(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::RELATIVE_BRANCH,
+        ..info
+    }
+}
+
+const fn narrow_load(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::NARROW_LOAD,
+        ..info
+    }
+}
+
+const fn extend(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::EXTEND,
+        ..info
+    }
+}
+
+const fn float_math(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::FLOAT_MATH,
+        ..info
+    }
+}
+
+const fn float_rounding(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::FLOAT_ROUNDING,
+        ..info
+    }
+}
+
+const fn double(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::DOUBLE,
+        ..info
+    }
+}
+
+const fn vector(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::VECTOR,
+        ..info
+    }
+}
+
+const fn atomic(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::ATOMIC,
+        ..info
+    }
+}
+
+const fn syscall(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::SYSCALL,
+        ..info
+    }
+}
+
+const fn extension(info: OpcodeInfo) -> OpcodeInfo {
+    OpcodeInfo {
+        features: IsaFeatures::REGISTER_EXTENSION,
+        ..info
+    }
+}
+
+const BRANCH: &str = "00000ccc addr32";
+const COMPARE_BRANCH: &str = "00100ccc addr32";
+const REGISTERS: &str = "10oooooo xxxxyyyy";
+const IMMEDIATE: &str = "10oooooo xxxx0000 imm32";
+const REGISTER: &str = "10oooooo xxxx0000";
+const INT_ADDRESS: &[OperandKind] = &[IntRegister, Address];
+const INT_LITERAL: &[OperandKind] = &[IntRegister, Literal];
+const INTS: &[OperandKind] = &[IntRegister, IntRegister];
+const FLOATS: &[OperandKind] = &[FloatRegister, FloatRegister];
+const DOUBLES: &[OperandKind] = &[DoubleRegister, DoubleRegister];
+const INT_FLOAT: &[OperandKind] = &[IntRegister, FloatRegister];
+const VECTORS: &[OperandKind] = &[VectorRegister, VectorRegister];
+
+pub const OPCODES: &[OpcodeInfo] = &[
+    // Branches on a flag being set or clear
+    op(0x00, 0xff, "bz", BRANCH, &[Target], 0),
+    op(0x01, 0xff, "bv", BRANCH, &[Target], 0),
+    op(0x02, 0xff, "bc", BRANCH, &[Target], 0),
+    op(0x03, 0xff, "bn", BRANCH, &[Target], 0),
+    op(0x04, 0xff, "bp", BRANCH, &[Target], 0),
+    op(0x05, 0xff, "bnan", BRANCH, &[Target], 0),
+    op(0x06, 0xff, "binf", BRANCH, &[Target], 0),
+    op(0x07, 0xff, "bmm", BRANCH, &[Target], 0),
+    op(0x08, 0xff, "bnz", BRANCH, &[Target], 0),
+    op(0x09, 0xff, "bnv", BRANCH, &[Target], 0),
+    op(0x0a, 0xff, "bnc", BRANCH, &[Target], 0),
+    op(0x0b, 0xff, "bnn", BRANCH, &[Target], 0),
+    op(0x0c, 0xff, "bnp", BRANCH, &[Target], 0),
+    op(0x0d, 0xff, "bnnan", BRANCH, &[Target], 0),
+    op(0x0e, 0xff, "bninf", BRANCH, &[Target], 0),
+    op(0x0f, 0xff, "bnmm", BRANCH, &[Target], 0),
+    // Flags
+    op(0x10, 0xff, "clc", "00010000", &[], 1 << F_CARRY),
+    op(0x11, 0xff, "stc", "00010001", &[], 1 << F_CARRY),
+    privileged(op(0x12, 0xff, "dmm", "00010010", &[], 1 << F_MEMMAP_ENABLE)),
+    privileged(op(0x13, 0xff, "emm", "00010011", &[], 1 << F_MEMMAP_ENABLE)),
+    privileged(op(
+        0x14,
+        0xff,
+        "cli",
+        "00010100",
+        &[],
+        1 << F_INTERRUPT_ENABLE,
+    )),
+    privileged(op(
+        0x15,
+        0xff,
+        "sti",
+        "00010101",
+        &[],
+        1 << F_INTERRUPT_ENABLE,
+    )),
+    privileged(op(0x17, 0xff, "usr", "00010111", &[], 1 << F_USER_RING)),
+    // Calls and returns
+    op(0x18, 0xff, "call", "00011000 addr32", &[Target], 0),
+    op(0x19, 0xff, "ret", "00011001", &[], 0),
+    privileged(op(0x1a, 0xff, "iret", "00011010", &[], ALL_FLAGS)),
+    // Waits for an interrupt, see Cpu::is_halted
+    halt(privileged(op(0x1b, 0xff, "hlt", "00011011", &[], 0))),
+    // Memory ordering, see the memory model in the README
+    fence(op(0x1c, 0xff, "fence.acq", "00011100", &[], 0)),
+    fence(op(0x1d, 0xff, "fence.rel", "00011101", &[], 0)),
+    fence(op(0x1e, 0xff, "fence", "00011110", &[], 0)),
+    // Spin wait hint, see Cpu::paused
+    pause(op(0x1f, 0xff, "pause", "00011111", &[], 0)),
+    // Firmware services, see firmware.rs
+    firmware(privileged(op(0x20, 0xff, "fwcall", "00100000", &[], 0))),
+    // Requests from the user ring to the system ring, see the README
+    syscall(op(
+        0x21,
+        0xff,
+        "syscall",
+        "00100001",
+        &[],
+        1 << F_INTERRUPT_ENABLE | 1 << F_USER_RING | 0b111,
+    )),
+    // Branches on the result of cmp, signed then unsigned
+    compare_branch(op(0x22, 0xff, "blt", COMPARE_BRANCH, &[Target], 0)),
+    compare_branch(op(0x23, 0xff, "ble", COMPARE_BRANCH, &[Target], 0)),
+    compare_branch(op(0x24, 0xff, "bgt", COMPARE_BRANCH, &[Target], 0)),
+    compare_branch(op(0x25, 0xff, "bge", COMPARE_BRANCH, &[Target], 0)),
+    compare_branch(op(0x26, 0xff, "blo", COMPARE_BRANCH, &[Target], 0)),
+    compare_branch(op(0x27, 0xff, "bhs", COMPARE_BRANCH, &[Target], 0)),
+    // Relative forms of the flag branches, call, and an unconditional jump
+    relative_branch(op(0x28, 0xff, "bz.r", "00101000 rel32", &[Offset], 0)),
+    relative_branch(op(0x29, 0xff, "bv.r", "00101001 rel32", &[Offset], 0)),
+    relative_branch(op(0x2a, 0xff, "bc.r", "00101010 rel32", &[Offset], 0)),
+    relative_branch(op(0x2b, 0xff, "bn.r", "00101011 rel32", &[Offset], 0)),
+    relative_branch(op(0x2c, 0xff, "bp.r", "00101100 rel32", &[Offset], 0)),
+    relative_branch(op(0x2d, 0xff, "bnan.r", "00101101 rel32", &[Offset], 0)),
+    relative_branch(op(0x2e, 0xff, "binf.r", "00101110 rel32", &[Offset], 0)),
+    relative_branch(op(0x2f, 0xff, "bmm.r", "00101111 rel32", &[Offset], 0)),
+    relative_branch(op(0x30, 0xff, "bnz.r", "00110000 rel32", &[Offset], 0)),
+    relative_branch(op(0x31, 0xff, "bnv.r", "00110001 rel32", &[Offset], 0)),
+    relative_branch(op(0x32, 0xff, "bnc.r", "00110010 rel32", &[Offset], 0)),
+    relative_branch(op(0x33, 0xff, "bnn.r", "00110011 rel32", &[Offset], 0)),
+    relative_branch(op(0x34, 0xff, "bnp.r", "00110100 rel32", &[Offset], 0)),
+    relative_branch(op(0x35, 0xff, "bnnan.r", "00110101 rel32", &[Offset], 0)),
+    relative_branch(op(0x36, 0xff, "bninf.r", "00110110 rel32", &[Offset], 0)),
+    relative_branch(op(0x37, 0xff, "bnmm.r", "00110111 rel32", &[Offset], 0)),
+    relative_branch(op(0x38, 0xff, "call.r", "00111000 rel32", &[Offset], 0)),
+    relative_branch(op(0x39, 0xff, "jmp.r", "00111001 rel32", &[Offset], 0)),
+    // Extensions of the low byte or halfword of the second register into the first
+    extend(op(0x3a, 0xff, "sxtb", "00111010 xxxxyyyy", INTS, INT_FLAGS)),
+    extend(op(0x3b, 0xff, "sxth", "00111011 xxxxyyyy", INTS, INT_FLAGS)),
+    extend(op(0x3c, 0xff, "uxtb", "00111100 xxxxyyyy", INTS, INT_FLAGS)),
+    extend(op(0x3d, 0xff, "uxth", "00111101 xxxxyyyy", INTS, INT_FLAGS)),
+    // Register extension prefix
+    extension(op(
+        0x3e,
+        0xff,
+        "ext",
+        "00111110 000000ab",
+        &[RegisterExtension],
+        0,
+    )),
+    // Escape to the second page
+    op(
+        0x3f,
+        0xff,
+        "esc",
+        "00111111 oooooooo xxxxyyyy",
+        &[ExtendedOpcode],
+        0,
+    ),
+    // Loads from literals and addresses
+    op(
+        0x40,
+        0xf0,
+        "ldi",
+        "0100rrrr imm32",
+        &[IntRegister, Literal],
+        INT_FLAGS,
+    ),
+    float(op(
+        0x50,
+        0xf0,
+        "ldfi",
+        "0101rrrr imm32",
+        &[FloatRegister, Literal],
+        FLOAT_FLAGS,
+    )),
+    op(
+        0x60,
+        0xf0,
+        "ld",
+        "0110rrrr addr32",
+        &[IntRegister, Address],
+        INT_FLAGS,
+    ),
+    float(op(
+        0x70,
+        0xf0,
+        "ldf",
+        "0111rrrr addr32",
+        &[FloatRegister, Address],
+        FLOAT_FLAGS,
+    )),
+    // Arithmetic
+    op(0x80, 0xff, "iadd", REGISTERS, INTS, ADD_FLAGS),
+    op(0x81, 0xff, "isub", REGISTERS, INTS, ADD_FLAGS),
+    op(0x82, 0xff, "imul", REGISTERS, INTS, MUL_FLAGS),
+    op(0x83, 0xff, "idiv", REGISTERS, INTS, INT_FLAGS),
+    op(0x84, 0xff, "imod", REGISTERS, INTS, INT_FLAGS),
+    float(op(0x85, 0xff, "fadd", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    float(op(0x86, 0xff, "fsub", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    float(op(0x87, 0xff, "fmul", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    float(op(0x88, 0xff, "fdiv", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    // Bitwise operations
+    op(0x89, 0xff, "bsl", REGISTERS, INTS, SHIFT_FLAGS),
+    op(0x8a, 0xff, "bsr", REGISTERS, INTS, SHIFT_FLAGS),
+    op(0x8b, 0xff, "and", REGISTERS, INTS, INT_FLAGS),
+    op(0x8c, 0xff, "or", REGISTERS, INTS, INT_FLAGS),
+    op(0x8d, 0xff, "xor", REGISTERS, INTS, INT_FLAGS),
+    // Moves, conversions, and transmutes
+    op(0x8e, 0xff, "mov", REGISTERS, INTS, INT_FLAGS),
+    float(op(0x8f, 0xff, "fmov", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    float(op(
+        0x90,
+        0xff,
+        "ftoi",
+        REGISTERS,
+        &[IntRegister, FloatRegister],
+        INT_FLAGS,
+    )),
+    float(op(
+        0x91,
+        0xff,
+        "itof",
+        REGISTERS,
+        &[FloatRegister, IntRegister],
+        FLOAT_FLAGS,
+    )),
+    float(op(
+        0x92,
+        0xff,
+        "fbits",
+        REGISTERS,
+        &[IntRegister, FloatRegister],
+        INT_FLAGS,
+    )),
+    float(op(
+        0x93,
+        0xff,
+        "bitsf",
+        REGISTERS,
+        &[FloatRegister, IntRegister],
+        FLOAT_FLAGS,
+    )),
+    // Loads and stores through a register
+    op(0x94, 0xff, "ldr", REGISTERS, INTS, INT_FLAGS),
+    float(op(
+        0x95,
+        0xff,
+        "ldfr",
+        REGISTERS,
+        &[FloatRegister, IntRegister],
+        FLOAT_FLAGS,
+    )),
+    op(0x96, 0xff, "str", REGISTERS, INTS, 0),
+    op(0x97, 0xff, "strh", REGISTERS, INTS, 0),
+    op(0x98, 0xff, "strb", REGISTERS, INTS, 0),
+    float(op(
+        0x99,
+        0xff,
+        "strf",
+        REGISTERS,
+        &[FloatRegister, IntRegister],
+        0,
+    )),
+    // System registers
+    privileged(op(
+        0x9a,
+        0xff,
+        "msr",
+        REGISTERS,
+        &[IntRegister, SystemRegister],
+        ALL_FLAGS,
+    )),
+    op(
+        0x9b,
+        0xff,
+        "mrs",
+        REGISTERS,
+        &[SystemRegister, IntRegister],
+        0,
+    ),
+    // Full 64 bit product, the low word in the first register and the high word in the second
+    wide_multiply(op(0x9c, 0xff, "imulw", REGISTERS, INTS, MUL_FLAGS)),
+    // Two's complement arithmetic
+    signed(op(0x9d, 0xff, "imuls", REGISTERS, INTS, MUL_FLAGS)),
+    signed(op(0x9e, 0xff, "idivs", REGISTERS, INTS, MUL_FLAGS)),
+    signed(op(0x9f, 0xff, "imods", REGISTERS, INTS, INT_FLAGS)),
+    // Comparisons, which only set flags
+    compare(op(0xa0, 0xff, "cmp", REGISTERS, INTS, ADD_FLAGS)),
+    compare(op(
+        0xa1,
+        0xff,
+        "fcmp",
+        REGISTERS,
+        FLOATS,
+        FLOAT_COMPARE_FLAGS,
+    )),
+    // Complement and negation of the second register into the first, eg not x1, x1
+    negate(op(0xa2, 0xff, "not", REGISTERS, INTS, INT_FLAGS)),
+    negate(op(0xa3, 0xff, "neg", REGISTERS, INTS, ADD_FLAGS)),
+    negate(op(0xa4, 0xff, "fneg", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    // Rotates, and rotates through carry
+    rotate(op(0xa5, 0xff, "rol", REGISTERS, INTS, SHIFT_FLAGS)),
+    rotate(op(0xa6, 0xff, "ror", REGISTERS, INTS, SHIFT_FLAGS)),
+    rotate(op(0xa7, 0xff, "rcl", REGISTERS, INTS, SHIFT_FLAGS)),
+    rotate(op(0xa8, 0xff, "rcr", REGISTERS, INTS, SHIFT_FLAGS)),
+    // Arithmetic and logic with a literal, without carry in or borrow
+    immediate(op(0xa9, 0xff, "addi", IMMEDIATE, INT_LITERAL, ADD_FLAGS)),
+    immediate(op(0xaa, 0xff, "subi", IMMEDIATE, INT_LITERAL, ADD_FLAGS)),
+    immediate(op(0xab, 0xff, "andi", IMMEDIATE, INT_LITERAL, INT_FLAGS)),
+    immediate(op(0xac, 0xff, "ori", IMMEDIATE, INT_LITERAL, INT_FLAGS)),
+    immediate(op(0xad, 0xff, "xori", IMMEDIATE, INT_LITERAL, INT_FLAGS)),
+    immediate(op(0xae, 0xff, "shli", IMMEDIATE, INT_LITERAL, SHIFT_FLAGS)),
+    immediate(op(0xaf, 0xff, "shri", IMMEDIATE, INT_LITERAL, SHIFT_FLAGS)),
+    // Stack operations on x15, in the same direction as call and ret
+    push_pop(op(0xb0, 0xff, "push", REGISTER, &[IntRegister], 0)),
+    push_pop(op(0xb1, 0xff, "pop", REGISTER, &[IntRegister], 0)),
+    push_pop(op(0xb2, 0xff, "fpush", REGISTER, &[FloatRegister], 0)),
+    push_pop(op(0xb3, 0xff, "fpop", REGISTER, &[FloatRegister], 0)),
+    // Calls and jumps to the address in a register
+    indirect_branch(op(0xb4, 0xff, "callr", REGISTER, &[IntRegister], 0)),
+    indirect_branch(op(0xb5, 0xff, "jmpr", REGISTER, &[IntRegister], 0)),
+    // Byte and halfword loads from addresses and registers, zero or sign extended
+    narrow_load(op(0xb6, 0xff, "ldb", IMMEDIATE, INT_ADDRESS, INT_FLAGS)),
+    narrow_load(op(0xb7, 0xff, "ldbs", IMMEDIATE, INT_ADDRESS, INT_FLAGS)),
+    narrow_load(op(0xb8, 0xff, "ldh", IMMEDIATE, INT_ADDRESS, INT_FLAGS)),
+    narrow_load(op(0xb9, 0xff, "ldhs", IMMEDIATE, INT_ADDRESS, INT_FLAGS)),
+    narrow_load(op(0xba, 0xff, "ldrb", REGISTERS, INTS, INT_FLAGS)),
+    narrow_load(op(0xbb, 0xff, "ldrbs", REGISTERS, INTS, INT_FLAGS)),
+    narrow_load(op(0xbc, 0xff, "ldrh", REGISTERS, INTS, INT_FLAGS)),
+    narrow_load(op(0xbd, 0xff, "ldrhs", REGISTERS, INTS, INT_FLAGS)),
+    // Square root and absolute value of the second register into the first
+    float_math(op(0xbe, 0xff, "fsqrt", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    float_math(op(0xbf, 0xff, "fabs", REGISTERS, FLOATS, FLOAT_FLAGS)),
+    // Stores to addresses
+    op(
+        0xc0,
+        0xf0,
+        "st",
+        "1100rrrr addr32",
+        &[IntRegister, Address],
+        0,
+    ),
+    op(
+        0xd0,
+        0xf0,
+        "sth",
+        "1101rrrr addr32",
+        &[IntRegister, Address],
+        0,
+    ),
+    op(
+        0xe0,
+        0xf0,
+        "stb",
+        "1110rrrr addr32",
+        &[IntRegister, Address],
+        0,
+    ),
+    float(op(
+        0xf0,
+        0xf0,
+        "stf",
+        "1111rrrr addr32",
+        &[FloatRegister, Address],
+        0,
+    )),
+];
+
+const ESCAPED: &str = "00111111 oooooooo xxxxyyyy";
+
+// The second page, indexed by the byte after the escape
+pub const EXTENDED_OPCODES: &[OpcodeInfo] = &[
+    // Rounding to an integral float, with the rounding mode in the low two bits: to nearest with
+    // ties to even, towards zero, down, and up
+    float_rounding(op(0x00, 0xff, "fround", ESCAPED, FLOATS, FLOAT_FLAGS)),
+    float_rounding(op(0x01, 0xff, "ftrunc", ESCAPED, FLOATS, FLOAT_FLAGS)),
+    float_rounding(op(0x02, 0xff, "ffloor", ESCAPED, FLOATS, FLOAT_FLAGS)),
+    float_rounding(op(0x03, 0xff, "fceil", ESCAPED, FLOATS, FLOAT_FLAGS)),
+    // Float to int conversions with the same rounding modes, saturating when out of range
+    float_rounding(op(0x04, 0xff, "fcvt.rn", ESCAPED, INT_FLOAT, CONVERT_FLAGS)),
+    float_rounding(op(0x05, 0xff, "fcvt.rz", ESCAPED, INT_FLOAT, CONVERT_FLAGS)),
+    float_rounding(op(0x06, 0xff, "fcvt.rd", ESCAPED, INT_FLOAT, CONVERT_FLAGS)),
+    float_rounding(op(0x07, 0xff, "fcvt.ru", ESCAPED, INT_FLOAT, CONVERT_FLAGS)),
+    // Minimum and maximum, preferring a number over nan and -0 over +0 for the minimum
+    float_rounding(op(0x08, 0xff, "fmin", ESCAPED, FLOATS, FLOAT_FLAGS)),
+    float_rounding(op(0x09, 0xff, "fmax", ESCAPED, FLOATS, FLOAT_FLAGS)),
+    // Double precision arithmetic, loads and stores through an address register, and conversions
+    double(op(0x10, 0xff, "dadd", ESCAPED, DOUBLES, FLOAT_FLAGS)),
+    double(op(0x11, 0xff, "dsub", ESCAPED, DOUBLES, FLOAT_FLAGS)),
+    double(op(0x12, 0xff, "dmul", ESCAPED, DOUBLES, FLOAT_FLAGS)),
+    double(op(0x13, 0xff, "ddiv", ESCAPED, DOUBLES, FLOAT_FLAGS)),
+    double(op(
+        0x14,
+        0xff,
+        "dcmp",
+        ESCAPED,
+        DOUBLES,
+        1 << F_ZERO | 1 << F_NEGATIVE | 1 << F_NAN | 1 << F_OVERFLOW | 1 << F_CARRY,
+    )),
+    double(op(
+        0x18,
+        0xff,
+        "ldrd",
+        ESCAPED,
+        &[DoubleRegister, IntRegister],
+        FLOAT_FLAGS,
+    )),
+    double(op(
+        0x19,
+        0xff,
+        "strd",
+        ESCAPED,
+        &[DoubleRegister, IntRegister],
+        0,
+    )),
+    double(op(
+        0x1c,
+        0xff,
+        "dtof",
+        ESCAPED,
+        &[FloatRegister, DoubleRegister],
+        FLOAT_FLAGS,
+    )),
+    double(op(
+        0x1d,
+        0xff,
+        "ftod",
+        ESCAPED,
+        &[DoubleRegister, FloatRegister],
+        FLOAT_FLAGS,
+    )),
+    double(op(
+        0x1e,
+        0xff,
+        "dtoi",
+        ESCAPED,
+        &[IntRegister, DoubleRegister],
+        INT_FLAGS,
+    )),
+    double(op(
+        0x1f,
+        0xff,
+        "itod",
+        ESCAPED,
+        &[DoubleRegister, IntRegister],
+        FLOAT_FLAGS,
+    )),
+    // Packed arithmetic on four 32 bit lanes, comparisons set a lane to all ones where they hold
+    vector(op(0x20, 0xff, "vadd.i", ESCAPED, VECTORS, 0)),
+    vector(op(0x21, 0xff, "vsub.i", ESCAPED, VECTORS, 0)),
+    vector(op(0x22, 0xff, "vmul.i", ESCAPED, VECTORS, 0)),
+    vector(op(0x23, 0xff, "vcmpeq.i", ESCAPED, VECTORS, 0)),
+    vector(op(0x24, 0xff, "vcmplt.i", ESCAPED, VECTORS, 0)),
+    vector(op(0x28, 0xff, "vadd.f", ESCAPED, VECTORS, 0)),
+    vector(op(0x29, 0xff, "vsub.f", ESCAPED, VECTORS, 0)),
+    vector(op(0x2a, 0xff, "vmul.f", ESCAPED, VECTORS, 0)),
+    vector(op(0x2b, 0xff, "vcmpeq.f", ESCAPED, VECTORS, 0)),
+    vector(op(0x2c, 0xff, "vcmplt.f", ESCAPED, VECTORS, 0)),
+    vector(op(
+        0x30,
+        0xff,
+        "vld",
+        ESCAPED,
+        &[VectorRegister, IntRegister],
+        0,
+    )),
+    vector(op(
+        0x31,
+        0xff,
+        "vst",
+        ESCAPED,
+        &[VectorRegister, IntRegister],
+        0,
+    )),
+    // Atomic operations on the word at the address in the second register, see the memory model
+    atomic(op(0x38, 0xff, "cas", ESCAPED, INTS, 1 << F_ZERO)),
+    atomic(op(0x39, 0xff, "xchg", ESCAPED, INTS, 0)),
+    atomic(op(0x3a, 0xff, "xadd", ESCAPED, INTS, 0)),
+];
+
+// Finds the instruction an opcode byte decodes to, None for unassigned opcodes
+pub fn lookup(opcode: u8) -> Option<&'static OpcodeInfo> {
+    OPCODES.iter().find(|info| info.matches(opcode))
+}
+
+pub fn lookup_mnemonic(mnemonic: &str) -> Option<&'static OpcodeInfo> {
+    OPCODES.iter().find(|info| info.mnemonic == mnemonic)
+}
+
+// Finds the instruction the byte after the escape decodes to
+pub fn lookup_extended(func: u8) -> Option<&'static OpcodeInfo> {
+    EXTENDED_OPCODES.iter().find(|info| info.matches(func))
+}
+
+pub fn lookup_extended_mnemonic(mnemonic: &str) -> Option<&'static OpcodeInfo> {
+    EXTENDED_OPCODES
+        .iter()
+        .find(|info| info.mnemonic == mnemonic)
+}
+
+// The whole table as a JSON array of objects, one per instruction, with the escaped page last
+pub fn opcodes_json() -> String {
+    let mut out = String::from("[\n");
+    let table = OPCODES.iter().map(|info| (info, false));
+    let table = table.chain(EXTENDED_OPCODES.iter().map(|info| (info, true)));
+    let len = OPCODES.len() + EXTENDED_OPCODES.len();
+    for (i, (info, extended)) in table.enumerate() {
+        let operands: Vec<String> = info
+            .operands
+            .iter()
+            .map(|kind| format!("\"{}\"", kind.name()))
+            .collect();
+        let flags: Vec<String> = flag_names(info.flags)
+            .iter()
+            .map(|name| format!("\"{}\"", name))
+            .collect();
+        let _ = write!(
+            out,
+            "  {{\"opcode\": {}, \"mask\": {}, \"mnemonic\": \"{}\", \"pattern\": \"{}\", \
+             \"operands\": [{}], \"flags\": [{}], \"privileged\": {}, \"cycles\": {}, \
+             \"features\": {}, \"extended\": {}}}",
+            info.opcode,
+            info.mask,
+            info.mnemonic,
+            info.pattern,
+            operands.join(", "),
+            flags.join(", "),
+            info.privileged,
+            info.cycles,
+            info.features.bits(),
+            extended
+        );
+        out.push_str(if i + 1 == len { "\n" } else { ",\n" });
+    }
+    out.push(']');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, InvalidMemoryAccess, SimpleAddress, StepOutcome};
+
+    #[test]
+    fn table_matches_decoder() {
+        for opcode in 0..=255u8 {
+            let info = lookup(opcode);
+            let required = IsaFeatures::required(opcode);
+            if let Some(info) = info {
+                assert_eq!(info.features, required, "{:#04x}", opcode);
+                assert_eq!(
+                    OPCODES.iter().filter(|i| i.matches(opcode)).count(),
+                    1,
+                    "{:#04x}",
+                    opcode
+                );
+            }
+
+            // Unassigned opcodes fault
+            if info.is_none() {
+                let mut cpu = Cpu::new(SimpleAddress::default());
+                cpu.addressing.load(0, &[opcode, 0xff]);
+                assert_eq!(
+                    cpu.step(),
+                    StepOutcome::Faulted(InvalidMemoryAccess::IllegalOpcode(opcode))
+                );
+            }
+
+            // And the same for the escaped page
+            let info = lookup_extended(opcode);
+            if let Some(info) = info {
+                assert_eq!(info.features, IsaFeatures::required_extended(opcode));
+            } else {
+                let mut cpu = Cpu::new(SimpleAddress::default());
+                cpu.addressing.load(0, &[ESCAPE, opcode, 0x00]);
+                assert_eq!(
+                    cpu.step(),
+                    StepOutcome::Faulted(InvalidMemoryAccess::IllegalOpcode(ESCAPE))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn export() {
+        assert_eq!(lookup(0x43).unwrap().mnemonic, "ldi");
+        assert_eq!(lookup_mnemonic("iret").unwrap().opcode, 0x1a);
+        assert!(lookup(0x16).is_none());
+        assert_eq!(
+            flag_names(lookup(0x80).unwrap().flags),
+            vec!["zero", "overflow", "carry", "parity", "negative"]
+        );
+
+        let json = opcodes_json();
+        assert!(json.starts_with("[\n  {\"opcode\": 0, \"mask\": 255, \"mnemonic\": \"bz\""));
+        assert!(json.contains(
+            "\"mnemonic\": \"msr\", \"pattern\": \"10oooooo xxxxyyyy\", \
+             \"operands\": [\"int_register\", \"system_register\"]"
+        ));
+        assert_eq!(
+            json.matches("\"mnemonic\"").count(),
+            OPCODES.len() + EXTENDED_OPCODES.len()
+        );
+        assert!(json.contains("\"mnemonic\": \"fmax\""));
+    }
+}
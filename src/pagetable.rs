@@ -0,0 +1,383 @@
+// Page table listing and validation
+// Walks the memory map pointed to by memmap, whether or not it is enabled, and lists every virtual
+// range as mapped or free. Consecutive pages are merged when they map to consecutive physical
+// addresses with the same permissions. Tables are read with read_debug, the same way the cpu
+// indexes them.
+// Validation checks the listing and the tables for mistakes that are legal but almost certainly
+// unintended.
+
+use std::fmt;
+
+use crate::translate::PAGE_BITS;
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Mapped {
+        physical_start: u32,
+        permissions: u8,
+    },
+
+    // The top level entry is zero, so there is no second level table
+    NoTable,
+
+    // The second level entry is not present
+    NotPresent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub virtual_start: u32,
+
+    // Inclusive, so that a region can reach the end of the address space
+    pub virtual_end: u32,
+
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn size(&self) -> u64 {
+        (self.virtual_end - self.virtual_start) as u64 + 1
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.kind, RegionKind::Mapped { .. })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageTableListing {
+    pub memmap: u32,
+    pub regions: Vec<Region>,
+}
+
+impl PageTableListing {
+    pub fn mapped(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(|region| region.is_mapped())
+    }
+}
+
+fn permission_string(permissions: u8) -> String {
+    [(0b100, 'r'), (0b010, 'w'), (0b001, 'x')]
+        .iter()
+        .map(|&(bit, c)| if permissions & bit != 0 { c } else { '-' })
+        .collect()
+}
+
+impl fmt::Display for PageTableListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        writeln!(f, "memmap {:#010x}", self.memmap)?;
+        for region in self.regions.iter() {
+            write!(
+                f,
+                "{:#010x}-{:#010x} ",
+                region.virtual_start, region.virtual_end
+            )?;
+            match region.kind {
+                RegionKind::Mapped {
+                    physical_start,
+                    permissions,
+                } => writeln!(
+                    f,
+                    "-> {:#010x}-{:#010x} {}",
+                    physical_start,
+                    physical_start as u64 + region.size() - 1,
+                    permission_string(permissions)
+                )?,
+                RegionKind::NoTable => writeln!(f, "free (no table)")?,
+                RegionKind::NotPresent => writeln!(f, "free")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Diagnostic {
+    // A table can be written through a mapping, so the guest can change its own memory map
+    WritableTable { table: u32, virtual_start: u32 },
+
+    // A table can be read or executed through a mapping
+    MappedTable { table: u32, virtual_start: u32 },
+
+    // A table lies past the end of physical memory
+    TableOutOfRange { table: u32 },
+
+    // A mapped frame lies past the end of physical memory
+    FrameOutOfRange { virtual_start: u32, frame: u32 },
+
+    // A frame address is not aligned to the page size
+    UnalignedFrame { virtual_start: u32, frame: u32 },
+
+    // A page is both writable and executable
+    WriteExecute { virtual_start: u32 },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match *self {
+            Diagnostic::WritableTable {
+                table,
+                virtual_start,
+            } => write!(
+                f,
+                "table {:#010x} is writable through {:#010x}",
+                table, virtual_start
+            ),
+            Diagnostic::MappedTable {
+                table,
+                virtual_start,
+            } => write!(
+                f,
+                "table {:#010x} is mapped at {:#010x}",
+                table, virtual_start
+            ),
+            Diagnostic::TableOutOfRange { table } => {
+                write!(f, "table {:#010x} is outside physical memory", table)
+            }
+            Diagnostic::FrameOutOfRange {
+                virtual_start,
+                frame,
+            } => write!(
+                f,
+                "{:#010x} maps {:#010x} outside physical memory",
+                virtual_start, frame
+            ),
+            Diagnostic::UnalignedFrame {
+                virtual_start,
+                frame,
+            } => write!(
+                f,
+                "{:#010x} maps unaligned frame {:#010x}",
+                virtual_start, frame
+            ),
+            Diagnostic::WriteExecute { virtual_start } => {
+                write!(f, "{:#010x} is writable and executable", virtual_start)
+            }
+        }
+    }
+}
+
+// Bytes the cpu may read from a table, which has 256 entries a byte apart
+const TABLE_SPAN: u32 = 256 + 3;
+
+pub(crate) fn read_word<T>(memory: &mut T, addr: u32) -> u32
+where
+    T: Address,
+{
+    (0..4).fold(0, |acc, i| {
+        acc | (memory.read_debug(addr.wrapping_add(i)) as u32) << (8 * i)
+    })
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn page_tables(&mut self) -> PageTableListing {
+        let page_size = 1u32 << PAGE_BITS;
+        let mut regions: Vec<Region> = vec![];
+        let mut push = |virtual_start: u32, len: u32, kind: RegionKind| {
+            if let Some(last) = regions.last_mut() {
+                let merge = match (last.kind, kind) {
+                    (
+                        RegionKind::Mapped {
+                            physical_start: a,
+                            permissions: p,
+                        },
+                        RegionKind::Mapped {
+                            physical_start: b,
+                            permissions: q,
+                        },
+                    ) => p == q && a as u64 + last.size() == b as u64,
+                    (a, b) => a == b,
+                };
+                if merge {
+                    last.virtual_end = virtual_start + (len - 1);
+                    return;
+                }
+            }
+            regions.push(Region {
+                virtual_start,
+                virtual_end: virtual_start + (len - 1),
+                kind,
+            });
+        };
+
+        let memmap = self.mmu.root();
+        for top in 0..256u32 {
+            let table = read_word(&mut self.addressing, memmap.wrapping_add(top));
+            if table == 0 {
+                push(top << 24, 1 << 24, RegionKind::NoTable);
+                continue;
+            }
+
+            for i in 0..256u32 {
+                let entry = read_word(&mut self.addressing, table.wrapping_add(i));
+                let kind = if entry & 0x80000000 != 0 {
+                    RegionKind::Mapped {
+                        physical_start: entry & 0x0fffffff,
+                        permissions: (entry >> 28) as u8 & 0x07,
+                    }
+                } else {
+                    RegionKind::NotPresent
+                };
+                push(top << 24 | i << PAGE_BITS, page_size, kind);
+            }
+        }
+
+        PageTableListing { memmap, regions }
+    }
+
+    // Checks the memory map for common mistakes, whether or not it is enabled
+    // Physical memory bounds are only checked if the memory knows its size
+    pub fn validate_page_tables(&mut self) -> Vec<Diagnostic> {
+        let listing = self.page_tables();
+        let size = self.addressing.size();
+        let page_size = 1u32 << PAGE_BITS;
+        let mut diagnostics = vec![];
+
+        let memmap = self.mmu.root();
+        let mut tables = vec![memmap];
+        for top in 0..256u32 {
+            let table = read_word(&mut self.addressing, memmap.wrapping_add(top));
+            if table != 0 && !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        if let Some(size) = size {
+            for &table in tables.iter() {
+                if table as u64 + TABLE_SPAN as u64 > size {
+                    diagnostics.push(Diagnostic::TableOutOfRange { table });
+                }
+            }
+        }
+
+        for region in listing.mapped() {
+            let (frame, permissions) = match region.kind {
+                RegionKind::Mapped {
+                    physical_start,
+                    permissions,
+                } => (physical_start, permissions),
+                _ => continue,
+            };
+            let virtual_start = region.virtual_start;
+
+            if permissions & 0b011 == 0b011 {
+                diagnostics.push(Diagnostic::WriteExecute { virtual_start });
+            }
+
+            // Regions can cover several pages, each of which is reported
+            let mut page = 0;
+            while page < region.size() {
+                let frame = frame.wrapping_add(page as u32);
+                let virtual_start = virtual_start.wrapping_add(page as u32);
+                if frame % page_size != 0 {
+                    diagnostics.push(Diagnostic::UnalignedFrame {
+                        virtual_start,
+                        frame,
+                    });
+                }
+                if let Some(size) = size {
+                    if frame as u64 + page_size as u64 > size {
+                        diagnostics.push(Diagnostic::FrameOutOfRange {
+                            virtual_start,
+                            frame,
+                        });
+                    }
+                }
+                page += page_size as u64;
+            }
+
+            let end = frame as u64 + region.size();
+            for &table in tables.iter() {
+                if (table as u64) < end && table as u64 + TABLE_SPAN as u64 > frame as u64 {
+                    let virtual_start = virtual_start.wrapping_add(table.saturating_sub(frame));
+                    diagnostics.push(if permissions & 0b010 != 0 {
+                        Diagnostic::WritableTable {
+                            table,
+                            virtual_start,
+                        }
+                    } else {
+                        Diagnostic::MappedTable {
+                            table,
+                            virtual_start,
+                        }
+                    });
+                }
+            }
+        }
+
+        diagnostics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn listing() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.mmu.set_root(0x100);
+        cpu.addressing.load(0x101, &0x200u32.to_le_bytes());
+        cpu.addressing.load(0x202, &0xe0080000u32.to_le_bytes());
+        cpu.addressing.load(0x208, &0xd0090000u32.to_le_bytes());
+
+        let listing = cpu.page_tables();
+        assert_eq!(listing.mapped().count(), 2);
+        assert_eq!(
+            listing.to_string(),
+            "memmap 0x00000100\n\
+             0x00000000-0x0101ffff free\n\
+             0x01020000-0x0102ffff -> 0x00080000-0x0008ffff rw-\n\
+             0x01030000-0x0107ffff free\n\
+             0x01080000-0x0108ffff -> 0x00090000-0x0009ffff r-x\n\
+             0x01090000-0x02ffffff free\n\
+             0x03000000-0xffffffff free (no table)\n"
+        );
+    }
+
+    #[test]
+    fn validation() {
+        let mut cpu = Cpu::new(SimpleAddress::new(0x100000));
+        cpu.mmu.set_root(0x100);
+        cpu.addressing.load(0x101, &0x200u32.to_le_bytes());
+
+        // Clean read only mapping
+        cpu.addressing.load(0x208, &0xc0090000u32.to_le_bytes());
+        assert_eq!(cpu.validate_page_tables(), vec![]);
+
+        // Writable and executable, unaligned, and past the end of memory
+        cpu.addressing.load(0x20c, &0xf00f0010u32.to_le_bytes());
+        assert_eq!(
+            cpu.validate_page_tables(),
+            vec![
+                Diagnostic::WriteExecute {
+                    virtual_start: 0x010c0000
+                },
+                Diagnostic::UnalignedFrame {
+                    virtual_start: 0x010c0000,
+                    frame: 0x000f0010
+                },
+                Diagnostic::FrameOutOfRange {
+                    virtual_start: 0x010c0000,
+                    frame: 0x000f0010
+                },
+            ]
+        );
+
+        // Mapping the tables themselves
+        cpu.addressing.load(0x20c, &0xe0000000u32.to_le_bytes());
+        let diagnostics = cpu.validate_page_tables();
+        assert!(diagnostics.contains(&Diagnostic::WritableTable {
+            table: 0x200,
+            virtual_start: 0x010c0200
+        }));
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "table 0x00000100 is writable through 0x010c0100"
+        );
+    }
+}
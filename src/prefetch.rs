@@ -0,0 +1,167 @@
+// Instruction prefetch buffer
+// Instead of translating and fetching every byte of an instruction on its own, the cpu fetches the
+// aligned PREFETCH_BYTES chunk around pc with a single translation and serves the following bytes
+// from the buffer. Jumps out of the chunk refill it. Chunks never cross a page, so one translation
+// covers all of their bytes. Without the memory map there is nothing to translate and refills cost
+// more than they save, so bytes are fetched directly.
+//
+// Chunks are only prefetched when none of their bytes have read side effects and fetching them
+// raises no bus error, otherwise the bytes are fetched one at a time as before. The buffer is
+// emptied when the cpu writes into the chunk, the memory map changes (see walkcache.rs), the host
+// takes hold of memory with addressing, or a fence is executed. Code written by other agents, eg
+// another core or a DMA engine, is only guaranteed to be fetched after a fence.
+
+use crate::{Address, Cpu, F_MEMMAP_ENABLE};
+
+pub const PREFETCH_BYTES: u32 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PrefetchStats {
+    // Chunks fetched into the buffer
+    pub fills: u64,
+
+    // Instruction bytes served from the buffer without a translation
+    pub hits: u64,
+
+    // Times the buffer was emptied before it was done with
+    pub invalidations: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PrefetchBuffer {
+    enabled: bool,
+
+    // Virtual and physical address of the chunk, if the buffer holds one
+    chunk: Option<(u32, u32)>,
+    bytes: [u8; PREFETCH_BYTES as usize],
+    pub(crate) stats: PrefetchStats,
+}
+
+impl PrefetchBuffer {
+    pub(crate) fn new() -> PrefetchBuffer {
+        PrefetchBuffer {
+            enabled: true,
+            chunk: None,
+            bytes: [0; PREFETCH_BYTES as usize],
+            stats: PrefetchStats::default(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, pc: u32) -> Option<u8> {
+        match self.chunk {
+            Some((virt, _)) if pc & !(PREFETCH_BYTES - 1) == virt => {
+                self.stats.hits += 1;
+                Some(self.bytes[(pc & (PREFETCH_BYTES - 1)) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn invalidate(&mut self) {
+        if self.chunk.take().is_some() {
+            self.stats.invalidations += 1;
+        }
+    }
+
+    // Empties the buffer if a physical address written to is part of the chunk
+    pub(crate) fn on_write(&mut self, addr: u32) {
+        if let Some((_, phys)) = self.chunk {
+            if addr.wrapping_sub(phys) < PREFETCH_BYTES {
+                self.invalidate();
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // Turning the buffer off makes every instruction byte a separate fetch, as on a cpu without
+    // one, eg to compare against another emulator access by access
+    pub fn set_prefetch(&mut self, enabled: bool) {
+        self.prefetch.enabled = enabled;
+        self.prefetch.invalidate();
+    }
+
+    pub fn prefetch_stats(&self) -> PrefetchStats {
+        self.prefetch.stats
+    }
+
+    pub fn reset_prefetch_stats(&mut self) {
+        self.prefetch.stats = PrefetchStats::default();
+    }
+
+    // Fetches the chunk around pc, whose byte at pc has already been translated to phys, returning
+    // the byte at pc if the buffer now holds the chunk
+    pub(crate) fn fill_prefetch(&mut self, pc: u32, phys: u32) -> Option<u8> {
+        if !self.prefetch.enabled || !self.get_flag(F_MEMMAP_ENABLE) {
+            return None;
+        }
+        let offset = pc & (PREFETCH_BYTES - 1);
+        let phys = phys.wrapping_sub(offset);
+        let pure = (0..PREFETCH_BYTES).all(|i| {
+            !self
+                .addressing
+                .read_effect(phys.wrapping_add(i))
+                .has_side_effects()
+        });
+        if !pure {
+            return None;
+        }
+
+        for i in 0..PREFETCH_BYTES {
+            self.prefetch.bytes[i as usize] = self.addressing.fetch(phys.wrapping_add(i));
+        }
+        if self.addressing.bus_error().is_some() {
+            return None;
+        }
+        self.prefetch.chunk = Some((pc & !(PREFETCH_BYTES - 1), phys));
+        self.prefetch.stats.fills += 1;
+        Some(self.prefetch.bytes[offset as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn prefetch() {
+        // ldi x0, 1; st x0, 0xb (over the immediate of the next instruction); ldi x1, 2
+        let mut memory = SimpleAddress::new(0x1000);
+        memory.load(0, &[0x40, 0x01, 0x00, 0x00, 0x00]);
+        memory.load(5, &[0xc0, 0x0b, 0x00, 0x00, 0x00]);
+        memory.load(10, &[0x41, 0x02, 0x00, 0x00, 0x00]);
+
+        // Identity map the first 64K with every permission
+        memory.load(0x100, &0x200u32.to_le_bytes());
+        memory.load(0x200, &0xf0000000u32.to_le_bytes());
+        let mut cpu = Cpu::new(memory);
+        cpu.mmu.set_root(0x100);
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+
+        cpu.step();
+        cpu.step();
+        assert_eq!(
+            cpu.prefetch_stats(),
+            PrefetchStats {
+                fills: 1,
+                hits: 9,
+                invalidations: 1,
+            }
+        );
+
+        // The next instruction is fetched again, as the store changed it
+        cpu.step();
+        assert_eq!(cpu.xs[1], 1);
+        assert_eq!(cpu.prefetch_stats().fills, 2);
+
+        cpu.set_prefetch(false);
+        cpu.reset_prefetch_stats();
+        cpu.xs[crate::R_PC] = 0;
+        cpu.step();
+        assert_eq!(cpu.prefetch_stats(), PrefetchStats::default());
+    }
+}
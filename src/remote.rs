@@ -0,0 +1,663 @@
+// Remote control protocol
+// Lets a program in another process drive a machine over TCP or any other byte stream. Every
+// message is a frame: a u32 little endian payload length followed by the payload. The client sends
+// requests and the server answers each with one response, sending any events the request caused
+// first. Server frames start with a kind byte, 0 for a response and 1 for an event.
+//
+// Requests (opcode, then arguments):
+// 0x01 load          u32 addr, bytes
+// 0x02 run           u64 max steps, stops at breakpoints
+// 0x03 step          u64 steps, ignores breakpoints
+// 0x04 registers
+// 0x05 set register  u8 kind (0 integer, 1 float, 2 flags), u8 index, u32 value (float bits)
+// 0x06 read memory   u32 addr, u32 len
+// 0x07 write memory  u32 addr, bytes
+// 0x08 break         u32 addr
+// 0x09 clear break   u32 addr
+// 0x0a subscribe     u8 events (bit 0 steps, bit 1 faults), replacing the previous subscription
+//
+// Responses (status, then data):
+// 0x00 ok
+// 0x01 stopped       u8 reason (0 step limit, 1 breakpoint, 2 shutdown), u64 steps
+// 0x02 registers     u8 count, count u32 integer registers, count u32 float bits, u32 flags
+// 0x03 memory        bytes
+// 0x04 error         message in UTF-8
+// 0x05 names         u8 length and UTF-8 bytes of each name, see server.rs
+//
+// Events:
+// 0x00 step          u32 pc of the next instruction
+// 0x01 fault         u32 fault cause, u32 pc of the faulting instruction
+//
+// Memory accesses are virtual and go through peek and poke, see debug.rs.
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+use crate::{Address, Cpu, StepOutcome, R_PC};
+
+pub const SUBSCRIBE_STEPS: u8 = 1 << 0;
+pub const SUBSCRIBE_FAULTS: u8 = 1 << 1;
+
+// Frames longer than this are rejected rather than allocated
+pub const MAX_FRAME: u32 = 64 << 20;
+
+// Longest read memory request, leaving room for the kind and status bytes of the response frame
+pub const MAX_READ: u32 = MAX_FRAME - 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    Int(u8),
+    Float(u8),
+    Flags,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Load { addr: u32, data: Vec<u8> },
+    Run { max_steps: u64 },
+    Step { steps: u64 },
+    Registers,
+    SetRegister { register: Register, value: u32 },
+    ReadMemory { addr: u32, len: u32 },
+    WriteMemory { addr: u32, data: Vec<u8> },
+    Break(u32),
+    ClearBreak(u32),
+    Subscribe(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    StepLimit,
+    Breakpoint,
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok,
+    Stopped {
+        reason: StopReason,
+        steps: u64,
+    },
+
+    // Floats as bits so that NaNs survive
+    Registers {
+        xs: Vec<u32>,
+        fs: Vec<u32>,
+        flags: u32,
+    },
+
+    Memory(Vec<u8>),
+    Error(String),
+    Names(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    Step { pc: u32 },
+    Fault { cause: u32, pc: u32 },
+}
+
+pub(crate) fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub(crate) struct Reader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(protocol_error("message is truncated"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub(crate) fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    // A u8 length and that many bytes of UTF-8
+    pub(crate) fn name(&mut self) -> io::Result<String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| protocol_error("name is not UTF-8"))
+    }
+
+    pub(crate) fn rest(&mut self) -> Vec<u8> {
+        let rest = self.bytes.to_vec();
+        self.bytes = &[];
+        rest
+    }
+}
+
+// Names longer than 255 bytes are truncated to fit their length byte
+pub(crate) fn write_name(out: &mut Vec<u8>, name: &str) {
+    let mut len = name.len().min(u8::MAX as usize);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    out.push(len as u8);
+    out.extend_from_slice(&name.as_bytes()[..len]);
+}
+
+impl Request {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Request::Load { addr, data } => {
+                out.push(0x01);
+                out.extend_from_slice(&addr.to_le_bytes());
+                out.extend_from_slice(data);
+            }
+            Request::Run { max_steps } => {
+                out.push(0x02);
+                out.extend_from_slice(&max_steps.to_le_bytes());
+            }
+            Request::Step { steps } => {
+                out.push(0x03);
+                out.extend_from_slice(&steps.to_le_bytes());
+            }
+            Request::Registers => out.push(0x04),
+            Request::SetRegister { register, value } => {
+                let (kind, index) = match register {
+                    Register::Int(i) => (0, *i),
+                    Register::Float(i) => (1, *i),
+                    Register::Flags => (2, 0),
+                };
+                out.extend_from_slice(&[0x05, kind, index]);
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            Request::ReadMemory { addr, len } => {
+                out.push(0x06);
+                out.extend_from_slice(&addr.to_le_bytes());
+                out.extend_from_slice(&len.to_le_bytes());
+            }
+            Request::WriteMemory { addr, data } => {
+                out.push(0x07);
+                out.extend_from_slice(&addr.to_le_bytes());
+                out.extend_from_slice(data);
+            }
+            Request::Break(addr) => {
+                out.push(0x08);
+                out.extend_from_slice(&addr.to_le_bytes());
+            }
+            Request::ClearBreak(addr) => {
+                out.push(0x09);
+                out.extend_from_slice(&addr.to_le_bytes());
+            }
+            Request::Subscribe(events) => out.extend_from_slice(&[0x0a, *events]),
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Request> {
+        let mut r = Reader { bytes };
+        let request = match r.u8()? {
+            0x01 => Request::Load {
+                addr: r.u32()?,
+                data: r.rest(),
+            },
+            0x02 => Request::Run {
+                max_steps: r.u64()?,
+            },
+            0x03 => Request::Step { steps: r.u64()? },
+            0x04 => Request::Registers,
+            0x05 => {
+                let register = match (r.u8()?, r.u8()?) {
+                    (0, i) => Register::Int(i),
+                    (1, i) => Register::Float(i),
+                    (2, _) => Register::Flags,
+                    _ => return Err(protocol_error("unknown register kind")),
+                };
+                Request::SetRegister {
+                    register,
+                    value: r.u32()?,
+                }
+            }
+            0x06 => Request::ReadMemory {
+                addr: r.u32()?,
+                len: r.u32()?,
+            },
+            0x07 => Request::WriteMemory {
+                addr: r.u32()?,
+                data: r.rest(),
+            },
+            0x08 => Request::Break(r.u32()?),
+            0x09 => Request::ClearBreak(r.u32()?),
+            0x0a => Request::Subscribe(r.u8()?),
+            _ => return Err(protocol_error("unknown request")),
+        };
+        if !r.bytes.is_empty() {
+            return Err(protocol_error("trailing bytes after request"));
+        }
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Response::Ok => out.push(0x00),
+            Response::Stopped { reason, steps } => {
+                let reason = match reason {
+                    StopReason::StepLimit => 0,
+                    StopReason::Breakpoint => 1,
+                    StopReason::Shutdown => 2,
+                };
+                out.extend_from_slice(&[0x01, reason]);
+                out.extend_from_slice(&steps.to_le_bytes());
+            }
+            Response::Registers { xs, fs, flags } => {
+                out.extend_from_slice(&[0x02, xs.len() as u8]);
+                for x in xs.iter().chain(fs.iter()) {
+                    out.extend_from_slice(&x.to_le_bytes());
+                }
+                out.extend_from_slice(&flags.to_le_bytes());
+            }
+            Response::Memory(data) => {
+                out.push(0x03);
+                out.extend_from_slice(data);
+            }
+            Response::Error(message) => {
+                out.push(0x04);
+                out.extend_from_slice(message.as_bytes());
+            }
+            Response::Names(names) => {
+                out.push(0x05);
+                for name in names {
+                    write_name(&mut out, name);
+                }
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Response> {
+        let mut r = Reader { bytes };
+        Ok(match r.u8()? {
+            0x00 => Response::Ok,
+            0x01 => {
+                let reason = match r.u8()? {
+                    0 => StopReason::StepLimit,
+                    1 => StopReason::Breakpoint,
+                    2 => StopReason::Shutdown,
+                    _ => return Err(protocol_error("unknown stop reason")),
+                };
+                Response::Stopped {
+                    reason,
+                    steps: r.u64()?,
+                }
+            }
+            0x02 => {
+                let count = r.u8()? as usize;
+                let xs = (0..count).map(|_| r.u32()).collect::<io::Result<_>>()?;
+                let fs = (0..count).map(|_| r.u32()).collect::<io::Result<_>>()?;
+                Response::Registers {
+                    xs,
+                    fs,
+                    flags: r.u32()?,
+                }
+            }
+            0x03 => Response::Memory(r.rest()),
+            0x04 => Response::Error(String::from_utf8_lossy(&r.rest()).into_owned()),
+            0x05 => {
+                let mut names = vec![];
+                while !r.bytes.is_empty() {
+                    names.push(r.name()?);
+                }
+                Response::Names(names)
+            }
+            _ => return Err(protocol_error("unknown response")),
+        })
+    }
+}
+
+impl Event {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            Event::Step { pc } => {
+                out.push(0x00);
+                out.extend_from_slice(&pc.to_le_bytes());
+            }
+            Event::Fault { cause, pc } => {
+                out.push(0x01);
+                out.extend_from_slice(&cause.to_le_bytes());
+                out.extend_from_slice(&pc.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Event> {
+        let mut r = Reader { bytes };
+        Ok(match r.u8()? {
+            0x00 => Event::Step { pc: r.u32()? },
+            0x01 => Event::Fault {
+                cause: r.u32()?,
+                pc: r.u32()?,
+            },
+            _ => return Err(protocol_error("unknown event")),
+        })
+    }
+}
+
+pub fn write_frame<W: Write>(stream: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME as usize {
+        return Err(protocol_error("frame is too long"));
+    }
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+// Sends the events caused by a request, then its response
+pub(crate) fn write_reply<W: Write>(
+    stream: &mut W,
+    response: &Response,
+    events: &[Event],
+) -> io::Result<()> {
+    for event in events {
+        let mut payload = vec![1];
+        payload.extend(event.to_bytes());
+        write_frame(stream, &payload)?;
+    }
+    let mut payload = vec![0];
+    payload.extend(response.to_bytes());
+    write_frame(stream, &payload)
+}
+
+// None at the end of the stream
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME {
+        return Err(protocol_error("frame is too long"));
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+// A machine being controlled remotely, with the breakpoints and subscriptions of its client
+pub struct Session<T, const N: usize = 16>
+where
+    T: Address,
+{
+    pub cpu: Cpu<T, N>,
+    breakpoints: BTreeSet<u32>,
+    subscriptions: u8,
+}
+
+impl<T, const N: usize> Session<T, N>
+where
+    T: Address,
+{
+    pub fn new(cpu: Cpu<T, N>) -> Session<T, N> {
+        Session {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            subscriptions: 0,
+        }
+    }
+
+    pub fn handle<F>(&mut self, request: Request, mut on_event: F) -> Response
+    where
+        F: FnMut(Event),
+    {
+        match request {
+            Request::Load { addr, data } | Request::WriteMemory { addr, data } => {
+                if self.cpu.poke_range(addr, &data) {
+                    Response::Ok
+                } else {
+                    Response::Error(format!("{:#x} is not mapped", addr))
+                }
+            }
+            Request::Run { max_steps } => self.run(max_steps, true, &mut on_event),
+            Request::Step { steps } => self.run(steps, false, &mut on_event),
+            Request::Registers => Response::Registers {
+                xs: self.cpu.xs.to_vec(),
+                fs: self.cpu.fs.iter().map(|f| f.to_bits()).collect(),
+                flags: self.cpu.flags,
+            },
+            Request::SetRegister { register, value } => {
+                match register {
+                    Register::Int(i) if (i as usize) < N => self.cpu.xs[i as usize] = value,
+                    Register::Float(i) if (i as usize) < N => {
+                        self.cpu.fs[i as usize] = f32::from_bits(value)
+                    }
+                    Register::Flags => self.cpu.flags = value,
+                    _ => return Response::Error("no such register".to_string()),
+                }
+                Response::Ok
+            }
+            Request::ReadMemory { len, .. } if len > MAX_READ => {
+                Response::Error(format!("cannot read more than {:#x} bytes", MAX_READ))
+            }
+            Request::ReadMemory { addr, len } => match self.cpu.peek_range(addr, len) {
+                Some(data) => Response::Memory(data),
+                None => Response::Error(format!("{:#x} is not mapped", addr)),
+            },
+            Request::Break(addr) => {
+                self.breakpoints.insert(addr);
+                Response::Ok
+            }
+            Request::ClearBreak(addr) => {
+                self.breakpoints.remove(&addr);
+                Response::Ok
+            }
+            Request::Subscribe(events) => {
+                self.subscriptions = events;
+                Response::Ok
+            }
+        }
+    }
+
+    fn run<F>(&mut self, max_steps: u64, breakpoints: bool, on_event: &mut F) -> Response
+    where
+        F: FnMut(Event),
+    {
+        let mut steps = 0;
+        let reason = loop {
+            if steps == max_steps {
+                break StopReason::StepLimit;
+            }
+            steps += 1;
+            if self.cpu.step() == StepOutcome::Shutdown {
+                break StopReason::Shutdown;
+            }
+
+            let pc = self.cpu.xs[R_PC];
+            if self.cpu.last_fault().is_some() && self.subscriptions & SUBSCRIBE_FAULTS != 0 {
+                on_event(Event::Fault {
+                    cause: self.cpu.fault_cause,
+                    pc: self.cpu.instruction_pc,
+                });
+            }
+            if self.subscriptions & SUBSCRIBE_STEPS != 0 {
+                on_event(Event::Step { pc });
+            }
+            if breakpoints && self.breakpoints.contains(&pc) {
+                break StopReason::Breakpoint;
+            }
+        };
+        Response::Stopped { reason, steps }
+    }
+
+    // Answers requests until the client closes the stream
+    // Malformed requests get an error response, broken streams end the session with an error
+    pub fn serve<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        while let Some(frame) = read_frame(stream)? {
+            let mut events = vec![];
+            let response = match Request::from_bytes(&frame) {
+                Ok(request) => self.handle(request, |event| events.push(event)),
+                Err(e) => Response::Error(e.to_string()),
+            };
+            write_reply(stream, &response, &events)?;
+        }
+        Ok(())
+    }
+
+    // Serves clients one at a time, forever
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            // A client going away does not stop the server
+            let _ = self.serve(&mut stream?);
+        }
+        Ok(())
+    }
+}
+
+pub struct Client<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        Client { stream }
+    }
+
+    // Sends a request and waits for its response, along with the events sent before it
+    pub fn request(&mut self, request: &Request) -> io::Result<(Response, Vec<Event>)> {
+        self.exchange(&request.to_bytes())
+    }
+
+    // Sends an already encoded request, such as one of the server requests in server.rs
+    pub fn exchange(&mut self, payload: &[u8]) -> io::Result<(Response, Vec<Event>)> {
+        write_frame(&mut self.stream, payload)?;
+        let mut events = vec![];
+        loop {
+            let frame = read_frame(&mut self.stream)?
+                .ok_or_else(|| protocol_error("server closed the connection"))?;
+            match frame.split_first() {
+                Some((0, response)) => return Ok((Response::from_bytes(response)?, events)),
+                Some((1, event)) => events.push(Event::from_bytes(event)?),
+                _ => return Err(protocol_error("unknown frame kind")),
+            }
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn encoding() {
+        let requests = [
+            Request::Load {
+                addr: 0x100,
+                data: vec![1, 2, 3],
+            },
+            Request::SetRegister {
+                register: Register::Float(3),
+                value: 1.5f32.to_bits(),
+            },
+            Request::Subscribe(SUBSCRIBE_FAULTS),
+        ];
+        for request in requests.iter() {
+            assert_eq!(&Request::from_bytes(&request.to_bytes()).unwrap(), request);
+        }
+        let response = Response::Registers {
+            xs: vec![1, 2],
+            fs: vec![3, 4],
+            flags: 5,
+        };
+        assert_eq!(
+            Response::from_bytes(&response.to_bytes()).unwrap(),
+            response
+        );
+        assert!(Request::from_bytes(&[0x04, 0x00]).is_err());
+    }
+
+    #[test]
+    fn over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut session = Session::new(Cpu::new(SimpleAddress::default()));
+            let (mut stream, _) = listener.accept().unwrap();
+            session.serve(&mut stream).unwrap();
+            session.cpu.xs[1]
+        });
+
+        // ldi x1, 7; idiv x1, x2
+        let mut client = Client::new(TcpStream::connect(addr).unwrap());
+        let mut request = |r| client.request(&r).unwrap();
+        let program = vec![0x41, 0x07, 0x00, 0x00, 0x00, 0x83, 0x12];
+        assert_eq!(
+            request(Request::Load {
+                addr: 0,
+                data: program
+            }),
+            (Response::Ok, vec![])
+        );
+        request(Request::Break(5));
+        request(Request::Subscribe(SUBSCRIBE_STEPS | SUBSCRIBE_FAULTS));
+        assert_eq!(
+            request(Request::Run { max_steps: 100 }),
+            (
+                Response::Stopped {
+                    reason: StopReason::Breakpoint,
+                    steps: 1
+                },
+                vec![Event::Step { pc: 5 }]
+            )
+        );
+
+        let (response, events) = request(Request::Step { steps: 1 });
+        assert_eq!(
+            response,
+            Response::Stopped {
+                reason: StopReason::StepLimit,
+                steps: 1
+            }
+        );
+        assert_eq!(events[0], Event::Fault { cause: 3, pc: 5 });
+        assert_eq!(
+            request(Request::ReadMemory { addr: 0, len: 2 }).0,
+            Response::Memory(vec![0x41, 0x07])
+        );
+        assert!(matches!(
+            request(Request::ReadMemory {
+                addr: 0,
+                len: u32::MAX
+            })
+            .0,
+            Response::Error(_)
+        ));
+
+        drop(client);
+        assert_eq!(server.join().unwrap(), 7);
+    }
+}
@@ -0,0 +1,551 @@
+// Versioned binary container for machine save states
+// All integers are little endian. A file is a header followed by sections:
+//
+// Header:
+// 0x00 magic "CPUWUSAV"
+// 0x08 u16 major version
+// 0x0a u16 minor version
+// 0x0c u32 number of sections
+//
+// Section:
+// 0x00 4 byte tag
+// 0x04 u32 flags, bit 0 set if a reader must understand the section to load the file
+// 0x08 u64 payload length
+// 0x10 payload
+//
+// Sections:
+// "CPU " register count, xs, fs (as bits), flags, interrupt mask, memmap, system sp, vector base,
+//        fault address, fault cause, asid, shadow sp (u32 each), cycles (u64), vector registers
+//        (u32 lanes, since minor version 1)
+// "MEM " u32 start address, u32 length, u8 compression (0 none, 1 run length), data
+// "DEV " u16 name length, name, device defined state
+//
+// Compatibility rules:
+// - Readers reject files with a major version they do not know
+// - Minor versions only append fields to the end of sections or add new sections. Readers ignore
+//   trailing bytes they do not understand and skip unknown sections unless they are required
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::vector::VECTOR_REGISTERS;
+use crate::{Address, Cpu, CpuState};
+
+pub const MAGIC: &[u8; 8] = b"CPUWUSAV";
+pub const MAJOR_VERSION: u16 = 1;
+pub const MINOR_VERSION: u16 = 1;
+
+// Section flag for sections that cannot be skipped
+pub const SECTION_REQUIRED: u32 = 1;
+
+const COMPRESSION_NONE: u8 = 0;
+const COMPRESSION_RLE: u8 = 1;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16, u16),
+    Truncated,
+    MissingCpu,
+    RegisterCount(u32),
+    UnknownCompression(u8),
+    UnknownRequiredSection([u8; 4]),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            SaveStateError::Io(e) => write!(f, "{}", e),
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(major, minor) => {
+                write!(f, "unsupported save state version {}.{}", major, minor)
+            }
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::MissingCpu => write!(f, "save state has no cpu section"),
+            SaveStateError::RegisterCount(n) => write!(f, "save state has {} registers", n),
+            SaveStateError::UnknownCompression(c) => write!(f, "unknown compression {}", c),
+            SaveStateError::UnknownRequiredSection(tag) => {
+                write!(
+                    f,
+                    "unknown required section {:?}",
+                    String::from_utf8_lossy(tag)
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+impl From<std::io::Error> for SaveStateError {
+    fn from(e: std::io::Error) -> SaveStateError {
+        SaveStateError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryRegion {
+    pub start: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceState {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SaveState<const N: usize = 16> {
+    pub state: CpuState<N>,
+    pub cycles: u64,
+    pub memory: Vec<MemoryRegion>,
+    pub devices: Vec<DeviceState>,
+}
+
+impl<const N: usize> SaveState<N> {
+    // Captures the cpu and the given (start, length) ranges of physical memory
+    pub fn capture<T>(cpu: &mut Cpu<T, N>, ranges: &[(u32, u32)]) -> SaveState<N>
+    where
+        T: Address,
+    {
+        let memory = ranges
+            .iter()
+            .map(|&(start, len)| MemoryRegion {
+                start,
+                data: (0..len)
+                    .map(|i| cpu.addressing.read_debug(start.wrapping_add(i)))
+                    .collect(),
+            })
+            .collect();
+
+        SaveState {
+            state: cpu.state(),
+            cycles: cpu.cycles,
+            memory,
+            devices: vec![],
+        }
+    }
+
+    pub fn add_device(&mut self, name: &str, data: Vec<u8>) {
+        self.devices.push(DeviceState {
+            name: name.to_string(),
+            data,
+        });
+    }
+
+    pub fn device(&self, name: &str) -> Option<&[u8]> {
+        self.devices
+            .iter()
+            .find(|device| device.name == name)
+            .map(|device| &device.data[..])
+    }
+
+    // Restores the cpu and memory, device state is left to the caller
+    pub fn apply<T>(&self, cpu: &mut Cpu<T, N>)
+    where
+        T: Address,
+    {
+        cpu.set_state(&self.state);
+        cpu.cycles = self.cycles;
+        for region in self.memory.iter() {
+            for (i, byte) in region.data.iter().enumerate() {
+                cpu.addressing
+                    .write(region.start.wrapping_add(i as u32), *byte);
+            }
+        }
+    }
+
+    pub fn to_bytes(&self, compress: bool) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+        out.extend_from_slice(&MINOR_VERSION.to_le_bytes());
+        let sections = 1 + self.memory.len() + self.devices.len();
+        out.extend_from_slice(&(sections as u32).to_le_bytes());
+
+        let s = &self.state;
+        let mut cpu = vec![];
+        cpu.extend_from_slice(&(N as u32).to_le_bytes());
+        for x in s.xs.iter() {
+            cpu.extend_from_slice(&x.to_le_bytes());
+        }
+        for f in s.fs.iter() {
+            cpu.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        let words = [
+            s.flags,
+            s.interrupt_mask as u32,
+            s.memmap,
+            s.system_sp,
+            s.vector_base,
+            s.fault_address,
+            s.fault_cause,
+            s.asid,
+            s.shadow_sp,
+        ];
+        for word in words.iter() {
+            cpu.extend_from_slice(&word.to_le_bytes());
+        }
+        cpu.extend_from_slice(&self.cycles.to_le_bytes());
+        for lane in s.vs.iter().flatten() {
+            cpu.extend_from_slice(&lane.to_le_bytes());
+        }
+        write_section(&mut out, b"CPU ", SECTION_REQUIRED, &cpu);
+
+        for region in self.memory.iter() {
+            let mut mem = vec![];
+            mem.extend_from_slice(&region.start.to_le_bytes());
+            mem.extend_from_slice(&(region.data.len() as u32).to_le_bytes());
+            if compress {
+                mem.push(COMPRESSION_RLE);
+                mem.extend_from_slice(&rle_compress(&region.data));
+            } else {
+                mem.push(COMPRESSION_NONE);
+                mem.extend_from_slice(&region.data);
+            }
+            write_section(&mut out, b"MEM ", SECTION_REQUIRED, &mem);
+        }
+
+        for device in self.devices.iter() {
+            let mut dev = vec![];
+            dev.extend_from_slice(&(device.name.len() as u16).to_le_bytes());
+            dev.extend_from_slice(device.name.as_bytes());
+            dev.extend_from_slice(&device.data);
+            write_section(&mut out, b"DEV ", 0, &dev);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<SaveState<N>, SaveStateError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(8)? != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        let major = r.u16()?;
+        let minor = r.u16()?;
+        if major != MAJOR_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(major, minor));
+        }
+
+        let mut cpu = None;
+        let mut memory = vec![];
+        let mut devices = vec![];
+        for _ in 0..r.u32()? {
+            let mut tag = [0; 4];
+            tag.copy_from_slice(r.take(4)?);
+            let flags = r.u32()?;
+            let len = r.u64()?;
+            if len > (bytes.len() - r.pos) as u64 {
+                return Err(SaveStateError::Truncated);
+            }
+            let mut s = Reader {
+                bytes: r.take(len as usize)?,
+                pos: 0,
+            };
+
+            match &tag {
+                b"CPU " => cpu = Some(read_cpu(&mut s)?),
+                b"MEM " => {
+                    let start = s.u32()?;
+                    let len = s.u32()? as usize;
+                    let data = match s.u8()? {
+                        COMPRESSION_NONE => s.take(len)?.to_vec(),
+                        COMPRESSION_RLE => rle_decompress(s.rest(), len)?,
+                        c => return Err(SaveStateError::UnknownCompression(c)),
+                    };
+                    memory.push(MemoryRegion { start, data });
+                }
+                b"DEV " => {
+                    let len = s.u16()? as usize;
+                    let name = String::from_utf8_lossy(s.take(len)?).into_owned();
+                    devices.push(DeviceState {
+                        name,
+                        data: s.rest().to_vec(),
+                    });
+                }
+                _ if flags & SECTION_REQUIRED != 0 => {
+                    return Err(SaveStateError::UnknownRequiredSection(tag));
+                }
+                _ => (),
+            }
+        }
+
+        let (state, cycles) = cpu.ok_or(SaveStateError::MissingCpu)?;
+        Ok(SaveState {
+            state,
+            cycles,
+            memory,
+            devices,
+        })
+    }
+
+    pub fn save<P>(&self, path: P, compress: bool) -> Result<(), SaveStateError>
+    where
+        P: AsRef<Path>,
+    {
+        fs::write(path, self.to_bytes(compress))?;
+        Ok(())
+    }
+
+    pub fn load<P>(path: P) -> Result<SaveState<N>, SaveStateError>
+    where
+        P: AsRef<Path>,
+    {
+        SaveState::from_bytes(&fs::read(path)?)
+    }
+}
+
+fn read_cpu<const N: usize>(s: &mut Reader) -> Result<(CpuState<N>, u64), SaveStateError> {
+    let count = s.u32()?;
+    if count as usize != N {
+        return Err(SaveStateError::RegisterCount(count));
+    }
+    let mut xs = [0; N];
+    for x in xs.iter_mut() {
+        *x = s.u32()?;
+    }
+    let mut fs = [0.0; N];
+    for f in fs.iter_mut() {
+        *f = f32::from_bits(s.u32()?);
+    }
+
+    let mut state = CpuState {
+        xs,
+        fs,
+        vs: [[0; 4]; VECTOR_REGISTERS],
+        flags: s.u32()?,
+        interrupt_mask: s.u32()? as u8,
+        memmap: s.u32()?,
+        system_sp: s.u32()?,
+        vector_base: s.u32()?,
+        fault_address: s.u32()?,
+        fault_cause: s.u32()?,
+        asid: s.u32()?,
+        shadow_sp: s.u32()?,
+    };
+    let cycles = s.u64()?;
+
+    // Files from before minor version 1 end here and leave the vector registers zeroed
+    if s.bytes.len() - s.pos >= 16 * VECTOR_REGISTERS {
+        for lane in state.vs.iter_mut().flatten() {
+            *lane = s.u32()?;
+        }
+    }
+    Ok((state, cycles))
+}
+
+fn write_section(out: &mut Vec<u8>, tag: &[u8; 4], flags: u32, payload: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(SaveStateError::Truncated);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let bytes = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+        bytes
+    }
+
+    fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+}
+
+// Run length encoding
+// A control byte c below 0x80 is followed by c + 1 literal bytes, otherwise the next byte is
+// repeated c - 0x7d times (3 to 130)
+pub(crate) fn rle_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut literals: Vec<u8> = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == data[i] && run < 130 {
+            run += 1;
+        }
+
+        if run >= 3 {
+            flush_literals(&mut out, &mut literals);
+            out.push((run + 0x7d) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            literals.push(data[i]);
+            if literals.len() == 0x80 {
+                flush_literals(&mut out, &mut literals);
+            }
+            i += 1;
+        }
+    }
+    flush_literals(&mut out, &mut literals);
+    out
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+    if !literals.is_empty() {
+        out.push((literals.len() - 1) as u8);
+        out.append(literals);
+    }
+}
+
+pub(crate) fn rle_decompress(data: &[u8], len: usize) -> Result<Vec<u8>, SaveStateError> {
+    let mut r = Reader {
+        bytes: data,
+        pos: 0,
+    };
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let c = r.u8()?;
+        if c < 0x80 {
+            out.extend_from_slice(r.take(c as usize + 1)?);
+        } else {
+            let byte = r.u8()?;
+            out.resize(out.len() + (c as usize - 0x7d), byte);
+        }
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    fn machine() -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[3] = 0xdeadbeef;
+        cpu.fs[2] = 1.5;
+        cpu.vs[5] = [1, 2, 3, 4];
+        cpu.vector_base = 0x1000;
+        cpu.cycles = 77;
+        cpu.addressing
+            .load(0x100, b"uwu uwu uwu\0\0\0\0\0\0\0\0\0\0");
+        cpu
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut cpu = machine();
+        let mut save = SaveState::capture(&mut cpu, &[(0x100, 0x400)]);
+        save.add_device("rng", vec![1, 2, 3]);
+
+        for &compress in [false, true].iter() {
+            let bytes = save.to_bytes(compress);
+            let loaded = SaveState::<16>::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded.memory, save.memory);
+            assert_eq!(loaded.device("rng"), Some(&[1, 2, 3][..]));
+
+            let mut restored = Cpu::new(SimpleAddress::default());
+            loaded.apply(&mut restored);
+            assert_eq!(restored.xs[3], 0xdeadbeef);
+            assert_eq!(restored.fs[2], 1.5);
+            assert_eq!(restored.vs[5], [1, 2, 3, 4]);
+            assert_eq!(restored.vector_base, 0x1000);
+            assert_eq!(restored.cycles(), 77);
+            assert_eq!(restored.addressing.dump(0x100, 4), b"uwu ");
+        }
+        assert!(save.to_bytes(true).len() < save.to_bytes(false).len());
+    }
+
+    #[test]
+    fn compatibility() {
+        let mut cpu = machine();
+        let bytes = SaveState::capture(&mut cpu, &[]).to_bytes(false);
+
+        // A newer minor version with an extra optional section and a longer cpu section
+        let mut newer = bytes[..0x10].to_vec();
+        newer[0x0a] = MINOR_VERSION as u8 + 1;
+        newer[0x0c] = 2;
+        let cpu_section = &bytes[0x10..];
+        let mut extended = cpu_section[0x10..].to_vec();
+        extended.extend_from_slice(&[0xff; 4]);
+        write_section(&mut newer, b"CPU ", SECTION_REQUIRED, &extended);
+        write_section(&mut newer, b"NEW ", 0, &[1, 2, 3]);
+        let loaded = SaveState::<16>::from_bytes(&newer).unwrap();
+        assert_eq!(loaded.state.xs[3], 0xdeadbeef);
+        assert_eq!(loaded.cycles, 77);
+        assert_eq!(loaded.state.vs[5], [1, 2, 3, 4]);
+
+        // Version 1.0 files have no vector registers
+        let mut older = bytes[..0x10].to_vec();
+        older[0x0a] = 0;
+        write_section(&mut older, b"CPU ", SECTION_REQUIRED, &cpu_section[0x10..0x10 + 0xb0]);
+        let loaded = SaveState::<16>::from_bytes(&older).unwrap();
+        assert_eq!(loaded.cycles, 77);
+        assert_eq!(loaded.state.vs[5], [0; 4]);
+
+        // Unknown required sections and major versions are rejected
+        let mut required = bytes.clone();
+        required[0x0c] = 2;
+        write_section(&mut required, b"NEW ", SECTION_REQUIRED, &[]);
+        assert!(matches!(
+            SaveState::<16>::from_bytes(&required),
+            Err(SaveStateError::UnknownRequiredSection(tag)) if &tag == b"NEW "
+        ));
+
+        let mut major = bytes.clone();
+        major[0x08] = 2;
+        assert!(matches!(
+            SaveState::<16>::from_bytes(&major),
+            Err(SaveStateError::UnsupportedVersion(2, MINOR_VERSION))
+        ));
+
+        assert!(matches!(
+            SaveState::<16>::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SaveStateError::Truncated)
+        ));
+        assert!(matches!(
+            SaveState::<32>::from_bytes(&bytes),
+            Err(SaveStateError::RegisterCount(16))
+        ));
+    }
+
+    #[test]
+    fn rle() {
+        let mut data = vec![0; 300];
+        data.extend_from_slice(b"abcabc");
+        data.extend((0..200).map(|i| i as u8));
+        data.extend_from_slice(&[7, 7]);
+        let compressed = rle_compress(&data);
+        assert_eq!(rle_decompress(&compressed, data.len()).unwrap(), data);
+    }
+}
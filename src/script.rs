@@ -0,0 +1,655 @@
+// Debugger automation scripts
+// A script is a list of hooks checked after every step:
+//
+// break 0x1000 if x1 == 3 { print x1, [sp]; stop }
+// watch byte[0x2000]
+// fault { print pc, fault; dump sp, 20 }
+//
+// break runs when execution reaches an address and its condition holds, watch when the value of
+// an expression changes, and fault when an instruction faults. A hook without a block stops the
+// run, and a hook with one stops it only if the block runs stop. Statements are print (values in
+// hex), dump (bytes of memory), set (an integer register), and stop.
+//
+// Expressions are 32 bit unsigned integers with the operators of C, where comparisons give 0 or 1.
+// Operands are numbers (decimal or hex with 0x), registers (x0-x31, pc, sp, base, flags), cycles,
+// fault (the fault cause register), and memory reads: [addr] for a word, half[addr], byte[addr].
+// Memory is read with peek, and reading unmapped memory or dividing by 0 ends the run with an
+// error.
+
+use std::fmt;
+use std::fmt::Write;
+
+use crate::{Address, Cpu, InvalidMemoryAccess, StepOutcome, R_BASE, R_PC, R_SP};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptError {
+    // Message and byte offset in the script
+    Parse(String, usize),
+
+    Unmapped(u32),
+    DivideByZero,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            ScriptError::Parse(message, pos) => write!(f, "{} at offset {}", message, pos),
+            ScriptError::Unmapped(addr) => write!(f, "{:#x} is not mapped", addr),
+            ScriptError::DivideByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(u32),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+// Longest first so that eg << is not read as <
+const SYMBOLS: &[&str] = &[
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "[", "]", "{", "}", ",", ";", "=",
+    "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "!", "~",
+];
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ScriptError> {
+    let mut tokens = vec![];
+    let bytes = source.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '#' {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && (bytes[i] as char).is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let text = &source[start..i];
+            let value = match text.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => text.parse(),
+            };
+            let value = value.map_err(|_| ScriptError::Parse("bad number".to_string(), start))?;
+            tokens.push((Token::Number(value), start));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push((Token::Ident(source[start..i].to_string()), start));
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|s| source[i..].starts_with(*s))
+                .ok_or_else(|| ScriptError::Parse(format!("unexpected {}", c), start))?;
+            i += symbol.len();
+            tokens.push((Token::Symbol(symbol), start));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(u32),
+    Register(usize),
+    Flags,
+    Cycles,
+    Fault,
+
+    // Address and size in bytes
+    Memory(Box<Expr>, u32),
+
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Print(Vec<Expr>),
+    Dump(Expr, Expr),
+    Set(usize, Expr),
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Trigger {
+    Break(u32, Option<Expr>),
+    Watch(Expr),
+    Fault,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Hook {
+    trigger: Trigger,
+
+    // None to stop without running anything
+    actions: Option<Vec<Statement>>,
+}
+
+// Binary operators from the loosest binding to the tightest
+const PRECEDENCE: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |t| t.1)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, ScriptError> {
+        Err(ScriptError::Parse(message.to_string(), self.offset()))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.0)
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), ScriptError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            self.error(&format!("expected {}", symbol))
+        }
+    }
+
+    fn eat_ident(&mut self, ident: &str) -> bool {
+        if self.peek() == Some(&Token::Ident(ident.to_string())) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn hook(&mut self) -> Result<Hook, ScriptError> {
+        let trigger = if self.eat_ident("break") {
+            let addr = match self.peek() {
+                Some(&Token::Number(addr)) => addr,
+                _ => return self.error("expected an address"),
+            };
+            self.pos += 1;
+            let condition = if self.eat_ident("if") {
+                Some(self.expr(0)?)
+            } else {
+                None
+            };
+            Trigger::Break(addr, condition)
+        } else if self.eat_ident("watch") {
+            Trigger::Watch(self.expr(0)?)
+        } else if self.eat_ident("fault") {
+            Trigger::Fault
+        } else {
+            return self.error("expected break, watch, or fault");
+        };
+
+        let actions = if self.eat("{") {
+            let mut actions = vec![];
+            while !self.eat("}") {
+                actions.push(self.statement()?);
+                if !self.eat(";") {
+                    self.expect("}")?;
+                    break;
+                }
+            }
+            Some(actions)
+        } else {
+            None
+        };
+        Ok(Hook { trigger, actions })
+    }
+
+    fn statement(&mut self) -> Result<Statement, ScriptError> {
+        if self.eat_ident("print") {
+            let mut values = vec![self.expr(0)?];
+            while self.eat(",") {
+                values.push(self.expr(0)?);
+            }
+            Ok(Statement::Print(values))
+        } else if self.eat_ident("dump") {
+            let addr = self.expr(0)?;
+            self.expect(",")?;
+            Ok(Statement::Dump(addr, self.expr(0)?))
+        } else if self.eat_ident("set") {
+            let register = match self.peek() {
+                Some(Token::Ident(name)) => register(name),
+                _ => None,
+            };
+            let register = match register {
+                Some(register) => register,
+                None => return self.error("expected a register"),
+            };
+            self.pos += 1;
+            self.expect("=")?;
+            Ok(Statement::Set(register, self.expr(0)?))
+        } else if self.eat_ident("stop") {
+            Ok(Statement::Stop)
+        } else {
+            self.error("expected print, dump, set, or stop")
+        }
+    }
+
+    fn expr(&mut self, level: usize) -> Result<Expr, ScriptError> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.expr(level + 1)?;
+        'outer: loop {
+            for op in PRECEDENCE[level] {
+                if self.eat(op) {
+                    let rhs = self.expr(level + 1)?;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ScriptError> {
+        for op in ["-", "!", "~"].iter() {
+            if self.eat(op) {
+                return Ok(Expr::Unary(op, Box::new(self.unary()?)));
+            }
+        }
+
+        if self.eat("(") {
+            let expr = self.expr(0)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if self.eat("[") {
+            return self.memory(4);
+        }
+
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Ident(name)) => match name.as_str() {
+                "byte" | "half" => {
+                    self.expect("[")?;
+                    self.memory(if name == "byte" { 1 } else { 2 })
+                }
+                "flags" => Ok(Expr::Flags),
+                "cycles" => Ok(Expr::Cycles),
+                "fault" => Ok(Expr::Fault),
+                _ => match register(&name) {
+                    Some(register) => Ok(Expr::Register(register)),
+                    None => {
+                        self.pos -= 1;
+                        self.error(&format!("unknown name {}", name))
+                    }
+                },
+            },
+            _ => {
+                self.pos -= 1;
+                self.error("expected an expression")
+            }
+        }
+    }
+
+    // After the opening bracket
+    fn memory(&mut self, size: u32) -> Result<Expr, ScriptError> {
+        let addr = self.expr(0)?;
+        self.expect("]")?;
+        Ok(Expr::Memory(Box::new(addr), size))
+    }
+}
+
+fn register(name: &str) -> Option<usize> {
+    match name {
+        "pc" => Some(R_PC),
+        "sp" => Some(R_SP),
+        "base" => Some(R_BASE),
+        _ => name
+            .strip_prefix('x')
+            .and_then(|i| i.parse().ok())
+            .filter(|&i| i < 32),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Breakpoint(u32),
+
+    // Index of the watch among the watches of the script, and its old and new values
+    Watch(usize, u32, u32),
+
+    Fault(InvalidMemoryAccess),
+    StepLimit,
+    Shutdown,
+    Error(ScriptError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptRun {
+    pub reason: StopReason,
+    pub steps: u64,
+
+    // Text printed by print and dump
+    pub output: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Script {
+    hooks: Vec<Hook>,
+
+    // Last value of each watch, None before the first run
+    watched: Vec<Option<u32>>,
+}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Script, ScriptError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            end: source.len(),
+        };
+        let mut hooks = vec![];
+        while parser.peek().is_some() {
+            hooks.push(parser.hook()?);
+        }
+        let watches = hooks
+            .iter()
+            .filter(|h| matches!(h.trigger, Trigger::Watch(_)))
+            .count();
+        Ok(Script {
+            hooks,
+            watched: vec![None; watches],
+        })
+    }
+
+    // Steps the cpu until a hook stops it, for at most max_steps steps
+    // Watches compare against their values at the end of the previous run, or at the start of the
+    // first one
+    pub fn run<T, const N: usize>(&mut self, cpu: &mut Cpu<T, N>, max_steps: u64) -> ScriptRun
+    where
+        T: Address,
+    {
+        let mut output = String::new();
+        let mut steps = 0;
+        let reason = self.run_hooks(cpu, max_steps, &mut steps, &mut output);
+        ScriptRun {
+            reason: match reason {
+                Ok(reason) => reason,
+                Err(e) => StopReason::Error(e),
+            },
+            steps,
+            output,
+        }
+    }
+
+    fn run_hooks<T, const N: usize>(
+        &mut self,
+        cpu: &mut Cpu<T, N>,
+        max_steps: u64,
+        steps: &mut u64,
+        output: &mut String,
+    ) -> Result<StopReason, ScriptError>
+    where
+        T: Address,
+    {
+        let watches = self.hooks.iter().filter_map(|h| match &h.trigger {
+            Trigger::Watch(expr) => Some(expr),
+            _ => None,
+        });
+        for (expr, watched) in watches.zip(self.watched.iter_mut()) {
+            if watched.is_none() {
+                *watched = Some(eval(cpu, expr)?);
+            }
+        }
+
+        while *steps < max_steps {
+            *steps += 1;
+            if cpu.step() == StepOutcome::Shutdown {
+                return Ok(StopReason::Shutdown);
+            }
+
+            let mut stop = None;
+            let mut watch = 0;
+            for hook in self.hooks.iter() {
+                let reason = match &hook.trigger {
+                    Trigger::Break(addr, condition) if cpu.xs[R_PC] == *addr => {
+                        let hit = match condition {
+                            Some(condition) => eval(cpu, condition)? != 0,
+                            None => true,
+                        };
+                        if hit {
+                            Some(StopReason::Breakpoint(*addr))
+                        } else {
+                            None
+                        }
+                    }
+                    Trigger::Break(_, _) => None,
+                    Trigger::Watch(expr) => {
+                        let old = self.watched[watch].unwrap();
+                        let new = eval(cpu, expr)?;
+                        self.watched[watch] = Some(new);
+                        watch += 1;
+                        if old != new {
+                            Some(StopReason::Watch(watch - 1, old, new))
+                        } else {
+                            None
+                        }
+                    }
+                    Trigger::Fault => cpu.last_fault().map(StopReason::Fault),
+                };
+
+                if let Some(reason) = reason {
+                    let stops = match &hook.actions {
+                        Some(actions) => execute(cpu, actions, output)?,
+                        None => true,
+                    };
+                    if stops && stop.is_none() {
+                        stop = Some(reason);
+                    }
+                }
+            }
+            if let Some(reason) = stop {
+                return Ok(reason);
+            }
+        }
+        Ok(StopReason::StepLimit)
+    }
+}
+
+// Returns whether the statements ran stop
+fn execute<T, const N: usize>(
+    cpu: &mut Cpu<T, N>,
+    statements: &[Statement],
+    output: &mut String,
+) -> Result<bool, ScriptError>
+where
+    T: Address,
+{
+    for statement in statements {
+        match statement {
+            Statement::Print(values) => {
+                let values = values
+                    .iter()
+                    .map(|v| eval(cpu, v).map(|v| format!("{:#x}", v)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                writeln!(output, "{}", values.join(" ")).unwrap();
+            }
+            Statement::Dump(addr, len) => {
+                let addr = eval(cpu, addr)?;
+                let len = eval(cpu, len)?;
+                for row in (0..len).step_by(16) {
+                    let start = addr.wrapping_add(row);
+                    let bytes = cpu
+                        .peek_range(start, (len - row).min(16))
+                        .ok_or(ScriptError::Unmapped(start))?;
+                    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                    writeln!(output, "{:08x}: {}", start, hex.join(" ")).unwrap();
+                }
+            }
+            Statement::Set(register, value) => {
+                let value = eval(cpu, value)?;
+                if let Some(x) = cpu.xs.get_mut(*register) {
+                    *x = value;
+                }
+            }
+            Statement::Stop => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+fn eval<T, const N: usize>(cpu: &mut Cpu<T, N>, expr: &Expr) -> Result<u32, ScriptError>
+where
+    T: Address,
+{
+    Ok(match expr {
+        Expr::Number(value) => *value,
+        Expr::Register(register) => cpu.xs.get(*register).copied().unwrap_or(0),
+        Expr::Flags => cpu.flags,
+        Expr::Cycles => cpu.cycles as u32,
+        Expr::Fault => cpu.fault_cause,
+        Expr::Memory(addr, size) => {
+            let addr = eval(cpu, addr)?;
+            let bytes = cpu
+                .peek_range(addr, *size)
+                .ok_or(ScriptError::Unmapped(addr))?;
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| value << 8 | byte as u32)
+        }
+        Expr::Unary(op, value) => {
+            let value = eval(cpu, value)?;
+            match *op {
+                "-" => value.wrapping_neg(),
+                "!" => (value == 0) as u32,
+                "~" => !value,
+                _ => unreachable!("nya :("),
+            }
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = eval(cpu, lhs)?;
+
+            // Short circuit so that eg x1 != 0 && [x1] == 3 does not read address 0
+            match *op {
+                "&&" if lhs == 0 => return Ok(0),
+                "||" if lhs != 0 => return Ok(1),
+                _ => (),
+            }
+            let rhs = eval(cpu, rhs)?;
+            match *op {
+                "||" | "&&" => (rhs != 0) as u32,
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "==" => (lhs == rhs) as u32,
+                "!=" => (lhs != rhs) as u32,
+                "<" => (lhs < rhs) as u32,
+                "<=" => (lhs <= rhs) as u32,
+                ">" => (lhs > rhs) as u32,
+                ">=" => (lhs >= rhs) as u32,
+                "<<" => lhs.wrapping_shl(rhs),
+                ">>" => lhs.wrapping_shr(rhs),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" => lhs.checked_div(rhs).ok_or(ScriptError::DivideByZero)?,
+                "%" => lhs.checked_rem(rhs).ok_or(ScriptError::DivideByZero)?,
+                _ => unreachable!("nya :("),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    // ldi x1, 1; loop: iadd x1, x1; bnz loop
+    fn doubling() -> Cpu<SimpleAddress> {
+        let mut memory = SimpleAddress::default();
+        memory.load(
+            0,
+            &[
+                0x41, 0x01, 0x00, 0x00, 0x00, 0x80, 0x11, 0x08, 0x05, 0, 0, 0,
+            ],
+        );
+        memory.load(0x100, &0xdeadbeefu32.to_le_bytes());
+        Cpu::new(memory)
+    }
+
+    #[test]
+    fn breakpoints_and_watches() {
+        let mut cpu = doubling();
+        let mut script = Script::parse(
+            "# stops once x1 reaches 8
+            break 0x5 if x1 >= 8 && byte[0x100] == 0xef { print x1, [0x100] >> 16, -1; stop }
+            break 0x7 { print cycles }",
+        )
+        .unwrap();
+        let run = script.run(&mut cpu, 100);
+        assert_eq!(run.reason, StopReason::Breakpoint(5));
+        assert_eq!(run.output, "0x2\n0x4\n0x6\n0x8 0xdead 0xffffffff\n");
+
+        let mut script = Script::parse("watch x1 > 100").unwrap();
+        let run = script.run(&mut cpu, 100);
+        assert_eq!(run.reason, StopReason::Watch(0, 0, 1));
+        assert_eq!(cpu.xs[1], 128);
+    }
+
+    #[test]
+    fn faults_and_errors() {
+        // idiv x1, x2 with x2 = 0
+        let mut memory = SimpleAddress::default();
+        memory.load(0, &[0x83, 0x12]);
+        let mut cpu = Cpu::new(memory);
+        let mut script = Script::parse("fault { print fault, pc; set x2 = 1; stop }").unwrap();
+        let run = script.run(&mut cpu, 10);
+        assert_eq!(
+            run.reason,
+            StopReason::Fault(InvalidMemoryAccess::DivideByZero)
+        );
+        assert_eq!(run.output, "0x3 0x0\n");
+        assert_eq!(cpu.xs[2], 1);
+
+        assert_eq!(
+            Script::parse("break 0x10 { print x1 +  }").unwrap_err(),
+            ScriptError::Parse("expected an expression".to_string(), 25)
+        );
+        let mut script = Script::parse("watch [0x1000000 / x0]").unwrap();
+        assert_eq!(
+            script.run(&mut cpu, 10).reason,
+            StopReason::Error(ScriptError::DivideByZero)
+        );
+    }
+}
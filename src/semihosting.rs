@@ -0,0 +1,239 @@
+// Semihosting file access
+// Lets guest test programs load fixtures and write results as host files, without a filesystem or
+// block driver of their own. The default firmware (see firmware.rs) offers these services once it
+// is given a sandbox directory, guest paths are relative to the sandbox and cannot leave it.
+//
+// Services, with the handle of an open file in x1 where there is one:
+// 7  FW_OPEN  opens the path of x2 bytes at x1 with mode x3, x0 = handle
+// 8  FW_CLOSE closes handle x1
+// 9  FW_FREAD reads up to x3 bytes to x2, x0 = bytes read, 0 at the end of the file
+// 10 FW_FWRITE writes x3 bytes at x2, x0 = bytes written
+// 11 FW_SEEK  moves to offset x2 (signed) from the start, current position, or end for x3 = 0, 1,
+//             or 2, x0 = new position (the low word of it)
+//
+// Every service returns FW_ERROR in x0 when it fails.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::firmware::{FirmwareAction, GuestMemory, FW_ERROR};
+use crate::InvalidMemoryAccess;
+
+pub const FW_OPEN: u32 = 7;
+pub const FW_CLOSE: u32 = 8;
+pub const FW_FREAD: u32 = 9;
+pub const FW_FWRITE: u32 = 10;
+pub const FW_SEEK: u32 = 11;
+
+pub const MODE_READ: u32 = 0;
+
+// Creates the file, or truncates it if it exists
+pub const MODE_WRITE: u32 = 1;
+
+// Creates the file if it does not exist, writes go to its end
+pub const MODE_APPEND: u32 = 2;
+
+// Reads and writes an existing file
+pub const MODE_READ_WRITE: u32 = 3;
+
+// Longest path a guest may pass, and most bytes moved by one read or write
+pub const MAX_PATH: u32 = 4096;
+pub const MAX_TRANSFER: u32 = 1 << 20;
+
+#[derive(Debug)]
+pub struct HostFiles {
+    root: PathBuf,
+    files: BTreeMap<u32, File>,
+    next_handle: u32,
+}
+
+impl HostFiles {
+    // The sandbox directory must exist
+    pub fn new<P: AsRef<Path>>(root: P) -> std::io::Result<HostFiles> {
+        Ok(HostFiles {
+            root: fs::canonicalize(root)?,
+            files: BTreeMap::new(),
+            next_handle: 1,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn open_files(&self) -> usize {
+        self.files.len()
+    }
+
+    // Handles the file services, None for any other service
+    pub fn call(
+        &mut self,
+        service: u32,
+        args: [u32; 3],
+        memory: &mut dyn GuestMemory,
+    ) -> Option<Result<FirmwareAction, InvalidMemoryAccess>> {
+        let result = match service {
+            FW_OPEN => self.open(args, memory),
+            FW_CLOSE => Ok(self.files.remove(&args[0]).map(|_| 0)),
+            FW_FREAD => self.read(args, memory),
+            FW_FWRITE => self.write(args, memory),
+            FW_SEEK => Ok(self.seek(args)),
+            _ => return None,
+        };
+        Some(result.map(|x0| FirmwareAction::Return(x0.unwrap_or(FW_ERROR), 0)))
+    }
+
+    // Relative paths of plain names only, so that nothing outside the sandbox can be named
+    // Symbolic links inside the sandbox could still lead out of it, so the directory, and the file
+    // if it exists, must also resolve to somewhere inside
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let plain = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if path.as_os_str().is_empty() || !plain {
+            return None;
+        }
+
+        let path = self.root.join(path);
+        let inside = |path: &Path| fs::canonicalize(path).is_ok_and(|p| p.starts_with(&self.root));
+        if !inside(path.parent()?) || (fs::symlink_metadata(&path).is_ok() && !inside(&path)) {
+            return None;
+        }
+        Some(path)
+    }
+
+    fn open(
+        &mut self,
+        [addr, len, mode]: [u32; 3],
+        memory: &mut dyn GuestMemory,
+    ) -> Result<Option<u32>, InvalidMemoryAccess> {
+        if len > MAX_PATH {
+            return Ok(None);
+        }
+        let bytes = (0..len)
+            .map(|i| memory.read(addr.wrapping_add(i)))
+            .collect::<Result<Vec<u8>, _>>()?;
+        let path = match String::from_utf8(bytes).ok().and_then(|p| self.resolve(&p)) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            MODE_READ_WRITE => options.read(true).write(true),
+            _ => return Ok(None),
+        };
+        let file = match options.open(&path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1).max(1);
+        self.files.insert(handle, file);
+        Ok(Some(handle))
+    }
+
+    fn read(
+        &mut self,
+        [handle, addr, len]: [u32; 3],
+        memory: &mut dyn GuestMemory,
+    ) -> Result<Option<u32>, InvalidMemoryAccess> {
+        let file = match self.files.get_mut(&handle) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let mut data = vec![0; len.min(MAX_TRANSFER) as usize];
+        let read = match file.read(&mut data) {
+            Ok(read) => read,
+            Err(_) => return Ok(None),
+        };
+        for (i, byte) in data[..read].iter().enumerate() {
+            memory.write(addr.wrapping_add(i as u32), *byte)?;
+        }
+        Ok(Some(read as u32))
+    }
+
+    fn write(
+        &mut self,
+        [handle, addr, len]: [u32; 3],
+        memory: &mut dyn GuestMemory,
+    ) -> Result<Option<u32>, InvalidMemoryAccess> {
+        if !self.files.contains_key(&handle) {
+            return Ok(None);
+        }
+        let data = (0..len.min(MAX_TRANSFER))
+            .map(|i| memory.read(addr.wrapping_add(i)))
+            .collect::<Result<Vec<u8>, _>>()?;
+        let file = self.files.get_mut(&handle).unwrap();
+        Ok(file.write_all(&data).ok().map(|()| data.len() as u32))
+    }
+
+    fn seek(&mut self, [handle, offset, whence]: [u32; 3]) -> Option<u32> {
+        let file = self.files.get_mut(&handle)?;
+        let from = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset as i32 as i64),
+            2 => SeekFrom::End(offset as i32 as i64),
+            _ => return None,
+        };
+        file.seek(from).ok().map(|position| position as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firmware::DefaultFirmware;
+    use crate::{Cpu, SimpleAddress};
+
+    #[test]
+    fn sandboxed_files() {
+        let root = std::env::temp_dir().join(format!("cpuwu-semihosting-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("fixture.txt"), b"fixture").unwrap();
+
+        // fwcall, over and over
+        let mut memory = SimpleAddress::new(0x1000);
+        memory.load(0, &[0x20; 16]);
+        memory.load(0x100, b"fixture.txt");
+        memory.load(0x120, b"out.txt");
+        memory.load(0x140, b"../escape");
+        let mut cpu = Cpu::new(memory);
+        let files = HostFiles::new(&root).unwrap();
+        cpu.set_firmware(Some(Box::new(DefaultFirmware::new().files(files))));
+
+        let call = |cpu: &mut Cpu<SimpleAddress>, service, x1, x2, x3| {
+            cpu.xs[0] = service;
+            cpu.xs[1] = x1;
+            cpu.xs[2] = x2;
+            cpu.xs[3] = x3;
+            cpu.step();
+            cpu.xs[0]
+        };
+
+        let input = call(&mut cpu, FW_OPEN, 0x100, 11, MODE_READ);
+        assert_ne!(input, FW_ERROR);
+        assert_eq!(call(&mut cpu, FW_SEEK, input, 3, 0), 3);
+        assert_eq!(call(&mut cpu, FW_FREAD, input, 0x200, 16), 4);
+        assert_eq!(cpu.peek_range(0x200, 4), Some(b"ture".to_vec()));
+        assert_eq!(call(&mut cpu, FW_FREAD, input, 0x200, 16), 0);
+        assert_eq!(call(&mut cpu, FW_CLOSE, input, 0, 0), 0);
+        assert_eq!(call(&mut cpu, FW_CLOSE, input, 0, 0), FW_ERROR);
+
+        let output = call(&mut cpu, FW_OPEN, 0x120, 7, MODE_WRITE);
+        assert_eq!(call(&mut cpu, FW_FWRITE, output, 0x100, 7), 7);
+        assert_eq!(call(&mut cpu, FW_OPEN, 0x140, 9, MODE_WRITE), FW_ERROR);
+        assert_eq!(call(&mut cpu, FW_OPEN, 0x100, 11, 9), FW_ERROR);
+
+        let written = fs::read(root.join("out.txt"));
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(written.unwrap(), b"fixture");
+    }
+}
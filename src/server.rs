@@ -0,0 +1,377 @@
+// Headless server for many machines
+// Manages named machine instances and serves them over the remote control protocol, see
+// remote.rs. Each instance runs on its own thread so clients driving different instances do not
+// wait for each other, while requests to the same instance are answered in order.
+//
+// Server requests use opcodes above those of the remote protocol, names are a u8 length followed
+// by UTF-8:
+// 0x20 create        name, then optionally a save state to start from
+// 0x21 destroy       name
+// 0x22 snapshot      name, answered with the save state as memory
+// 0x23 clone         name of the instance to copy, name of the new instance
+// 0x24 list          answered with the names in order
+// 0x25 select        name of the instance that the remote protocol requests go to
+//
+// Snapshots and clones carry the registers, cycle count, and all of physical memory when its size
+// is known. Breakpoints and subscriptions belong to the instance and are not copied, and neither
+// is device state.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::remote::{self, Event, Reader, Request, Response, Session};
+use crate::savestate::SaveState;
+use crate::{Address, Cpu};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerRequest {
+    Create {
+        name: String,
+        state: Option<Vec<u8>>,
+    },
+    Destroy(String),
+    Snapshot(String),
+    Clone {
+        from: String,
+        to: String,
+    },
+    List,
+    Select(String),
+
+    // A remote protocol request for the selected instance
+    Machine(Request),
+}
+
+impl ServerRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        match self {
+            ServerRequest::Create { name, state } => {
+                out.push(0x20);
+                remote::write_name(&mut out, name);
+                if let Some(state) = state {
+                    out.extend_from_slice(state);
+                }
+            }
+            ServerRequest::Destroy(name) => {
+                out.push(0x21);
+                remote::write_name(&mut out, name);
+            }
+            ServerRequest::Snapshot(name) => {
+                out.push(0x22);
+                remote::write_name(&mut out, name);
+            }
+            ServerRequest::Clone { from, to } => {
+                out.push(0x23);
+                remote::write_name(&mut out, from);
+                remote::write_name(&mut out, to);
+            }
+            ServerRequest::List => out.push(0x24),
+            ServerRequest::Select(name) => {
+                out.push(0x25);
+                remote::write_name(&mut out, name);
+            }
+            ServerRequest::Machine(request) => return request.to_bytes(),
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<ServerRequest> {
+        let mut r = Reader { bytes };
+        let request = match bytes.first() {
+            Some(0x20..=0x25) => match r.u8()? {
+                0x20 => {
+                    let name = r.name()?;
+                    let state = Some(r.rest()).filter(|state| !state.is_empty());
+                    ServerRequest::Create { name, state }
+                }
+                0x21 => ServerRequest::Destroy(r.name()?),
+                0x22 => ServerRequest::Snapshot(r.name()?),
+                0x23 => ServerRequest::Clone {
+                    from: r.name()?,
+                    to: r.name()?,
+                },
+                0x24 => ServerRequest::List,
+                0x25 => ServerRequest::Select(r.name()?),
+                _ => unreachable!("nya :("),
+            },
+            _ => return Ok(ServerRequest::Machine(Request::from_bytes(bytes)?)),
+        };
+        if !r.bytes.is_empty() {
+            return Err(remote::protocol_error("trailing bytes after request"));
+        }
+        Ok(request)
+    }
+}
+
+enum Command<const N: usize> {
+    Handle(Request, Sender<(Response, Vec<Event>)>),
+    Capture(Sender<SaveState<N>>),
+}
+
+type Factory<T, const N: usize> = dyn Fn() -> Cpu<T, N> + Send + Sync;
+
+// Cheap to clone, clones share the same instances
+pub struct Server<T, const N: usize = 16>
+where
+    T: Address,
+{
+    factory: Arc<Factory<T, N>>,
+    instances: Arc<Mutex<BTreeMap<String, Sender<Command<N>>>>>,
+}
+
+impl<T, const N: usize> Clone for Server<T, N>
+where
+    T: Address,
+{
+    fn clone(&self) -> Server<T, N> {
+        Server {
+            factory: self.factory.clone(),
+            instances: self.instances.clone(),
+        }
+    }
+}
+
+impl<T, const N: usize> Server<T, N>
+where
+    T: Address + 'static,
+{
+    // New instances are built by the factory on their own thread
+    pub fn new<F>(factory: F) -> Server<T, N>
+    where
+        F: Fn() -> Cpu<T, N> + Send + Sync + 'static,
+    {
+        Server {
+            factory: Arc::new(factory),
+            instances: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.instances.lock().unwrap().keys().cloned().collect()
+    }
+
+    // Starts an instance, restored from a save state if one is given
+    pub fn create(&self, name: &str, state: Option<SaveState<N>>) -> Result<(), String> {
+        let mut instances = self.instances.lock().unwrap();
+        if instances.contains_key(name) {
+            return Err(format!("{} already exists", name));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let factory = self.factory.clone();
+        thread::spawn(move || {
+            let mut session = Session::new(factory());
+            if let Some(state) = state {
+                state.apply(&mut session.cpu);
+            }
+
+            // Ends once the instance is destroyed and the last request is answered
+            for command in receiver {
+                match command {
+                    Command::Handle(request, reply) => {
+                        let mut events = vec![];
+                        let response = session.handle(request, |event| events.push(event));
+                        let _ = reply.send((response, events));
+                    }
+                    Command::Capture(reply) => {
+                        let size = session.cpu.addressing().size().unwrap_or(0);
+                        let len = size.min(u32::MAX as u64) as u32;
+                        let _ = reply.send(SaveState::capture(&mut session.cpu, &[(0, len)]));
+                    }
+                }
+            }
+        });
+        instances.insert(name.to_string(), sender);
+        Ok(())
+    }
+
+    pub fn destroy(&self, name: &str) -> bool {
+        self.instances.lock().unwrap().remove(name).is_some()
+    }
+
+    // None if there is no such instance
+    pub fn snapshot(&self, name: &str) -> Option<SaveState<N>> {
+        let (reply, receiver) = mpsc::channel();
+        self.send(name, Command::Capture(reply))?;
+        receiver.recv().ok()
+    }
+
+    pub fn clone_instance(&self, from: &str, to: &str) -> Result<(), String> {
+        let state = self
+            .snapshot(from)
+            .ok_or_else(|| format!("no instance {}", from))?;
+        self.create(to, Some(state))
+    }
+
+    // None if there is no such instance
+    pub fn request(&self, name: &str, request: Request) -> Option<(Response, Vec<Event>)> {
+        let (reply, receiver) = mpsc::channel();
+        self.send(name, Command::Handle(request, reply))?;
+        receiver.recv().ok()
+    }
+
+    fn send(&self, name: &str, command: Command<N>) -> Option<()> {
+        // The lock is released before waiting for the reply
+        let instances = self.instances.lock().unwrap();
+        instances.get(name)?.send(command).ok()
+    }
+
+    // Answers one request for a connection that has the given instance selected
+    pub fn handle(
+        &self,
+        selected: &mut Option<String>,
+        request: ServerRequest,
+    ) -> (Response, Vec<Event>) {
+        let result = |result: Result<(), String>| match result {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e),
+        };
+        let response = match request {
+            ServerRequest::Create { name, state } => {
+                let state = match state.map(|state| SaveState::from_bytes(&state)) {
+                    Some(Ok(state)) => Some(state),
+                    Some(Err(e)) => return (Response::Error(e.to_string()), vec![]),
+                    None => None,
+                };
+                result(self.create(&name, state))
+            }
+            ServerRequest::Destroy(name) => match self.destroy(&name) {
+                true => Response::Ok,
+                false => Response::Error(format!("no instance {}", name)),
+            },
+            ServerRequest::Snapshot(name) => match self.snapshot(&name) {
+                Some(state) => Response::Memory(state.to_bytes(true)),
+                None => Response::Error(format!("no instance {}", name)),
+            },
+            ServerRequest::Clone { from, to } => result(self.clone_instance(&from, &to)),
+            ServerRequest::List => Response::Names(self.names()),
+            ServerRequest::Select(name) => {
+                *selected = Some(name);
+                Response::Ok
+            }
+            ServerRequest::Machine(request) => {
+                let name = match selected {
+                    Some(name) => name,
+                    None => return (Response::Error("no instance selected".to_string()), vec![]),
+                };
+                match self.request(name, request) {
+                    Some(reply) => return reply,
+                    None => Response::Error(format!("no instance {}", name)),
+                }
+            }
+        };
+        (response, vec![])
+    }
+
+    // Answers requests until the client closes the stream
+    pub fn serve<S: Read + Write>(&self, stream: &mut S) -> io::Result<()> {
+        let mut selected = None;
+        while let Some(frame) = remote::read_frame(stream)? {
+            let (response, events) = match ServerRequest::from_bytes(&frame) {
+                Ok(request) => self.handle(&mut selected, request),
+                Err(e) => (Response::Error(e.to_string()), vec![]),
+            };
+            remote::write_reply(stream, &response, &events)?;
+        }
+        Ok(())
+    }
+
+    // Serves each client on its own thread, forever
+    pub fn listen<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            let mut stream = stream?;
+            let server = self.clone();
+            thread::spawn(move || {
+                let _ = server.serve(&mut stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::{Client, StopReason};
+    use crate::{SimpleAddress, R_PC};
+    use std::net::TcpStream;
+
+    #[test]
+    fn instances() {
+        let server = Server::new(|| Cpu::new(SimpleAddress::new(0x1000)));
+        server.create("a", None).unwrap();
+        assert!(server.create("a", None).is_err());
+
+        // ldi x1, 7
+        let load = Request::Load {
+            addr: 0,
+            data: vec![0x41, 0x07, 0x00, 0x00, 0x00],
+        };
+        assert_eq!(server.request("a", load).unwrap().0, Response::Ok);
+        server.request("a", Request::Step { steps: 1 });
+
+        server.clone_instance("a", "b").unwrap();
+        server.request("a", Request::Step { steps: 1 });
+        let state = server.snapshot("b").unwrap();
+        assert_eq!(state.state.xs[1], 7);
+        assert_eq!(state.state.xs[R_PC], 5);
+        assert_eq!(state.cycles, 1);
+        assert_eq!(server.snapshot("a").unwrap().cycles, 2);
+
+        assert!(server.destroy("a"));
+        assert_eq!(server.names(), vec!["b".to_string()]);
+        assert!(server.request("a", Request::Registers).is_none());
+    }
+
+    #[test]
+    fn over_tcp() {
+        let server = Server::new(|| Cpu::new(SimpleAddress::new(0x1000)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = server.clone();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            serving.serve(&mut stream).unwrap();
+        });
+
+        let mut client = Client::new(TcpStream::connect(addr).unwrap());
+        let mut request = |r: ServerRequest| client.exchange(&r.to_bytes()).unwrap().0;
+        assert!(matches!(
+            request(ServerRequest::Machine(Request::Registers)),
+            Response::Error(_)
+        ));
+        request(ServerRequest::Create {
+            name: "vm".to_string(),
+            state: None,
+        });
+        let snapshot = match request(ServerRequest::Snapshot("vm".to_string())) {
+            Response::Memory(bytes) => bytes,
+            response => panic!("{:?}", response),
+        };
+        request(ServerRequest::Create {
+            name: "restored".to_string(),
+            state: Some(snapshot),
+        });
+        assert_eq!(
+            request(ServerRequest::List),
+            Response::Names(vec!["restored".to_string(), "vm".to_string()])
+        );
+
+        request(ServerRequest::Select("restored".to_string()));
+        assert_eq!(
+            request(ServerRequest::Machine(Request::Step { steps: 3 })),
+            Response::Stopped {
+                reason: StopReason::StepLimit,
+                steps: 3
+            }
+        );
+        assert_eq!(server.snapshot("restored").unwrap().cycles, 3);
+        assert_eq!(server.snapshot("vm").unwrap().cycles, 0);
+    }
+}
@@ -0,0 +1,243 @@
+// Canonical text snapshots of machine state for regression tests
+// The format is stable and line based so snapshot files produce readable diffs when instruction
+// semantics change.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use crate::{
+    Address, Cpu, CpuState, F_CARRY, F_INFINITE, F_INTERRUPT_ENABLE, F_MEMMAP_ENABLE, F_NAN,
+    F_NEGATIVE, F_NESTED_INTERRUPTS, F_OVERFLOW, F_PARITY, F_SHADOW_STACK, F_USER_RING, F_ZERO,
+};
+
+// Environment variable that makes assert_snapshot overwrite mismatching snapshot files
+pub const UPDATE_SNAPSHOTS: &str = "CPUWU_UPDATE_SNAPSHOTS";
+
+fn flag_names(flags: u32) -> String {
+    let names = [
+        (F_NESTED_INTERRUPTS, "I"),
+        (F_SHADOW_STACK, "S"),
+        (F_MEMMAP_ENABLE, "M"),
+        (F_USER_RING, "R"),
+        (F_INFINITE, "F"),
+        (F_NAN, "A"),
+        (F_NEGATIVE, "N"),
+        (F_PARITY, "P"),
+        (F_CARRY, "C"),
+        (F_OVERFLOW, "V"),
+        (F_ZERO, "Z"),
+        (F_INTERRUPT_ENABLE, "Q"),
+    ];
+
+    names
+        .iter()
+        .map(|&(flag, name)| if flags & (1 << flag) != 0 { name } else { "-" })
+        .collect()
+}
+
+pub fn state_snapshot<const N: usize>(state: &CpuState<N>) -> String {
+    let mut out = String::new();
+
+    for (i, x) in state.xs.iter().enumerate() {
+        let _ = writeln!(out, "x{:<2} = {:#010x}", i, x);
+    }
+    for (i, f) in state.fs.iter().enumerate() {
+        let _ = writeln!(out, "f{:<2} = {:#010x} ({:?})", i, f.to_bits(), f);
+    }
+    for (i, v) in state.vs.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "v{:<2} = {:#010x} {:#010x} {:#010x} {:#010x}",
+            i, v[0], v[1], v[2], v[3]
+        );
+    }
+    let _ = writeln!(
+        out,
+        "flags = {:#010x} [{}] last interrupt {}",
+        state.flags,
+        flag_names(state.flags),
+        state.flags & 0x7
+    );
+    let _ = writeln!(out, "mask = {:#010b}", state.interrupt_mask);
+    let _ = writeln!(out, "memmap = {:#010x}", state.memmap);
+    let _ = writeln!(out, "system_sp = {:#010x}", state.system_sp);
+    let _ = writeln!(out, "vector_base = {:#010x}", state.vector_base);
+    let _ = writeln!(out, "fault_address = {:#010x}", state.fault_address);
+    let _ = writeln!(out, "fault_cause = {:#010x}", state.fault_cause);
+    let _ = writeln!(out, "asid = {:#010x}", state.asid);
+    let _ = writeln!(out, "shadow_sp = {:#010x}", state.shadow_sp);
+
+    out
+}
+
+// Hex dump of a range of physical memory, sixteen bytes per line
+pub fn memory_snapshot<T>(memory: &mut T, start: u32, len: u32) -> String
+where
+    T: Address,
+{
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "memory {:#010x}..{:#010x}",
+        start,
+        start.wrapping_add(len)
+    );
+
+    let mut offset = 0;
+    while offset < len {
+        let addr = start.wrapping_add(offset);
+        let count = (len - offset).min(16);
+        let bytes: Vec<u8> = (0..count)
+            .map(|i| memory.read_debug(addr.wrapping_add(i)))
+            .collect();
+
+        let _ = write!(out, "{:08x}:", addr);
+        for byte in bytes.iter() {
+            let _ = write!(out, " {:02x}", byte);
+        }
+        for _ in count..16 {
+            out.push_str("   ");
+        }
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| {
+                if (0x20..0x7f).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, " |{}|", ascii);
+
+        offset += count;
+    }
+
+    out
+}
+
+// Architectural state followed by each of the given (start, length) memory ranges
+pub fn snapshot<T, const N: usize>(cpu: &mut Cpu<T, N>, ranges: &[(u32, u32)]) -> String
+where
+    T: Address,
+{
+    let mut out = state_snapshot(&cpu.state());
+    for &(start, len) in ranges.iter() {
+        out.push('\n');
+        out.push_str(&memory_snapshot(&mut cpu.addressing, start, len));
+    }
+    out
+}
+
+// Compares a snapshot against the contents of a file, creating the file if it does not exist yet
+// Setting CPUWU_UPDATE_SNAPSHOTS rewrites the file instead of failing
+pub fn assert_snapshot<P>(path: P, actual: &str)
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let expected = match fs::read_to_string(path) {
+        Ok(expected) if std::env::var_os(UPDATE_SNAPSHOTS).is_none() => expected,
+        _ => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).expect("failed to create snapshot directory");
+            }
+            fs::write(path, actual).expect("failed to write snapshot");
+            return;
+        }
+    };
+
+    if expected == actual {
+        return;
+    }
+
+    let mut diff = String::new();
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+    for i in 0..expected_lines.len().max(actual_lines.len()) {
+        match (expected_lines.get(i), actual_lines.get(i)) {
+            (Some(e), Some(a)) if e == a => (),
+            (e, a) => {
+                if let Some(e) = e {
+                    let _ = writeln!(diff, "{:>4} - {}", i + 1, e);
+                }
+                if let Some(a) = a {
+                    let _ = writeln!(diff, "{:>4} + {}", i + 1, a);
+                }
+            }
+        }
+    }
+
+    panic!(
+        "snapshot {} does not match (set {} to update):\n{}",
+        path.display(),
+        UPDATE_SNAPSHOTS,
+        diff
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn state_format() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[3] = 0xdeadbeef;
+        cpu.fs[1] = 0.618;
+        cpu.set_vector(2, [1, 2, 3, 0xffff_ffff]);
+        cpu.flags = 1 << F_ZERO | 1 << F_CARRY | 1 << F_SHADOW_STACK | 2;
+
+        let text = state_snapshot(&cpu.state());
+        assert!(text.contains("x3  = 0xdeadbeef\n"));
+        assert!(text.contains("f1  = 0x3f1e353f (0.618)\n"));
+        assert!(text.contains("v2  = 0x00000001 0x00000002 0x00000003 0xffffffff\n"));
+        assert!(text.contains("flags = 0x00002052 [-S------C-Z-] last interrupt 2\n"));
+        assert!(text.contains("shadow_sp = 0x00000000\n"));
+
+        // Machines with more registers list all of them
+        let mut cpu = Cpu::<_, 32>::with_registers(SimpleAddress::default());
+        cpu.xs[31] = 7;
+        let mut state = cpu.state();
+        state.fault_cause = 6;
+        let text = state_snapshot(&state);
+        assert!(text.contains("x31 = 0x00000007\n"));
+        assert!(text.contains("fault_cause = 0x00000006\n"));
+    }
+
+    #[test]
+    fn memory_format() {
+        let mut memory = SimpleAddress::default();
+        for (i, byte) in b"hello, world!!!!uwu".iter().enumerate() {
+            memory.write(0x100 + i as u32, *byte);
+        }
+
+        let text = memory_snapshot(&mut memory, 0x100, 19);
+        assert_eq!(
+            text,
+            "memory 0x00000100..0x00000113\n\
+             00000100: 68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 21 21 21 |hello, world!!!!|\n\
+             00000110: 75 77 75                                        |uwu|\n"
+        );
+    }
+
+    #[test]
+    fn snapshot_files() {
+        let path = std::env::temp_dir()
+            .join(format!("cpuwu-snapshot-{}", std::process::id()))
+            .join("state.snap");
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let text = snapshot(&mut cpu, &[(0, 4)]);
+
+        assert_snapshot(&path, &text);
+        assert_snapshot(&path, &text);
+
+        cpu.xs[0] = 1;
+        let changed = snapshot(&mut cpu, &[(0, 4)]);
+        let result = std::panic::catch_unwind(|| assert_snapshot(&path, &changed));
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        assert!(result.is_err());
+    }
+}
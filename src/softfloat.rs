@@ -0,0 +1,319 @@
+// Software floating point
+// IEEE 754 single precision arithmetic done entirely with integers, so that results are the same
+// bits on every host whatever its FPU does with rounding, subnormals, or extended precision.
+// Rounding is always to nearest, ties to even. Every NaN result is the canonical quiet NaN
+// 0x7fc00000, where hardware FPUs disagree on the sign and payload they produce.
+//
+// Values are passed as their bits so that they never go through host float registers, which may
+// quiet signalling NaNs on the way.
+
+use crate::{Address, Cpu};
+
+const SIGN: u32 = 0x8000_0000;
+const INFINITY: u32 = 0x7f80_0000;
+const FRACTION: u32 = 0x007f_ffff;
+pub const CANONICAL_NAN: u32 = 0x7fc0_0000;
+
+fn is_nan(a: u32) -> bool {
+    a & !SIGN > INFINITY
+}
+
+fn is_infinite(a: u32) -> bool {
+    a & !SIGN == INFINITY
+}
+
+fn is_zero(a: u32) -> bool {
+    a & !SIGN == 0
+}
+
+// Splits a finite non-zero value into its significand and exponent, with the leading one of the
+// significand moved to bit 23 even for subnormals
+fn unpack(a: u32) -> (u32, i32) {
+    let exponent = (a >> 23 & 0xff) as i32;
+    let fraction = a & FRACTION;
+    if exponent == 0 {
+        let shift = fraction.leading_zeros() - 8;
+        (fraction << shift, -149 - shift as i32)
+    } else {
+        (fraction | 1 << 23, exponent - 150)
+    }
+}
+
+// Rounds sign × sig × 2^exp to the nearest value
+// Bits of an inexact sig that were dropped must be kept as a sticky bit below the ones that are
+// exact, so that the value cannot be mistaken for a tie
+fn round_pack(sign: u32, exp: i32, sig: u128) -> u32 {
+    if sig == 0 {
+        return sign;
+    }
+    let shift = sig.leading_zeros();
+    let (sig, exp) = (sig << shift, exp - shift as i32);
+
+    // Value of the lowest bit kept, 24 bits below the leading one or the subnormal step
+    let unit = (exp + 127 - 23).max(-149);
+    let drop = (unit - exp) as u32;
+    let (mut q, rem, half) = match drop {
+        0..=127 => (sig >> drop, sig & ((1 << drop) - 1), 1 << (drop - 1)),
+        128 => (0, sig, 1 << 127),
+        _ => (0, sig, u128::MAX),
+    };
+    if rem > half || rem == half && q & 1 == 1 {
+        q += 1;
+    }
+
+    let (q, unit) = if q == 1 << 24 {
+        (q >> 1, unit + 1)
+    } else {
+        (q, unit)
+    };
+    if q & 1 << 23 == 0 {
+        // Subnormal
+        return sign | q as u32;
+    }
+    let biased = unit + 150;
+    if biased >= 0xff {
+        sign | INFINITY
+    } else {
+        sign | (biased as u32) << 23 | q as u32 & FRACTION
+    }
+}
+
+pub fn add(a: u32, b: u32) -> u32 {
+    if is_nan(a) || is_nan(b) {
+        return CANONICAL_NAN;
+    }
+    if is_infinite(a) || is_infinite(b) {
+        return match (is_infinite(a), is_infinite(b)) {
+            (true, true) if (a ^ b) & SIGN != 0 => CANONICAL_NAN,
+            (true, _) => a,
+            _ => b,
+        };
+    }
+    if is_zero(a) || is_zero(b) {
+        return match (is_zero(a), is_zero(b)) {
+            // -0 only when both are
+            (true, true) => a & b,
+            (true, _) => b,
+            _ => a,
+        };
+    }
+
+    // x is the operand with the larger magnitude
+    let (x, y) = if a & !SIGN >= b & !SIGN {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let ((sig_x, exp_x), (sig_y, exp_y)) = (unpack(x), unpack(y));
+
+    // Aligned with 41 bits below the smaller operand's last bit, the lowest kept sticky
+    let distance = (exp_x - exp_y) as u32;
+    let wide_x = (sig_x as u128) << 41;
+    let wide_y = if distance > 64 {
+        1
+    } else {
+        let aligned = (sig_y as u128) << 40;
+        let sticky = aligned & ((1 << distance) - 1) != 0;
+        (aligned >> distance) << 1 | sticky as u128
+    };
+    let sig = if (x ^ y) & SIGN == 0 {
+        wide_x + wide_y
+    } else {
+        wide_x - wide_y
+    };
+
+    // Exact cancellation is +0
+    if sig == 0 {
+        return 0;
+    }
+    round_pack(x & SIGN, exp_x - 41, sig)
+}
+
+pub fn sub(a: u32, b: u32) -> u32 {
+    add(a, b ^ SIGN)
+}
+
+pub fn mul(a: u32, b: u32) -> u32 {
+    let sign = (a ^ b) & SIGN;
+    if is_nan(a) || is_nan(b) {
+        return CANONICAL_NAN;
+    }
+    if is_infinite(a) || is_infinite(b) {
+        return if is_zero(a) || is_zero(b) {
+            CANONICAL_NAN
+        } else {
+            sign | INFINITY
+        };
+    }
+    if is_zero(a) || is_zero(b) {
+        return sign;
+    }
+
+    let ((sig_a, exp_a), (sig_b, exp_b)) = (unpack(a), unpack(b));
+    round_pack(sign, exp_a + exp_b, sig_a as u128 * sig_b as u128)
+}
+
+pub fn div(a: u32, b: u32) -> u32 {
+    let sign = (a ^ b) & SIGN;
+    if is_nan(a) || is_nan(b) {
+        return CANONICAL_NAN;
+    }
+    match (is_infinite(a), is_infinite(b), is_zero(a), is_zero(b)) {
+        (true, true, _, _) | (_, _, true, true) => return CANONICAL_NAN,
+        (true, _, _, _) | (_, _, _, true) => return sign | INFINITY,
+        (_, true, _, _) | (_, _, true, _) => return sign,
+        _ => (),
+    }
+
+    // The quotient has at least 40 bits, with the remainder kept as a sticky bit below them
+    let ((sig_a, exp_a), (sig_b, exp_b)) = (unpack(a), unpack(b));
+    let dividend = (sig_a as u128) << 64;
+    let (quotient, remainder) = (dividend / sig_b as u128, dividend % sig_b as u128);
+    round_pack(
+        sign,
+        exp_a - exp_b - 65,
+        quotient << 1 | (remainder != 0) as u128,
+    )
+}
+
+pub fn sqrt(a: u32) -> u32 {
+    if is_nan(a) || a & SIGN != 0 && !is_zero(a) {
+        return CANONICAL_NAN;
+    }
+    if is_infinite(a) || is_zero(a) {
+        return a;
+    }
+
+    // With an even exponent and the significand moved up 100 bits, the root has over 60 bits and
+    // whether it is exact is kept as a sticky bit below them
+    let (sig, exp) = unpack(a);
+    let (sig, exp) = if exp & 1 != 0 {
+        ((sig as u128) << 101, exp - 101)
+    } else {
+        ((sig as u128) << 100, exp - 100)
+    };
+    let root = isqrt(sig);
+    round_pack(0, exp / 2 - 1, root << 1 | (root * root != sig) as u128)
+}
+
+// Largest integer whose square is at most x, a digit at a time
+fn isqrt(x: u128) -> u128 {
+    let (mut x, mut root) = (x, 0u128);
+    let mut bit = 1u128 << 126;
+    while bit > x {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if x >= root + bit {
+            x -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+pub fn from_i32(x: i32) -> u32 {
+    let sign = if x < 0 { SIGN } else { 0 };
+    round_pack(sign, 0, x.unsigned_abs() as u128)
+}
+
+// Truncates towards zero, saturating out of range values and turning NaN into 0 like the hardware
+// conversion
+pub fn to_i32(a: u32) -> i32 {
+    if is_nan(a) {
+        return 0;
+    }
+    let negative = a & SIGN != 0;
+    if is_infinite(a) || a & !SIGN >= 0x4f00_0000 {
+        return if negative { i32::MIN } else { i32::MAX };
+    }
+    if a & !SIGN < 0x3f80_0000 {
+        return 0;
+    }
+
+    // Below 2^31, so the significand shifted into place fits
+    let (sig, exp) = unpack(a);
+    let magnitude = if exp < 0 { sig >> -exp } else { sig << exp } as i32;
+    if negative {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // Makes the float opcodes use this module instead of the host FPU, eg so that recordings
+    // replay exactly on another host
+    pub fn set_soft_float(&mut self, soft_float: bool) {
+        self.soft_float = soft_float;
+    }
+
+    pub fn soft_float(&self) -> bool {
+        self.soft_float
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(op: fn(u32, u32) -> u32, host: fn(f32, f32) -> f32, a: u32, b: u32) {
+        let expected = host(f32::from_bits(a), f32::from_bits(b));
+        let result = op(a, b);
+        if expected.is_nan() {
+            assert_eq!(result, CANONICAL_NAN, "{:#x} {:#x}", a, b);
+        } else {
+            assert_eq!(result, expected.to_bits(), "{:#x} {:#x}", a, b);
+        }
+    }
+
+    #[test]
+    fn matches_ieee() {
+        // Edge cases along with a deterministic spread of other values
+        let mut values = vec![
+            0,
+            SIGN,
+            1,
+            0x807f_ffff,
+            0x0080_0000,
+            0x3f80_0000,
+            0xbf80_0000,
+            0x3f80_0001,
+            0x4b80_0000,
+            0x7f7f_ffff,
+            0xff7f_ffff,
+            INFINITY,
+            SIGN | INFINITY,
+            CANONICAL_NAN,
+            0x7f80_0001,
+            0x3400_0000,
+            0x4f00_0000,
+            0xcf00_0000,
+        ];
+        let mut state = 0x1234_5678u32;
+        for _ in 0..120 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            values.push(state);
+        }
+
+        for &a in values.iter() {
+            for &b in values.iter() {
+                check(add, |a, b| a + b, a, b);
+                check(sub, |a, b| a - b, a, b);
+                check(mul, |a, b| a * b, a, b);
+                check(div, |a, b| a / b, a, b);
+            }
+            check(|a, _| sqrt(a), |a, _| a.sqrt(), a, 0);
+            assert_eq!(to_i32(a), f32::from_bits(a) as i32, "{:#x}", a);
+            assert_eq!(from_i32(a as i32), (a as i32 as f32).to_bits(), "{:#x}", a);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+// Source level stepping
+// Steps instructions until execution reaches a different source line, using the line table of an
+// object. Code without line information, such as interrupt handlers from another object, is
+// stepped through. next_line runs a call to completion as if it were one instruction, which ends
+// when the call returns to the instruction after it with the stack popped back to where it was.
+// Both stop early if the cpu shuts down or after a limit on the number of steps.
+
+use crate::object::Object;
+use crate::{Address, Cpu, StepOutcome, R_PC, R_SP};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineStep {
+    // A different source line was reached after this many steps
+    Reached(u64),
+
+    StepLimit,
+    Shutdown,
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn step_line(&mut self, object: &Object, max_steps: u64) -> LineStep {
+        self.line_step(object, max_steps, false)
+    }
+
+    pub fn next_line(&mut self, object: &Object, max_steps: u64) -> LineStep {
+        self.line_step(object, max_steps, true)
+    }
+
+    fn line_step(&mut self, object: &Object, max_steps: u64, over_calls: bool) -> LineStep {
+        let start = object.location(self.xs[R_PC]);
+        let mut steps = 0;
+        loop {
+            // Return address and stack pointer to wait for when stepping over a call
+            let call = if over_calls && self.peek(self.xs[R_PC]) == Some(0x18) {
+                Some((self.xs[R_PC].wrapping_add(5), self.xs[R_SP]))
+            } else {
+                None
+            };
+
+            loop {
+                if steps == max_steps {
+                    return LineStep::StepLimit;
+                }
+                steps += 1;
+                if self.step() == StepOutcome::Shutdown {
+                    return LineStep::Shutdown;
+                }
+
+                match call {
+                    Some((ret, sp)) if self.xs[R_PC] != ret || self.xs[R_SP] < sp => (),
+                    _ => break,
+                }
+            }
+
+            let location = object.location(self.xs[R_PC]);
+            if location.is_some() && location != start {
+                return LineStep::Reached(steps);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Instruction, Operands};
+    use crate::SimpleAddress;
+
+    fn program() -> Object {
+        // main.s:
+        // 1  call f
+        // 2  ldi x1, 1
+        // 3  ldi x2, 2
+        // f:
+        // 4  ldi x3, 3
+        // 5  ret
+        let mut object = Object::new(0x1000);
+        object.set_line("main.s", 1);
+        object.push(&Instruction {
+            opcode: 0x18,
+            operands: Operands::Word(0x100f),
+        });
+        for (line, r) in [(2, 1), (3, 2), (4, 3)].iter() {
+            object.set_line("main.s", *line);
+            object.push(&Instruction {
+                opcode: 0x40,
+                operands: Operands::RegisterWord(*r, *r as u64),
+            });
+        }
+        object.set_line("main.s", 5);
+        object.push(&Instruction {
+            opcode: 0x19,
+            operands: Operands::None,
+        });
+        object
+    }
+
+    fn load(object: &Object) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.load(object.base, &object.code);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_PC] = object.base;
+        cpu
+    }
+
+    #[test]
+    fn step_and_next() {
+        let object = program();
+        let line = |cpu: &Cpu<SimpleAddress>| object.location(cpu.xs[R_PC]).unwrap().line;
+
+        let mut cpu = load(&object);
+        assert_eq!(cpu.step_line(&object, 100), LineStep::Reached(1));
+        assert_eq!(line(&cpu), 4);
+        assert_eq!(cpu.step_line(&object, 100), LineStep::Reached(1));
+        assert_eq!(cpu.step_line(&object, 100), LineStep::Reached(1));
+        assert_eq!(line(&cpu), 2);
+
+        let mut cpu = load(&object);
+        assert_eq!(cpu.next_line(&object, 100), LineStep::Reached(3));
+        assert_eq!(line(&cpu), 2);
+        assert_eq!(cpu.xs[3], 3);
+        assert_eq!(cpu.next_line(&object, 100), LineStep::Reached(1));
+        assert_eq!(line(&cpu), 3);
+
+        let mut cpu = load(&object);
+        assert_eq!(cpu.next_line(&object, 2), LineStep::StepLimit);
+    }
+}
@@ -0,0 +1,121 @@
+// Symbol tables
+// Names for guest addresses, usually the labels of functions and data. An address belongs to the
+// symbol at or below it with the highest address, so a function symbol covers all of its code up
+// to the next symbol.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+}
+
+// A symbol and how far into it an address is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SymbolOffset<'a> {
+    pub symbol: &'a Symbol,
+    pub offset: u32,
+}
+
+impl fmt::Display for SymbolOffset<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        if self.offset == 0 {
+            write!(f, "{}", self.symbol.name)
+        } else {
+            write!(f, "{}+{:#x}", self.symbol.name, self.offset)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolTable {
+    // Sorted by address
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    // Symbols at the same address are kept in the order they were inserted, and the last one is
+    // used to describe the address
+    pub fn insert(&mut self, name: &str, addr: u32) {
+        let i = self.symbols.partition_point(|s| s.addr <= addr);
+        self.symbols.insert(
+            i,
+            Symbol {
+                name: name.to_string(),
+                addr,
+            },
+        );
+    }
+
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    pub fn lookup(&self, addr: u32) -> Option<SymbolOffset<'_>> {
+        let i = self
+            .symbols
+            .partition_point(|s| s.addr <= addr)
+            .checked_sub(1)?;
+        let symbol = &self.symbols[i];
+        Some(SymbolOffset {
+            symbol,
+            offset: addr - symbol.addr,
+        })
+    }
+
+    // Symbolic name of an address, or the address in hex if no symbol covers it
+    pub fn describe(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some(symbol) => symbol.to_string(),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    // Lowest address first
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // Moves every symbol by delta, wrapping around the address space
+    // Symbols that wrap are sorted again
+    pub(crate) fn shift(&mut self, delta: u32) {
+        for symbol in self.symbols.iter_mut() {
+            symbol.addr = symbol.addr.wrapping_add(delta);
+        }
+        self.symbols.sort_by_key(|s| s.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("main", 0x1000);
+        symbols.insert("helper", 0x1040);
+        symbols.insert("_start", 0x800);
+        assert_eq!(symbols.get("helper"), Some(0x1040));
+        assert_eq!(symbols.describe(0x1000), "main");
+        assert_eq!(symbols.describe(0x103f), "main+0x3f");
+        assert_eq!(symbols.describe(0x2000), "helper+0xfc0");
+        assert_eq!(symbols.describe(0x10), "0x00000010");
+
+        symbols.shift(0x100);
+        assert_eq!(symbols.describe(0x1100), "main");
+    }
+}
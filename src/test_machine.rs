@@ -0,0 +1,213 @@
+// Harness for integration tests written as guest programs
+// The guest prints by storing bytes to the output address and finishes by storing an exit code to
+// the exit address (zero for success).
+
+use crate::{Address, Cpu, CpuState, ReadEffect, SimpleAddress, Width};
+
+pub const TEST_OUTPUT: u32 = 0xffff0000;
+pub const TEST_EXIT: u32 = 0xffff0004;
+
+pub struct TestAddress<T>
+where
+    T: Address,
+{
+    inner: T,
+    output_addr: u32,
+    exit_addr: u32,
+    output: Vec<u8>,
+    exit: Option<u8>,
+}
+
+impl<T> TestAddress<T>
+where
+    T: Address,
+{
+    pub fn new(inner: T, output_addr: u32, exit_addr: u32) -> TestAddress<T> {
+        TestAddress {
+            inner,
+            output_addr,
+            exit_addr,
+            output: vec![],
+            exit: None,
+        }
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit
+    }
+}
+
+impl<T> Address for TestAddress<T>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> u8 {
+        self.inner.read(addr)
+    }
+
+    fn fetch(&mut self, addr: u32) -> u8 {
+        self.inner.fetch(addr)
+    }
+
+    fn read_effect(&self, addr: u32) -> ReadEffect {
+        self.inner.read_effect(addr)
+    }
+
+    fn read_debug(&mut self, addr: u32) -> u8 {
+        self.inner.read_debug(addr)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.inner.size()
+    }
+
+    fn bus_error(&mut self) -> Option<u32> {
+        self.inner.bus_error()
+    }
+
+    fn write(&mut self, addr: u32, data: u8) {
+        if addr == self.output_addr {
+            self.output.push(data);
+        } else if addr == self.exit_addr {
+            // Only the first byte of a wider store counts as the exit code
+            self.exit.get_or_insert(data);
+        } else {
+            self.inner.write(addr, data);
+        }
+    }
+
+    fn read_width(&mut self, addr: u32, width: Width) -> u32 {
+        self.inner.read_width(addr, width)
+    }
+
+    fn write_width(&mut self, addr: u32, width: Width, data: u32) {
+        let registers = [self.output_addr, self.exit_addr];
+        if registers
+            .iter()
+            .any(|&r| r.wrapping_sub(addr) < width.bytes())
+        {
+            for i in 0..width.bytes() {
+                self.write(addr.wrapping_add(i), (data >> (8 * i)) as u8);
+            }
+        } else {
+            self.inner.write_width(addr, width, data);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Exited(u8),
+    TimedOut,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestRun {
+    pub outcome: Outcome,
+    pub output: Vec<u8>,
+    pub steps: usize,
+    pub state: CpuState,
+}
+
+impl TestRun {
+    pub fn passed(&self) -> bool {
+        self.outcome == Outcome::Exited(0)
+    }
+
+    pub fn output_string(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+}
+
+pub struct TestMachine {
+    cpu: Cpu<TestAddress<SimpleAddress>>,
+}
+
+impl TestMachine {
+    pub fn new(program: &[u8]) -> TestMachine {
+        TestMachine::with_addresses(program, TEST_OUTPUT, TEST_EXIT)
+    }
+
+    // Loads the program at address 0, where execution starts
+    pub fn with_addresses(program: &[u8], output_addr: u32, exit_addr: u32) -> TestMachine {
+        let mut memory = SimpleAddress::default();
+        for (addr, byte) in program.iter().enumerate() {
+            memory.write(addr as u32, *byte);
+        }
+
+        TestMachine {
+            cpu: Cpu::new(TestAddress::new(memory, output_addr, exit_addr)),
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu<TestAddress<SimpleAddress>> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<TestAddress<SimpleAddress>> {
+        &mut self.cpu
+    }
+
+    // Runs until the guest writes an exit code or max_steps instructions have been executed
+    pub fn run(&mut self, max_steps: usize) -> TestRun {
+        let mut steps = 0;
+        while self.cpu.addressing.exit_code().is_none() && steps < max_steps {
+            self.cpu.step();
+            steps += 1;
+        }
+
+        TestRun {
+            outcome: match self.cpu.addressing.exit_code() {
+                Some(code) => Outcome::Exited(code),
+                None => Outcome::TimedOut,
+            },
+            output: self.cpu.addressing.output().to_vec(),
+            steps,
+            state: self.cpu.state(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn print_and_exit(text: &[u8], code: u8) -> Vec<u8> {
+        let mut program = vec![];
+        for byte in text.iter() {
+            // x0 = byte; store byte x0 -> output
+            program.extend_from_slice(&[0x40, *byte, 0, 0, 0, 0xe0]);
+            program.extend_from_slice(&TEST_OUTPUT.to_le_bytes());
+        }
+        program.extend_from_slice(&[0x40, code, 0, 0, 0, 0xe0]);
+        program.extend_from_slice(&TEST_EXIT.to_le_bytes());
+        program
+    }
+
+    #[test]
+    fn passing_program() {
+        let run = TestMachine::new(&print_and_exit(b"uwu", 0)).run(100);
+        assert!(run.passed());
+        assert_eq!(run.output_string(), "uwu");
+        assert_eq!(run.steps, 8);
+    }
+
+    #[test]
+    fn failing_program() {
+        let run = TestMachine::new(&print_and_exit(b"", 3)).run(100);
+        assert!(!run.passed());
+        assert_eq!(run.outcome, Outcome::Exited(3));
+    }
+
+    #[test]
+    fn timeout() {
+        // Spin forever: mov x13, 0
+        let run = TestMachine::new(&[0x4d, 0, 0, 0, 0]).run(50);
+        assert_eq!(run.outcome, Outcome::TimedOut);
+        assert_eq!(run.steps, 50);
+    }
+}
@@ -0,0 +1,126 @@
+// Address translation queries
+// Looks up how a virtual address would be translated for an access, without performing the access
+// or changing any state. Page tables are read with read_debug.
+
+use crate::{Address, Cpu, InvalidMemoryAccess, EXEC, F_MEMMAP_ENABLE, READ, WRITE};
+
+// Pages mapped by a single memory map entry are 64K
+pub const PAGE_BITS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    // Permission bits required by the access, in the layout used by memory map entries
+    pub fn permissions(self) -> u8 {
+        match self {
+            Access::Read => READ,
+            Access::Write => WRITE,
+            Access::Execute => EXEC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TranslationInfo {
+    pub virtual_address: u32,
+    pub physical_address: u32,
+
+    // Start of the page containing the address
+    pub virtual_base: u32,
+    pub physical_base: u32,
+
+    // Read, write, and execute permission bits (0b100, 0b010, 0b001)
+    pub permissions: u8,
+
+    // Whether the address went through the memory map rather than being used as is
+    pub mapped: bool,
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn translate(
+        &mut self,
+        addr: u32,
+        access: Access,
+    ) -> Result<TranslationInfo, InvalidMemoryAccess> {
+        let offset = addr & ((1 << PAGE_BITS) - 1);
+        if !self.get_flag(F_MEMMAP_ENABLE) {
+            return Ok(TranslationInfo {
+                virtual_address: addr,
+                physical_address: addr,
+                virtual_base: addr - offset,
+                physical_base: addr - offset,
+                permissions: READ | WRITE | EXEC,
+                mapped: false,
+            });
+        }
+
+        let (p, phys) = self
+            .mmu
+            .walk(&mut self.addressing, addr, true)
+            .ok_or(InvalidMemoryAccess::UsedFreePage)?;
+        let required = access.permissions();
+        if p & required != required {
+            return Err(InvalidMemoryAccess::InvalidPermissions(p, required));
+        }
+
+        Ok(TranslationInfo {
+            virtual_address: addr,
+            physical_address: phys,
+            virtual_base: addr - offset,
+            physical_base: phys.wrapping_sub(offset) & 0x0fffffff,
+            permissions: p & 0x07,
+            mapped: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn translation() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        assert_eq!(
+            cpu.translate(0x12345, Access::Write).unwrap(),
+            TranslationInfo {
+                virtual_address: 0x12345,
+                physical_address: 0x12345,
+                virtual_base: 0x10000,
+                physical_base: 0x10000,
+                permissions: 0b111,
+                mapped: false,
+            }
+        );
+
+        // Map virtual 0x01020000-0x0102ffff read and execute to 0x80000
+        cpu.mmu.set_root(0x100);
+        cpu.addressing.load(0x101, &0x200u32.to_le_bytes());
+        cpu.addressing.load(0x202, &0xd0080000u32.to_le_bytes());
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+
+        let info = cpu.translate(0x01020010, Access::Execute).unwrap();
+        assert_eq!(info.physical_address, 0x80010);
+        assert_eq!(info.physical_base, 0x80000);
+        assert_eq!(info.virtual_base, 0x01020000);
+        assert_eq!(info.permissions, 0b101);
+        assert_eq!(
+            cpu.translate(0x01020010, Access::Write),
+            Err(InvalidMemoryAccess::InvalidPermissions(0x0d, WRITE))
+        );
+        assert_eq!(
+            cpu.translate(0x03000000, Access::Read),
+            Err(InvalidMemoryAccess::UsedFreePage)
+        );
+        assert_eq!(cpu.fault_address, 0);
+    }
+}
@@ -0,0 +1,179 @@
+// Layout of the frame pushed to the system stack on interrupt entry
+// The frame is five little endian words. Going up from the lowest address they are the
+// interrupted program counter, x12, flags, base pointer, and stack pointer. After entry the stack
+// pointer points at the start of the frame (or the byte below it with the legacy stack convention).
+
+use crate::{Address, Cpu, InvalidMemoryAccess, READ, R_BASE, WRITE};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TrapFrame {
+    pub pc: u32,
+    pub int: u32,
+    pub flags: u32,
+    pub base: u32,
+    pub sp: u32,
+}
+
+impl TrapFrame {
+    pub const SIZE: u32 = 20;
+
+    pub fn pc(mut self, pc: u32) -> TrapFrame {
+        self.pc = pc;
+        self
+    }
+
+    pub fn int(mut self, int: u32) -> TrapFrame {
+        self.int = int;
+        self
+    }
+
+    pub fn flags(mut self, flags: u32) -> TrapFrame {
+        self.flags = flags;
+        self
+    }
+
+    pub fn base(mut self, base: u32) -> TrapFrame {
+        self.base = base;
+        self
+    }
+
+    pub fn sp(mut self, sp: u32) -> TrapFrame {
+        self.sp = sp;
+        self
+    }
+
+    // Words from the lowest address up
+    pub(crate) fn words(&self) -> [u32; 5] {
+        [self.pc, self.int, self.flags, self.base, self.sp]
+    }
+
+    fn from_words(words: [u32; 5]) -> TrapFrame {
+        TrapFrame {
+            pc: words[0],
+            int: words[1],
+            flags: words[2],
+            base: words[3],
+            sp: words[4],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 20] {
+        let mut bytes = [0; 20];
+        for (i, word) in self.words().iter().enumerate() {
+            bytes[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; 20]) -> TrapFrame {
+        let mut words = [0; 5];
+        for (i, word) in words.iter_mut().enumerate() {
+            let mut b = [0; 4];
+            b.copy_from_slice(&bytes[i * 4..i * 4 + 4]);
+            *word = u32::from_le_bytes(b);
+        }
+        TrapFrame::from_words(words)
+    }
+
+    // Reads a frame from physical memory
+    pub fn read_from<T>(memory: &mut T, addr: u32) -> TrapFrame
+    where
+        T: Address,
+    {
+        let mut bytes = [0; 20];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = memory.read(addr.wrapping_add(i as u32));
+        }
+        TrapFrame::from_bytes(&bytes)
+    }
+
+    // Writes a frame to physical memory
+    pub fn write_to<T>(&self, memory: &mut T, addr: u32)
+    where
+        T: Address,
+    {
+        for (i, byte) in self.to_bytes().iter().enumerate() {
+            memory.write(addr.wrapping_add(i as u32), *byte);
+        }
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    // Reads a frame at a virtual address, without the access being seen by analyses
+    pub fn read_trap_frame(&mut self, addr: u32) -> Result<TrapFrame, InvalidMemoryAccess> {
+        let mut bytes = [0; 20];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let addr = self.check_memory(addr.wrapping_add(i as u32), READ)?;
+            *byte = self.addressing.read(addr);
+        }
+        Ok(TrapFrame::from_bytes(&bytes))
+    }
+
+    // Writes a frame at a virtual address, without the access being seen by analyses
+    pub fn write_trap_frame(
+        &mut self,
+        addr: u32,
+        frame: &TrapFrame,
+    ) -> Result<(), InvalidMemoryAccess> {
+        for (i, byte) in frame.to_bytes().iter().enumerate() {
+            let addr = self.check_memory(addr.wrapping_add(i as u32), WRITE)?;
+            self.addressing.write(addr, *byte);
+            self.on_phys_write(addr);
+        }
+        Ok(())
+    }
+
+    // Frame of the interrupt being handled, as long as the handler has not moved the base pointer
+    pub fn current_trap_frame(&mut self) -> Result<TrapFrame, InvalidMemoryAccess> {
+        self.read_trap_frame(self.stack_top(self.xs[R_BASE]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, NMI_BIT, R_SP};
+
+    #[test]
+    fn round_trip() {
+        let frame = TrapFrame::default()
+            .pc(0x1234)
+            .int(7)
+            .flags(0x808)
+            .base(0xbeef)
+            .sp(0xcafe);
+        let mut memory = SimpleAddress::default();
+        frame.write_to(&mut memory, 0x500);
+        assert_eq!(memory.read(0x500), 0x34);
+        assert_eq!(memory.read(0x504), 7);
+        assert_eq!(memory.read(0x510), 0xfe);
+        assert_eq!(TrapFrame::read_from(&mut memory, 0x500), frame);
+    }
+
+    #[test]
+    fn interrupt_entry() {
+        let mut memory = SimpleAddress::default();
+        // Handler for non-maskable interrupt 2 is at 0x4000
+        for (i, byte) in 0x4000u32.to_le_bytes().iter().enumerate() {
+            memory.write(8 + i as u32, *byte);
+        }
+        let mut cpu = Cpu::new(memory);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_BASE] = 0x7000;
+        cpu.xs[12] = 9;
+        cpu.xs[crate::R_PC] = 0x60;
+        cpu.nmi(2);
+
+        let frame = cpu.current_trap_frame().unwrap();
+        assert_eq!(
+            frame,
+            TrapFrame::default().pc(0x60).int(9).base(0x7000).sp(0x8000)
+        );
+        assert_eq!(cpu.xs[12], 2 | NMI_BIT);
+        assert_eq!(cpu.flags & 0b111, 2);
+        assert_eq!(cpu.read_trap_frame(cpu.xs[R_SP]).unwrap(), frame);
+    }
+}
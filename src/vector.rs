@@ -0,0 +1,145 @@
+// Vector unit
+// Eight 128 bit registers, v0-v7, each holding four 32 bit lanes that the opcodes treat as either
+// integers or floats. The operations are in the escaped page and only decode with the vector
+// feature enabled. Integer lanes wrap, float lanes follow soft float mode like the scalar float
+// opcodes, and neither changes the flags. Comparisons set every bit of a lane where the condition
+// holds and clear it otherwise, so the result can be used as a mask.
+//
+// Loads and stores move all 16 bytes at the address in an integer register, lane 0 first.
+
+use crate::{softfloat, Address, Cpu, InvalidMemoryAccess, Width};
+
+pub const VECTOR_REGISTERS: usize = 8;
+
+pub type Vector = [u32; 4];
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn vector(&self, v: usize) -> Vector {
+        self.vs[v]
+    }
+
+    pub fn set_vector(&mut self, v: usize, lanes: Vector) {
+        self.vs[v] = lanes;
+    }
+
+    fn vector_register(&self, v: usize) -> Result<usize, InvalidMemoryAccess> {
+        if v < VECTOR_REGISTERS {
+            Ok(v)
+        } else {
+            Err(InvalidMemoryAccess::InvalidRegister(v))
+        }
+    }
+
+    fn float_lanes(
+        &self,
+        a: u32,
+        b: u32,
+        host: fn(f32, f32) -> f32,
+        soft: fn(u32, u32) -> u32,
+    ) -> u32 {
+        if self.soft_float {
+            soft(a, b)
+        } else {
+            host(f32::from_bits(a), f32::from_bits(b)).to_bits()
+        }
+    }
+
+    // Executes a vector instruction from the escaped page
+    // 0x20-0x24 - integer add, subtract, multiply, compare equal, compare less (signed)
+    // 0x28-0x2c - the same for floats
+    // 0x30, 0x31 - load and store at the address in an integer register
+    pub(crate) fn vector_op(
+        &mut self,
+        func: u8,
+        fst: usize,
+        snd: usize,
+    ) -> Result<(), InvalidMemoryAccess> {
+        let v0 = self.vector_register(fst)?;
+        match func {
+            0x30 => {
+                let addr = self.xs[snd];
+                let mut lanes = [0; 4];
+                for (i, lane) in lanes.iter_mut().enumerate() {
+                    *lane = self.read_sized(addr.wrapping_add(4 * i as u32), Width::Word)?;
+                }
+                self.vs[v0] = lanes;
+            }
+            0x31 => {
+                let addr = self.xs[snd];
+                for (i, &lane) in self.vs[v0].clone().iter().enumerate() {
+                    self.write_sized(addr.wrapping_add(4 * i as u32), Width::Word, lane)?;
+                }
+            }
+            _ => {
+                let (a, b) = (self.vs[v0], self.vs[self.vector_register(snd)?]);
+                let mut res = [0; 4];
+                for i in 0..4 {
+                    let (x, y) = (a[i], b[i]);
+                    let (fx, fy) = (f32::from_bits(x), f32::from_bits(y));
+                    res[i] = match func {
+                        0x20 => x.wrapping_add(y),
+                        0x21 => x.wrapping_sub(y),
+                        0x22 => x.wrapping_mul(y),
+                        0x23 => 0u32.wrapping_sub((x == y) as u32),
+                        0x24 => 0u32.wrapping_sub(((x as i32) < y as i32) as u32),
+                        0x28 => self.float_lanes(x, y, |a, b| a + b, softfloat::add),
+                        0x29 => self.float_lanes(x, y, |a, b| a - b, softfloat::sub),
+                        0x2a => self.float_lanes(x, y, |a, b| a * b, softfloat::mul),
+                        0x2b => 0u32.wrapping_sub((fx == fy) as u32),
+                        _ => 0u32.wrapping_sub((fx < fy) as u32),
+                    };
+                }
+                self.vs[v0] = res;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Cpu, InvalidMemoryAccess, SimpleAddress, StepOutcome, R_PC, R_SP};
+
+    #[test]
+    fn vector_unit() {
+        // vld v1, x0; vadd.f v1, v1; vcmplt.i v2, v1; vst v2, x1
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let program = [
+            0x3f, 0x30, 0x10, 0x3f, 0x28, 0x11, 0x3f, 0x24, 0x21, 0x3f, 0x31, 0x21,
+        ];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        for (i, x) in [1.5f32, -2.0, 0.0, 3.0].iter().enumerate() {
+            let addr = 0x100 + 4 * i;
+            cpu.addressing.memory[addr..addr + 4].copy_from_slice(&x.to_bits().to_le_bytes());
+        }
+        cpu.xs[0] = 0x100;
+        cpu.xs[1] = 0x200;
+        cpu.set_vector(2, [0x4000_0000, 0, 0, 0x7fff_ffff]);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(
+            cpu.vector(1),
+            [3.0f32.to_bits(), (-4.0f32).to_bits(), 0, 6.0f32.to_bits()]
+        );
+
+        // Lanes are compared as signed integers, so the negative float is less than zero
+        assert_eq!(cpu.vector(2), [u32::MAX, 0, 0, 0]);
+        assert_eq!(
+            cpu.addressing.memory[0x200..0x208],
+            [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]
+        );
+
+        // There are only eight vector registers
+        cpu.addressing.memory[0..3].copy_from_slice(&[0x3f, 0x20, 0x80]);
+        cpu.xs[R_PC] = 0;
+        cpu.xs[R_SP] = 0x8000;
+        assert_eq!(
+            cpu.step(),
+            StepOutcome::Faulted(InvalidMemoryAccess::InvalidRegister(8))
+        );
+    }
+}
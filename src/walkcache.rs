@@ -0,0 +1,115 @@
+// Cache of the top level memory map table
+// Every translated byte used to read its 4 byte top level entry from guest memory. The entries are
+// cached instead, and the cache is emptied when memmap is written, the memory map is turned on or
+// off, the architectural state is restored, or the cpu writes over the table itself. Changes made
+// to the table by anything else, eg another core or a DMA engine, take effect once memmap is
+// written again. Debug walks always read the tables from memory and leave the cache alone.
+
+use crate::{Address, Cpu};
+
+// One entry per 16M of address space, the entries are a byte apart and 4 bytes long
+const TOP_LEVEL_ENTRIES: usize = 256;
+const TOP_LEVEL_SPAN: u32 = TOP_LEVEL_ENTRIES as u32 + 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WalkStats {
+    // Memory map walks made by guest accesses
+    pub walks: u64,
+
+    // Bytes of page table read from memory
+    pub table_reads: u64,
+
+    // Bytes of page table the cache saved reading
+    pub reads_saved: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TopLevelCache {
+    memmap: u32,
+    entries: Box<[Option<u32>]>,
+    pub(crate) stats: WalkStats,
+}
+
+impl TopLevelCache {
+    pub(crate) fn new() -> TopLevelCache {
+        TopLevelCache {
+            memmap: 0,
+            entries: vec![None; TOP_LEVEL_ENTRIES].into_boxed_slice(),
+            stats: WalkStats::default(),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+    }
+
+    // Entries cached for another table are dropped
+    pub(crate) fn get(&mut self, memmap: u32, index: u32) -> Option<u32> {
+        if memmap != self.memmap {
+            self.clear();
+            self.memmap = memmap;
+        }
+        self.entries[index as usize]
+    }
+
+    pub(crate) fn insert(&mut self, index: u32, entry: u32) {
+        self.entries[index as usize] = Some(entry);
+    }
+
+    // Empties the cache if a physical address written to is part of the table
+    pub(crate) fn on_write(&mut self, addr: u32) {
+        if addr.wrapping_sub(self.memmap) < TOP_LEVEL_SPAN {
+            self.clear();
+        }
+    }
+}
+
+impl<T, const N: usize> Cpu<T, N>
+where
+    T: Address,
+{
+    pub fn walk_stats(&self) -> WalkStats {
+        self.mmu.stats()
+    }
+
+    pub fn reset_walk_stats(&mut self) {
+        self.mmu.reset_stats();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InvalidMemoryAccess, SimpleAddress, Width, F_MEMMAP_ENABLE};
+
+    #[test]
+    fn top_level_cache() {
+        // Identity map the first 64K with every permission
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.mmu.set_root(0x100);
+        cpu.addressing.load(0x100, &0x200u32.to_le_bytes());
+        cpu.addressing.load(0x200, &0xf0000000u32.to_le_bytes());
+        cpu.addressing.load(0x10, &0x12345678u32.to_le_bytes());
+        cpu.set_flag(F_MEMMAP_ENABLE, true);
+
+        // Only the first byte reads the top level entry
+        assert_eq!(cpu.read_sized(0x10, Width::Word), Ok(0x12345678));
+        assert_eq!(
+            cpu.walk_stats(),
+            WalkStats {
+                walks: 4,
+                table_reads: 20,
+                reads_saved: 12,
+            }
+        );
+
+        // Writing over the table empties the cache
+        cpu.reset_walk_stats();
+        assert_eq!(cpu.write_sized(0x101, Width::Byte, 0), Ok(()));
+        assert_eq!(
+            cpu.read_sized(0x10, Width::Byte),
+            Err(InvalidMemoryAccess::UsedFreePage)
+        );
+        assert_eq!(cpu.walk_stats().reads_saved, 4);
+    }
+}